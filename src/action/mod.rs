@@ -1,16 +1,126 @@
 use std::path::{Path, PathBuf};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::io::Read;
+use std::time::{Duration, Instant};
 use serde::{Serialize, Deserialize};
-use tracing::{info, debug};
-use crate::error::Result;
+use tracing::{info, debug, warn};
+use crate::backup::Backup;
+use crate::error::{LaszooError, Result};
+use crate::package::ActionRecord;
+use crate::plan::Plan;
+use crate::service::units::{NotifyOutcome, ServiceNotify, Systemd};
+
+/// Captured output kept per stream, the end of longer output is kept
+const MAX_CAPTURED_OUTPUT: usize = 64 * 1024;
+
+/// How long output is still read once a command exited. Whatever it left
+/// running in the background may hold its pipes open for much longer.
+const OUTPUT_GRACE: Duration = Duration::from_millis(500);
 
 /// Action configuration for files/directories
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct ActionConfig {
     /// Command to run before applying changes
     pub before: Option<String>,
     /// Command to run after applying changes
     pub after: Option<String>,
+    /// Command checking rendered content before it is installed, with `%s`
+    /// standing for the path of the file to check
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub validate: Option<String>,
+    /// Units to reload or restart after applying changes
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub notify: Vec<ServiceNotify>,
+}
+
+impl ActionConfig {
+    pub fn is_empty(&self) -> bool {
+        self.before.is_none() && self.after.is_none() && self.validate.is_none() && self.notify.is_empty()
+    }
+
+    /// These actions with `update` applied: the commands it gives replace
+    /// these, an empty one removing it, and so do the units it gives
+    pub fn merge(mut self, update: ActionUpdate) -> Self {
+        fn replace(current: &mut Option<String>, update: Option<String>) {
+            if let Some(command) = update {
+                *current = (!command.is_empty()).then_some(command);
+            }
+        }
+        replace(&mut self.before, update.before);
+        replace(&mut self.after, update.after);
+        replace(&mut self.validate, update.validate);
+        if let Some(notify) = update.notify {
+            self.notify = notify;
+        }
+        self
+    }
+
+    /// What runs after the file is written: the after command, then the
+    /// units to notify
+    pub fn after_steps(&self) -> Vec<AfterStep> {
+        self.after.iter().cloned().map(AfterStep::Command)
+            .chain(self.notify.iter().cloned().map(AfterStep::Notify))
+            .collect()
+    }
+}
+
+impl std::fmt::Display for ActionConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let commands: Vec<String> = [("before", &self.before), ("after", &self.after), ("validate", &self.validate)]
+            .into_iter()
+            .filter_map(|(phase, command)| command.as_ref().map(|c| format!("{} `{}`", phase, c)))
+            .chain(self.notify.iter().map(|n| format!("notify `{}`", n)))
+            .collect();
+        if commands.is_empty() {
+            f.write_str("no actions")
+        } else {
+            f.write_str(&commands.join(", "))
+        }
+    }
+}
+
+/// A change to the actions stored for a file, see `ActionConfig::merge`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ActionUpdate {
+    pub before: Option<String>,
+    pub after: Option<String>,
+    pub validate: Option<String>,
+    /// Units to notify instead, an empty list removing them all
+    pub notify: Option<Vec<ServiceNotify>>,
+}
+
+impl ActionUpdate {
+    pub fn is_empty(&self) -> bool {
+        self.before.is_none() && self.after.is_none() && self.validate.is_none() && self.notify.is_none()
+    }
+}
+
+/// A step run after a file is written
+#[derive(Debug, Clone, PartialEq)]
+pub enum AfterStep {
+    /// A shell command
+    Command(String),
+    /// A unit to reload or restart
+    Notify(ServiceNotify),
+}
+
+impl std::fmt::Display for AfterStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AfterStep::Command(command) => f.write_str(command),
+            AfterStep::Notify(notify) => write!(f, "notify {}", notify),
+        }
+    }
+}
+
+/// Actions in effect for a file on this machine
+#[derive(Debug, Clone)]
+pub struct EffectiveActions {
+    pub path: PathBuf,
+    /// Group the actions were set in, None when set on this machine
+    pub group: Option<String>,
+    pub actions: ActionConfig,
 }
 
 /// Actions manifest for storing file-specific actions
@@ -49,20 +159,274 @@ impl ActionsManifest {
         Ok(())
     }
 
-    pub fn set_actions(&mut self, file_path: &Path, before: Option<String>, after: Option<String>) {
-        if before.is_none() && after.is_none() {
-            // Remove entry if both are None
+    pub fn set_actions(&mut self, file_path: &Path, actions: ActionConfig) {
+        if actions.is_empty() {
+            // Remove entry if no action is left
             self.actions.remove(file_path);
         } else {
-            self.actions.insert(
-                file_path.to_path_buf(),
-                ActionConfig { before, after }
-            );
+            self.actions.insert(file_path.to_path_buf(), actions);
         }
     }
 
+    /// Actions for a file: its own, or else those of the most specific
+    /// directory or glob pattern (`*`, `?` and `**` across directories)
+    /// covering it. The scope with the most literal leading components wins,
+    /// a directory before a glob pattern.
     pub fn get_actions(&self, file_path: &Path) -> Option<&ActionConfig> {
-        self.actions.get(file_path)
+        if let Some(actions) = self.actions.get(file_path) {
+            return Some(actions);
+        }
+        self.actions.iter()
+            .filter(|(scope, _)| scope_covers(scope, file_path))
+            .max_by_key(|(scope, _)| (literal_components(scope), !is_glob(scope), std::cmp::Reverse(*scope)))
+            .map(|(_, actions)| actions)
+    }
+}
+
+/// Components of a scope before its first wildcard
+fn literal_components(scope: &Path) -> usize {
+    scope.components()
+        .take_while(|c| !c.as_os_str().to_string_lossy().contains(['*', '?']))
+        .count()
+}
+
+/// Whether actions set for `scope`, a directory or glob pattern, apply to a file
+pub(crate) fn scope_covers(scope: &Path, file_path: &Path) -> bool {
+    if is_glob(scope) {
+        let pattern: Vec<_> = scope.components().collect();
+        let path: Vec<_> = file_path.components().collect();
+        glob_components(&pattern, &path)
+    } else {
+        file_path != scope && file_path.starts_with(scope)
+    }
+}
+
+/// Whether an action path is a glob pattern rather than a file or directory
+pub fn is_glob(path: &Path) -> bool {
+    path.to_string_lossy().contains(['*', '?'])
+}
+
+fn glob_components(pattern: &[std::path::Component], path: &[std::path::Component]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((first, rest)) if first.as_os_str() == "**" => {
+            (0..=path.len()).any(|skip| glob_components(rest, &path[skip..]))
+        }
+        Some((first, rest)) => match path.split_first() {
+            Some((name, path)) => {
+                glob_name(&first.as_os_str().to_string_lossy(), &name.as_os_str().to_string_lossy())
+                    && glob_components(rest, path)
+            }
+            None => false,
+        },
+    }
+}
+
+/// Match one path component against `*` and `?` wildcards
+fn glob_name(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` was, and the name position it currently matches up to
+    let mut star = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// What an action runs for, passed to the command as `LASZOO_*` environment
+/// variables
+#[derive(Debug, Clone, Default)]
+pub struct ActionContext {
+    pub phase: String,
+    pub group: Option<String>,
+    pub file: Option<PathBuf>,
+    pub template: Option<PathBuf>,
+    /// Every file a coalesced action runs for
+    pub files: Vec<PathBuf>,
+}
+
+impl ActionContext {
+    pub fn new(phase: &str) -> Self {
+        Self { phase: phase.to_string(), ..Default::default() }
+    }
+
+    fn env(&self, hostname: &str) -> Vec<(&'static str, Option<String>)> {
+        let path = |p: &Option<PathBuf>| p.as_ref().map(|p| p.display().to_string());
+        vec![
+            ("LASZOO_PHASE", Some(self.phase.clone())),
+            ("LASZOO_HOST", Some(hostname.to_string())),
+            ("LASZOO_GROUP", self.group.clone()),
+            ("LASZOO_FILE", path(&self.file)),
+            ("LASZOO_TEMPLATE", path(&self.template)),
+            ("LASZOO_FILES", (!self.files.is_empty()).then(|| {
+                self.files.iter().map(|f| f.display().to_string()).collect::<Vec<_>>().join("\n")
+            })),
+        ]
+    }
+}
+
+/// Actions of files applied together, so a command shared by many files
+/// runs once: the before actions of all the files ahead of the first write,
+/// an after action once every file is written. After actions keep the order
+/// their files were applied in.
+///
+/// A batch is planned along with its files and shared with the plan's
+/// changes, which record how the actions went and which files were written.
+#[derive(Debug, Clone, Default)]
+pub struct ActionBatch {
+    state: Arc<Mutex<BatchState>>,
+}
+
+#[derive(Debug, Default)]
+struct BatchState {
+    /// Before actions planned in this batch
+    before_planned: HashSet<String>,
+    /// Before actions run, with the error of those that failed
+    before_run: HashMap<String, Option<String>>,
+    after: Vec<DeferredAction>,
+    /// Files written, with what they replaced
+    written: HashMap<PathBuf, Replaced>,
+}
+
+impl ActionBatch {
+    fn state(&self) -> std::sync::MutexGuard<'_, BatchState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Plan a before action, true unless another file of the batch already has
+    pub fn plan_before(&self, command: &str) -> bool {
+        self.state().before_planned.insert(command.to_string())
+    }
+
+    /// How a before action went in this batch, None when it has yet to run
+    pub fn before_result(&self, command: &str) -> Option<Result<()>> {
+        self.state().before_run.get(command).map(|error| match error {
+            Some(error) => Err(LaszooError::Other(error.clone())),
+            None => Ok(()),
+        })
+    }
+
+    /// Record that a before action ran, and how it went
+    pub fn record_before(&self, command: &str, result: &Result<()>) {
+        let error = result.as_ref().err().map(|e| match e {
+            LaszooError::Other(message) => message.clone(),
+            e => e.to_string(),
+        });
+        self.state().before_run.insert(command.to_string(), error);
+    }
+
+    /// Defer a file's after step, joining the same step deferred for other
+    /// files. It runs once the file is `written`.
+    pub fn defer_after(&self, step: AfterStep, file: DeferredFile) {
+        let mut state = self.state();
+        match state.after.iter_mut().find(|a| a.step == step) {
+            Some(action) => {
+                if !action.files.iter().any(|f| f.path == file.path) {
+                    action.files.push(file);
+                }
+            }
+            None => state.after.push(DeferredAction { step, files: vec![file] }),
+        }
+    }
+
+    /// Record that a file was written, replacing `replaced`
+    pub fn written(&self, path: &Path, replaced: Replaced) {
+        // Written twice, the first backup holds the content to go back to
+        self.state().written.entry(path.to_path_buf()).or_insert(replaced);
+    }
+
+    /// The after steps deferred so far
+    pub fn deferred(&self) -> Vec<DeferredAction> {
+        self.state().after.clone()
+    }
+
+    /// Take the after steps of the files that were written, with what they
+    /// replaced
+    pub fn take_after(&self) -> Vec<DeferredAction> {
+        let mut state = self.state();
+        let after = std::mem::take(&mut state.after);
+        after.into_iter()
+            .filter_map(|mut action| {
+                action.files.retain_mut(|file| match state.written.get(&file.path) {
+                    Some(replaced) => {
+                        file.replaced = replaced.clone();
+                        true
+                    }
+                    None => false,
+                });
+                (!action.files.is_empty()).then_some(action)
+            })
+            .collect()
+    }
+}
+
+/// An after action waiting for the end of a batch
+#[derive(Debug, Clone)]
+pub struct DeferredAction {
+    pub step: AfterStep,
+    pub files: Vec<DeferredFile>,
+}
+
+/// A file written in a batch, with what it replaced
+#[derive(Debug, Clone)]
+pub struct DeferredFile {
+    pub group: String,
+    pub path: PathBuf,
+    pub template: PathBuf,
+    pub replaced: Replaced,
+}
+
+/// What writing a file replaced
+#[derive(Debug, Clone, PartialEq)]
+pub enum Replaced {
+    /// Nothing, the file was created
+    Nothing,
+    /// Content kept in this backup
+    Backup(Backup),
+    /// Content that wasn't backed up
+    Unsaved,
+}
+
+/// How an action command ended and what it printed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ActionOutput {
+    pub command: String,
+    /// Exit code, None when the command was killed
+    pub exit_code: Option<i32>,
+    pub timed_out: bool,
+    pub duration_ms: u64,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl ActionOutput {
+    pub fn success(&self) -> bool {
+        !self.timed_out && self.exit_code == Some(0)
+    }
+
+    fn describe_failure(&self) -> String {
+        if self.timed_out {
+            format!("timed out after {}ms", self.duration_ms)
+        } else {
+            match self.exit_code {
+                Some(code) => format!("exited with status {}", code),
+                None => "killed by a signal".to_string(),
+            }
+        }
     }
 }
 
@@ -70,6 +434,8 @@ impl ActionsManifest {
 pub struct ActionManager {
     mfs_mount: PathBuf,
     hostname: String,
+    timeout: Option<Duration>,
+    services: Systemd,
 }
 
 impl ActionManager {
@@ -78,9 +444,30 @@ impl ActionManager {
             .to_string_lossy()
             .to_string();
             
+        let timeout = crate::config::ActionsConfig::default().timeout();
         Self {
             mfs_mount,
             hostname,
+            timeout,
+            services: Systemd::new(timeout),
+        }
+    }
+
+    /// Kill actions and service manager commands still running after
+    /// `timeout`, None lets them run
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self.services = Systemd::new(timeout);
+        self
+    }
+
+    /// A manager with the same settings, for a plan to carry along
+    fn detached(&self) -> Self {
+        Self {
+            mfs_mount: self.mfs_mount.clone(),
+            hostname: self.hostname.clone(),
+            timeout: self.timeout,
+            services: Systemd::new(self.timeout),
         }
     }
 
@@ -117,100 +504,616 @@ impl ActionManager {
         Ok(None)
     }
 
+    /// Actions stored for a file in a group, or on this machine when no
+    /// group is given
+    pub fn stored_actions(&self, group: Option<&str>, file_path: &Path) -> Result<ActionConfig> {
+        let manifest_path = match group {
+            Some(group) => self.get_group_actions_path(group),
+            None => self.get_machine_actions_path(),
+        };
+        Ok(ActionsManifest::load(&manifest_path)?.actions.get(file_path).cloned().unwrap_or_default())
+    }
+
+    /// Plan changing the stored actions of a file, see `ActionConfig::merge`.
+    /// With `clear` they are all removed. Returns what will be stored.
+    pub fn plan_update_actions(&self, group: Option<&str>, file_path: &Path, update: ActionUpdate, clear: bool, plan: &mut Plan) -> Result<ActionConfig> {
+        let stored = self.stored_actions(group, file_path)?;
+        let actions = if clear { ActionConfig::default() } else { stored.clone().merge(update) };
+        if actions == stored {
+            return Ok(actions);
+        }
+
+        let manifest_path = match group {
+            Some(group) => self.get_group_actions_path(group),
+            None => self.get_machine_actions_path(),
+        };
+        if actions.is_empty() {
+            plan.metadata(&manifest_path, format!("clear actions for {}", file_path.display()));
+        } else {
+            plan.metadata(&manifest_path, format!("set actions for {}: {}", file_path.display(), actions));
+        }
+        let manager = self.detached();
+        let (group, file_path, new) = (group.map(str::to_string), file_path.to_path_buf(), actions.clone());
+        plan.then(move || match group {
+            Some(group) => manager.set_group_actions(&group, &file_path, new),
+            None => manager.set_machine_actions(&file_path, new),
+        });
+        Ok(actions)
+    }
+
+    /// Actions in effect on this machine for every file with actions in
+    /// `groups` or set on this machine, which take precedence. Sorted by path.
+    pub fn effective_actions(&self, groups: &[String]) -> Result<Vec<EffectiveActions>> {
+        let mut effective = std::collections::BTreeMap::new();
+        for group in groups {
+            for (path, actions) in ActionsManifest::load(&self.get_group_actions_path(group))?.actions {
+                effective.entry(path.clone()).or_insert(EffectiveActions { path, group: Some(group.clone()), actions });
+            }
+        }
+        for (path, actions) in ActionsManifest::load(&self.get_machine_actions_path())?.actions {
+            effective.insert(path.clone(), EffectiveActions { path, group: None, actions });
+        }
+        Ok(effective.into_values().collect())
+    }
+
     /// Set actions for a file in a group
-    pub fn set_group_actions(&self, group: &str, file_path: &Path, before: Option<String>, after: Option<String>) -> Result<()> {
+    pub fn set_group_actions(&self, group: &str, file_path: &Path, actions: ActionConfig) -> Result<()> {
         let manifest_path = self.get_group_actions_path(group);
         let mut manifest = ActionsManifest::load(&manifest_path)?;
         
-        manifest.set_actions(file_path, before.clone(), after.clone());
+        let removed = actions.is_empty();
+        log_actions(&actions);
+        manifest.set_actions(file_path, actions);
         manifest.save(&manifest_path)?;
         
-        if before.is_some() || after.is_some() {
-            info!("Set actions for {} in group {}", file_path.display(), group);
-            if let Some(b) = &before {
-                debug!("  Before: {}", b);
-            }
-            if let Some(a) = &after {
-                debug!("  After: {}", a);
-            }
-        } else {
+        if removed {
             info!("Removed actions for {} in group {}", file_path.display(), group);
+        } else {
+            info!("Set actions for {} in group {}", file_path.display(), group);
         }
         
         Ok(())
     }
 
     /// Set actions for a file on this machine
-    pub fn set_machine_actions(&self, file_path: &Path, before: Option<String>, after: Option<String>) -> Result<()> {
+    pub fn set_machine_actions(&self, file_path: &Path, actions: ActionConfig) -> Result<()> {
         let manifest_path = self.get_machine_actions_path();
         let mut manifest = ActionsManifest::load(&manifest_path)?;
         
-        manifest.set_actions(file_path, before.clone(), after.clone());
+        let removed = actions.is_empty();
+        log_actions(&actions);
+        manifest.set_actions(file_path, actions);
         manifest.save(&manifest_path)?;
         
-        if before.is_some() || after.is_some() {
-            info!("Set machine-specific actions for {}", file_path.display());
-            if let Some(b) = &before {
-                debug!("  Before: {}", b);
-            }
-            if let Some(a) = &after {
-                debug!("  After: {}", a);
-            }
-        } else {
+        if removed {
             info!("Removed machine-specific actions for {}", file_path.display());
+        } else {
+            info!("Set machine-specific actions for {}", file_path.display());
         }
         
         Ok(())
     }
 
-    /// Execute an action command
-    pub fn execute_action(&self, command: &str) -> Result<()> {
-        use std::process::Command;
-        
-        info!("Executing action: {}", command);
+    /// Execute an action command with the context in its environment. The
+    /// command is killed, along with anything it started, once the timeout
+    /// passes. Its exit status and output are recorded under
+    /// `actions/<host>/` whether it succeeds or not.
+    pub fn execute_action(&self, command: &str, context: &ActionContext) -> Result<ActionOutput> {
+        info!("Executing {} action: {}", context.phase, command);
         
-        let output = Command::new("sh")
-            .arg("-c")
-            .arg(command)
-            .output()?;
-
-        if output.status.success() {
-            let stdout = String::from_utf8_lossy(&output.stdout);
-            if !stdout.is_empty() {
-                debug!("Action output: {}", stdout);
+        let output = self.run_command(command, context)?;
+        self.record(command, context, &output);
+
+        if output.success() {
+            info!("Action finished in {}ms", output.duration_ms);
+            if !output.stdout.is_empty() {
+                debug!("Action output: {}", output.stdout);
             }
-            Ok(())
+            Ok(output)
         } else {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            Err(crate::error::LaszooError::Other(
-                format!("Action command failed: {}", stderr)
-            ))
+            warn!("Action `{}` {}: {}", command, output.describe_failure(), output.stderr.trim_end());
+            Err(LaszooError::Other(format!(
+                "Action command {}: {}", output.describe_failure(), output.stderr.trim_end()
+            )))
+        }
+    }
+
+    fn run_command(&self, command: &str, context: &ActionContext) -> Result<ActionOutput> {
+        let mut cmd = std::process::Command::new("sh");
+        cmd.arg("-c").arg(command);
+        for (name, value) in context.env(&self.hostname) {
+            match value {
+                Some(value) => cmd.env(name, value),
+                None => cmd.env_remove(name),
+            };
+        }
+        run_process(cmd, command, self.timeout)
+    }
+
+    /// Keep the outcome of an action on the shared filesystem. Failing to
+    /// record does not fail the action.
+    fn record(&self, command: &str, context: &ActionContext, output: &ActionOutput) {
+        let record = ActionRecord {
+            timestamp: chrono::Utc::now(),
+            hostname: self.hostname.clone(),
+            action_type: format!("{}_action", context.phase),
+            target: context.file.as_ref()
+                .map(|f| f.display().to_string())
+                .unwrap_or_else(|| command.to_string()),
+            group: context.group.clone(),
+            status: if output.success() { "completed" } else { "failed" }.to_string(),
+            details: (!output.success()).then(|| output.describe_failure()),
+            output: Some(output.clone()),
+        };
+        if let Err(e) = record.save(&self.mfs_mount) {
+            warn!("Failed to record {} action for {}: {}", context.phase, record.target, e);
         }
     }
 
+    /// The before command of a file, if any
+    pub fn before_action(&self, group: &str, file_path: &Path) -> Result<Option<String>> {
+        Ok(self.load_actions_for_file(group, file_path)?.and_then(|actions| actions.before))
+    }
+
+    /// The steps run after a file is written
+    pub fn after_steps(&self, group: &str, file_path: &Path) -> Result<Vec<AfterStep>> {
+        Ok(self.load_actions_for_file(group, file_path)?.map(|a| a.after_steps()).unwrap_or_default())
+    }
+
     /// Execute before and after actions for a file
-    pub fn execute_file_actions(&self, group: &str, file_path: &Path, phase: ActionPhase) -> Result<()> {
-        if let Some(actions) = self.load_actions_for_file(group, file_path)? {
-            match phase {
-                ActionPhase::Before => {
-                    if let Some(cmd) = &actions.before {
-                        self.execute_action(cmd)?;
-                    }
-                }
-                ActionPhase::After => {
-                    if let Some(cmd) = &actions.after {
-                        self.execute_action(cmd)?;
-                    }
-                }
+    pub fn execute_file_actions(&self, group: &str, file_path: &Path, template_path: &Path, phase: ActionPhase) -> Result<()> {
+        let steps = match phase {
+            ActionPhase::Before => self.before_action(group, file_path)?.into_iter().map(AfterStep::Command).collect(),
+            ActionPhase::After => self.after_steps(group, file_path)?,
+        };
+        let context = ActionContext {
+            group: Some(group.to_string()),
+            file: Some(file_path.to_path_buf()),
+            template: Some(template_path.to_path_buf()),
+            ..ActionContext::new(phase.as_str())
+        };
+        for step in steps {
+            self.run_step(&step, &context)?;
+        }
+        Ok(())
+    }
+
+    fn run_step(&self, step: &AfterStep, context: &ActionContext) -> Result<()> {
+        match step {
+            AfterStep::Command(command) => self.execute_action(command, context).map(|_| ()),
+            AfterStep::Notify(notify) => self.notify_service(notify, context).map(|_| ()),
+        }
+    }
+
+    /// Reload or restart a unit, see `Systemd::notify`, and record the
+    /// outcome with the unit's state like any other action
+    pub fn notify_service(&self, notify: &ServiceNotify, context: &ActionContext) -> Result<NotifyOutcome> {
+        info!("Notifying {}", notify);
+        let result = self.services.notify(notify);
+        let (status, details) = match &result {
+            Ok(outcome) => {
+                info!("{}", outcome);
+                ("completed", outcome.to_string())
             }
+            Err(e) => {
+                warn!("Notifying {} failed: {}", notify, e);
+                ("failed", e.to_string())
+            }
+        };
+
+        let record = ActionRecord {
+            timestamp: chrono::Utc::now(),
+            hostname: self.hostname.clone(),
+            action_type: "notify".to_string(),
+            target: notify.to_string(),
+            group: context.group.clone(),
+            status: status.to_string(),
+            details: Some(details),
+            output: None,
+        };
+        if let Err(e) = record.save(&self.mfs_mount) {
+            warn!("Failed to record notifying {}: {}", notify, e);
+        }
+        result
+    }
+
+    /// Run an after step deferred to the end of a batch, once for all of
+    /// its files. `LASZOO_FILE` and `LASZOO_TEMPLATE` are only set when it
+    /// ran for a single file, `LASZOO_FILES` lists them all.
+    pub fn execute_deferred(&self, action: &DeferredAction) -> Result<()> {
+        let single = match action.files.as_slice() {
+            [file] => Some(file),
+            _ => None,
+        };
+        let context = ActionContext {
+            group: action.files.first().map(|f| f.group.clone()),
+            file: single.map(|f| f.path.clone()),
+            template: single.map(|f| f.template.clone()),
+            files: action.files.iter().map(|f| f.path.clone()).collect(),
+            ..ActionContext::new(ActionPhase::After.as_str())
+        };
+        self.run_step(&action.step, &context)
+    }
+
+    /// Run a file's validate command against `candidate`, rendered content
+    /// that is not in place yet. `%s` in the command stands for the
+    /// candidate's path. Files without a validate command always pass.
+    pub fn validate_file(&self, group: &str, file_path: &Path, template_path: &Path, candidate: &Path) -> Result<()> {
+        let Some(command) = self.load_actions_for_file(group, file_path)?.and_then(|a| a.validate) else {
+            return Ok(());
+        };
+
+        let context = ActionContext {
+            group: Some(group.to_string()),
+            file: Some(file_path.to_path_buf()),
+            template: Some(template_path.to_path_buf()),
+            ..ActionContext::new("validate")
+        };
+        self.execute_action(&validate_command(&command, candidate), &context)
+            .map_err(|e| LaszooError::Other(format!(
+                "Validation of {} failed, the file was left unchanged: {}", file_path.display(), e
+            )))?;
+        Ok(())
+    }
+
+    /// Plan the validation `validate_file` runs for a file
+    pub fn plan_validate(&self, group: &str, file_path: &Path, plan: &mut Plan) -> Result<()> {
+        if let Some(command) = self.load_actions_for_file(group, file_path)?.and_then(|a| a.validate) {
+            plan.action("validate", Some(file_path), &command);
         }
         Ok(())
     }
 }
 
+fn log_actions(actions: &ActionConfig) {
+    if let Some(b) = &actions.before {
+        debug!("  Before: {}", b);
+    }
+    if let Some(a) = &actions.after {
+        debug!("  After: {}", a);
+    }
+    if let Some(v) = &actions.validate {
+        debug!("  Validate: {}", v);
+    }
+    for n in &actions.notify {
+        debug!("  Notify: {}", n);
+    }
+}
+
+/// Put the shell-quoted path of the file to check in place of `%s`
+fn validate_command(command: &str, candidate: &Path) -> String {
+    let quoted = format!("'{}'", candidate.display().to_string().replace('\'', "'\\''"));
+    command.replace("%s", &quoted)
+}
+
+/// Run a command to completion with its output captured. It is killed,
+/// along with anything it started, once `timeout` passes. Processes it left
+/// in the background are not waited for, their output is read for
+/// `OUTPUT_GRACE` at most. The wait happens outside the async runtime's
+/// scheduling, so other tasks keep running.
+pub(crate) fn run_process(mut cmd: std::process::Command, command: &str, timeout: Option<Duration>) -> Result<ActionOutput> {
+    use std::os::unix::process::CommandExt;
+    use std::process::Stdio;
+
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // Its own process group, so a timeout kills whatever it started
+        .process_group(0);
+
+    let started = Instant::now();
+    let mut child = cmd.spawn()?;
+    let pid = child.id();
+    let stdout = capture(child.stdout.take());
+    let stderr = capture(child.stderr.take());
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let _ = tx.send(child.wait());
+    });
+
+    let (status, timed_out, stdout, stderr) = block_in_place(|| -> Result<_> {
+        let waited = match timeout {
+            Some(timeout) => rx.recv_timeout(timeout).ok(),
+            None => rx.recv().ok(),
+        };
+        let (status, timed_out) = match waited {
+            Some(status) => (status?, false),
+            None => {
+                // SAFETY: kill(2) has no memory-safety requirements
+                unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) };
+                let status = rx.recv().map_err(|_| LaszooError::Other(format!("Lost track of `{}`", command)))??;
+                (status, true)
+            }
+        };
+        let deadline = Instant::now() + OUTPUT_GRACE;
+        Ok((status, timed_out, stdout.finish(deadline), stderr.finish(deadline)))
+    })?;
+
+    Ok(ActionOutput {
+        command: command.to_string(),
+        exit_code: status.code(),
+        timed_out,
+        duration_ms: started.elapsed().as_millis() as u64,
+        stdout,
+        stderr,
+    })
+}
+
+/// Run blocking work from inside the runtime, handing this worker's other
+/// tasks to the rest of the pool while it blocks
+fn block_in_place<T>(work: impl FnOnce() -> T) -> T {
+    use tokio::runtime::{Handle, RuntimeFlavor};
+
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => tokio::task::block_in_place(work),
+        _ => work(),
+    }
+}
+
+/// Output of a child's stream, read on a thread of its own
+struct Captured {
+    /// The last `MAX_CAPTURED_OUTPUT` bytes read so far
+    buf: Arc<Mutex<VecDeque<u8>>>,
+    /// Signalled once the stream is closed
+    closed: std::sync::mpsc::Receiver<()>,
+}
+
+impl Captured {
+    /// The output read by the time the stream is closed, or by `deadline`
+    /// if it is still open then
+    fn finish(self, deadline: Instant) -> String {
+        let _ = self.closed.recv_timeout(deadline.saturating_duration_since(Instant::now()));
+        let buf = self.buf.lock().unwrap();
+        let (front, back) = buf.as_slices();
+        String::from_utf8_lossy(&[front, back].concat()).into_owned()
+    }
+}
+
+/// Read a child's output stream on a thread, keeping the end of it when it
+/// runs past `MAX_CAPTURED_OUTPUT`
+fn capture(stream: Option<impl Read + Send + 'static>) -> Captured {
+    let buf = Arc::new(Mutex::new(VecDeque::new()));
+    let (tx, closed) = std::sync::mpsc::channel();
+    let kept = buf.clone();
+    std::thread::spawn(move || {
+        if let Some(mut stream) = stream {
+            let mut chunk = [0u8; 8192];
+            loop {
+                match stream.read(&mut chunk) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        let mut buf = kept.lock().unwrap();
+                        buf.extend(&chunk[..n]);
+                        let excess = buf.len().saturating_sub(MAX_CAPTURED_OUTPUT);
+                        buf.drain(..excess);
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                    Err(_) => break,
+                }
+            }
+        }
+        let _ = tx.send(());
+    });
+    Captured { buf, closed }
+}
+
 /// Phase of action execution
 #[derive(Debug, Clone, Copy)]
 pub enum ActionPhase {
     Before,
     After,
-}
\ No newline at end of file
+}
+
+impl ActionPhase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ActionPhase::Before => "before",
+            ActionPhase::After => "after",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Store an action update the way `laszoo act` does
+    fn update_actions(manager: &ActionManager, group: Option<&str>, file_path: &Path, update: ActionUpdate, clear: bool) -> Result<ActionConfig> {
+        let mut plan = Plan::new();
+        let stored = manager.plan_update_actions(group, file_path, update, clear, &mut plan)?;
+        plan.run_blocking()?;
+        Ok(stored)
+    }
+
+    #[test]
+    fn test_action_gets_context_and_is_recorded() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = ActionManager::new(dir.path().to_path_buf());
+        let context = ActionContext {
+            phase: "after".to_string(),
+            group: Some("web".to_string()),
+            file: Some(PathBuf::from("/etc/app.conf")),
+            template: None,
+            files: Vec::new(),
+        };
+
+        let output = manager.execute_action("echo \"$LASZOO_PHASE $LASZOO_GROUP $LASZOO_FILE\"", &context).unwrap();
+        assert_eq!(output.stdout, "after web /etc/app.conf\n");
+
+        assert!(manager.execute_action("echo broken >&2; exit 3", &context).is_err());
+        let records: Vec<ActionRecord> = std::fs::read_dir(dir.path().join("actions").join(&manager.hostname))
+            .unwrap()
+            .map(|e| serde_json::from_str(&std::fs::read_to_string(e.unwrap().path()).unwrap()).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        let failed = records.iter().find(|r| r.status == "failed").unwrap();
+        let output = failed.output.as_ref().unwrap();
+        assert_eq!(output.exit_code, Some(3));
+        assert_eq!(output.stderr, "broken\n");
+        assert_eq!(failed.target, "/etc/app.conf");
+    }
+
+    #[test]
+    fn test_validate_command_quotes_candidate() {
+        assert_eq!(validate_command("visudo -c -f %s", Path::new("/etc/.sudoers.laszoo-1.tmp")),
+            "visudo -c -f '/etc/.sudoers.laszoo-1.tmp'");
+        assert_eq!(validate_command("check %s", Path::new("/tmp/it's")), "check '/tmp/it'\\''s'");
+    }
+
+    #[test]
+    fn test_batch_runs_each_command_once_in_order() {
+        let file = |name: &str| DeferredFile {
+            group: "web".to_string(),
+            path: PathBuf::from(name),
+            template: PathBuf::from(format!("{}.lasz", name)),
+            replaced: Replaced::Unsaved,
+        };
+        let batch = ActionBatch::default();
+        assert!(batch.plan_before("systemctl stop nginx"));
+        assert!(!batch.plan_before("systemctl stop nginx"));
+        assert!(batch.before_result("systemctl stop nginx").is_none());
+        batch.record_before("systemctl stop nginx", &Ok(()));
+        batch.record_before("systemctl stop php-fpm", &Err(LaszooError::Other("exited with status 1".to_string())));
+        assert!(batch.before_result("systemctl stop nginx").unwrap().is_ok());
+        assert_eq!(batch.before_result("systemctl stop php-fpm").unwrap().unwrap_err().to_string(), "Other error: exited with status 1");
+
+        let nginx = AfterStep::Notify("nginx:reload".parse().unwrap());
+        let php = AfterStep::Command("systemctl reload php-fpm".to_string());
+        batch.defer_after(nginx.clone(), file("/etc/nginx/b.conf"));
+        batch.defer_after(php.clone(), file("/etc/php.ini"));
+        batch.defer_after(nginx.clone(), file("/etc/nginx/a.conf"));
+        batch.defer_after(nginx.clone(), file("/etc/nginx/b.conf"));
+        batch.defer_after(nginx.clone(), file("/etc/nginx/c.conf"));
+
+        // Only files that were written get their after actions
+        for path in ["/etc/nginx/b.conf", "/etc/php.ini", "/etc/nginx/a.conf"] {
+            batch.written(Path::new(path), Replaced::Unsaved);
+        }
+        let after = batch.take_after();
+        let steps: Vec<String> = after.iter().map(|a| a.step.to_string()).collect();
+        assert_eq!(steps, ["notify nginx:reload", "systemctl reload php-fpm"]);
+        let files: Vec<&Path> = after[0].files.iter().map(|f| f.path.as_path()).collect();
+        assert_eq!(files, [Path::new("/etc/nginx/b.conf"), Path::new("/etc/nginx/a.conf")]);
+    }
+
+    #[test]
+    fn test_machine_actions_take_precedence() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = ActionManager::new(dir.path().to_path_buf());
+        let app = Path::new("/etc/app.conf");
+        let db = Path::new("/etc/db.conf");
+        let command = |c: &str| ActionUpdate { after: Some(c.to_string()), ..Default::default() };
+
+        update_actions(&manager, Some("web"), app, command("reload app"), false).unwrap();
+        update_actions(&manager, Some("web"), db, command("reload db"), false).unwrap();
+        let update = ActionUpdate { before: Some("stop app".to_string()), ..Default::default() };
+        let stored = update_actions(&manager, Some("web"), app, update, false).unwrap();
+        assert_eq!(stored.to_string(), "before `stop app`, after `reload app`");
+        update_actions(&manager, None, app, command("restart app"), false).unwrap();
+
+        let effective = manager.effective_actions(&["web".to_string()]).unwrap();
+        assert_eq!(effective.len(), 2);
+        assert_eq!(effective[0].path, app);
+        assert_eq!(effective[0].group, None);
+        assert_eq!(effective[0].actions.to_string(), "after `restart app`");
+        assert_eq!(effective[1].group.as_deref(), Some("web"));
+
+        // An empty command removes just that one, and an empty list of units
+        // the units. Clearing removes the entry.
+        let notify = |units: &[&str]| ActionUpdate { notify: Some(units.iter().map(|u| u.parse().unwrap()).collect()), ..Default::default() };
+        let stored = update_actions(&manager, Some("web"), app, notify(&["app:reload"]), false).unwrap();
+        assert_eq!(stored.to_string(), "before `stop app`, after `reload app`, notify `app:reload`");
+        let stored = update_actions(&manager, Some("web"), app, command(""), false).unwrap();
+        assert_eq!(stored.to_string(), "before `stop app`, notify `app:reload`");
+        let stored = update_actions(&manager, Some("web"), app, notify(&[]), false).unwrap();
+        assert_eq!(stored.to_string(), "before `stop app`");
+        update_actions(&manager, Some("web"), db, ActionUpdate::default(), true).unwrap();
+        assert!(manager.load_actions_for_file("web", db).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_scoped_actions_longest_match() {
+        let mut manifest = ActionsManifest::new();
+        let command = |c: &str| ActionConfig { after: Some(c.to_string()), ..Default::default() };
+        manifest.set_actions(Path::new("/etc/nginx"), command("directory"));
+        manifest.set_actions(Path::new("/etc/nginx/**/*.conf"), command("glob"));
+        manifest.set_actions(Path::new("/etc/nginx/sites-enabled"), command("subdirectory"));
+        manifest.set_actions(Path::new("/etc/nginx/nginx.conf"), command("file"));
+        let after = |path: &str| manifest.get_actions(Path::new(path)).and_then(|a| a.after.clone());
+
+        assert_eq!(after("/etc/nginx/nginx.conf").as_deref(), Some("file"));
+        // As specific as the directory, which wins the tie
+        assert_eq!(after("/etc/nginx/conf.d/gzip.conf").as_deref(), Some("directory"));
+        assert_eq!(after("/etc/nginx/mime.types").as_deref(), Some("directory"));
+        assert_eq!(after("/etc/nginx/sites-enabled/default.conf").as_deref(), Some("subdirectory"));
+        assert_eq!(after("/etc/hosts"), None);
+        assert_eq!(after("/etc/nginx.conf"), None);
+
+        // A longer glob pattern is not more specific than a deeper directory
+        let mut manifest = ActionsManifest::new();
+        manifest.set_actions(Path::new("/etc/nginx/**/*.conf"), command("glob"));
+        manifest.set_actions(Path::new("/etc/nginx/conf.d"), command("conf.d"));
+        let after = |path: &str| manifest.get_actions(Path::new(path)).and_then(|a| a.after.clone());
+        assert_eq!(after("/etc/nginx/conf.d/gzip.conf").as_deref(), Some("conf.d"));
+        assert_eq!(after("/etc/nginx/sites-enabled/default.conf").as_deref(), Some("glob"));
+
+        assert!(glob_name("*.conf", "a.conf"));
+        assert!(glob_name("site-?.c*f", "site-1.conf"));
+        assert!(!glob_name("*.conf", "a.conf.bak"));
+        let components = |p: &'static str| Path::new(p).components().collect::<Vec<_>>();
+        assert!(glob_components(&components("/etc/**/*.conf"), &components("/etc/a.conf")));
+        assert!(!glob_components(&components("/etc/*/*.conf"), &components("/etc/a/b/c.conf")));
+    }
+
+    #[test]
+    fn test_stored_actions_are_exact() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = ActionManager::new(dir.path().to_path_buf());
+        let reload = ActionConfig { after: Some("nginx -s reload".to_string()), ..Default::default() };
+        manager.set_group_actions("web", Path::new("/etc/nginx"), reload.clone()).unwrap();
+
+        // The directory's actions apply to the file but aren't stored for it
+        let file = Path::new("/etc/nginx/nginx.conf");
+        assert_eq!(manager.load_actions_for_file("web", file).unwrap(), Some(reload.clone()));
+        assert!(manager.stored_actions(Some("web"), file).unwrap().is_empty());
+
+        let validate = ActionUpdate { validate: Some("nginx -t -c %s".to_string()), ..Default::default() };
+        let stored = update_actions(&manager, Some("web"), file, validate, false).unwrap();
+        assert_eq!(stored.to_string(), "validate `nginx -t -c %s`");
+        assert_eq!(manager.stored_actions(Some("web"), Path::new("/etc/nginx")).unwrap(), reload);
+    }
+
+    #[test]
+    fn test_action_is_killed_after_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = ActionManager::new(dir.path().to_path_buf())
+            .with_timeout(Some(Duration::from_millis(200)));
+
+        let started = Instant::now();
+        let err = manager.execute_action("sleep 30; echo done", &ActionContext::new("before")).unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(err.to_string().contains("timed out"), "{}", err);
+    }
+
+    #[test]
+    fn test_background_process_does_not_hold_up_action() {
+        let mut cmd = std::process::Command::new("sh");
+        cmd.arg("-c").arg("echo started; sleep 30 &");
+
+        let started = Instant::now();
+        let output = run_process(cmd, "echo started; sleep 30 &", Some(Duration::from_millis(200))).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(output.success(), "{:?}", output);
+        assert_eq!(output.stdout.trim(), "started");
+    }
+
+    #[test]
+    fn test_captured_output_keeps_the_end() {
+        let mut cmd = std::process::Command::new("sh");
+        cmd.arg("-c").arg("head -c 100000 /dev/zero | tr '\\0' a; echo end");
+
+        let output = run_process(cmd, "long output", None).unwrap();
+        assert_eq!(output.stdout.len(), MAX_CAPTURED_OUTPUT);
+        assert!(output.stdout.ends_with("aend\n"));
+    }
+}
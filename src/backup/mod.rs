@@ -0,0 +1,244 @@
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use tracing::{debug, info};
+use crate::config::Config;
+use crate::error::{LaszooError, Result};
+use crate::metadata::FileMetadata;
+
+/// File name format of a backup version, sortable and unique per microsecond
+const VERSION_FORMAT: &str = "%Y%m%dT%H%M%S%.6fZ";
+
+/// A stored version of a file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backup {
+    /// Where the content is stored in the backup directory
+    pub path: PathBuf,
+    /// When the version was replaced
+    pub taken_at: DateTime<Utc>,
+    pub size: u64,
+}
+
+impl Backup {
+    /// Identifier of this version, usable with `--at`
+    pub fn id(&self) -> String {
+        self.path.file_name().unwrap_or_default().to_string_lossy().to_string()
+    }
+}
+
+/// Local shadow store holding the previous content of files Laszoo overwrites.
+///
+/// Versions of `/etc/fstab` live in `<root>/etc/fstab/<timestamp>`, with the
+/// file's metadata in a `.attrs` file next to each version. The store is kept
+/// on local disk so it remains usable when the shared filesystem is not.
+#[derive(Clone)]
+pub struct BackupStore {
+    root: PathBuf,
+    keep: usize,
+    max_age_days: u64,
+}
+
+impl BackupStore {
+    pub fn new(root: PathBuf, keep: usize, max_age_days: u64) -> Self {
+        Self { root, keep, max_age_days }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(
+            config.state_dir.join("backups"),
+            config.backups.keep,
+            config.backups.max_age_days,
+        )
+    }
+
+    /// Directory holding the versions of a file. A file that no longer exists
+    /// is looked up by the absolute path given.
+    fn versions_dir(&self, path: &Path) -> Result<PathBuf> {
+        let path = if path.symlink_metadata().is_ok() {
+            crate::fs::absolute_path(path)?
+        } else {
+            path.to_path_buf()
+        };
+        Ok(self.root.join(path.strip_prefix("/").unwrap_or(&path)))
+    }
+
+    /// Store the current content of a file before it is overwritten.
+    ///
+    /// Nothing is stored if the file does not exist, is not a regular file, or
+    /// matches the latest version already stored.
+    pub fn save(&self, path: &Path) -> Result<Option<Backup>> {
+        if self.keep == 0 || !std::fs::symlink_metadata(path).is_ok_and(|m| m.is_file()) {
+            return Ok(None);
+        }
+
+        let content = std::fs::read(path)?;
+        if let Some(latest) = self.list(path)?.pop() {
+            if crate::fs::content_checksum(&std::fs::read(&latest.path)?) == crate::fs::content_checksum(&content) {
+                debug!("Latest backup of {:?} is current, not storing another", path);
+                return Ok(None);
+            }
+        }
+
+        let dir = self.versions_dir(path)?;
+        std::fs::create_dir_all(&dir)?;
+        let taken_at = Utc::now();
+        let backup_path = dir.join(taken_at.format(VERSION_FORMAT).to_string());
+
+        let metadata = FileMetadata::capture(path)?;
+        crate::fs::atomic_write(&backup_path, &content, |_| Ok(()))?;
+        metadata.save_for_template(&backup_path)?;
+        debug!("Backed up {:?} to {:?}", path, backup_path);
+
+        let pruned = self.prune(path)?;
+        if pruned > 0 {
+            debug!("Removed {} expired backups of {:?}", pruned, path);
+        }
+
+        Ok(Some(Backup { path: backup_path, taken_at, size: content.len() as u64 }))
+    }
+
+    /// Stored versions of a file, oldest first
+    pub fn list(&self, path: &Path) -> Result<Vec<Backup>> {
+        let dir = self.versions_dir(path)?;
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut backups = Vec::new();
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let Ok(taken_at) = NaiveDateTime::parse_from_str(&name, VERSION_FORMAT) else { continue };
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+            backups.push(Backup {
+                path: entry.path(),
+                taken_at: taken_at.and_utc(),
+                size: metadata.len(),
+            });
+        }
+
+        backups.sort_by_key(|b| b.taken_at);
+        Ok(backups)
+    }
+
+    /// The most recent version stored at or before `at`, or the latest one
+    pub fn find(&self, path: &Path, at: Option<DateTime<Utc>>) -> Result<Backup> {
+        self.list(path)?
+            .into_iter()
+            .rev()
+            .find(|b| at.is_none_or(|at| b.taken_at <= at))
+            .ok_or_else(|| match at {
+                Some(at) => LaszooError::Other(format!(
+                    "No backup of {} taken at or before {}", path.display(), at.with_timezone(&Local)
+                )),
+                None => LaszooError::Other(format!("No backup of {}", path.display())),
+            })
+    }
+
+    /// Bring back a stored version of a file.
+    ///
+    /// The content being replaced is itself backed up first, so a restore can
+    /// be undone with another restore.
+    pub fn restore(&self, path: &Path, at: Option<DateTime<Utc>>) -> Result<Backup> {
+        let backup = self.find(path, at)?;
+        let content = std::fs::read(&backup.path)?;
+        let metadata = FileMetadata::load_for_template(&backup.path)?;
+
+        self.save(path)?;
+        crate::fs::atomic_write(path, &content, |temp| match &metadata {
+            Some(metadata) => metadata.apply(temp),
+            None => Ok(()),
+        })?;
+
+        info!("Restored {:?} from backup taken at {}", path, backup.taken_at);
+        Ok(backup)
+    }
+
+    /// Remove versions beyond the retention limits. The most recent version is
+    /// kept regardless of its age.
+    fn prune(&self, path: &Path) -> Result<usize> {
+        let max_age = chrono::Duration::days(self.max_age_days as i64);
+        let now = Utc::now();
+        let mut pruned = 0;
+
+        for (index, backup) in self.list(path)?.into_iter().rev().enumerate() {
+            if index == 0 || (index < self.keep && now - backup.taken_at <= max_age) {
+                continue;
+            }
+            std::fs::remove_file(&backup.path)?;
+            let _ = std::fs::remove_file(FileMetadata::sidecar_path(&backup.path));
+            pruned += 1;
+        }
+
+        Ok(pruned)
+    }
+}
+
+/// Parse a point in time given on the command line: RFC 3339, a backup id,
+/// or a local `YYYY-MM-DD[ HH:MM[:SS]]`. A bare date means the end of that day.
+pub fn parse_time(raw: &str) -> Result<DateTime<Utc>> {
+    let raw = raw.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(raw) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    if let Ok(time) = NaiveDateTime::parse_from_str(raw, VERSION_FORMAT) {
+        return Ok(time.and_utc());
+    }
+
+    let local = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(raw, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(raw, "%Y-%m-%d").ok()
+                .and_then(|date| date.and_hms_opt(23, 59, 59))
+        })
+        .ok_or_else(|| LaszooError::Other(format!("Invalid time: {}", raw)))?;
+
+    Local.from_local_datetime(&local)
+        .earliest()
+        .map(|time| time.with_timezone(&Utc))
+        .ok_or_else(|| LaszooError::Other(format!("Invalid local time: {}", raw)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_restore_and_retention() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = BackupStore::new(dir.path().join("backups"), 2, 30);
+        let file = dir.path().join("fstab");
+
+        for content in ["one", "two", "two", "three"] {
+            std::fs::write(&file, content).unwrap();
+            store.save(&file).unwrap();
+        }
+
+        // Unchanged content is not stored twice, and only two versions are kept
+        let backups = store.list(&file).unwrap();
+        assert_eq!(backups.len(), 2);
+        assert_eq!(std::fs::read_to_string(&backups[0].path).unwrap(), "two");
+
+        let restored = store.restore(&file, Some(backups[0].taken_at)).unwrap();
+        assert_eq!(restored, backups[0]);
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "two");
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(
+            parse_time("2024-05-01T10:00:00Z").unwrap(),
+            Utc.with_ymd_and_hms(2024, 5, 1, 10, 0, 0).unwrap()
+        );
+        assert_eq!(
+            parse_time("20240501T100000.000000Z").unwrap(),
+            Utc.with_ymd_and_hms(2024, 5, 1, 10, 0, 0).unwrap()
+        );
+        assert!(parse_time("2024-05-01").is_ok());
+        assert!(parse_time("yesterday").is_err());
+    }
+}
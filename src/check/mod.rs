@@ -0,0 +1,321 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use serde::Serialize;
+use tracing::debug;
+use crate::enrollment::{EnrollmentManager, FileStatus};
+use crate::error::Result;
+use crate::fs::content_checksum;
+use crate::package::{PackageManager, PackageOperation, PackageStatus};
+
+/// How an item on this machine compares with what apply would make of it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Compliant,
+    Drifted,
+    Missing,
+    Error,
+}
+
+impl CheckStatus {
+    /// Exit code of the status, as Nagios and Icinga plugins use them
+    pub fn exit_code(self) -> i32 {
+        match self {
+            CheckStatus::Compliant => 0,
+            CheckStatus::Drifted => 1,
+            CheckStatus::Missing => 2,
+            CheckStatus::Error => 3,
+        }
+    }
+
+    fn nagios_state(self) -> &'static str {
+        match self {
+            CheckStatus::Compliant => "OK",
+            CheckStatus::Drifted => "WARNING",
+            CheckStatus::Missing => "CRITICAL",
+            CheckStatus::Error => "UNKNOWN",
+        }
+    }
+}
+
+impl fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckStatus::Compliant => write!(f, "compliant"),
+            CheckStatus::Drifted => write!(f, "drifted"),
+            CheckStatus::Missing => write!(f, "missing"),
+            CheckStatus::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// What kind of thing was checked
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckKind {
+    File,
+    Package,
+    Membership,
+}
+
+impl fmt::Display for CheckKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckKind::File => write!(f, "file"),
+            CheckKind::Package => write!(f, "package"),
+            CheckKind::Membership => write!(f, "membership"),
+        }
+    }
+}
+
+/// Result of checking one enrolled file, package operation or group membership
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CheckItem {
+    pub kind: CheckKind,
+    pub group: String,
+    /// File path, package name or group name
+    pub name: String,
+    pub status: CheckStatus,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub detail: String,
+}
+
+impl CheckItem {
+    fn new(kind: CheckKind, group: &str, name: impl Into<String>, status: CheckStatus, detail: impl Into<String>) -> Self {
+        Self { kind, group: group.to_string(), name: name.into(), status, detail: detail.into() }
+    }
+}
+
+/// Results of `laszoo check` for one machine
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CheckReport {
+    pub host: String,
+    pub status: CheckStatus,
+    pub items: Vec<CheckItem>,
+}
+
+impl CheckReport {
+    pub fn new(host: String, items: Vec<CheckItem>) -> Self {
+        let status = items.iter().map(|i| i.status).max().unwrap_or(CheckStatus::Compliant);
+        Self { host, status, items }
+    }
+
+    /// Exit code of the worst item: 0 compliant, 1 drifted, 2 missing, 3 error
+    pub fn exit_code(&self) -> i32 {
+        self.status.exit_code()
+    }
+
+    pub fn count(&self, status: CheckStatus) -> usize {
+        self.items.iter().filter(|i| i.status == status).count()
+    }
+
+    /// One line in the Nagios plugin format, with the counts as performance data
+    pub fn nagios_line(&self) -> String {
+        let statuses = [CheckStatus::Error, CheckStatus::Missing, CheckStatus::Drifted, CheckStatus::Compliant];
+        let summary: Vec<String> = statuses.iter()
+            .filter(|s| self.count(**s) > 0)
+            .map(|s| {
+                let names: Vec<&str> = self.items.iter()
+                    .filter(|i| i.status == *s && *s != CheckStatus::Compliant)
+                    .map(|i| i.name.as_str())
+                    .collect();
+                if names.is_empty() {
+                    format!("{} {}", self.count(*s), s)
+                } else {
+                    format!("{} {} ({})", self.count(*s), s, names.join(", "))
+                }
+            })
+            .collect();
+        let perfdata: Vec<String> = statuses.iter().rev()
+            .map(|s| format!("{}={}", s, self.count(*s)))
+            .collect();
+
+        format!("LASZOO {} - {} | {}",
+            self.status.nagios_state(),
+            if summary.is_empty() { "nothing to check".to_string() } else { summary.join(", ") },
+            perfdata.join(" "))
+    }
+}
+
+/// Evaluates what this machine should look like against what it does, without
+/// changing anything. Files are compared with their templates rendered the way
+/// apply renders them, so a compliant machine is one apply would leave alone.
+pub struct Checker {
+    mfs_mount: PathBuf,
+    hostname: String,
+}
+
+impl Checker {
+    pub fn new(mfs_mount: PathBuf, hostname: String) -> Self {
+        Self { mfs_mount, hostname }
+    }
+
+    /// Check the groups this machine belongs to, or only `group`
+    pub async fn check(&self, group: Option<&str>) -> Result<CheckReport> {
+        crate::fs::ensure_distributed_fs_available(&self.mfs_mount)?;
+
+        let manager = EnrollmentManager::new(self.mfs_mount.clone(), String::new());
+        let machine_groups = manager.load_machine_groups()?;
+        let groups: Vec<String> = match group {
+            Some(group) => vec![group.to_string()],
+            None => machine_groups.clone(),
+        };
+
+        let mut items = self.check_memberships(&machine_groups, &groups, group.is_none());
+        for group in groups.iter().filter(|g| machine_groups.contains(g)) {
+            items.extend(self.check_files(&manager, group)?);
+            items.extend(self.check_packages(group).await?);
+        }
+        items.sort_by(|a, b| a.group.cmp(&b.group));
+        Ok(CheckReport::new(self.hostname.clone(), items))
+    }
+
+    /// A group in the machine's groups.conf needs its membership link. When
+    /// all groups are checked, a link left for a group the machine is no
+    /// longer in counts as drift.
+    fn check_memberships(&self, machine_groups: &[String], groups: &[String], all: bool) -> Vec<CheckItem> {
+        let memberships = self.mfs_mount.join("memberships");
+        let mut items = Vec::new();
+
+        for group in groups {
+            let link = memberships.join(group).join(&self.hostname);
+            let (status, detail) = if !machine_groups.contains(group) {
+                (CheckStatus::Missing, "not in this machine's groups.conf".to_string())
+            } else if link.symlink_metadata().is_err() {
+                (CheckStatus::Missing, format!("no membership link {}", link.display()))
+            } else {
+                (CheckStatus::Compliant, String::new())
+            };
+            items.push(CheckItem::new(CheckKind::Membership, group, group.as_str(), status, detail));
+        }
+
+        if all {
+            let mut stale: Vec<String> = std::fs::read_dir(&memberships).into_iter()
+                .flatten()
+                .flatten()
+                .filter(|e| e.path().join(&self.hostname).symlink_metadata().is_ok())
+                .map(|e| e.file_name().to_string_lossy().into_owned())
+                .filter(|g| !machine_groups.contains(g))
+                .collect();
+            stale.sort();
+            for group in stale {
+                items.push(CheckItem::new(CheckKind::Membership, &group, group.as_str(), CheckStatus::Drifted,
+                    "membership link left for a group not in this machine's groups.conf"));
+            }
+        }
+        items
+    }
+
+    fn check_files(&self, manager: &EnrollmentManager, group: &str) -> Result<Vec<CheckItem>> {
+        let mut templates = manager.list_group_templates(group)?;
+        templates.sort_by(|a, b| a.1.cmp(&b.1));
+
+        Ok(templates.iter()
+            .map(|(template_path, target_path)| {
+                let (status, detail) = check_file(manager, template_path, target_path)
+                    .unwrap_or_else(|e| (CheckStatus::Error, e.to_string()));
+                debug!("Check of {:?}: {}", target_path, status);
+                CheckItem::new(CheckKind::File, group, target_path.display().to_string(), status, detail)
+            })
+            .collect())
+    }
+
+    async fn check_packages(&self, group: &str) -> Result<Vec<CheckItem>> {
+        let operations = PackageManager::new(self.mfs_mount.clone())
+            .load_package_operations(group, Some(&self.hostname))?;
+        if operations.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let Some(pkg_mgr) = crate::package::detect_package_manager() else {
+            return Ok(operations.iter()
+                .filter_map(|op| op.name())
+                .map(|name| CheckItem::new(CheckKind::Package, group, name, CheckStatus::Error,
+                    "no supported package manager detected"))
+                .collect());
+        };
+
+        let mut items = Vec::new();
+        for op in &operations {
+            let (status, detail) = match op {
+                PackageOperation::Install { name } | PackageOperation::Upgrade { name, .. } => {
+                    match crate::package::check_package_status(&pkg_mgr, name).await {
+                        PackageStatus::Missing => (CheckStatus::Missing, "not installed"),
+                        PackageStatus::PendingUpdates if matches!(op, PackageOperation::Upgrade { .. }) => {
+                            (CheckStatus::Drifted, "update available")
+                        }
+                        _ => (CheckStatus::Compliant, ""),
+                    }
+                }
+                PackageOperation::Remove { name } | PackageOperation::Purge { name } => {
+                    match crate::package::check_package_status(&pkg_mgr, name).await {
+                        PackageStatus::Missing => (CheckStatus::Compliant, ""),
+                        _ => (CheckStatus::Drifted, "installed"),
+                    }
+                }
+                PackageOperation::UpgradeAll { .. } => {
+                    if crate::package::check_system_updates(&pkg_mgr).await
+                        && !crate::package::check_phased_updates(&pkg_mgr).await
+                    {
+                        (CheckStatus::Drifted, "updates available")
+                    } else {
+                        (CheckStatus::Compliant, "")
+                    }
+                }
+                // Kept packages are left alone and ++update is a one-off refresh
+                PackageOperation::Keep { .. } | PackageOperation::UpdateAll { .. } => continue,
+            };
+            let name = op.name().unwrap_or("++upgrade");
+            items.push(CheckItem::new(CheckKind::Package, group, name, status, detail));
+        }
+        Ok(items)
+    }
+}
+
+/// Compare a file with its rendered template and the metadata apply would
+/// restore onto it
+fn check_file(manager: &EnrollmentManager, template_path: &Path, target_path: &Path) -> Result<(CheckStatus, String)> {
+    let rendered = manager.render_template_bytes(template_path, target_path)?;
+    let Some(local) = manager.local_content(template_path, target_path)? else {
+        return Ok((CheckStatus::Missing, "does not exist".to_string()));
+    };
+
+    if content_checksum(&local) != content_checksum(&rendered) {
+        let detail = match manager.check_file_status(target_path)? {
+            Some(FileStatus::Modified) => "modified locally",
+            Some(FileStatus::Unchanged) => "template changed since last applied",
+            None => "differs from template",
+        };
+        return Ok((CheckStatus::Drifted, detail.to_string()));
+    }
+
+    if !manager.is_symlink_template(template_path, target_path)? {
+        let changes = manager.metadata_changes(template_path, target_path)?;
+        if !changes.is_empty() {
+            return Ok((CheckStatus::Drifted, changes.join(", ")));
+        }
+    }
+    Ok((CheckStatus::Compliant, String::new()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_worst_item_sets_exit_code_and_nagios_state() {
+        let item = |name: &str, status| CheckItem::new(CheckKind::File, "web", name, status, "");
+        let report = CheckReport::new("web01".to_string(), vec![
+            item("/etc/a.conf", CheckStatus::Compliant),
+            item("/etc/b.conf", CheckStatus::Drifted),
+            item("/etc/c.conf", CheckStatus::Compliant),
+        ]);
+        assert_eq!(report.exit_code(), 1);
+        assert_eq!(report.nagios_line(),
+            "LASZOO WARNING - 1 drifted (/etc/b.conf), 2 compliant | compliant=2 drifted=1 missing=0 error=0");
+
+        let report = CheckReport::new("web01".to_string(), Vec::new());
+        assert_eq!(report.exit_code(), 0);
+        assert!(report.nagios_line().starts_with("LASZOO OK - nothing to check"));
+    }
+}
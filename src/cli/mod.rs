@@ -1,5 +1,6 @@
 use clap::{Parser, Subcommand};
 use std::path::PathBuf;
+use crate::service::units::ServiceNotify;
 
 #[derive(Parser, Debug)]
 #[command(name = "laszoo")]
@@ -57,9 +58,21 @@ pub enum Commands {
         #[arg(long, value_name = "COMMAND", alias = "end")]
         after: Option<String>,
         
-        /// Sync action: converge (default), rollback, freeze, or drift
-        #[arg(long, default_value = "converge")]
-        action: SyncAction,
+        /// Command checking new content before it is installed, `%s` is
+        /// replaced by the path of the file to check (e.g. "visudo -c -f %s")
+        #[arg(long, value_name = "COMMAND")]
+        validate: Option<String>,
+        
+        /// Unit to reload or restart after applying changes, e.g.
+        /// "nginx:reload" (repeatable). Units that can't reload are restarted,
+        /// units that are not running are not reloaded but are restarted.
+        #[arg(long = "notify-service", value_name = "UNIT:ACTION")]
+        notify_service: Vec<ServiceNotify>,
+        
+        /// Sync action for the enrolled files: converge, rollback, freeze, or
+        /// drift (the group's setting, converge by default, if not given)
+        #[arg(long)]
+        action: Option<SyncAction>,
     },
     
     /// Unenroll files from Laszoo management
@@ -72,6 +85,65 @@ pub enum Commands {
         paths: Vec<PathBuf>,
     },
     
+    /// Change the sync action of an enrolled file or directory
+    SetAction {
+        /// Enrolled file or directory
+        path: PathBuf,
+        
+        /// Sync action: converge, rollback, freeze, or drift
+        action: SyncAction,
+        
+        /// Override the action on this machine only
+        #[arg(short, long)]
+        machine: bool,
+    },
+    
+    
+    /// Set or clear the commands run around applying a file, or list them
+    #[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+    Act {
+        #[command(subcommand)]
+        command: Option<ActCommands>,
+        
+        /// Enrolled file or directory, or a glob pattern such as
+        /// "/etc/nginx/**/*.conf"
+        #[arg(required = true)]
+        path: Option<PathBuf>,
+        
+        /// Group to set the actions in
+        #[arg(short, long, required_unless_present = "machine")]
+        group: Option<String>,
+        
+        /// Set the actions on this machine only, replacing the group's
+        #[arg(short, long, conflicts_with = "group")]
+        machine: bool,
+        
+        /// Command to run before applying changes (empty to remove it)
+        #[arg(long, value_name = "COMMAND")]
+        before: Option<String>,
+        
+        /// Command to run after applying changes (empty to remove it)
+        #[arg(long, value_name = "COMMAND")]
+        after: Option<String>,
+        
+        /// Command checking new content before it is installed, `%s` is the
+        /// file to check (empty to remove it)
+        #[arg(long, value_name = "COMMAND")]
+        validate: Option<String>,
+        
+        /// Unit to reload or restart after applying changes, e.g.
+        /// "nginx:reload" (repeatable, replaces the units notified before)
+        #[arg(long = "notify-service", value_name = "UNIT:ACTION")]
+        notify_service: Vec<ServiceNotify>,
+        
+        /// Stop notifying the units notified before
+        #[arg(long, conflicts_with = "notify_service")]
+        no_notify_service: bool,
+        
+        /// Remove all actions of the file
+        #[arg(long, conflicts_with_all = ["before", "after", "validate", "notify_service", "no_notify_service"])]
+        clear: bool,
+    },
     
     /// Show status of enrolled files and synchronization
     Status {
@@ -90,6 +162,73 @@ pub enum Commands {
         commits: u32,
     },
     
+    /// Restore a file from the local backups taken before it was overwritten
+    Restore {
+        /// File to restore
+        path: PathBuf,
+        
+        /// Restore the most recent backup taken at or before this time
+        /// (RFC 3339, "YYYY-MM-DD HH:MM[:SS]", a date, or a backup id)
+        #[arg(long)]
+        at: Option<String>,
+        
+        /// List the available backups instead of restoring
+        #[arg(short, long, conflicts_with = "at")]
+        list: bool,
+    },
+    
+    /// Show or settle conflicting template versions saved by simultaneous converges
+    Resolve {
+        /// Enrolled file with a conflicting template version
+        path: PathBuf,
+        
+        /// Make this host's saved version the template
+        #[arg(long, value_name = "HOST")]
+        take: Option<String>,
+        
+        /// Keep the current template and discard the saved versions
+        #[arg(long, conflicts_with = "take")]
+        keep: bool,
+    },
+    
+    /// Show differences between local files and their rendered templates
+    Diff {
+        /// Group to compare (all groups this machine belongs to if not specified)
+        group: Option<String>,
+        
+        /// Compare only specific files or directories (all if not specified)
+        #[arg(short, long)]
+        files: Vec<PathBuf>,
+        
+        /// Output results as JSON
+        #[arg(long)]
+        json: bool,
+        
+        /// Disable coloured output
+        #[arg(long)]
+        no_color: bool,
+    },
+    
+    /// Check enrolled files, packages and group memberships without changing anything
+    Check {
+        /// Group to check (all groups this machine belongs to if not specified)
+        group: Option<String>,
+
+        /// Output results as JSON
+        #[arg(long, conflicts_with = "nagios")]
+        json: bool,
+
+        /// Print a single Nagios/Icinga plugin line
+        #[arg(long)]
+        nagios: bool,
+    },
+
+    /// Inspect files that drifted from their templates, or accept their drift
+    Drift {
+        #[command(subcommand)]
+        command: DriftCommands,
+    },
+    
     /// Apply templates from a group to the local system
     Apply {
         /// Group name to apply templates from
@@ -115,6 +254,12 @@ pub enum Commands {
         command: GroupsCommands,
     },
     
+    /// Manage template variables for groups and machines
+    Vars {
+        #[command(subcommand)]
+        command: VarsCommands,
+    },
+    
     /// Initialize Laszoo in current directory
     Init {
         /// Shared filesystem mount point
@@ -190,6 +335,12 @@ pub enum Commands {
         command: ServiceCommands,
     },
     
+    /// Take, release and list locks shared by all hosts
+    Lock {
+        #[command(subcommand)]
+        command: LockCommands,
+    },
+    
     /// Launch the web UI
     WebUI {
         /// Port to listen on
@@ -226,7 +377,82 @@ pub enum ServiceCommands {
     Status,
 }
 
-#[derive(clap::ValueEnum, Clone, Debug, Default)]
+#[derive(Subcommand, Debug)]
+pub enum LockCommands {
+    /// Take a lock, held for the calling process until released or expired
+    Acquire {
+        /// Lock name
+        name: String,
+        
+        /// Seconds until the lease expires unless renewed by acquiring again
+        #[arg(long, default_value = "300")]
+        ttl: u64,
+        
+        /// Seconds to wait for the lock to become free
+        #[arg(long, default_value = "0")]
+        wait: u64,
+        
+        /// Process the lease is held for (defaults to the calling process)
+        #[arg(long)]
+        pid: Option<u32>,
+    },
+    
+    /// Release a lock held from this machine
+    Release {
+        /// Lock name
+        name: String,
+        
+        /// Release the lock even when another machine holds it
+        #[arg(long)]
+        force: bool,
+    },
+    
+    /// List locks and their holders
+    List,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum DriftCommands {
+    /// List drifted files
+    List {
+        /// Host to list (this machine if not specified)
+        #[arg(long, conflicts_with = "all")]
+        host: Option<String>,
+        
+        /// List drifted files of every host
+        #[arg(long)]
+        all: bool,
+    },
+    
+    /// Show the recorded drift of a file
+    Show {
+        /// Drifted file
+        path: PathBuf,
+        
+        /// Host the file drifted on (this machine if not specified)
+        #[arg(long)]
+        host: Option<String>,
+    },
+    
+    /// Keep this machine's drifted version as its machine-specific template
+    Accept {
+        /// Drifted file
+        path: PathBuf,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ActCommands {
+    /// List the actions in effect on this machine, machine-specific ones
+    /// replacing the group's
+    List {
+        /// Group to list (all groups this machine belongs to if not specified)
+        group: Option<String>,
+    },
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum SyncAction {
     /// Capture changes from local system and apply to template (default)
     #[default]
@@ -239,6 +465,16 @@ pub enum SyncAction {
     Drift,
 }
 
+impl std::fmt::Display for SyncAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use clap::ValueEnum;
+        match self.to_possible_value() {
+            Some(value) => f.write_str(value.get_name()),
+            None => write!(f, "{:?}", self),
+        }
+    }
+}
+
 #[derive(Subcommand, Debug)]
 pub enum GroupCommands {
     /// Add a machine to this group
@@ -271,4 +507,58 @@ pub enum GroupCommands {
 pub enum GroupsCommands {
     /// List all groups
     List,
-}
\ No newline at end of file
+}
+
+#[derive(Subcommand, Debug)]
+pub enum VarsCommands {
+    /// Show the value of a variable
+    Get {
+        /// Variable name (use dots for nested tables, e.g. nginx.port)
+        key: String,
+        
+        /// Read from this group's variables
+        #[arg(long)]
+        group: Option<String>,
+        
+        /// Read from this machine's variables
+        #[arg(long, conflicts_with = "group")]
+        machine: Option<String>,
+        
+        /// Show the merged value this machine renders templates with
+        #[arg(long, conflicts_with = "machine")]
+        resolved: bool,
+    },
+    
+    /// Set a variable for a group or machine
+    #[command(group(clap::ArgGroup::new("scope").required(true).args(["group", "machine"])))]
+    Set {
+        /// Variable name (use dots for nested tables, e.g. nginx.port)
+        key: String,
+        
+        /// Value (JSON literals such as 8080, true or [1, 2] keep their type)
+        value: String,
+        
+        /// Set the variable for this group
+        #[arg(long)]
+        group: Option<String>,
+        
+        /// Set the variable for this machine
+        #[arg(long)]
+        machine: Option<String>,
+    },
+    
+    /// List variables
+    List {
+        /// List this group's variables
+        #[arg(long)]
+        group: Option<String>,
+        
+        /// List this machine's variables
+        #[arg(long, conflicts_with = "group")]
+        machine: Option<String>,
+        
+        /// List the merged variables this machine renders templates with
+        #[arg(long, conflicts_with = "machine")]
+        resolved: bool,
+    },
+}
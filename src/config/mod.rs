@@ -31,6 +31,18 @@ pub struct Config {
     /// Logging configuration
     #[serde(default)]
     pub logging: LoggingConfig,
+    
+    /// Local state directory for data that must not live on the shared mount
+    #[serde(default = "default_state_dir")]
+    pub state_dir: PathBuf,
+    
+    /// Retention of local backups taken before files are overwritten
+    #[serde(default)]
+    pub backups: BackupConfig,
+    
+    /// Execution of before/after actions
+    #[serde(default)]
+    pub actions: ActionsConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -48,6 +60,30 @@ pub struct MonitoringConfig {
     pub poll_interval: u64,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupConfig {
+    /// Number of versions kept per file (0 disables backups)
+    #[serde(default = "default_backup_keep")]
+    pub keep: usize,
+    
+    /// Versions older than this are removed, except the most recent one
+    #[serde(default = "default_backup_max_age_days")]
+    pub max_age_days: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActionsConfig {
+    /// Seconds an action may run before it is killed (0 for no limit)
+    #[serde(default = "default_action_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+impl ActionsConfig {
+    pub fn timeout(&self) -> Option<std::time::Duration> {
+        (self.timeout_secs > 0).then(|| std::time::Duration::from_secs(self.timeout_secs))
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LoggingConfig {
     /// Log level (trace, debug, info, warn, error)
@@ -72,6 +108,9 @@ impl Default for Config {
             ollama_model: default_ollama_model(),
             monitoring: MonitoringConfig::default(),
             logging: LoggingConfig::default(),
+            state_dir: default_state_dir(),
+            backups: BackupConfig::default(),
+            actions: ActionsConfig::default(),
         }
     }
 }
@@ -86,6 +125,23 @@ impl Default for MonitoringConfig {
     }
 }
 
+impl Default for BackupConfig {
+    fn default() -> Self {
+        Self {
+            keep: default_backup_keep(),
+            max_age_days: default_backup_max_age_days(),
+        }
+    }
+}
+
+impl Default for ActionsConfig {
+    fn default() -> Self {
+        Self {
+            timeout_secs: default_action_timeout_secs(),
+        }
+    }
+}
+
 impl Default for LoggingConfig {
     fn default() -> Self {
         Self {
@@ -131,6 +187,14 @@ impl Config {
         // Apply environment variable overrides
         config.apply_env_overrides();
         
+        // Only root can write the default state directory
+        if config.state_dir == default_state_dir() && !is_writable(&config.state_dir) {
+            if let Some(user_dir) = user_state_dir() {
+                tracing::debug!("{:?} is not writable, keeping state in {:?}", config.state_dir, user_dir);
+                config.state_dir = user_dir;
+            }
+        }
+        
         Ok(config)
     }
     
@@ -165,6 +229,14 @@ impl Config {
         if let Ok(level) = std::env::var("LASZOO_LOG_LEVEL") {
             self.logging.level = level;
         }
+        
+        if let Ok(state_dir) = std::env::var("LASZOO_STATE_DIR") {
+            self.state_dir = PathBuf::from(state_dir);
+        }
+        
+        if let Ok(timeout) = std::env::var("LASZOO_ACTION_TIMEOUT") {
+            self.actions.timeout_secs = timeout.parse().unwrap_or(self.actions.timeout_secs);
+        }
     }
     
     pub fn save(&self, path: &Path) -> Result<()> {
@@ -224,6 +296,45 @@ fn default_poll_interval() -> u64 {
     30
 }
 
+fn default_state_dir() -> PathBuf {
+    PathBuf::from("/var/lib/laszoo")
+}
+
+/// `$XDG_STATE_HOME/laszoo`, by default `~/.local/state/laszoo`
+fn user_state_dir() -> Option<PathBuf> {
+    let state_home = std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state")))?;
+    Some(state_home.join("laszoo"))
+}
+
+/// Whether `dir`, or the closest ancestor that exists when it doesn't yet,
+/// can be written by this process
+fn is_writable(dir: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+    
+    let Some(existing) = dir.ancestors().find(|p| p.exists()) else {
+        return false;
+    };
+    let Ok(path) = std::ffi::CString::new(existing.as_os_str().as_bytes()) else {
+        return false;
+    };
+    unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 }
+}
+
+fn default_backup_keep() -> usize {
+    10
+}
+
+fn default_backup_max_age_days() -> u64 {
+    30
+}
+
+fn default_action_timeout_secs() -> u64 {
+    300
+}
+
 fn default_log_level() -> String {
     "info".to_string()
 }
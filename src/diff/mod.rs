@@ -0,0 +1,148 @@
+use std::path::{Path, PathBuf};
+use serde::Serialize;
+use similar::TextDiff;
+use tracing::debug;
+use crate::enrollment::EnrollmentManager;
+use crate::error::Result;
+use crate::fs::{content_checksum, is_binary_content};
+
+/// How a local file compares with its rendered template
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffStatus {
+    Unchanged,
+    Drifted,
+    Missing,
+    /// The template could not be rendered or the file read
+    Error,
+}
+
+/// Difference between a local file and what apply would write
+#[derive(Debug, Clone, Serialize)]
+pub struct FileDiff {
+    pub group: String,
+    pub path: PathBuf,
+    pub template_path: PathBuf,
+    pub status: DiffStatus,
+    /// Unified diff from the local file to the rendered template
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub diff: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl FileDiff {
+    pub fn has_drift(&self) -> bool {
+        matches!(self.status, DiffStatus::Drifted | DiffStatus::Missing)
+    }
+}
+
+/// Compare every template in a group with the local file it renders to.
+/// Files that can't be compared are listed with the error.
+///
+/// `files` restricts the comparison to the given paths, or to files below the
+/// given directories; an empty list compares everything.
+pub fn diff_group(manager: &EnrollmentManager, group: &str, files: &[PathBuf]) -> Result<Vec<FileDiff>> {
+    let mut diffs = Vec::new();
+
+    for (template_path, target_path) in manager.list_group_templates(group)? {
+        if !files.is_empty() && !files.iter().any(|f| target_path.starts_with(f)) {
+            continue;
+        }
+
+        let file_diff = diff_file(manager, group, &template_path, &target_path)
+            .unwrap_or_else(|e| FileDiff {
+                group: group.to_string(),
+                path: target_path,
+                template_path,
+                status: DiffStatus::Error,
+                diff: String::new(),
+                error: Some(e.to_string()),
+            });
+        diffs.push(file_diff);
+    }
+
+    diffs.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(diffs)
+}
+
+fn diff_file(manager: &EnrollmentManager, group: &str, template_path: &Path, target_path: &Path) -> Result<FileDiff> {
+    let rendered = manager.render_template_bytes(template_path, target_path)?;
+    let (status, local) = if let Some(local) = manager.local_content(template_path, target_path)? {
+        let status = if content_checksum(&local) == content_checksum(&rendered) {
+            DiffStatus::Unchanged
+        } else {
+            DiffStatus::Drifted
+        };
+        (status, local)
+    } else {
+        (DiffStatus::Missing, Vec::new())
+    };
+
+    debug!("Diff status for {:?}: {:?}", target_path, status);
+
+    let old_label = format!("{} (local)", target_path.display());
+    let new_label = format!("{} (rendered from {})", target_path.display(), group);
+    let diff = if status == DiffStatus::Unchanged {
+        String::new()
+    } else if is_binary_content(&local) || is_binary_content(&rendered) {
+        format!("Binary files {} and {} differ\n", old_label, new_label)
+    } else {
+        unified_diff(
+            &String::from_utf8_lossy(&local),
+            &String::from_utf8_lossy(&rendered),
+            &old_label,
+            &new_label,
+        )
+    };
+
+    Ok(FileDiff {
+        group: group.to_string(),
+        path: target_path.to_path_buf(),
+        template_path: template_path.to_path_buf(),
+        status,
+        diff,
+        error: None,
+    })
+}
+
+/// Produce a unified diff with three lines of context
+pub fn unified_diff(old: &str, new: &str, old_label: &str, new_label: &str) -> String {
+    TextDiff::from_lines(old, new)
+        .unified_diff()
+        .context_radius(3)
+        .header(old_label, new_label)
+        .missing_newline_hint(true)
+        .to_string()
+}
+
+/// Colour a unified diff with ANSI escapes for terminal output
+pub fn colorize(diff: &str) -> String {
+    diff.lines()
+        .map(|line| {
+            let colour = if line.starts_with("+++") || line.starts_with("---") {
+                "\x1b[1m"
+            } else if line.starts_with("@@") {
+                "\x1b[36m"
+            } else if line.starts_with('+') {
+                "\x1b[32m"
+            } else if line.starts_with('-') {
+                "\x1b[31m"
+            } else {
+                return format!("{}\n", line);
+            };
+            format!("{}{}\x1b[0m\n", colour, line)
+        })
+        .collect()
+}
+
+/// Resolve a user-supplied path filter to the absolute form used in manifests
+pub fn resolve_filter_path(path: &Path) -> Result<PathBuf> {
+    if path.exists() {
+        Ok(path.canonicalize()?)
+    } else if path.is_absolute() {
+        Ok(path.to_path_buf())
+    } else {
+        Ok(std::env::current_dir()?.join(path))
+    }
+}
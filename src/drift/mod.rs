@@ -0,0 +1,265 @@
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+use tracing::debug;
+use crate::enrollment::EnrollmentManager;
+use crate::error::Result;
+use crate::fs::{content_checksum, is_binary_content};
+use crate::plan::Plan;
+
+/// Records kept per file, older ones are dropped
+const MAX_RECORDS: usize = 100;
+
+/// One observation of a file that differs from its rendered template
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DriftRecord {
+    pub recorded_at: chrono::DateTime<chrono::Utc>,
+    /// Checksum of the local content
+    pub hash: String,
+    /// Unified diff from the rendered template to the local file
+    pub diff: String,
+}
+
+/// A rollback vote that ended in a split. The file is left as it is until
+/// its template or its content changes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SplitVerdict {
+    /// Checksum of the group template the vote was taken on
+    pub template_hash: String,
+    pub majority_hosts: Vec<String>,
+    pub minority_hosts: Vec<String>,
+}
+
+/// Drift of one file on one host
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DriftEntry {
+    pub path: PathBuf,
+    pub group: String,
+    pub host: String,
+    /// When the file first drifted, kept until it is back in sync or accepted
+    pub first_drift: chrono::DateTime<chrono::Utc>,
+    /// Observations, oldest first
+    pub records: Vec<DriftRecord>,
+    /// Set for a rollback file the group couldn't agree on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub split: Option<SplitVerdict>,
+}
+
+impl DriftEntry {
+    pub fn latest(&self) -> Option<&DriftRecord> {
+        self.records.last()
+    }
+}
+
+/// Ledger of files using the `drift` sync action that differ from their
+/// template, and of rollback files left alone after a split vote, kept per
+/// host under `machines/<host>/drift/` on the shared filesystem as one JSON
+/// file per drifted path.
+#[derive(Clone)]
+pub struct DriftLedger {
+    mfs_mount: PathBuf,
+    hostname: String,
+}
+
+impl DriftLedger {
+    pub fn new(mfs_mount: PathBuf, hostname: String) -> Self {
+        Self { mfs_mount, hostname }
+    }
+
+    fn host_dir(&self, host: &str) -> PathBuf {
+        crate::fs::get_machine_dir(&self.mfs_mount, "", host).join("drift")
+    }
+
+    fn entry_path(&self, host: &str, path: &Path) -> PathBuf {
+        let mut entry_path = self.host_dir(host).join(path.strip_prefix("/").unwrap_or(path));
+        let name = format!("{}.json", entry_path.file_name().unwrap_or_default().to_string_lossy());
+        entry_path.set_file_name(name);
+        entry_path
+    }
+
+    /// Compare an enrolled file with its rendered template. Plans recording
+    /// a difference, or removing the entry of a file that matches its
+    /// template again; `current` tells which once the plan ran.
+    pub fn plan_check(&self, manager: &EnrollmentManager, group: &str, template_path: &Path, file_path: &Path, plan: &mut Plan) -> Result<()> {
+        let rendered = manager.render_template_bytes(template_path, file_path)?;
+        match manager.local_content(template_path, file_path)? {
+            Some(local) if content_checksum(&local) != content_checksum(&rendered) => {
+                let hash = content_checksum(&local);
+                let recorded = self.current(file_path)?;
+                if recorded.as_ref().and_then(|e| e.latest()).is_none_or(|r| r.hash != hash) {
+                    plan.metadata(&self.entry_path(&self.hostname, file_path),
+                        format!("record drift of {}", file_path.display()));
+                }
+                let (ledger, path, group) = (self.clone(), file_path.to_path_buf(), group.to_string());
+                plan.then(move || ledger.record(&path, &group, &rendered, &local).map(|_| ()));
+                Ok(())
+            }
+            _ => self.plan_clear(file_path, plan),
+        }
+    }
+
+    /// Add an observation of a drifted file, unless the content is the same
+    /// as last recorded
+    pub fn record(&self, path: &Path, group: &str, rendered: &[u8], local: &[u8]) -> Result<DriftEntry> {
+        self.observe(path, group, rendered, local, None)
+    }
+
+    /// Record a rollback file left unchanged after a split vote
+    pub fn record_split(&self, path: &Path, group: &str, rendered: &[u8], local: &[u8], verdict: SplitVerdict) -> Result<DriftEntry> {
+        self.observe(path, group, rendered, local, Some(verdict))
+    }
+
+    /// Plan `record_split`
+    pub fn plan_record_split(&self, path: &Path, group: &str, rendered: Vec<u8>, local: Vec<u8>, verdict: SplitVerdict, plan: &mut Plan) {
+        plan.metadata(&self.entry_path(&self.hostname, path), format!("record split vote on {}", path.display()));
+        let (ledger, path, group) = (self.clone(), path.to_path_buf(), group.to_string());
+        plan.then(move || ledger.record_split(&path, &group, &rendered, &local, verdict).map(|_| ()));
+    }
+
+    /// Whether a split vote was recorded for exactly this template and local
+    /// content, so voting again would change nothing
+    pub fn split_unchanged(&self, path: &Path, template_hash: &str, local_hash: &str) -> Result<bool> {
+        Ok(self.get(&self.hostname, path)?.is_some_and(|entry| {
+            entry.split.as_ref().is_some_and(|split| split.template_hash == template_hash)
+                && entry.latest().is_some_and(|r| r.hash == local_hash)
+        }))
+    }
+
+    fn observe(&self, path: &Path, group: &str, rendered: &[u8], local: &[u8], split: Option<SplitVerdict>) -> Result<DriftEntry> {
+        let now = chrono::Utc::now();
+        let hash = content_checksum(local);
+        let mut entry = self.get(&self.hostname, path)?.unwrap_or_else(|| DriftEntry {
+            path: path.to_path_buf(),
+            group: group.to_string(),
+            host: self.hostname.clone(),
+            first_drift: now,
+            records: Vec::new(),
+            split: None,
+        });
+
+        let seen = entry.latest().is_some_and(|r| r.hash == hash);
+        if seen && entry.split == split {
+            return Ok(entry);
+        }
+
+        if !seen {
+            let diff = if is_binary_content(local) || is_binary_content(rendered) {
+                format!("Binary files {0} (rendered) and {0} (local) differ\n", path.display())
+            } else {
+                crate::diff::unified_diff(
+                    &String::from_utf8_lossy(rendered),
+                    &String::from_utf8_lossy(local),
+                    &format!("{} (rendered)", path.display()),
+                    &format!("{} (local)", path.display()),
+                )
+            };
+            entry.records.push(DriftRecord { recorded_at: now, hash, diff });
+        }
+        entry.group = group.to_string();
+        entry.split = split;
+        if entry.records.len() > MAX_RECORDS {
+            entry.records.drain(..entry.records.len() - MAX_RECORDS);
+        }
+
+        let entry_path = self.entry_path(&self.hostname, path);
+        if let Some(parent) = entry_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let content = serde_json::to_string_pretty(&entry)?;
+        crate::fs::atomic_write(&entry_path, content.as_bytes(), |_| Ok(()))?;
+        debug!("Recorded drift of {:?} ({} records)", path, entry.records.len());
+        Ok(entry)
+    }
+
+    /// Forget the drift of a file on this host
+    pub fn clear(&self, path: &Path) -> Result<Option<DriftEntry>> {
+        let entry = self.get(&self.hostname, path)?;
+        if entry.is_some() {
+            std::fs::remove_file(self.entry_path(&self.hostname, path))?;
+            debug!("Cleared drift of {:?}", path);
+        }
+        Ok(entry)
+    }
+
+    /// Plan `clear`
+    pub fn plan_clear(&self, path: &Path, plan: &mut Plan) -> Result<()> {
+        if self.current(path)?.is_some() {
+            plan.remove(&self.entry_path(&self.hostname, path));
+        }
+        let (ledger, path) = (self.clone(), path.to_path_buf());
+        plan.then(move || ledger.clear(&path).map(|_| ()));
+        Ok(())
+    }
+
+    /// Drift of a file on this host
+    pub fn current(&self, path: &Path) -> Result<Option<DriftEntry>> {
+        self.get(&self.hostname, path)
+    }
+
+    pub fn get(&self, host: &str, path: &Path) -> Result<Option<DriftEntry>> {
+        match std::fs::read_to_string(self.entry_path(host, path)) {
+            Ok(content) => Ok(Some(serde_json::from_str(&content)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Drifted files of a host, by path
+    pub fn list(&self, host: &str) -> Result<Vec<DriftEntry>> {
+        let dir = self.host_dir(host);
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut entries = Vec::new();
+        for file in walkdir::WalkDir::new(&dir) {
+            let file = file?;
+            if file.file_type().is_file() && file.path().extension() == Some(std::ffi::OsStr::new("json")) {
+                let content = std::fs::read_to_string(file.path())?;
+                entries.push(serde_json::from_str::<DriftEntry>(&content)?);
+            }
+        }
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(entries)
+    }
+
+    /// Hosts with a drift ledger
+    pub fn hosts(&self) -> Result<Vec<String>> {
+        let machines = crate::fs::get_machines_dir(&self.mfs_mount, "");
+        let mut hosts = Vec::new();
+        if let Ok(dir) = std::fs::read_dir(&machines) {
+            for entry in dir.flatten() {
+                if entry.path().join("drift").is_dir() {
+                    hosts.push(entry.file_name().to_string_lossy().into_owned());
+                }
+            }
+        }
+        hosts.sort();
+        Ok(hosts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repeated_content_is_recorded_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let ledger = DriftLedger::new(dir.path().to_path_buf(), "web01".to_string());
+        let path = Path::new("/etc/app.conf");
+
+        let first = ledger.record(path, "web", b"workers = 4\n", b"workers = 8\n").unwrap();
+        let again = ledger.record(path, "web", b"workers = 4\n", b"workers = 8\n").unwrap();
+        assert_eq!(first, again);
+        assert!(first.records[0].diff.contains("+workers = 8"));
+
+        let changed = ledger.record(path, "web", b"workers = 4\n", b"workers = 16\n").unwrap();
+        assert_eq!(changed.records.len(), 2);
+        assert_eq!(changed.first_drift, first.first_drift);
+        assert_eq!(ledger.list("web01").unwrap(), vec![changed.clone()]);
+        assert_eq!(ledger.hosts().unwrap(), vec!["web01".to_string()]);
+
+        assert_eq!(ledger.clear(path).unwrap(), Some(changed));
+        assert!(ledger.list("web01").unwrap().is_empty());
+    }
+}
@@ -2,11 +2,23 @@ use std::path::{Path, PathBuf};
 use std::fs;
 use std::collections::HashMap;
 use serde::{Serialize, Deserialize};
-use tracing::{info, debug, warn};
+use tracing::{info, debug, warn, error};
 use crate::error::{LaszooError, Result};
-use crate::action::{ActionManager, ActionPhase};
+use crate::action::{ActionBatch, ActionConfig, ActionManager, ActionPhase, DeferredFile, Replaced};
+use crate::backup::BackupStore;
+use crate::cli::SyncAction;
+use crate::drift::DriftLedger;
+use crate::majority::{MajorityResolver, Resolution};
+use crate::plan::Plan;
+use crate::lock::LockManager;
+use crate::version::TemplateVersions;
 use sha2::{Sha256, Digest};
 
+/// How long a group manifest write may hold the manifest lock
+const MANIFEST_LEASE_TTL: std::time::Duration = std::time::Duration::from_secs(30);
+/// How long to wait for another host to finish writing a group manifest
+const MANIFEST_LOCK_WAIT: std::time::Duration = std::time::Duration::from_secs(10);
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EnrollmentEntry {
     pub original_path: PathBuf,
@@ -17,6 +29,16 @@ pub struct EnrollmentEntry {
     pub template_path: Option<PathBuf>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub is_hybrid: Option<bool>,
+    /// Binary files are stored verbatim and skip template processing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub is_binary: Option<bool>,
+    /// Symlinks are managed as links; their template holds the link target
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub is_symlink: Option<bool>,
+    /// How local changes to this file are handled; the group setting applies
+    /// when unset. On a machine manifest entry it overrides the group entry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sync_action: Option<SyncAction>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub enrolled_directory: Option<PathBuf>,
 }
@@ -67,11 +89,56 @@ impl EnrollmentManifest {
     pub fn remove_entry(&mut self, path: &Path) -> Option<EnrollmentEntry> {
         self.entries.remove(path)
     }
+    
+    /// Enrolled directory containing a path, the innermost one if nested
+    pub fn enrolled_directory_of(&self, path: &Path) -> Option<&EnrollmentEntry> {
+        self.entries.values()
+            .filter(|e| e.checksum == "directory" && path.starts_with(&e.original_path))
+            .max_by_key(|e| e.original_path.components().count())
+    }
+    
+    /// Refuse to enroll a path already enrolled here, unless forced.
+    /// Returns whether an enrolled path is taken over.
+    pub fn check_enrollable(&self, path: &Path, force: bool) -> Result<bool> {
+        match self.is_enrolled(path) {
+            Some(existing) if !force => Err(LaszooError::AlreadyEnrolled {
+                path: path.to_path_buf(),
+                group: existing.group.clone(),
+            }),
+            existing => Ok(existing.is_some()),
+        }
+    }
+
+    /// Sync action set on the entry for a path, or on the enrolled directory
+    /// containing it
+    pub fn sync_action_for(&self, path: &Path) -> Option<SyncAction> {
+        self.is_enrolled(path)
+            .and_then(|e| e.sync_action)
+            .or_else(|| self.enrolled_directory_of(path).and_then(|e| e.sync_action))
+    }
+}
+
+/// How paths are enrolled
+#[derive(Debug, Clone, Default)]
+pub struct EnrollOptions {
+    /// Take over a path already enrolled in the group
+    pub force: bool,
+    /// Keep the template for this machine only
+    pub machine_specific: bool,
+    /// Machine template supplying the quack values of the group template
+    pub hybrid: bool,
+    /// Also enroll hidden files and directories inside an enrolled directory
+    pub include_hidden: bool,
+    /// Actions stored for the enrolled path
+    pub actions: ActionConfig,
 }
 
+#[derive(Clone)]
 pub struct EnrollmentManager {
     mfs_mount: PathBuf,
     hostname: String,
+    backups: Option<BackupStore>,
+    action_timeout: Option<std::time::Duration>,
 }
 
 impl EnrollmentManager {
@@ -83,8 +150,30 @@ impl EnrollmentManager {
         Self {
             mfs_mount,
             hostname,
+            backups: None,
+            action_timeout: crate::config::ActionsConfig::default().timeout(),
         }
     }
+    
+    /// Back up the previous content of files before applying templates over them
+    pub fn with_backups(mut self, backups: BackupStore) -> Self {
+        self.backups = Some(backups);
+        self
+    }
+
+    /// Kill before/after actions still running after `timeout`
+    pub fn with_action_timeout(mut self, timeout: Option<std::time::Duration>) -> Self {
+        self.action_timeout = timeout;
+        self
+    }
+
+    fn action_manager(&self) -> ActionManager {
+        ActionManager::new(self.mfs_mount.clone()).with_timeout(self.action_timeout)
+    }
+
+    fn locks(&self) -> LockManager {
+        LockManager::new(self.mfs_mount.clone())
+    }
 
     pub fn manifest_path(&self) -> PathBuf {
         crate::fs::get_machine_dir(&self.mfs_mount, "", &self.hostname)
@@ -108,30 +197,49 @@ impl EnrollmentManager {
         manifest.save(&self.manifest_path())
     }
     
-    pub fn save_group_manifest(&self, group: &str, manifest: &EnrollmentManifest) -> Result<()> {
-        manifest.save(&self.group_manifest_path(group))
+    /// Load, change and save a group's manifest. Every host in the group
+    /// writes it, so the whole update holds the manifest's lock.
+    pub fn update_group_manifest<T>(&self, group: &str, update: impl FnOnce(&mut EnrollmentManifest) -> Result<T>) -> Result<T> {
+        let locks = self.locks();
+        let _lease = locks.hold(&format!("manifest.{}", group), MANIFEST_LEASE_TTL, MANIFEST_LOCK_WAIT)?;
+        let mut manifest = self.load_group_manifest(group)?;
+        let result = update(&mut manifest)?;
+        manifest.save(&self.group_manifest_path(group))?;
+        Ok(result)
     }
 
-    /// Enroll a file or directory into a group
-    pub fn enroll_path(&self, group: &str, path: Option<&Path>, force: bool, machine_specific: bool, hybrid: bool, before: Option<String>, after: Option<String>) -> Result<()> {
+    /// Enroll a file or directory into a group, or with no path enroll this
+    /// machine into the group and apply its templates
+    pub fn enroll_path(&self, group: &str, path: Option<&Path>, options: &EnrollOptions) -> Result<()> {
+        let mut plan = Plan::new();
+        self.plan_enroll_path(group, path, options, &mut plan)?;
+        plan.run_blocking()
+    }
+
+    /// Plan `enroll_path`, failing before anything is changed where it would
+    pub fn plan_enroll_path(&self, group: &str, path: Option<&Path>, options: &EnrollOptions, plan: &mut Plan) -> Result<()> {
         // If no path specified, enroll the machine into the group
-        if path.is_none() {
-            return self.enroll_machine_to_group(group);
-        }
+        let Some(path) = path else {
+            info!("Enrolling machine {} into group {}", self.hostname, group);
+            self.plan_add_machine_to_group(group, plan)?;
+            return self.plan_group_templates(group, plan);
+        };
 
-        let path = path.unwrap();
+        let is_symlink = fs::symlink_metadata(path)
+            .map(|m| m.file_type().is_symlink())
+            .unwrap_or(false);
         
-        // Ensure path exists
-        if !path.exists() {
+        // Ensure path exists (a dangling symlink is still a valid link to manage)
+        if !path.exists() && !is_symlink {
             return Err(LaszooError::FileNotFound { 
                 path: path.to_path_buf() 
             });
         }
 
-        if path.is_file() {
-            self.enroll_file(path, group, force, machine_specific, hybrid, before, after)
+        if is_symlink || path.is_file() {
+            self.plan_enroll_file(path, group, options, plan)
         } else if path.is_dir() {
-            self.enroll_directory(path, group, force, machine_specific, hybrid, before, after)
+            self.plan_enroll_directory(path, group, options, plan)
         } else {
             Err(LaszooError::InvalidPath { 
                 path: path.to_path_buf() 
@@ -139,332 +247,387 @@ impl EnrollmentManager {
         }
     }
 
-    /// Enroll a file into a group
-    pub fn enroll_file(&self, file_path: &Path, group: &str, force: bool, machine_specific: bool, hybrid: bool, before: Option<String>, after: Option<String>) -> Result<()> {
+    /// Plan enrolling a file into a group
+    fn plan_enroll_file(&self, file_path: &Path, group: &str, options: &EnrollOptions, plan: &mut Plan) -> Result<()> {
         // First ensure this machine is in the group
-        self.add_machine_to_group(group)?;
+        self.plan_add_machine_to_group(group, plan)?;
         
         // Get absolute path
-        let abs_path = file_path.canonicalize()?;
+        let abs_path = crate::fs::absolute_path(file_path)?;
+        let is_symlink = fs::symlink_metadata(&abs_path)?.file_type().is_symlink();
         
-        // Check if this file is within any already-enrolled directories
-        let group_manifest = self.load_group_manifest(group)?;
-        for (enrolled_path, entry) in &group_manifest.entries {
-            if entry.checksum == "directory" {
-                // Check if our file is within this directory
-                if abs_path.starts_with(enrolled_path) {
-                    // This file is within an enrolled directory, just create the template
+        // Check if this file is within any already-enrolled directories.
+        // Symlinks always get their own entry so they stay links.
+        if !is_symlink {
+            if let Some(enrolled_path) = self.adopting_directory(group, &abs_path)? {
+                // This file is within an enrolled directory, just create the template
+                let group_template_path = self.get_group_template_path(group, &abs_path)?;
+                let content = fs::read(&abs_path)?;
+                plan.write_file(&group_template_path, &content);
+                self.plan_record_metadata(&abs_path, &group_template_path, plan)?;
+                
+                let this = self.clone();
+                plan.then(move || {
                     info!("File {:?} is within enrolled directory {:?}, adopting into directory", abs_path, enrolled_path);
-                    
-                    // Read file content
-                    let content = fs::read_to_string(&abs_path)?;
-                    
-                    // Create group template
-                    let group_template_path = crate::fs::get_group_template_path(
-                        &self.mfs_mount, 
-                        "", 
-                        group,
-                        &abs_path
-                    )?;
-                    
-                    // Ensure parent directory exists
-                    if let Some(parent) = group_template_path.parent() {
-                        fs::create_dir_all(parent)?;
-                    }
-                    
-                    // Create template
-                    fs::write(&group_template_path, &content)?;
-                    info!("Created group template at {:?}", group_template_path);
-                    
-                    // Copy metadata
-                    self.copy_metadata(&abs_path, &group_template_path)?;
-                    
+                    this.create_template(&abs_path, &group_template_path, &content)?;
                     info!("Successfully adopted {:?} into enrolled directory '{:?}'", abs_path, enrolled_path);
-                    return Ok(());
-                }
+                    Ok(())
+                });
+                return Ok(());
             }
         }
         
         // Not within any enrolled directory, proceed with normal enrollment
-        self.enroll_file_with_dir(file_path, group, force, machine_specific, hybrid, None, before, after)
+        self.plan_enroll_file_with_dir(file_path, group, options, None, plan)
     }
     
-    /// Enroll a file into a group with optional directory tracking
-    fn enroll_file_with_dir(&self, file_path: &Path, group: &str, force: bool, machine_specific: bool, hybrid: bool, enrolled_directory: Option<&Path>, before: Option<String>, after: Option<String>) -> Result<()> {
+    /// The enrolled directory of `group` a file at `abs_path` is adopted
+    /// into when enrolled there, if any
+    fn adopting_directory(&self, group: &str, abs_path: &Path) -> Result<Option<PathBuf>> {
+        Ok(self.load_group_manifest(group)?
+            .entries
+            .iter()
+            .find(|(enrolled_path, entry)| entry.checksum == "directory" && abs_path.starts_with(enrolled_path))
+            .map(|(enrolled_path, _)| enrolled_path.clone()))
+    }
+    
+    /// Check that a sync action can be given to `path` when enrolling it in
+    /// `group`. A file adopted into an enrolled directory shares the
+    /// directory's action, it can only have its own on a machine.
+    pub fn check_sync_action_target(&self, group: &str, path: &Path, machine: bool) -> Result<()> {
+        let abs_path = crate::fs::absolute_path(path)?;
+        if machine || !fs::symlink_metadata(&abs_path).is_ok_and(|m| m.is_file()) {
+            return Ok(());
+        }
+        match self.adopting_directory(group, &abs_path)? {
+            Some(dir) => Err(LaszooError::Other(format!(
+                "{} would be part of enrolled directory {}; set the action on the directory or use --machine",
+                abs_path.display(), dir.display()
+            ))),
+            None => Ok(()),
+        }
+    }
+    
+    /// Plan enrolling a file into a group with optional directory tracking
+    fn plan_enroll_file_with_dir(&self, file_path: &Path, group: &str, options: &EnrollOptions, enrolled_directory: Option<&Path>, plan: &mut Plan) -> Result<()> {
         // Check permissions
-        if let Err(_) = fs::metadata(file_path) {
-            return Err(LaszooError::PermissionDenied { 
+        let is_symlink = match fs::symlink_metadata(file_path) {
+            Ok(metadata) => metadata.file_type().is_symlink(),
+            Err(_) => return Err(LaszooError::PermissionDenied { 
                 path: file_path.to_path_buf() 
-            });
-        }
+            }),
+        };
 
-        // Get absolute path
-        let abs_path = file_path.canonicalize()?;
+        // Get absolute path, without following a symlink being enrolled
+        let abs_path = crate::fs::absolute_path(file_path)?;
         
         // Calculate checksum
         let checksum = self.calculate_checksum(&abs_path)?;
         
-        // Read file content
-        let content = fs::read_to_string(&abs_path)?;
-        
-        if machine_specific || hybrid {
-            // Create machine-specific template
-            let mut machine_template_path = crate::fs::get_machine_file_path(
-                &self.mfs_mount,
-                "",
-                &self.hostname,
-                &abs_path
-            )?;
-            // Append .lasz to the full filename (preserving original extension)
-            let filename = machine_template_path.file_name()
-                .and_then(|n| n.to_str())
-                .unwrap_or("");
-            machine_template_path.set_file_name(format!("{}.lasz", filename));
-            
-            // Ensure parent directory exists
-            if let Some(parent) = machine_template_path.parent() {
-                fs::create_dir_all(parent)?;
-            }
-            
-            // Write machine-specific template
-            fs::write(&machine_template_path, &content)?;
-            info!("Created machine-specific template at {:?}", machine_template_path);
-            
-            // Copy metadata
-            self.copy_metadata(&abs_path, &machine_template_path)?;
-            
-            // Load machine manifest and add entry
-            let mut machine_manifest = self.load_manifest()?;
-            
-            // For machine-specific enrollment, we always allow it to override
-            // Just warn if it was already enrolled
-            if let Some(existing) = machine_manifest.is_enrolled(&abs_path) {
-                info!("Overriding previous enrollment in group '{}'", existing.group);
+        // Read file content, binary files are stored verbatim and symlinks as their target
+        let content = if is_symlink {
+            info!("Enrolling {:?} as a symlink", abs_path);
+            crate::fs::read_link_bytes(&abs_path)?
+        } else {
+            fs::read(&abs_path)?
+        };
+        let is_binary = !is_symlink && crate::fs::is_binary_content(&content);
+        if is_binary {
+            info!("Enrolling {:?} as a binary file", abs_path);
+        }
+        if options.hybrid && (is_binary || is_symlink) {
+            return Err(LaszooError::Template(format!(
+                "Cannot enroll {:?} in hybrid mode, only text files support quack tags", abs_path
+            )));
+        }
+        
+        let entry = EnrollmentEntry {
+            original_path: abs_path.clone(),
+            checksum,
+            group: group.to_string(),
+            enrolled_at: chrono::Utc::now(),
+            last_synced: None,
+            template_path: None,
+            is_hybrid: None,
+            is_binary: if is_binary { Some(true) } else { None },
+            is_symlink: if is_symlink { Some(true) } else { None },
+            sync_action: None,
+            enrolled_directory: enrolled_directory.map(|p| p.to_path_buf()),
+        };
+        
+        if options.machine_specific || options.hybrid {
+            // Create machine-specific template, appending .lasz to the full filename
+            let machine_template_path = self.get_machine_template_path(&abs_path)?;
+            plan.write_file(&machine_template_path, &content);
+            if !is_symlink {
+                self.plan_record_metadata(&abs_path, &machine_template_path, plan)?;
             }
+            plan.metadata(&self.manifest_path(), format!(
+                "enroll {} as machine-specific for group '{}'", abs_path.display(), group
+            ));
             
-            let machine_entry = EnrollmentEntry {
-                original_path: abs_path.clone(),
-                checksum: checksum.clone(),
-                group: group.to_string(),
-                enrolled_at: chrono::Utc::now(),
-                last_synced: None,
-                template_path: Some(machine_template_path),
-                is_hybrid: if hybrid { Some(true) } else { None },
-                enrolled_directory: enrolled_directory.map(|p| p.to_path_buf()),
+            let entry = EnrollmentEntry {
+                template_path: Some(machine_template_path.clone()),
+                is_hybrid: if options.hybrid { Some(true) } else { None },
+                ..entry
             };
-            
-            machine_manifest.add_entry(machine_entry);
-            self.save_manifest(&machine_manifest)?;
-            
-            info!("Successfully enrolled {:?} as machine-specific for group '{}'", abs_path, group);
+            let this = self.clone();
+            plan.then(move || {
+                // Ensure parent directory exists
+                if let Some(parent) = machine_template_path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                
+                // Write machine-specific template
+                fs::write(&machine_template_path, &content)?;
+                info!("Created machine-specific template at {:?}", machine_template_path);
+                
+                // Copy metadata
+                if !is_symlink {
+                    this.record_metadata(&entry.original_path, &machine_template_path)?;
+                }
+                
+                // For machine-specific enrollment, we always allow it to override
+                info!("Successfully enrolled {:?} as machine-specific for group '{}'", entry.original_path, entry.group);
+                this.add_entry(entry, true, true)
+            });
         } else {
             // Create/update group template
-            let group_template_path = crate::fs::get_group_template_path(
-                &self.mfs_mount, 
-                "", 
-                group,
-                &abs_path
-            )?;
-            
-            // Ensure parent directory exists
-            if let Some(parent) = group_template_path.parent() {
-                fs::create_dir_all(parent)?;
-            }
+            let group_template_path = self.get_group_template_path(group, &abs_path)?;
             
             // If this is the first enrollment for this file in this group, create template
-            if !group_template_path.exists() {
-                fs::write(&group_template_path, &content)?;
-                info!("Created group template at {:?}", group_template_path);
-                
-                // Copy metadata
-                self.copy_metadata(&abs_path, &group_template_path)?;
-            }
-            
-            // Load group manifest and add entry
-            let mut group_manifest = self.load_group_manifest(group)?;
-            
-            // Check if already enrolled in group manifest
-            if let Some(existing) = group_manifest.is_enrolled(&abs_path) {
-                if !force {
-                    return Err(LaszooError::AlreadyEnrolled {
-                        path: abs_path.clone(),
-                        group: existing.group.clone(),
-                    });
+            let new_template = !group_template_path.exists();
+            if new_template {
+                plan.write(&group_template_path, None, &content);
+                if !is_symlink {
+                    self.plan_record_metadata(&abs_path, &group_template_path, plan)?;
                 }
-                info!("Force enrolling file in group manifest");
             }
             
-            let group_entry = EnrollmentEntry {
-                original_path: abs_path.clone(),
-                checksum,
-                group: group.to_string(),
-                enrolled_at: chrono::Utc::now(),
-                last_synced: None,
-                template_path: Some(group_template_path),
-                is_hybrid: None,
-                enrolled_directory: enrolled_directory.map(|p| p.to_path_buf()),
-            };
+            // Checked again when the entry is added, another host may enroll it in between
+            self.load_group_manifest(group)?.check_enrollable(&abs_path, options.force)?;
+            plan.metadata(&self.group_manifest_path(group), format!(
+                "enroll {} into group '{}'", abs_path.display(), group
+            ));
             
-            group_manifest.add_entry(group_entry);
-            self.save_group_manifest(group, &group_manifest)?;
-            
-            info!("Successfully enrolled {:?} into group '{}'", abs_path, group);
-        }
-        
-        // Store actions if provided
-        if before.is_some() || after.is_some() {
-            let action_manager = ActionManager::new(self.mfs_mount.clone());
-            if machine_specific {
-                action_manager.set_machine_actions(&abs_path, before, after)?;
-            } else {
-                action_manager.set_group_actions(group, &abs_path, before, after)?;
-            }
+            let entry = EnrollmentEntry {
+                template_path: Some(group_template_path.clone()),
+                ..entry
+            };
+            let (this, force) = (self.clone(), options.force);
+            plan.then(move || {
+                if new_template {
+                    // Ensure parent directory exists
+                    if let Some(parent) = group_template_path.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    this.versions().write_unchecked(&group_template_path, &content)?;
+                    info!("Created group template at {:?}", group_template_path);
+                    
+                    // Copy metadata
+                    if !is_symlink {
+                        this.record_metadata(&entry.original_path, &group_template_path)?;
+                    }
+                }
+                
+                info!("Successfully enrolled {:?} into group '{}'", entry.original_path, entry.group);
+                this.add_entry(entry, false, force)
+            });
         }
         
-        Ok(())
+        self.plan_store_actions(group, &abs_path, options, plan)
     }
 
-    /// Enroll a directory recursively
-    fn enroll_directory(&self, dir_path: &Path, group: &str, force: bool, machine_specific: bool, hybrid: bool, before: Option<String>, after: Option<String>) -> Result<()> {
+    /// Plan enrolling a directory recursively. Hidden files and directories
+    /// in it are left out unless `options.include_hidden`.
+    fn plan_enroll_directory(&self, dir_path: &Path, group: &str, options: &EnrollOptions, plan: &mut Plan) -> Result<()> {
         // First ensure this machine is in the group
-        self.add_machine_to_group(group)?;
+        self.plan_add_machine_to_group(group, plan)?;
         
         let abs_path = dir_path.canonicalize()?;
+        let machine_specific = options.machine_specific;
         
-        // First enroll the directory itself as a marker
-        if machine_specific {
-            // Create machine-specific directory entry
-            let mut machine_manifest = self.load_manifest()?;
-            
-            // Check if already enrolled
-            if let Some(existing) = machine_manifest.is_enrolled(&abs_path) {
-                if !force {
-                    return Err(LaszooError::AlreadyEnrolled {
-                        path: abs_path.clone(),
-                        group: existing.group.clone(),
-                    });
-                }
-                info!("Force enrolling directory in machine manifest");
-            }
-            
-            let machine_entry = EnrollmentEntry {
-                original_path: abs_path.clone(),
-                checksum: "directory".to_string(),  // Special marker for directories
-                group: group.to_string(),
-                enrolled_at: chrono::Utc::now(),
-                last_synced: None,
-                template_path: None,  // Directories don't have templates
-                is_hybrid: if hybrid { Some(true) } else { None },
-                enrolled_directory: Some(abs_path.clone()),  // Mark this as an enrolled directory
-            };
-            
-            machine_manifest.add_entry(machine_entry);
-            self.save_manifest(&machine_manifest)?;
-            
-            info!("Successfully enrolled directory {:?} as machine-specific for group '{}'", abs_path, group);
+        // First enroll the directory itself as a marker, checked again when
+        // the entry is added
+        let (manifest, manifest_path) = if machine_specific {
+            (self.load_manifest()?, self.manifest_path())
         } else {
-            // Create group directory entry
-            let mut group_manifest = self.load_group_manifest(group)?;
-            
-            // Check if already enrolled in group manifest
-            if let Some(existing) = group_manifest.is_enrolled(&abs_path) {
-                if !force {
-                    return Err(LaszooError::AlreadyEnrolled {
-                        path: abs_path.clone(),
-                        group: existing.group.clone(),
-                    });
-                }
-                info!("Force enrolling directory in group manifest");
+            (self.load_group_manifest(group)?, self.group_manifest_path(group))
+        };
+        manifest.check_enrollable(&abs_path, options.force)?;
+        plan.metadata(&manifest_path, format!(
+            "enroll directory {} into group '{}'{}", abs_path.display(), group,
+            if machine_specific { " as machine-specific" } else { "" }
+        ));
+        
+        let entry = EnrollmentEntry {
+            original_path: abs_path.clone(),
+            checksum: "directory".to_string(),  // Special marker for directories
+            group: group.to_string(),
+            enrolled_at: chrono::Utc::now(),
+            last_synced: None,
+            template_path: None,  // Directories don't have templates
+            is_hybrid: if machine_specific && options.hybrid { Some(true) } else { None },
+            is_binary: None,
+            is_symlink: None,
+            sync_action: None,
+            enrolled_directory: Some(abs_path.clone()),  // Mark this as an enrolled directory
+        };
+        let (this, force) = (self.clone(), options.force);
+        plan.then(move || {
+            let (path, group) = (entry.original_path.clone(), entry.group.clone());
+            this.add_entry(entry, machine_specific, force)?;
+            if machine_specific {
+                info!("Successfully enrolled directory {:?} as machine-specific for group '{}'", path, group);
+            } else {
+                info!("Successfully enrolled directory {:?} into group '{}'", path, group);
             }
-            
-            let group_entry = EnrollmentEntry {
-                original_path: abs_path.clone(),
-                checksum: "directory".to_string(),  // Special marker for directories
-                group: group.to_string(),
-                enrolled_at: chrono::Utc::now(),
-                last_synced: None,
-                template_path: None,  // Directories don't have templates
-                is_hybrid: None,
-                enrolled_directory: Some(abs_path.clone()),  // Mark this as an enrolled directory
-            };
-            
-            group_manifest.add_entry(group_entry);
-            self.save_group_manifest(group, &group_manifest)?;
-            
-            info!("Successfully enrolled directory {:?} into group '{}'", abs_path, group);
-        }
+            Ok(())
+        });
         
         // Now copy all existing files in the directory to templates
-        for entry in walkdir::WalkDir::new(&abs_path) {
+        let entries = walkdir::WalkDir::new(&abs_path)
+            .into_iter()
+            .filter_entry(|entry| options.include_hidden || entry.depth() == 0 || !is_hidden(entry));
+        for entry in entries {
             let entry = entry?;
-            if entry.file_type().is_file() {
-                // Create template for this file
-                let file_path = entry.path();
-                let content = fs::read_to_string(file_path)?;
-                
-                let group_template_path = crate::fs::get_group_template_path(
-                    &self.mfs_mount, 
-                    "", 
-                    group,
-                    file_path
-                )?;
-                
-                // Ensure parent directory exists
-                if let Some(parent) = group_template_path.parent() {
-                    fs::create_dir_all(parent)?;
-                }
-                
-                // Create template if it doesn't exist
+            if entry.file_type().is_symlink() {
+                // Links inside the directory are enrolled individually so they stay links
+                let link_options = EnrollOptions {
+                    force: true,
+                    hybrid: false,
+                    actions: ActionConfig::default(),
+                    ..options.clone()
+                };
+                self.plan_enroll_file_with_dir(entry.path(), group, &link_options, Some(&abs_path), plan)?;
+            } else if entry.file_type().is_file() {
+                // Create template for this file if it doesn't exist
+                let group_template_path = self.get_group_template_path(group, entry.path())?;
                 if !group_template_path.exists() {
-                    fs::write(&group_template_path, &content)?;
-                    self.copy_metadata(file_path, &group_template_path)?;
-                    debug!("Created template for directory file: {:?}", group_template_path);
+                    let content = fs::read(entry.path())?;
+                    plan.write(&group_template_path, None, &content);
+                    self.plan_record_metadata(entry.path(), &group_template_path, plan)?;
+                    
+                    let (this, file_path) = (self.clone(), entry.path().to_path_buf());
+                    plan.then(move || {
+                        this.create_template(&file_path, &group_template_path, &content)?;
+                        debug!("Created template for directory file: {:?}", group_template_path);
+                        Ok(())
+                    });
                 }
             }
         }
         
         // Store actions if provided (for the directory itself)
-        if before.is_some() || after.is_some() {
-            let action_manager = ActionManager::new(self.mfs_mount.clone());
-            if machine_specific {
-                action_manager.set_machine_actions(&abs_path, before, after)?;
-            } else {
-                action_manager.set_group_actions(group, &abs_path, before, after)?;
+        self.plan_store_actions(group, &abs_path, options, plan)
+    }
+
+    /// Add an entry to this machine's manifest, or else to its group's,
+    /// keeping the sync action already set for the path. A path already
+    /// enrolled is only taken over with `force`.
+    fn add_entry(&self, entry: EnrollmentEntry, machine: bool, force: bool) -> Result<()> {
+        let group = entry.group.clone();
+        let add = |manifest: &mut EnrollmentManifest| {
+            if manifest.check_enrollable(&entry.original_path, force)? {
+                info!("Force enrolling {:?} in {} manifest", entry.original_path, if machine { "machine" } else { "group" });
             }
-        }
+            let sync_action = manifest.is_enrolled(&entry.original_path).and_then(|e| e.sync_action);
+            manifest.add_entry(EnrollmentEntry { sync_action, ..entry });
+            Ok(())
+        };
         
-        Ok(())
+        if machine {
+            let mut manifest = self.load_manifest()?;
+            add(&mut manifest)?;
+            self.save_manifest(&manifest)
+        } else {
+            self.update_group_manifest(&group, add)
+        }
     }
 
-    /// Enroll a machine into a group without specifying files
-    fn enroll_machine_to_group(&self, group: &str) -> Result<()> {
-        info!("Enrolling machine {} into group {}", self.hostname, group);
-        
-        // Add machine to group
-        self.add_machine_to_group(group)?;
+    /// Write the template of a file found in an enrolled directory, with the
+    /// file's metadata
+    fn create_template(&self, file_path: &Path, template_path: &Path, content: &[u8]) -> Result<()> {
+        if let Some(parent) = template_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(template_path, content)?;
+        self.record_metadata(file_path, template_path)
+    }
+
+    /// Plan storing the actions given when enrolling a path
+    fn plan_store_actions(&self, group: &str, abs_path: &Path, options: &EnrollOptions, plan: &mut Plan) -> Result<()> {
+        if options.actions.is_empty() {
+            return Ok(());
+        }
         
-        // Apply all templates from the group
-        self.apply_group_templates(group)?;
+        let action_manager = self.action_manager();
+        let machine_specific = options.machine_specific;
+        let actions_path = if machine_specific {
+            action_manager.get_machine_actions_path()
+        } else {
+            action_manager.get_group_actions_path(group)
+        };
+        plan.metadata(&actions_path, format!("set actions for {}: {}", abs_path.display(), options.actions));
         
+        let (this, group, path, actions) = (self.clone(), group.to_string(), abs_path.to_path_buf(), options.actions.clone());
+        plan.then(move || {
+            let action_manager = this.action_manager();
+            if machine_specific {
+                action_manager.set_machine_actions(&path, actions)
+            } else {
+                action_manager.set_group_actions(&group, &path, actions)
+            }
+        });
         Ok(())
     }
     
-    /// Add this machine to a group (creates group if needed)
-    pub fn add_machine_to_group(&self, group: &str) -> Result<()> {
-        // Create group directory if it doesn't exist
+    /// Plan adding this machine to a group (creates group if needed)
+    pub fn plan_add_machine_to_group(&self, group: &str, plan: &mut Plan) -> Result<()> {
         let group_dir = crate::fs::get_group_dir(&self.mfs_mount, "", group);
+        let mut groups = self.load_machine_groups()?;
+        let member = groups.iter().any(|g| g == group);
+        if group_dir.exists() && member {
+            return Ok(());
+        }
+
         if !group_dir.exists() {
-            fs::create_dir_all(&group_dir)?;
-            info!("Created new group '{}'", group);
+            plan.metadata(&group_dir, format!("create group '{}'", group));
+        }
+        if !member {
+            groups.push(group.to_string());
+            groups.sort();
+            plan.write_file(&self.groups_file(), (groups.join("\n") + "\n").as_bytes());
+            plan.metadata(&self.mfs_mount.join("memberships").join(group).join(&self.hostname),
+                format!("link machine '{}' into group '{}'", self.hostname, group));
         }
         
-        // Update machine's groups.conf
-        let groups_file = self.mfs_mount
+        let (this, group) = (self.clone(), group.to_string());
+        plan.then(move || this.join_group(&group));
+        Ok(())
+    }
+
+    /// This machine's groups.conf
+    fn groups_file(&self) -> PathBuf {
+        self.mfs_mount
             .join("machines")
             .join(&self.hostname)
             .join("etc")
             .join("laszoo")
-            .join("groups.conf");
+            .join("groups.conf")
+    }
+
+    /// Create a group if needed and add this machine to it
+    fn join_group(&self, group: &str) -> Result<()> {
+        // Create group directory if it doesn't exist
+        let group_dir = crate::fs::get_group_dir(&self.mfs_mount, "", group);
+        if !group_dir.exists() {
+            fs::create_dir_all(&group_dir)?;
+            info!("Created new group '{}'", group);
+        }
+        
+        // Update machine's groups.conf
+        let groups_file = self.groups_file();
         
         // Create directory if needed
         if let Some(parent) = groups_file.parent() {
@@ -531,6 +694,145 @@ impl EnrollmentManager {
         Ok(())
     }
 
+    /// Sync action set for an enrolled file, or None to use the group default.
+    /// A machine manifest entry overrides the group's entry.
+    pub fn file_sync_action(&self, group: &str, file_path: &Path) -> Result<Option<SyncAction>> {
+        if let Some(action) = self.load_manifest()?.sync_action_for(file_path) {
+            return Ok(Some(action));
+        }
+        Ok(self.load_group_manifest(group)?.sync_action_for(file_path))
+    }
+
+    /// Sync action configured for the whole group in its config.json,
+    /// converge when none is set
+    pub fn group_sync_action(&self, group: &str) -> Result<SyncAction> {
+        let config_path = self.mfs_mount
+            .join("groups")
+            .join(group)
+            .join("config.json");
+
+        if !config_path.exists() {
+            return Ok(SyncAction::Converge);
+        }
+
+        let config: serde_json::Value = serde_json::from_str(&fs::read_to_string(&config_path)?)?;
+        Ok(match config.get("sync_action").and_then(|v| v.as_str()) {
+            Some("rollback") => SyncAction::Rollback,
+            Some("freeze") => SyncAction::Freeze,
+            Some("drift") => SyncAction::Drift,
+            _ => SyncAction::Converge,
+        })
+    }
+
+    /// Sync action for an enrolled file: its own setting or this machine's
+    /// override, falling back to the group's configured action
+    pub fn effective_sync_action(&self, group: &str, file_path: &Path) -> Result<SyncAction> {
+        match self.file_sync_action(group, file_path)? {
+            Some(action) => Ok(action),
+            None => self.group_sync_action(group),
+        }
+    }
+
+    /// Group an enrolled path belongs to, directly or through an enrolled directory
+    pub fn enrolled_group(&self, path: &Path) -> Result<Option<String>> {
+        let machine_manifest = self.load_manifest()?;
+        if let Some(entry) = machine_manifest.is_enrolled(path).or_else(|| machine_manifest.enrolled_directory_of(path)) {
+            return Ok(Some(entry.group.clone()));
+        }
+        
+        for group in self.load_machine_groups()? {
+            let group_manifest = self.load_group_manifest(&group)?;
+            if group_manifest.is_enrolled(path).is_some() || group_manifest.enrolled_directory_of(path).is_some() {
+                return Ok(Some(group));
+            }
+        }
+        Ok(None)
+    }
+    
+    /// Change the sync action of an enrolled file or directory after
+    /// enrollment. With `machine` it is stored as an override for this machine
+    /// only, otherwise on the group's entry. Returns the group of the path.
+    pub fn set_sync_action(&self, path: &Path, action: SyncAction, machine: bool) -> Result<String> {
+        let not_enrolled = || LaszooError::Other(format!("{} is not enrolled", path.display()));
+        let group = self.enrolled_group(path)?.ok_or_else(not_enrolled)?;
+        let group_manifest = self.load_group_manifest(&group)?;
+        
+        if machine {
+            let mut manifest = self.load_manifest()?;
+            let mut entry = match manifest.is_enrolled(path).or_else(|| group_manifest.is_enrolled(path)) {
+                Some(entry) => entry.clone(),
+                // A file inside an enrolled directory has no entry of its own yet
+                None => EnrollmentEntry {
+                    original_path: path.to_path_buf(),
+                    checksum: self.calculate_checksum(path).unwrap_or_default(),
+                    group: group.clone(),
+                    enrolled_at: chrono::Utc::now(),
+                    last_synced: None,
+                    template_path: Some(self.get_group_template_path(&group, path)?),
+                    is_hybrid: None,
+                    is_binary: None,
+                    is_symlink: None,
+                    sync_action: None,
+                    enrolled_directory: group_manifest.enrolled_directory_of(path)
+                        .or_else(|| manifest.enrolled_directory_of(path))
+                        .map(|e| e.original_path.clone()),
+                },
+            };
+            entry.sync_action = Some(action);
+            manifest.add_entry(entry);
+            self.save_manifest(&manifest)?;
+        } else {
+            self.update_group_manifest(&group, |manifest| {
+                if let Some(entry) = manifest.entries.get_mut(path) {
+                    entry.sync_action = Some(action);
+                    return Ok(());
+                }
+                Err(match manifest.enrolled_directory_of(path) {
+                    Some(dir) => LaszooError::Other(format!(
+                        "{} is part of enrolled directory {}; set the action on the directory or use --machine",
+                        path.display(), dir.original_path.display()
+                    )),
+                    None => not_enrolled(),
+                })
+            })?;
+        }
+        
+        info!("Set sync action of {:?} to {} in group '{}'{}", path, action, group,
+            if machine { " for this machine" } else { "" });
+        Ok(group)
+    }
+    
+    /// Read the groups this machine belongs to from its groups.conf
+    pub fn load_machine_groups(&self) -> Result<Vec<String>> {
+        let groups_file = self.groups_file();
+        
+        if !groups_file.exists() {
+            return Ok(Vec::new());
+        }
+        
+        Ok(fs::read_to_string(&groups_file)?
+            .lines()
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect())
+    }
+
+    /// Get the machine-specific template path for a file
+    pub fn get_machine_template_path(&self, file_path: &Path) -> Result<PathBuf> {
+        let mut template_path = crate::fs::get_machine_file_path(
+            &self.mfs_mount,
+            "",
+            &self.hostname,
+            file_path
+        )?;
+        let current_name = template_path.file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("");
+        template_path.set_file_name(format!("{}.lasz", current_name));
+        
+        Ok(template_path)
+    }
+
     /// Get the group template path for a file
     pub fn get_group_template_path(&self, group: &str, file_path: &Path) -> Result<PathBuf> {
         let group_dir = crate::fs::get_group_dir(&self.mfs_mount, "", group);
@@ -551,53 +853,44 @@ impl EnrollmentManager {
     
     /// Apply a single template file to its target location
     pub fn apply_single_template(&self, template_path: &Path, target_path: &Path) -> Result<()> {
-        // Determine group from template path
-        let group = if template_path.starts_with(self.mfs_mount.join("groups")) {
-            // Extract group name from path: .../groups/<group>/...
-            template_path.strip_prefix(&self.mfs_mount)
-                .ok()
-                .and_then(|p| p.components().nth(1))
-                .and_then(|c| c.as_os_str().to_str())
-                .unwrap_or("")
-        } else {
-            ""
-        };
-        
-        // Execute before action if configured
-        let action_manager = ActionManager::new(self.mfs_mount.clone());
-        
-        if !group.is_empty() {
-            action_manager.execute_file_actions(group, target_path, ActionPhase::Before)?;
-        }
-        
-        // Read template content
-        let template_content = std::fs::read_to_string(template_path)?;
-        
-        // Process the template
-        let final_content = crate::template::process_handlebars(&template_content, &self.hostname)?;
-        
-        // Create parent directory if needed
-        if let Some(parent) = target_path.parent() {
-            std::fs::create_dir_all(parent)?;
-        }
-        
-        // Write the processed content
-        std::fs::write(target_path, &final_content)?;
-        
-        // Copy metadata from template
-        self.copy_metadata(template_path, target_path)?;
-        
-        // Execute after action if configured
-        if !group.is_empty() {
-            action_manager.execute_file_actions(group, target_path, ActionPhase::After)?;
-        }
-        
+        let mut plan = Plan::new();
+        self.plan_single_template(template_path, target_path, &mut plan)?;
+        plan.run_blocking()
+    }
+
+    /// Plan `apply_single_template`, with the file's actions in a batch of its own
+    pub fn plan_single_template(&self, template_path: &Path, target_path: &Path, plan: &mut Plan) -> Result<()> {
+        let templates = [(template_path.to_path_buf(), target_path.to_path_buf())];
+        let batch = self.begin_action_batch(&templates, plan);
+        self.plan_single_template_in(&batch, template_path, target_path, plan)?;
+        self.finish_action_batch(batch, plan, first_failure);
         Ok(())
     }
 
-    /// Apply all templates from a group to the local system
-    pub fn apply_group_templates(&self, group: &str) -> Result<()> {
+    /// Plan applying a single template as part of `batch`, see `begin_action_batch`
+    pub fn plan_single_template_in(&self, batch: &ActionBatch, template_path: &Path, target_path: &Path, plan: &mut Plan) -> Result<()> {
+        self.plan_template(template_path, target_path, None, batch, plan)?;
+        Ok(())
+    }
+
+    /// Group whose actions run when a template is applied: the template's,
+    /// or for a machine template the group its target is enrolled in
+    fn action_group(&self, template_path: &Path, target_path: &Path) -> Result<Option<String>> {
+        let group = match self.template_group(template_path) {
+            Some(group) => Some(group),
+            None => self.enrolled_group(target_path)?,
+        };
+        Ok(group.filter(|g| !g.is_empty()))
+    }
+
+    /// List the templates of a group as (template path, target path) pairs
+    pub fn list_group_templates(&self, group: &str) -> Result<Vec<(PathBuf, PathBuf)>> {
         let group_dir = crate::fs::get_group_dir(&self.mfs_mount, "", group);
+        let mut templates = Vec::new();
+        // A group being created has no templates yet
+        if !group_dir.exists() {
+            return Ok(templates);
+        }
         
         // Walk the group directory
         for entry in walkdir::WalkDir::new(&group_dir) {
@@ -617,39 +910,129 @@ impl EnrollmentManager {
                     PathBuf::from("/").join(relative_path)
                 };
                 
-                // Apply the template
-                self.apply_template(group, template_path, &original_path)?;
+                templates.push((template_path.to_path_buf(), original_path));
             }
         }
         
-        Ok(())
+        // In the order the files were enrolled, a file in an enrolled
+        // directory taking the directory's place
+        let manifest = self.load_group_manifest(group)?;
+        templates.sort_by_cached_key(|(_, original_path)| {
+            let enrolled_at = manifest.is_enrolled(original_path)
+                .or_else(|| manifest.enrolled_directory_of(original_path))
+                .map(|e| e.enrolled_at);
+            (enrolled_at.is_none(), enrolled_at, original_path.clone())
+        });
+        
+        Ok(templates)
     }
 
-    /// Apply a single template to create/update a local file
-    fn apply_template(&self, group: &str, template_path: &Path, target_path: &Path) -> Result<()> {
-        info!("Applying template {:?} to {:?}", template_path, target_path);
-        
-        // Execute before action if configured
-        let action_manager = ActionManager::new(self.mfs_mount.clone());
-        action_manager.execute_file_actions(group, target_path, ActionPhase::Before)?;
+    /// Apply all templates from a group to the local system, in enrollment
+    /// order. Actions shared by several files run once for the group, after
+    /// actions once every file is written.
+    pub fn apply_group_templates(&self, group: &str) -> Result<()> {
+        let mut plan = Plan::new();
+        self.plan_group_templates(group, &mut plan)?;
+        plan.run_blocking()
+    }
+
+    /// Plan `apply_group_templates`. Applying stops at the first file that
+    /// fails, the files already written still get their after actions.
+    pub fn plan_group_templates(&self, group: &str, plan: &mut Plan) -> Result<()> {
+        let templates = self.list_group_templates(group)?;
         
-        // Read template content
-        let template_content = fs::read_to_string(template_path)?;
+        let batch = self.begin_action_batch(&templates, plan);
+        for (template_path, original_path) in &templates {
+            self.plan_template(template_path, original_path, Some(group), &batch, plan)?;
+        }
+        self.finish_action_batch(batch, plan, first_failure);
+        Ok(())
+    }
+
+    /// Plan applying `templates`, (template, target) pairs, together: the
+    /// before actions of all their files run first, each distinct command
+    /// once, ahead of any write. A file whose before action failed fails
+    /// when it is applied. After actions wait for `finish_action_batch`.
+    pub fn begin_action_batch(&self, templates: &[(PathBuf, PathBuf)], plan: &mut Plan) -> ActionBatch {
+        let batch = ActionBatch::default();
+        for (template_path, target_path) in templates {
+            // Errors show again when the file is planned
+            let _ = self.plan_before_action(&batch, template_path, target_path, plan);
+        }
+        batch
+    }
+
+    /// Plan the after actions deferred in `batch`, each command or unit
+    /// notification run once for the files of the batch that were written,
+    /// even when applying another file failed. The files of an after action
+    /// that fails are restored to their previous content and handed to
+    /// `failed` with the error, which decides how the plan ends.
+    pub fn finish_action_batch(&self, batch: ActionBatch, plan: &mut Plan, failed: impl FnOnce(Vec<(PathBuf, LaszooError)>) -> Result<()> + Send + 'static) {
+        for action in batch.deferred() {
+            let file = match action.files.as_slice() {
+                [file] => Some(file.path.as_path()),
+                _ => None,
+            };
+            plan.action(ActionPhase::After.as_str(), file, &action.step.to_string());
+        }
         
-        // Fix the machine-specific path - we need the relative path from root
-        let relative_path = if target_path.is_absolute() {
-            target_path.strip_prefix("/").unwrap_or(target_path)
-        } else {
-            target_path
+        let this = self.clone();
+        plan.finally(move || failed(this.run_after_actions(&batch)));
+    }
+
+    /// Run the after actions of the files written in `batch`. Returns the
+    /// files whose after action failed, with the error.
+    fn run_after_actions(&self, batch: &ActionBatch) -> Vec<(PathBuf, LaszooError)> {
+        let action_manager = self.action_manager();
+        let mut failed: Vec<(PathBuf, LaszooError)> = Vec::new();
+        for mut action in batch.take_after() {
+            // A file already restored when an earlier step for it failed is left alone
+            action.files.retain(|file| !failed.iter().any(|(path, _)| *path == file.path));
+            if action.files.is_empty() {
+                continue;
+            }
+            let Err(e) = action_manager.execute_deferred(&action) else {
+                continue;
+            };
+            for file in action.files {
+                self.revert_after_failure(&file.group, &file.path, file.replaced, &e);
+                failed.push((file.path, LaszooError::Other(e.to_string())));
+            }
+        }
+        failed
+    }
+
+    /// Plan a file's before action in `batch`, unless another file of the
+    /// batch shares it. The files sharing it get how it went.
+    fn plan_before_action(&self, batch: &ActionBatch, template_path: &Path, target_path: &Path, plan: &mut Plan) -> Result<Option<String>> {
+        let Some(group) = self.action_group(template_path, target_path)? else {
+            return Ok(None);
         };
+        let Some(command) = self.action_manager().before_action(&group, target_path)? else {
+            return Ok(None);
+        };
+        if !batch.plan_before(&command) {
+            return Ok(Some(command));
+        }
         
+        plan.action(ActionPhase::Before.as_str(), Some(target_path), &command);
+        let (this, batch) = (self.clone(), batch.clone());
+        let (template_path, target_path, before) = (template_path.to_path_buf(), target_path.to_path_buf(), command.clone());
+        plan.then(move || {
+            let result = this.action_manager().execute_file_actions(&group, &target_path, &template_path, ActionPhase::Before);
+            batch.record_before(&before, &result);
+            Ok(())
+        });
+        Ok(Some(command))
+    }
+
+    /// Render a group template for a target file the way apply would write it
+    ///
+    /// A machine-specific template for the target takes precedence, or supplies
+    /// the quack values for the group template when the enrollment is hybrid.
+    pub fn render_template(&self, template_path: &Path, target_path: &Path) -> Result<String> {
         // Build the machine-specific template path - preserve original extension
-        let mut machine_lasz_path = crate::fs::get_machine_dir(&self.mfs_mount, "", &self.hostname)
-            .join(relative_path);
-        let current_name = machine_lasz_path.file_name()
-            .and_then(|n| n.to_str())
-            .unwrap_or("");
-        machine_lasz_path.set_file_name(format!("{}.lasz", current_name));
+        let machine_lasz_path = self.get_machine_template_path(target_path)?;
         
         // Check if this is a hybrid enrollment
         let machine_manifest = self.load_manifest()?;
@@ -657,103 +1040,480 @@ impl EnrollmentManager {
             .and_then(|e| e.is_hybrid)
             .unwrap_or(false);
         
+        let vars = self.template_vars(template_path)?;
+        
         // Process content based on whether machine-specific template exists
         let final_content = if machine_lasz_path.exists() {
-            info!("Using machine-specific template from {:?}", machine_lasz_path);
+            debug!("Using machine-specific template from {:?}", machine_lasz_path);
             let machine_content = fs::read_to_string(&machine_lasz_path)?;
             
             if is_hybrid {
-                info!("Processing in hybrid mode");
+                debug!("Processing in hybrid mode");
                 // In hybrid mode, use group template with machine template providing quack values
-                crate::template::process_with_quacks(&template_content, &machine_content)?
+                let template_content = fs::read_to_string(template_path)?;
+                crate::template::process_with_quacks(&template_content, &machine_content, &vars)?
             } else {
                 // Machine-specific template takes full precedence - just process it for quack tags
-                crate::template::process_handlebars(&machine_content, &self.hostname)?
+                crate::template::process_handlebars_with_vars(&machine_content, &vars)?
             }
         } else {
             // Just process handlebars variables and quack tags from group template
-            crate::template::process_handlebars(&template_content, &self.hostname)?
+            let template_content = fs::read_to_string(template_path)?;
+            crate::template::process_handlebars_with_vars(&template_content, &vars)?
         };
         
-        // Create parent directory if needed
-        if let Some(parent) = target_path.parent() {
-            fs::create_dir_all(parent)?;
+        Ok(final_content)
+    }
+    
+    /// Render a template to the bytes apply would write. Binary templates are
+    /// returned verbatim, with a machine-specific copy taking precedence. For a
+    /// symlink this is the link target.
+    pub fn render_template_bytes(&self, template_path: &Path, target_path: &Path) -> Result<Vec<u8>> {
+        let machine_lasz_path = self.get_machine_template_path(target_path)?;
+        let source = if machine_lasz_path.exists() { machine_lasz_path } else { template_path.to_path_buf() };
+        let content = fs::read(&source)?;
+        
+        if self.is_binary_template(template_path, target_path, &content)? {
+            debug!("Using binary template {:?} verbatim", source);
+            return Ok(content);
+        }
+        
+        let rendered = self.render_template(template_path, target_path)?;
+        if self.is_symlink_template(template_path, target_path)? {
+            return Ok(rendered.trim_end_matches(['\r', '\n']).as_bytes().to_vec());
+        }
+        Ok(rendered.into_bytes())
+    }
+    
+    /// The enrollment entry for a target, from the machine manifest or else the template's group
+    fn enrolled_entry(&self, template_path: &Path, target_path: &Path) -> Result<Option<EnrollmentEntry>> {
+        if let Some(entry) = self.load_manifest()?.is_enrolled(target_path) {
+            return Ok(Some(entry.clone()));
+        }
+        
+        match self.template_group(template_path) {
+            Some(group) => Ok(self.load_group_manifest(&group)?.is_enrolled(target_path).cloned()),
+            None => Ok(None),
+        }
+    }
+    
+    /// Whether a target is enrolled as binary, or its template content is binary
+    pub fn is_binary_template(&self, template_path: &Path, target_path: &Path, content: &[u8]) -> Result<bool> {
+        if crate::fs::is_binary_content(content) {
+            return Ok(true);
         }
         
-        // Write the processed content
-        debug!("Writing content to {:?}, length: {}", target_path, final_content.len());
-        debug!("Content: {:?}", final_content);
-        fs::write(target_path, &final_content)?;
+        Ok(self.enrolled_entry(template_path, target_path)?
+            .and_then(|e| e.is_binary)
+            .unwrap_or(false))
+    }
+    
+    /// Whether a target is enrolled as a symlink
+    pub fn is_symlink_template(&self, template_path: &Path, target_path: &Path) -> Result<bool> {
+        Ok(self.enrolled_entry(template_path, target_path)?
+            .and_then(|e| e.is_symlink)
+            .unwrap_or(false))
+    }
+    
+    /// What a target currently holds, comparable with `render_template_bytes`:
+    /// the file content, or the link target for an enrolled symlink. `None` if missing.
+    pub fn local_content(&self, template_path: &Path, target_path: &Path) -> Result<Option<Vec<u8>>> {
+        let metadata = match fs::symlink_metadata(target_path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
         
-        // Copy metadata from template
-        self.copy_metadata(template_path, target_path)?;
+        if metadata.file_type().is_symlink() && self.is_symlink_template(template_path, target_path)? {
+            return Ok(Some(crate::fs::read_link_bytes(target_path)?));
+        }
+        if !target_path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(fs::read(target_path)?))
+    }
+    
+    /// Normalized hashes of a file and of its group template, `(local,
+    /// template)`, for the majority vote. The local content is mapped back into
+    /// template form first so host-specific values don't count as differences.
+    /// None if the file is missing or rendered from a machine-specific template.
+    pub fn normalized_hashes(&self, resolver: &MajorityResolver, template_path: &Path, file_path: &Path) -> Result<Option<(String, String)>> {
+        if self.get_machine_template_path(file_path)?.exists() {
+            return Ok(None);
+        }
+        let Some(local) = self.local_content(template_path, file_path)? else {
+            return Ok(None);
+        };
         
-        // Execute after action if configured
-        action_manager.execute_file_actions(group, target_path, ActionPhase::After)?;
+        let template = fs::read(template_path)?;
+        if self.is_binary_template(template_path, file_path, &template)? || crate::fs::is_binary_content(&local) {
+            return Ok(Some((crate::fs::content_checksum(&local), crate::fs::content_checksum(&template))));
+        }
         
-        // Check if this file should be adopted into an enrolled directory instead of creating individual entry
-        let abs_path = target_path.canonicalize().unwrap_or_else(|_| target_path.to_path_buf());
-        let mut should_create_entry = true;
+        let template = String::from_utf8_lossy(&template).into_owned();
+        let local = String::from_utf8_lossy(&local).into_owned();
+        let previous_render = self.render_template(template_path, file_path)?;
+        let engine = crate::template::TemplateEngine::new()?;
+        let local_hash = match engine.merge_file_changes_to_template(&template, &previous_render, &local) {
+            Ok(local_template) => resolver.normalized_hash(&local_template),
+            // The edit changes a rendered value, no other host can share this version
+            Err(LaszooError::SyncConflict(_)) => crate::fs::content_checksum(local.as_bytes()),
+            Err(e) => return Err(e),
+        };
         
-        // Check group manifest for enrolled directories
-        if let Ok(group_manifest) = self.load_group_manifest(group) {
-            for (enrolled_path, entry) in &group_manifest.entries {
-                if entry.checksum == "directory" {
-                    // Check if our file is within this directory
-                    if abs_path.starts_with(enrolled_path) {
-                        info!("File {:?} is within enrolled directory {:?}, not creating individual manifest entry", abs_path, enrolled_path);
-                        should_create_entry = false;
-                        break;
+        Ok(Some((local_hash, resolver.normalized_hash(&template))))
+    }
+    
+    /// Publish the normalized hash of every file of a group on this machine
+    pub fn publish_group_hashes(&self, group: &str) -> Result<()> {
+        let resolver = MajorityResolver::new()?;
+        for (template_path, original_path) in self.list_group_templates(group)? {
+            if let Some((hash, _)) = self.normalized_hashes(&resolver, &template_path, &original_path)? {
+                crate::majority::publish_hash(&self.mfs_mount, &self.hostname, group, &original_path, &hash)?;
+            }
+        }
+        Ok(())
+    }
+    
+    /// Publish this machine's hash of a file and vote on it with the other
+    /// members of its group
+    pub fn resolve_majority(&self, group: &str, template_path: &Path, file_path: &Path) -> Result<Option<Resolution>> {
+        self.vote_majority(group, template_path, file_path, true)
+    }
+    
+    /// Vote on a file like `resolve_majority`, without publishing this
+    /// machine's hash
+    pub fn preview_majority(&self, group: &str, template_path: &Path, file_path: &Path) -> Result<Option<Resolution>> {
+        self.vote_majority(group, template_path, file_path, false)
+    }
+    
+    /// Plan publishing this machine's hash of a file for its group's vote,
+    /// as `resolve_majority` does
+    pub fn plan_publish_vote(&self, group: &str, template_path: &Path, file_path: &Path, plan: &mut Plan) {
+        plan.metadata(&crate::majority::hashes_path(&self.mfs_mount, &self.hostname),
+            format!("publish hash of {} for group '{}'", file_path.display(), group));
+        let (this, group) = (self.clone(), group.to_string());
+        let (template_path, file_path) = (template_path.to_path_buf(), file_path.to_path_buf());
+        plan.then(move || this.resolve_majority(&group, &template_path, &file_path).map(|_| ()));
+    }
+    
+    fn vote_majority(&self, group: &str, template_path: &Path, file_path: &Path, publish: bool) -> Result<Option<Resolution>> {
+        let resolver = MajorityResolver::new()?;
+        let Some((local_hash, template_hash)) = self.normalized_hashes(&resolver, template_path, file_path)? else {
+            return Ok(None);
+        };
+        if publish {
+            crate::majority::publish_hash(&self.mfs_mount, &self.hostname, group, file_path, &local_hash)?;
+        }
+        
+        let members = crate::fs::list_machines_in_group(&self.mfs_mount, group)?;
+        let votes = crate::majority::collect_votes(&self.mfs_mount, group, file_path, &members)?;
+        Ok(Some(resolver.resolve(&self.hostname, &local_hash, &template_hash, &votes)?))
+    }
+    
+    /// Write rendered content to a target: create or repoint the link for an
+    /// enrolled symlink, otherwise write the file and restore its metadata.
+    /// Changed content must pass the file's validate command before it is
+    /// installed. Returns what it replaced.
+    fn write_target(&self, template_path: &Path, target_path: &Path, content: &[u8]) -> Result<Replaced> {
+        let mut replaced = match target_path.symlink_metadata() {
+            Ok(_) => Replaced::Unsaved,
+            Err(_) => Replaced::Nothing,
+        };
+        if self.is_symlink_template(template_path, target_path)? {
+            use std::os::unix::ffi::OsStrExt;
+            let link_target = Path::new(std::ffi::OsStr::from_bytes(content));
+            if crate::fs::ensure_symlink(target_path, link_target)? {
+                info!("Pointed symlink {:?} at {:?}", target_path, link_target);
+            }
+        } else {
+            let current = fs::read(target_path).ok();
+            let changed = current.as_deref() != Some(content);
+            // Machine templates are validated with the actions of the group the target is in
+            let group = match self.template_group(template_path) {
+                Some(group) => Some(group),
+                None => self.enrolled_group(target_path)?,
+            };
+            
+            // Write atomically, restoring ownership, permissions, ACLs and
+            // extended attributes before the new content becomes visible
+            crate::fs::atomic_write(target_path, content, |temp| {
+                self.restore_metadata(template_path, target_path, temp)?;
+                if let Some(group) = group.as_deref().filter(|_| changed) {
+                    self.action_manager().validate_file(group, target_path, template_path, temp)?;
+                }
+                if let (Some(backups), Some(current)) = (&self.backups, current.as_ref().filter(|_| changed)) {
+                    // A state directory that can't be written doesn't hold up the apply
+                    match backups.save(target_path) {
+                        Ok(_) => {
+                            if let Some(backup) = backups.find(target_path, None).ok()
+                                .filter(|b| fs::read(&b.path).is_ok_and(|stored| &stored == current)) {
+                                replaced = Replaced::Backup(backup);
+                            }
+                        }
+                        Err(e) => warn!("Failed to back up {:?}, writing it without a backup: {}", target_path, e),
                     }
                 }
+                Ok(())
+            })?;
+        }
+        
+        // Local edits to the file are now based on this version of the template
+        if self.template_group(template_path).is_some() {
+            if let Err(e) = self.versions().mark_synced(template_path) {
+                warn!("Failed to record synced version of {:?}: {}", template_path, e);
             }
         }
+        Ok(replaced)
+    }
+    
+    /// Put back what the write of a file whose after action failed
+    /// `replaced`, removing the file if it was created, and record the revert
+    fn revert_after_failure(&self, group: &str, target_path: &Path, replaced: Replaced, e: &LaszooError) {
+        let (status, details) = match (replaced, &self.backups) {
+            (Replaced::Backup(backup), Some(backups)) => match backups.restore(target_path, Some(backup.taken_at)) {
+                Ok(backup) => {
+                    warn!("After action for {:?} failed, restored the content it had before", target_path);
+                    ("completed", format!("restored the backup taken at {}", backup.taken_at))
+                }
+                Err(restore_error) => {
+                    error!("After action for {:?} failed and restoring its backup failed too: {}", target_path, restore_error);
+                    ("failed", format!("restoring the backup failed: {}", restore_error))
+                }
+            },
+            (Replaced::Nothing, _) => match fs::remove_file(target_path) {
+                Ok(()) => {
+                    warn!("After action for {:?} failed, removed the file it created", target_path);
+                    ("completed", "removed the file it created".to_string())
+                }
+                Err(remove_error) => {
+                    error!("After action for {:?} failed and removing the file failed too: {}", target_path, remove_error);
+                    ("failed", format!("removing the file failed: {}", remove_error))
+                }
+            },
+            _ => ("failed", "no previous content to restore".to_string()),
+        };
         
-        // Only create manifest entry if file is not within an enrolled directory
-        if should_create_entry {
-            let mut manifest = self.load_manifest()?;
-            let checksum = self.calculate_checksum(target_path)?;
-            
-            // Check group manifest to see if this file has enrolled_directory info
-            let enrolled_directory = if let Ok(group_manifest) = self.load_group_manifest(group) {
-                group_manifest.is_enrolled(target_path)
-                    .and_then(|e| e.enrolled_directory.as_ref())
-                    .map(|p| p.to_path_buf())
-            } else {
-                None
-            };
+        let record = crate::package::ActionRecord {
+            timestamp: chrono::Utc::now(),
+            hostname: self.hostname.clone(),
+            action_type: "revert".to_string(),
+            target: target_path.display().to_string(),
+            group: Some(group.to_string()),
+            status: status.to_string(),
+            details: Some(format!("after action failed ({}), {}", e, details)),
+            output: None,
+        };
+        if let Err(record_error) = record.save(&self.mfs_mount) {
+            warn!("Failed to record revert of {:?}: {}", target_path, record_error);
+        }
+    }
+    
+    /// Versions of the group templates as seen from this machine
+    pub fn versions(&self) -> TemplateVersions {
+        TemplateVersions::new(self.mfs_mount.clone(), self.hostname.clone())
+    }
+
+    /// Drift recorded for this machine's files
+    pub fn drift_ledger(&self) -> DriftLedger {
+        DriftLedger::new(self.mfs_mount.clone(), self.hostname.clone())
+    }
+
+    /// Make the local content of an enrolled file its machine-specific
+    /// template, so apply leaves it as it is on this machine. Returns the
+    /// group of the file.
+    pub fn promote_to_machine_template(&self, path: &Path) -> Result<String> {
+        let group = self.enrolled_group(path)?
+            .ok_or_else(|| LaszooError::Other(format!("{} is not enrolled", path.display())))?;
+        let enrolled_directory = self.load_group_manifest(&group)?
+            .enrolled_directory_of(path)
+            .map(|e| e.original_path.clone());
+
+        let options = EnrollOptions { force: true, machine_specific: true, ..Default::default() };
+        let mut plan = Plan::new();
+        self.plan_enroll_file_with_dir(path, &group, &options, enrolled_directory.as_deref(), &mut plan)?;
+        plan.run_blocking()?;
+        Ok(group)
+    }
+    
+    /// Group a template belongs to, from its path: .../groups/<group>/...
+    fn template_group(&self, template_path: &Path) -> Option<String> {
+        template_path.strip_prefix(self.mfs_mount.join("groups"))
+            .ok()
+            .and_then(|p| p.components().next())
+            .and_then(|c| c.as_os_str().to_str())
+            .map(|s| s.to_string())
+    }
+    
+    /// Variables available when rendering a template on this machine
+    pub fn template_vars(&self, template_path: &Path) -> Result<HashMap<String, serde_json::Value>> {
+        let group = self.template_group(template_path);
+        crate::vars::VarsManager::new(self.mfs_mount.clone(), self.hostname.clone())
+            .variables_for(group.as_deref())
+    }
+
+    /// Plan applying a template in `batch`: the write and the metadata
+    /// restored onto the file, once its before action succeeded, with its
+    /// after actions deferred. A template applied for its `group` also
+    /// records the file in this machine's manifest. Returns whether the
+    /// content changes.
+    fn plan_template(&self, template_path: &Path, target_path: &Path, group: Option<&str>, batch: &ActionBatch, plan: &mut Plan) -> Result<bool> {
+        let action_manager = self.action_manager();
+        let action_group = self.action_group(template_path, target_path)?;
+        let before = self.plan_before_action(batch, template_path, target_path, plan)?;
+        
+        // Render the template, binary templates are copied verbatim
+        let content = self.render_template_bytes(template_path, target_path)?;
+        let current = self.local_content(template_path, target_path)?;
+        let changed = current.as_deref() != Some(content.as_slice());
+        let is_symlink = self.is_symlink_template(template_path, target_path)?;
+        let is_binary = !is_symlink && crate::fs::is_binary_content(&content);
+        
+        if let Some(action_group) = action_group.as_deref().filter(|_| changed) {
+            action_manager.plan_validate(action_group, target_path, plan)?;
+        }
+        plan.write(target_path, current.as_deref(), &content);
+        if !is_symlink {
+            self.plan_restore_metadata(template_path, target_path, plan)?;
+        }
+        if let Some(action_group) = &action_group {
+            for step in action_manager.after_steps(action_group, target_path)? {
+                batch.defer_after(step, DeferredFile {
+                    group: action_group.clone(),
+                    path: target_path.to_path_buf(),
+                    template: template_path.to_path_buf(),
+                    replaced: Replaced::Unsaved,
+                });
+            }
+        }
+        let record = match group {
+            // Files in an enrolled directory have no entry of their own
+            Some(group) if is_symlink || self.load_group_manifest(group)?.enrolled_directory_of(target_path).is_none() => {
+                if changed {
+                    plan.metadata(&self.manifest_path(), format!("record checksum of {}", target_path.display()));
+                }
+                Some(group.to_string())
+            }
+            _ => None,
+        };
+        
+        let (this, batch) = (self.clone(), batch.clone());
+        let (template_path, target_path) = (template_path.to_path_buf(), target_path.to_path_buf());
+        plan.then(move || {
+            if let Some(result) = before.and_then(|command| batch.before_result(&command)) {
+                result?;
+            }
             
-            let entry = EnrollmentEntry {
-                original_path: target_path.to_path_buf(),
-                checksum,
-                group: group.to_string(),
-                enrolled_at: chrono::Utc::now(),
-                last_synced: Some(chrono::Utc::now()),
-                template_path: Some(template_path.to_path_buf()),
-                is_hybrid: if is_hybrid { Some(true) } else { None },
-                enrolled_directory,
-            };
+            info!("Applying template {:?} to {:?}", template_path, target_path);
+            debug!("Writing content to {:?}, length: {}", target_path, content.len());
+            let replaced = this.write_target(&template_path, &target_path, &content)?;
+            batch.written(&target_path, replaced);
             
-            manifest.add_entry(entry);
-            self.save_manifest(&manifest)?;
-        }
+            match record {
+                Some(group) => this.record_applied(&group, &template_path, &target_path, is_symlink, is_binary),
+                None => Ok(()),
+            }
+        });
+        Ok(changed)
+    }
+
+    /// Record an applied file in this machine's manifest
+    fn record_applied(&self, group: &str, template_path: &Path, target_path: &Path, is_symlink: bool, is_binary: bool) -> Result<()> {
+        let mut manifest = self.load_manifest()?;
+        let checksum = self.calculate_checksum(target_path)?;
+        let existing = manifest.is_enrolled(target_path);
+        let is_hybrid = existing.and_then(|e| e.is_hybrid).unwrap_or(false);
+        let sync_action = existing.and_then(|e| e.sync_action);
         
+        // Check group manifest to see if this file has enrolled_directory info
+        let enrolled_directory = if let Ok(group_manifest) = self.load_group_manifest(group) {
+            group_manifest.is_enrolled(target_path)
+                .and_then(|e| e.enrolled_directory.as_ref())
+                .map(|p| p.to_path_buf())
+        } else {
+            None
+        };
+        
+        let entry = EnrollmentEntry {
+            original_path: target_path.to_path_buf(),
+            checksum,
+            group: group.to_string(),
+            enrolled_at: chrono::Utc::now(),
+            last_synced: Some(chrono::Utc::now()),
+            template_path: Some(template_path.to_path_buf()),
+            is_hybrid: if is_hybrid { Some(true) } else { None },
+            is_binary: if is_binary { Some(true) } else { None },
+            is_symlink: if is_symlink { Some(true) } else { None },
+            sync_action,
+            enrolled_directory,
+        };
+        
+        manifest.add_entry(entry);
+        self.save_manifest(&manifest)
+    }
+
+    /// Plan what `record_metadata` would record
+    fn plan_record_metadata(&self, from: &Path, template_path: &Path, plan: &mut Plan) -> Result<()> {
+        let metadata = crate::metadata::FileMetadata::capture(from)?;
+        plan.metadata(&crate::metadata::FileMetadata::sidecar_path(template_path),
+            format!("record {}", metadata.changes_from(None).join(", ")));
         Ok(())
     }
 
+    /// Plan what `restore_metadata` would change on a target
+    fn plan_restore_metadata(&self, template_path: &Path, target_path: &Path, plan: &mut Plan) -> Result<()> {
+        let changes = self.metadata_changes(template_path, target_path)?;
+        if !changes.is_empty() {
+            plan.metadata(target_path, changes.join(", "));
+        }
+        Ok(())
+    }
+
+    /// How the recorded metadata differs from a target's, empty when apply
+    /// would leave its ownership and permissions alone
+    pub fn metadata_changes(&self, template_path: &Path, target_path: &Path) -> Result<Vec<String>> {
+        let machine_template = self.get_machine_template_path(target_path)?;
+        let recorded = match crate::metadata::FileMetadata::load_for_template(&machine_template)? {
+            Some(metadata) => Some(metadata),
+            None => crate::metadata::FileMetadata::load_for_template(template_path)?,
+        };
+
+        Ok(match recorded {
+            Some(recorded) => {
+                let current = crate::metadata::FileMetadata::capture(target_path).ok();
+                recorded.changes_from(current.as_ref())
+            }
+            None => Vec::new(),
+        })
+    }
+
+    /// Remove a file from this machine's manifest
     pub fn unenroll_file(&self, file_path: &Path) -> Result<()> {
+        let mut plan = Plan::new();
+        self.plan_unenroll_file(file_path, &mut plan)?;
+        plan.run_blocking()
+    }
+
+    /// Plan `unenroll_file`
+    pub fn plan_unenroll_file(&self, file_path: &Path, plan: &mut Plan) -> Result<()> {
         let abs_path = file_path.canonicalize()?;
-        let mut manifest = self.load_manifest()?;
+        if self.load_manifest()?.is_enrolled(&abs_path).is_none() {
+            warn!("File {:?} was not enrolled", abs_path);
+            return Ok(());
+        }
         
-        if let Some(entry) = manifest.remove_entry(&abs_path) {
+        plan.metadata(&self.manifest_path(), format!("unenroll {}", abs_path.display()));
+        let this = self.clone();
+        plan.then(move || {
             // Note: We don't remove the group template as other machines might be using it
-            self.save_manifest(&manifest)?;
-            info!("Successfully unenrolled {:?}", abs_path);
-            Ok(())
-        } else {
-            warn!("File {:?} was not enrolled", abs_path);
+            let mut manifest = this.load_manifest()?;
+            if manifest.remove_entry(&abs_path).is_some() {
+                this.save_manifest(&manifest)?;
+                info!("Successfully unenrolled {:?}", abs_path);
+            }
             Ok(())
-        }
+        });
+        Ok(())
     }
 
     pub fn list_enrolled_files(&self, group: Option<&str>) -> Result<Vec<EnrollmentEntry>> {
@@ -770,8 +1530,8 @@ impl EnrollmentManager {
     }
 
     pub fn check_file_status(&self, file_path: &Path) -> Result<Option<FileStatus>> {
-        // First check if file exists
-        if !file_path.exists() {
+        // First check if file exists (an enrolled symlink exists even if dangling)
+        if fs::symlink_metadata(file_path).is_err() {
             // File is missing - but we need to check if it's enrolled
             // Use the provided path as-is since we can't canonicalize a missing file
             
@@ -809,7 +1569,7 @@ impl EnrollmentManager {
         }
         
         // File exists, check its status
-        let abs_path = file_path.canonicalize()?;
+        let abs_path = crate::fs::absolute_path(file_path)?;
         
         // First check machine manifest
         let manifest = self.load_manifest()?;
@@ -858,35 +1618,63 @@ impl EnrollmentManager {
     }
 
     fn calculate_checksum(&self, path: &Path) -> Result<String> {
+        // A symlink's content is its target
+        if fs::symlink_metadata(path)?.file_type().is_symlink() {
+            return Ok(crate::fs::content_checksum(&crate::fs::read_link_bytes(path)?));
+        }
+        
         let mut file = fs::File::open(path)?;
         let mut hasher = Sha256::new();
         std::io::copy(&mut file, &mut hasher)?;
         Ok(format!("{:x}", hasher.finalize()))
     }
 
-    fn copy_metadata(&self, from: &Path, to: &Path) -> Result<()> {
-        let metadata = fs::metadata(from)?;
+    /// Record a file's metadata next to its template, and give the template the file's mode
+    fn record_metadata(&self, from: &Path, template_path: &Path) -> Result<()> {
+        let metadata = crate::metadata::FileMetadata::capture(from)?;
         
-        // Copy permissions
         #[cfg(unix)]
         {
             use std::os::unix::fs::PermissionsExt;
-            let permissions = metadata.permissions();
-            fs::set_permissions(to, permissions)?;
-        }
-        
-        // Note: Owner/group copying would require elevated privileges
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::MetadataExt;
-            let uid = metadata.uid();
-            let gid = metadata.gid();
-            debug!("Cannot copy ownership (uid: {}, gid: {}) to {:?} - requires elevated privileges", 
-                  uid, gid, to);
+            fs::set_permissions(template_path, fs::Permissions::from_mode(metadata.mode))?;
         }
         
+        metadata.save_for_template(template_path)?;
         Ok(())
     }
+    
+    /// Restore the metadata recorded for `target_path` onto `file`, the applied
+    /// content. The machine template's record wins over the group template's;
+    /// templates enrolled before metadata was recorded only pass on their
+    /// permissions.
+    fn restore_metadata(&self, template_path: &Path, target_path: &Path, file: &Path) -> Result<()> {
+        let machine_template = self.get_machine_template_path(target_path)?;
+        let recorded = match crate::metadata::FileMetadata::load_for_template(&machine_template)? {
+            Some(metadata) => Some(metadata),
+            None => crate::metadata::FileMetadata::load_for_template(template_path)?,
+        };
+        
+        match recorded {
+            Some(metadata) => metadata.apply(file),
+            None => {
+                fs::set_permissions(file, fs::metadata(template_path)?.permissions())?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Whether a directory entry is hidden, its name starting with a dot
+fn is_hidden(entry: &walkdir::DirEntry) -> bool {
+    entry.file_name().to_str().is_some_and(|name| name.starts_with('.'))
+}
+
+/// Fail a batch with the first file whose after action failed
+fn first_failure(failed: Vec<(PathBuf, LaszooError)>) -> Result<()> {
+    match failed.into_iter().next() {
+        Some((_, e)) => Err(e),
+        None => Ok(()),
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -30,6 +30,9 @@ pub enum LaszooError {
     #[error("Synchronization conflict: {0}")]
     SyncConflict(String),
     
+    #[error("Lock '{name}' is held by {holder}")]
+    LockHeld { name: String, holder: String },
+    
     #[error("Git error: {0}")]
     Git(#[from] git2::Error),
     
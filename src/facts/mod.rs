@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use serde::{Serialize, Deserialize};
+use tracing::{debug, info};
+use crate::error::Result;
+
+/// Facts about the local host, available to templates as `facts.*`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HostFacts {
+    pub hostname: String,
+    pub fqdn: String,
+    /// Address used to reach the default route, if there is one
+    pub primary_ipv4: Option<String>,
+    pub primary_ipv6: Option<String>,
+    /// All non-loopback addresses, sorted
+    pub ip_addresses: Vec<String>,
+    pub cpu_count: usize,
+    pub memory_bytes: u64,
+    pub memory_mb: u64,
+    pub os: OsRelease,
+    pub kernel: String,
+    pub machine_id: Option<String>,
+}
+
+/// Fields from /etc/os-release
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct OsRelease {
+    pub id: String,
+    pub version_id: String,
+    pub name: String,
+    pub pretty_name: String,
+}
+
+static LOCAL_FACTS: OnceLock<HostFacts> = OnceLock::new();
+
+impl HostFacts {
+    /// Facts for this host, collected once per process
+    pub fn local() -> &'static HostFacts {
+        LOCAL_FACTS.get_or_init(Self::collect)
+    }
+
+    /// Gather facts from the running system
+    pub fn collect() -> Self {
+        let hostname = gethostname::gethostname().to_string_lossy().to_string();
+        let ip_addresses = interface_addresses();
+
+        let primary_ipv4 = route_source("8.8.8.8:53")
+            .or_else(|| ip_addresses.iter().find(|ip| ip.is_ipv4()).copied());
+        let primary_ipv6 = route_source("[2001:4860:4860::8888]:53")
+            .or_else(|| ip_addresses.iter().find(|ip| ip.is_ipv6()).copied());
+
+        let memory_bytes = read_meminfo_total().unwrap_or(0);
+
+        let facts = Self {
+            fqdn: fqdn(&hostname),
+            hostname,
+            primary_ipv4: primary_ipv4.map(|ip| ip.to_string()),
+            primary_ipv6: primary_ipv6.map(|ip| ip.to_string()),
+            ip_addresses: ip_addresses.iter().map(|ip| ip.to_string()).collect(),
+            cpu_count: std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+            memory_bytes,
+            memory_mb: memory_bytes / (1024 * 1024),
+            os: read_os_release(Path::new("/etc/os-release")),
+            kernel: read_trimmed("/proc/sys/kernel/osrelease").unwrap_or_default(),
+            machine_id: read_trimmed("/etc/machine-id"),
+        };
+
+        debug!("Collected host facts: {:?}", facts);
+        facts
+    }
+
+    /// Location of a host's published facts on the shared filesystem
+    pub fn facts_path(mfs_mount: &Path, hostname: &str) -> PathBuf {
+        crate::fs::get_machine_dir(mfs_mount, "", hostname).join("facts.json")
+    }
+
+    /// Write these facts to machines/<host>/facts.json, leaving the file
+    /// untouched when nothing changed
+    pub fn publish(&self, mfs_mount: &Path) -> Result<()> {
+        let path = Self::facts_path(mfs_mount, &self.hostname);
+        let content = serde_json::to_string_pretty(self)?;
+
+        if std::fs::read_to_string(&path).map(|existing| existing == content).unwrap_or(false) {
+            return Ok(());
+        }
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&path, content)?;
+        info!("Published host facts to {:?}", path);
+        Ok(())
+    }
+
+    /// Read the facts another host published
+    pub fn load(mfs_mount: &Path, hostname: &str) -> Result<Self> {
+        let content = std::fs::read_to_string(Self::facts_path(mfs_mount, hostname))?;
+        Ok(serde_json::from_str(&content)?)
+    }
+}
+
+fn read_trimmed(path: &str) -> Option<String> {
+    std::fs::read_to_string(path)
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+fn fqdn(hostname: &str) -> String {
+    std::process::Command::new("hostname")
+        .arg("-f")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| hostname.to_string())
+}
+
+/// Source address the kernel picks to reach `target`. Connecting a UDP
+/// socket only selects a route, no packets are sent.
+fn route_source(target: &str) -> Option<IpAddr> {
+    let bind = if target.starts_with('[') { "[::]:0" } else { "0.0.0.0:0" };
+    let socket = UdpSocket::bind(bind).ok()?;
+    socket.connect(target).ok()?;
+    let ip = socket.local_addr().ok()?.ip();
+    (!ip.is_unspecified() && !ip.is_loopback()).then_some(ip)
+}
+
+/// Non-loopback, non-link-local addresses of all interfaces
+fn interface_addresses() -> Vec<IpAddr> {
+    let mut addresses = Vec::new();
+
+    unsafe {
+        let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+        if libc::getifaddrs(&mut ifaddrs) != 0 {
+            return addresses;
+        }
+
+        let mut current = ifaddrs;
+        while !current.is_null() {
+            let addr = (*current).ifa_addr;
+            if !addr.is_null() {
+                match (*addr).sa_family as i32 {
+                    libc::AF_INET => {
+                        let sin = &*(addr as *const libc::sockaddr_in);
+                        addresses.push(IpAddr::from(u32::from_be(sin.sin_addr.s_addr).to_be_bytes()));
+                    }
+                    libc::AF_INET6 => {
+                        let sin6 = &*(addr as *const libc::sockaddr_in6);
+                        addresses.push(IpAddr::from(sin6.sin6_addr.s6_addr));
+                    }
+                    _ => {}
+                }
+            }
+            current = (*current).ifa_next;
+        }
+
+        libc::freeifaddrs(ifaddrs);
+    }
+
+    addresses.retain(|ip| match ip {
+        IpAddr::V4(v4) => !v4.is_loopback() && !v4.is_link_local(),
+        IpAddr::V6(v6) => !v6.is_loopback() && (v6.segments()[0] & 0xffc0) != 0xfe80,
+    });
+    addresses.sort();
+    addresses.dedup();
+    addresses
+}
+
+fn read_meminfo_total() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    meminfo.lines()
+        .find(|line| line.starts_with("MemTotal:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|kb| kb.parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+}
+
+fn read_os_release(path: &Path) -> OsRelease {
+    let content = std::fs::read_to_string(path).unwrap_or_default();
+    parse_os_release(&content)
+}
+
+fn parse_os_release(content: &str) -> OsRelease {
+    let fields: HashMap<&str, String> = content.lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim(), value.trim().trim_matches('"').trim_matches('\'').to_string()))
+        .collect();
+
+    let field = |key: &str| fields.get(key).cloned().unwrap_or_default();
+    OsRelease {
+        id: field("ID"),
+        version_id: field("VERSION_ID"),
+        name: field("NAME"),
+        pretty_name: field("PRETTY_NAME"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_os_release() {
+        let os = parse_os_release("NAME=\"Ubuntu\"\nVERSION_ID=\"24.04\"\nID=ubuntu\nPRETTY_NAME=\"Ubuntu 24.04 LTS\"\n# comment\n");
+        assert_eq!(os.id, "ubuntu");
+        assert_eq!(os.version_id, "24.04");
+        assert_eq!(os.name, "Ubuntu");
+        assert_eq!(os.pretty_name, "Ubuntu 24.04 LTS");
+    }
+
+    #[test]
+    fn test_publish_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let facts = HostFacts::collect();
+        assert!(facts.cpu_count >= 1);
+
+        facts.publish(dir.path()).unwrap();
+        let loaded = HostFacts::load(dir.path(), &facts.hostname).unwrap();
+        assert_eq!(loaded, facts);
+    }
+}
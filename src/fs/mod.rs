@@ -88,6 +88,27 @@ pub fn ensure_distributed_fs_available(mount_path: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Whether an error means the shared filesystem went away
+pub fn is_filesystem_error(error: &LaszooError) -> bool {
+    match error {
+        LaszooError::Io(e) => {
+            // Check for common filesystem unavailability errors
+            matches!(e.kind(), 
+                std::io::ErrorKind::NotFound |
+                std::io::ErrorKind::PermissionDenied |
+                std::io::ErrorKind::Other
+            )
+        }
+        LaszooError::Other(msg) => {
+            msg.contains("filesystem") || 
+            msg.contains("mount") ||
+            msg.contains("not available") ||
+            msg.contains("Input/output error")
+        }
+        _ => false,
+    }
+}
+
 /// Get the Laszoo base directory within MooseFS
 pub fn get_laszoo_base(mfs_mount: &Path, _laszoo_dir: &str) -> PathBuf {
     mfs_mount.to_path_buf()
@@ -128,6 +149,34 @@ pub fn get_group_dir(mfs_mount: &Path, _laszoo_dir: &str, group_name: &str) -> P
     mfs_mount.join("groups").join(group_name)
 }
 
+/// List the machines that belong to a group, from each machine's groups.conf
+pub fn list_machines_in_group(mfs_mount: &Path, group_name: &str) -> Result<Vec<String>> {
+    let machines_dir = mfs_mount.join("machines");
+    let mut machines = Vec::new();
+
+    if let Ok(entries) = std::fs::read_dir(&machines_dir) {
+        for entry in entries.flatten() {
+            if let Some(machine_name) = entry.file_name().to_str() {
+                let groups_file = machines_dir
+                    .join(machine_name)
+                    .join("etc")
+                    .join("laszoo")
+                    .join("groups.conf");
+
+                if groups_file.exists() {
+                    let content = std::fs::read_to_string(&groups_file)?;
+                    if content.lines().any(|l| l.trim() == group_name) {
+                        machines.push(machine_name.to_string());
+                    }
+                }
+            }
+        }
+    }
+
+    machines.sort();
+    Ok(machines)
+}
+
 /// Get the path where a file's template would be stored in MooseFS for a specific host
 pub fn get_host_file_path(mfs_mount: &Path, _laszoo_dir: &str, hostname: &str, file_path: &Path) -> Result<PathBuf> {
     let host_dir = mfs_mount.join("machines").join(hostname);
@@ -164,6 +213,141 @@ pub fn get_group_template_path(mfs_mount: &Path, _laszoo_dir: &str, group_name:
     Ok(template_path)
 }
 
+/// Absolute path of a file without following a symlink at its final component,
+/// so an enrolled link is managed as the link rather than its target
+pub fn absolute_path(path: &Path) -> Result<PathBuf> {
+    let is_symlink = std::fs::symlink_metadata(path)
+        .map(|m| m.file_type().is_symlink())
+        .unwrap_or(false);
+    
+    if !is_symlink {
+        return Ok(path.canonicalize()?);
+    }
+    
+    let file_name = path.file_name()
+        .ok_or_else(|| LaszooError::InvalidPath { path: path.to_path_buf() })?;
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.canonicalize()?,
+        _ => std::env::current_dir()?,
+    };
+    Ok(parent.join(file_name))
+}
+
+/// Target of a symlink as raw bytes
+pub fn read_link_bytes(path: &Path) -> Result<Vec<u8>> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(std::fs::read_link(path)?.as_os_str().as_bytes().to_vec())
+}
+
+/// Make `link` a symlink pointing at `target`, replacing a file or link that is
+/// already there. The new link is renamed into place so it never goes missing.
+/// Returns whether anything changed.
+pub fn ensure_symlink(link: &Path, target: &Path) -> Result<bool> {
+    match std::fs::symlink_metadata(link) {
+        Ok(metadata) if metadata.file_type().is_symlink() => {
+            if std::fs::read_link(link)? == target {
+                return Ok(false);
+            }
+        }
+        Ok(metadata) if metadata.is_dir() => {
+            return Err(LaszooError::InvalidPath { path: link.to_path_buf() });
+        }
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            if let Some(parent) = link.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+        }
+        Err(e) => return Err(e.into()),
+    }
+    
+    let file_name = link.file_name()
+        .ok_or_else(|| LaszooError::InvalidPath { path: link.to_path_buf() })?;
+    let temp_link = link.with_file_name(format!(".{}.laszoo-link", file_name.to_string_lossy()));
+    let _ = std::fs::remove_file(&temp_link);
+    std::os::unix::fs::symlink(target, &temp_link)?;
+    std::fs::rename(&temp_link, link)?;
+    
+    Ok(true)
+}
+
+/// Replace the content of a file atomically.
+///
+/// The content is written to a temporary file in the same directory, which
+/// first takes on the metadata of the file it replaces and is then handed to
+/// `prepare` (e.g. to restore recorded ownership and mode). It is flushed to
+/// disk and renamed over `path`, so a crash or a full disk leaves either the
+/// old or the new file, never a partial one. A symlink is written through to
+/// the file it points at.
+pub fn atomic_write(path: &Path, content: &[u8], prepare: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+    use std::io::Write;
+    
+    let path = resolve_symlinks(path)?;
+    let file_name = path.file_name()
+        .ok_or_else(|| LaszooError::InvalidPath { path: path.clone() })?;
+    let parent = path.parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    std::fs::create_dir_all(parent)?;
+    
+    let temp_path = parent.join(format!(".{}.laszoo-{}.tmp", file_name.to_string_lossy(), std::process::id()));
+    let result: Result<()> = (|| {
+        let mut file = std::fs::File::create(&temp_path)?;
+        file.write_all(content)?;
+        
+        if std::fs::metadata(&path).is_ok_and(|m| m.is_file()) {
+            crate::metadata::FileMetadata::capture(&path)?.apply(&temp_path)?;
+        }
+        prepare(&temp_path)?;
+        
+        file.sync_all()?;
+        drop(file);
+        std::fs::rename(&temp_path, &path)?;
+        
+        // Persist the rename itself
+        std::fs::File::open(parent)?.sync_all()?;
+        Ok(())
+    })();
+    
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Follow `path` through any symlinks to the path they end at, which need
+/// not exist yet
+fn resolve_symlinks(path: &Path) -> Result<PathBuf> {
+    let mut path = path.to_path_buf();
+    for _ in 0..40 {
+        match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                let target = std::fs::read_link(&path)?;
+                path = match path.parent() {
+                    Some(parent) if target.is_relative() => parent.join(target),
+                    _ => target,
+                };
+            }
+            _ => return Ok(path),
+        }
+    }
+    Err(LaszooError::Other(format!("Too many levels of symbolic links at {:?}", path)))
+}
+
+/// Whether content should be handled as binary: it has a NUL byte near the
+/// start or is not valid UTF-8. Binary files skip template processing and are
+/// stored and applied byte-for-byte.
+pub fn is_binary_content(content: &[u8]) -> bool {
+    let head = &content[..content.len().min(8000)];
+    head.contains(&0) || std::str::from_utf8(content).is_err()
+}
+
+/// Calculate SHA256 checksum of in-memory content
+pub fn content_checksum(content: &[u8]) -> String {
+    use sha2::{Sha256, Digest};
+    format!("{:x}", Sha256::digest(content))
+}
+
 /// Calculate SHA256 checksum of a file
 pub fn calculate_file_checksum(path: &Path) -> Result<String> {
     use sha2::{Sha256, Digest};
@@ -182,4 +366,20 @@ pub fn calculate_file_checksum(path: &Path) -> Result<String> {
     }
     
     Ok(format!("{:x}", hasher.finalize()))
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_atomic_write_through_dangling_symlink() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let link = dir.path().join("current.conf");
+        std::os::unix::fs::symlink("releases/app.conf", &link).unwrap();
+        std::fs::create_dir(dir.path().join("releases")).unwrap();
+        
+        atomic_write(&link, b"workers = 4\n", |_| Ok(())).unwrap();
+        assert!(std::fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert_eq!(std::fs::read(dir.path().join("releases/app.conf")).unwrap(), b"workers = 4\n");
+    }
+}
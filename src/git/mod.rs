@@ -3,11 +3,25 @@ use git2::{Repository, Signature, IndexAddOption, Oid, StatusOptions, Status};
 use serde::{Deserialize, Serialize};
 use tracing::{info, debug, warn, error};
 use crate::error::{LaszooError, Result};
+use crate::lock::LockManager;
+
+/// How long a commit may hold the repository lock
+const COMMIT_LEASE_TTL: std::time::Duration = std::time::Duration::from_secs(60);
+/// How long to wait for another host's commit to finish
+const COMMIT_LOCK_WAIT: std::time::Duration = std::time::Duration::from_secs(30);
 
 pub struct GitManager {
     repo_path: PathBuf,
 }
 
+/// A file restored by a rollback
+#[derive(Debug, Clone)]
+pub struct RolledBackFile {
+    pub path: PathBuf,
+    /// True if the file did not exist in the restored version and was deleted
+    pub removed: bool,
+}
+
 #[derive(Debug, Serialize)]
 struct OllamaRequest {
     model: String,
@@ -121,14 +135,57 @@ impl GitManager {
         };
         
         // Create the commit
-        let signature = self.get_signature()?;
-        let tree_id = {
-            let mut index = repo.index()?;
-            index.write_tree()?
-        };
+        let commit_id = self.commit_index(&repo, &commit_message)?;
+        println!("\nCommit message:\n{}", commit_message);
+        
+        Ok(commit_id)
+    }
+    
+    /// Create a commit from the staged changes with the given message
+    pub fn commit(&self, message: &str) -> Result<Oid> {
+        let repo = self.init_repo()?;
+        self.commit_index(&repo, message)
+    }
+    
+    /// Create a commit of `paths` as they are staged on top of HEAD. Other
+    /// staged changes are left out of it and stay staged.
+    pub fn commit_paths(&self, message: &str, paths: &[PathBuf]) -> Result<Oid> {
+        let repo = self.init_repo()?;
+        self.commit_tree(&repo, message, |head| {
+            let staged = repo.index()?;
+            let mut index = git2::Index::new()?;
+            if let Some(head) = head {
+                index.read_tree(&head.tree()?)?;
+            }
+            for path in paths {
+                let relative_path = path.strip_prefix(&self.repo_path).unwrap_or(path);
+                match staged.get_path(relative_path, 0) {
+                    Some(entry) => index.add(&entry)?,
+                    None => index.remove_path(relative_path)?,
+                }
+            }
+            Ok(index.write_tree_to(&repo)?)
+        })
+    }
+    
+    /// Write the current index as a commit on top of HEAD
+    fn commit_index(&self, repo: &Repository, message: &str) -> Result<Oid> {
+        self.commit_tree(repo, message, |_| Ok(repo.index()?.write_tree()?))
+    }
+    
+    /// Commit the tree `tree` builds on top of HEAD, which it is given
+    fn commit_tree<F>(&self, repo: &Repository, message: &str, tree: F) -> Result<Oid>
+    where
+        F: FnOnce(Option<&git2::Commit>) -> Result<Oid>,
+    {
+        // Hosts share the repository, only one may move HEAD at a time
+        let locks = LockManager::new(self.repo_path.clone());
+        let _lease = locks.hold("git", COMMIT_LEASE_TTL, COMMIT_LOCK_WAIT)?;
         
+        let signature = self.get_signature()?;
+        let parent_commit = self.get_head_commit(repo).ok();
+        let tree_id = tree(parent_commit.as_ref())?;
         let tree = repo.find_tree(tree_id)?;
-        let parent_commit = self.get_head_commit(&repo).ok();
         
         let commit_id = match parent_commit {
             Some(parent) => {
@@ -136,7 +193,7 @@ impl GitManager {
                     Some("HEAD"),
                     &signature,
                     &signature,
-                    &commit_message,
+                    message,
                     &tree,
                     &[&parent],
                 )?
@@ -147,7 +204,7 @@ impl GitManager {
                     Some("HEAD"),
                     &signature,
                     &signature,
-                    &commit_message,
+                    message,
                     &tree,
                     &[],
                 )?
@@ -155,11 +212,153 @@ impl GitManager {
         };
         
         info!("Created commit: {}", commit_id);
-        println!("\nCommit message:\n{}", commit_message);
-        
         Ok(commit_id)
     }
     
+    /// Restore every file accepted by `filter` to the state it had before the
+    /// last `changes` commits that touched any of them, and stage the result.
+    ///
+    /// Paths passed to `filter` are relative to the repository root. Files that
+    /// did not exist at that point are removed. Returns the restored paths.
+    pub fn rollback_paths<F>(&self, filter: F, changes: usize) -> Result<Vec<RolledBackFile>>
+    where
+        F: Fn(&Path) -> bool,
+    {
+        if changes == 0 {
+            return Err(LaszooError::Other("Rollback needs at least one commit".to_string()));
+        }
+        
+        let repo = self.init_repo()?;
+        let head = self.get_head_commit(&repo)
+            .map_err(|_| LaszooError::Other("Repository has no commits to roll back".to_string()))?;
+        
+        // Refuse to clobber uncommitted work on the affected templates
+        let dirty: Vec<PathBuf> = self.get_status()?
+            .into_iter()
+            .filter_map(|(path, _)| path.strip_prefix(&self.repo_path).ok().map(|p| p.to_path_buf()))
+            .filter(|path| filter(path))
+            .collect();
+        if !dirty.is_empty() {
+            return Err(LaszooError::SyncConflict(format!(
+                "Uncommitted changes to {} file(s), commit them first: {}",
+                dirty.len(),
+                dirty.iter().map(|p| p.display().to_string()).collect::<Vec<_>>().join(", ")
+            )));
+        }
+        
+        // Walk first-parent history for commits that touched the selected paths
+        let mut revwalk = repo.revwalk()?;
+        revwalk.push(head.id())?;
+        revwalk.simplify_first_parent()?;
+        
+        let mut touching = Vec::new();
+        for oid in revwalk {
+            let commit = repo.find_commit(oid?)?;
+            let tree = commit.tree()?;
+            let parent_tree = match commit.parent_count() {
+                0 => None,
+                _ => Some(commit.parent(0)?.tree()?),
+            };
+            
+            let diff = repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), None)?;
+            let touched = diff.deltas().any(|delta| {
+                [delta.old_file().path(), delta.new_file().path()]
+                    .into_iter()
+                    .flatten()
+                    .any(|p| filter(p))
+            });
+            
+            if touched {
+                debug!("Commit {} touched rollback target", commit.id());
+                touching.push(commit);
+                if touching.len() == changes {
+                    break;
+                }
+            }
+        }
+        
+        if touching.len() < changes {
+            return Err(LaszooError::Other(format!(
+                "Only {} earlier version(s) found in history, cannot roll back {}",
+                touching.len(), changes
+            )));
+        }
+        
+        let oldest = &touching[changes - 1];
+        if oldest.parent_count() == 0 {
+            return Err(LaszooError::Other(format!(
+                "Commit {} introduced these files, there is no earlier version to restore",
+                oldest.id()
+            )));
+        }
+        let target_commit = oldest.parent(0)?;
+        let target_tree = target_commit.tree()?;
+        let head_tree = head.tree()?;
+        
+        let target_files = Self::collect_tree_paths(&target_tree, &filter)?;
+        let head_files = Self::collect_tree_paths(&head_tree, &filter)?;
+        
+        let mut index = repo.index()?;
+        let mut restored = Vec::new();
+        
+        for relative_path in &target_files {
+            let blob = target_tree.get_path(relative_path)?
+                .to_object(&repo)?
+                .peel_to_blob()?;
+            let full_path = self.repo_path.join(relative_path);
+            
+            let unchanged = head_tree.get_path(relative_path)
+                .map(|entry| entry.id() == blob.id())
+                .unwrap_or(false);
+            if unchanged {
+                continue;
+            }
+            
+            if let Some(parent) = full_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(&full_path, blob.content())?;
+            index.add_path(relative_path)?;
+            
+            info!("Restored {:?} from commit {}", relative_path, target_commit.id());
+            restored.push(RolledBackFile { path: full_path, removed: false });
+        }
+        
+        for relative_path in head_files.iter().filter(|p| !target_files.contains(p)) {
+            let full_path = self.repo_path.join(relative_path);
+            if full_path.exists() {
+                std::fs::remove_file(&full_path)?;
+            }
+            index.remove_path(relative_path)?;
+            
+            info!("Removed {:?}, it did not exist at commit {}", relative_path, target_commit.id());
+            restored.push(RolledBackFile { path: full_path, removed: true });
+        }
+        
+        index.write()?;
+        Ok(restored)
+    }
+    
+    /// Collect the blob paths in a tree that match `filter`
+    fn collect_tree_paths<F>(tree: &git2::Tree, filter: &F) -> Result<Vec<PathBuf>>
+    where
+        F: Fn(&Path) -> bool,
+    {
+        let mut paths = Vec::new();
+        tree.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+            if entry.kind() == Some(git2::ObjectType::Blob) {
+                if let Some(name) = entry.name() {
+                    let path = PathBuf::from(root).join(name);
+                    if filter(&path) {
+                        paths.push(path);
+                    }
+                }
+            }
+            git2::TreeWalkResult::Ok
+        })?;
+        Ok(paths)
+    }
+    
     /// Get staged diff
     fn get_staged_diff(&self) -> Result<String> {
         let repo = self.init_repo()?;
@@ -5,10 +5,22 @@ pub mod fs;
 pub mod logging;
 pub mod enrollment;
 pub mod template;
+pub mod diff;
+pub mod plan;
+pub mod drift;
+pub mod vars;
+pub mod facts;
+pub mod metadata;
+pub mod majority;
+pub mod lock;
+pub mod queue;
+pub mod version;
+pub mod backup;
 pub mod monitor;
 pub mod git;
 pub mod group;
 pub mod package;
+pub mod check;
 pub mod action;
 pub mod service;
 pub mod webui;
\ No newline at end of file
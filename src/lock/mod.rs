@@ -0,0 +1,345 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use serde::{Serialize, Deserialize};
+use tracing::{debug, info, warn};
+use crate::error::{LaszooError, Result};
+
+/// How often a waiting host checks whether a lock has become free
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// A time-limited claim on a named lock, stored in `<mount>/locks/<name>.lease`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Lease {
+    pub name: String,
+    /// Host holding the lease
+    pub owner: String,
+    pub pid: u32,
+    /// Increases every time the lock changes hands, so a resource can reject
+    /// writes from a holder whose lease has since expired
+    pub token: u64,
+    pub acquired_at: chrono::DateTime<chrono::Utc>,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl Lease {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= chrono::Utc::now()
+    }
+}
+
+/// Distributed locks built from lease files on the shared filesystem.
+///
+/// A lease is created with a hard link from a fully written temporary file,
+/// which fails if another host created it first. An expired lease is stolen
+/// by renaming it aside, which only one host can do, before creating a new
+/// one with the next fencing token. Released leases are left in place as
+/// expired so the token keeps increasing.
+pub struct LockManager {
+    dir: PathBuf,
+    hostname: String,
+    pid: u32,
+}
+
+impl LockManager {
+    /// Locks held as this host
+    pub fn new(mfs_mount: PathBuf) -> Self {
+        Self::with_host(mfs_mount, &gethostname::gethostname().to_string_lossy())
+    }
+
+    /// Locks held as another host
+    pub fn with_host(mfs_mount: PathBuf, hostname: &str) -> Self {
+        Self {
+            dir: mfs_mount.join("locks"),
+            hostname: hostname.to_string(),
+            pid: std::process::id(),
+        }
+    }
+
+    /// Take leases on behalf of another process
+    pub fn with_pid(mut self, pid: u32) -> Self {
+        self.pid = pid;
+        self
+    }
+
+    fn lease_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.lease", file_name(name)))
+    }
+
+    /// Private file name for this process next to a lease
+    fn scratch_path(&self, name: &str, suffix: &str) -> PathBuf {
+        self.dir.join(format!(".{}.{}.{}.{}", file_name(name), self.hostname, self.pid, suffix))
+    }
+
+    fn ensure_dir(&self) -> Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        // Leases are runtime state, keep them out of the template history
+        let gitignore = self.dir.join(".gitignore");
+        if !gitignore.exists() {
+            std::fs::write(&gitignore, "*\n")?;
+        }
+        Ok(())
+    }
+
+    /// The unexpired lease on a lock, if any
+    pub fn current(&self, name: &str) -> Result<Option<Lease>> {
+        Ok(read_lease(&self.lease_path(name))?.filter(|lease| !lease.is_expired()))
+    }
+
+    /// All leases, including expired and released ones, sorted by name
+    pub fn list(&self) -> Result<Vec<Lease>> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut leases = Vec::new();
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension() != Some(std::ffi::OsStr::new("lease")) {
+                continue;
+            }
+            match read_lease(&path) {
+                Ok(Some(lease)) => leases.push(lease),
+                Ok(None) => {}
+                Err(e) => warn!("Ignoring unreadable lease {:?}: {}", path, e),
+            }
+        }
+        leases.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(leases)
+    }
+
+    /// Take a lock if it is free or its lease has expired. A lease already
+    /// held by this host and process is extended. Returns None if another
+    /// holder has it.
+    pub fn try_acquire(&self, name: &str, ttl: Duration) -> Result<Option<Lease>> {
+        self.ensure_dir()?;
+        let path = self.lease_path(name);
+        let now = chrono::Utc::now();
+        let ttl = chrono::Duration::from_std(ttl)
+            .map_err(|e| LaszooError::Other(format!("Invalid lease duration: {}", e)))?;
+
+        let token = match read_lease(&path)? {
+            Some(current) if !current.is_expired() => {
+                if current.owner != self.hostname || current.pid != self.pid {
+                    return Ok(None);
+                }
+                let renewed = Lease { expires_at: now + ttl, ..current };
+                self.replace(&path, &renewed)?;
+                debug!("Renewed lease on {} until {}", name, renewed.expires_at);
+                return Ok(Some(renewed));
+            }
+            Some(stale) => {
+                if !self.steal(&path, &stale)? {
+                    return Ok(None);
+                }
+                info!("Took over expired lease on {} from {} (pid {})", name, stale.owner, stale.pid);
+                stale.token + 1
+            }
+            None => 1,
+        };
+
+        let lease = Lease {
+            name: name.to_string(),
+            owner: self.hostname.clone(),
+            pid: self.pid,
+            token,
+            acquired_at: now,
+            expires_at: now + ttl,
+        };
+        if !self.create(&path, &lease)? {
+            return Ok(None);
+        }
+        debug!("Acquired lease on {} with token {}", name, token);
+        Ok(Some(lease))
+    }
+
+    /// Take a lock, waiting up to `wait` for the current holder to release it
+    /// or for its lease to expire
+    pub fn acquire(&self, name: &str, ttl: Duration, wait: Duration) -> Result<Lease> {
+        let deadline = std::time::Instant::now() + wait;
+        loop {
+            if let Some(lease) = self.try_acquire(name, ttl)? {
+                return Ok(lease);
+            }
+            if std::time::Instant::now() >= deadline {
+                return Err(self.busy_error(name));
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Take a lock for the lifetime of the returned guard. A lease this
+    /// process already holds is extended and left in place when the guard
+    /// is dropped.
+    pub fn hold(&self, name: &str, ttl: Duration, wait: Duration) -> Result<LeaseGuard<'_>> {
+        let nested = self.current(name)?
+            .is_some_and(|lease| lease.owner == self.hostname && lease.pid == self.pid);
+        let lease = self.acquire(name, ttl, wait)?;
+        Ok(LeaseGuard { manager: self, lease, release: !nested })
+    }
+
+    /// Give up a lease. Nothing is changed if the lock has since been taken
+    /// over by someone else. Returns whether the lease was released.
+    pub fn release(&self, lease: &Lease) -> Result<bool> {
+        let path = self.lease_path(&lease.name);
+        match read_lease(&path)? {
+            Some(current) if current.token == lease.token && !current.is_expired() => {
+                let released = Lease { expires_at: chrono::Utc::now(), ..current };
+                self.replace(&path, &released)?;
+                debug!("Released lease on {} with token {}", lease.name, lease.token);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Release a lock by name when it is held from this host, or from any
+    /// host with `force`. Returns the released lease.
+    pub fn release_name(&self, name: &str, force: bool) -> Result<Option<Lease>> {
+        let Some(lease) = self.current(name)? else {
+            return Ok(None);
+        };
+        if lease.owner != self.hostname && !force {
+            return Err(LaszooError::Other(format!(
+                "Lock '{}' is held by {} (pid {}), use --force to release it", name, lease.owner, lease.pid
+            )));
+        }
+        Ok(self.release(&lease)?.then_some(lease))
+    }
+
+    fn busy_error(&self, name: &str) -> LaszooError {
+        let holder = match self.current(name) {
+            Ok(Some(lease)) => format!(
+                "{} (pid {}) until {}",
+                lease.owner, lease.pid, lease.expires_at.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S")
+            ),
+            // Released or taken over while we looked
+            _ => "another host".to_string(),
+        };
+        LaszooError::LockHeld { name: name.to_string(), holder }
+    }
+
+    /// Create a lease file unless one exists. Returns false if another host
+    /// created it first.
+    fn create(&self, path: &Path, lease: &Lease) -> Result<bool> {
+        let temp = self.scratch_path(&lease.name, "tmp");
+        std::fs::write(&temp, serde_json::to_string_pretty(lease)?)?;
+        let linked = std::fs::hard_link(&temp, path);
+        std::fs::remove_file(&temp)?;
+        match linked {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Overwrite a lease file this process holds
+    fn replace(&self, path: &Path, lease: &Lease) -> Result<()> {
+        let temp = self.scratch_path(&lease.name, "tmp");
+        std::fs::write(&temp, serde_json::to_string_pretty(lease)?)?;
+        std::fs::rename(&temp, path)?;
+        Ok(())
+    }
+
+    /// Move an expired lease out of the way. Returns false if another host got
+    /// there first.
+    fn steal(&self, path: &Path, stale: &Lease) -> Result<bool> {
+        let aside = self.scratch_path(&stale.name, "stale");
+        match std::fs::rename(path, &aside) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        }
+
+        // Another host may have replaced the lease between reading and renaming
+        let moved = read_lease(&aside)?;
+        if moved.as_ref().is_some_and(|lease| lease.token != stale.token || !lease.is_expired()) {
+            let _ = std::fs::hard_link(&aside, path);
+            std::fs::remove_file(&aside)?;
+            return Ok(false);
+        }
+        std::fs::remove_file(&aside)?;
+        Ok(true)
+    }
+}
+
+/// Releases its lease when dropped
+pub struct LeaseGuard<'a> {
+    manager: &'a LockManager,
+    lease: Lease,
+    release: bool,
+}
+
+impl LeaseGuard<'_> {
+    pub fn lease(&self) -> &Lease {
+        &self.lease
+    }
+}
+
+impl Drop for LeaseGuard<'_> {
+    fn drop(&mut self) {
+        if self.release {
+            if let Err(e) = self.manager.release(&self.lease) {
+                warn!("Failed to release lock '{}': {}", self.lease.name, e);
+            }
+        }
+    }
+}
+
+/// File name for a lock, with characters that can't appear in one replaced
+fn file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') { c } else { '_' })
+        .collect()
+}
+
+fn read_lease(path: &Path) -> Result<Option<Lease>> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => Ok(Some(serde_json::from_str(&content)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lease_exclusion_and_takeover() {
+        let dir = tempfile::tempdir().unwrap();
+        let web01 = LockManager::with_host(dir.path().to_path_buf(), "web01").with_pid(100);
+        let web02 = LockManager::with_host(dir.path().to_path_buf(), "web02").with_pid(200);
+
+        let lease = web01.try_acquire("patch.web", Duration::from_secs(60)).unwrap().unwrap();
+        assert_eq!(lease.token, 1);
+        assert!(web02.try_acquire("patch.web", Duration::from_secs(60)).unwrap().is_none());
+
+        // Released leases can be taken straight away, with the next token
+        assert!(web01.release(&lease).unwrap());
+        let lease = web02.try_acquire("patch.web", Duration::from_millis(1)).unwrap().unwrap();
+        assert_eq!(lease.token, 2);
+        assert_eq!(lease.owner, "web02");
+
+        // An expired lease is stolen, and its old holder can no longer release it
+        std::thread::sleep(Duration::from_millis(5));
+        let stolen = web01.try_acquire("patch.web", Duration::from_secs(60)).unwrap().unwrap();
+        assert_eq!(stolen.token, 3);
+        assert!(!web02.release(&lease).unwrap());
+        assert_eq!(web02.current("patch.web").unwrap(), Some(stolen));
+    }
+
+    #[test]
+    fn test_guard_releases_on_drop() {
+        let dir = tempfile::tempdir().unwrap();
+        let locks = LockManager::with_host(dir.path().to_path_buf(), "web01").with_pid(100);
+
+        {
+            let _guard = locks.hold("git", Duration::from_secs(60), Duration::ZERO).unwrap();
+            assert!(locks.current("git").unwrap().is_some());
+        }
+        assert!(locks.current("git").unwrap().is_none());
+        assert_eq!(locks.list().unwrap().len(), 1);
+    }
+}
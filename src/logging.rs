@@ -29,24 +29,24 @@ pub fn init_logging(config: &LoggingConfig, verbose: bool) -> Result<()> {
     // Configure format
     let format = config.format.clone();
     
-    // Set up subscriber based on format
+    // Set up subscriber based on format, logging to stderr so command output stays parseable
     match format.as_str() {
         "json" => {
             tracing_subscriber::registry()
                 .with(env_filter)
-                .with(fmt::layer().json().with_target(true))
+                .with(fmt::layer().json().with_target(true).with_writer(std::io::stderr))
                 .init();
         }
         "compact" => {
             tracing_subscriber::registry()
                 .with(env_filter)
-                .with(fmt::layer().compact().with_target(false))
+                .with(fmt::layer().compact().with_target(false).with_writer(std::io::stderr))
                 .init();
         }
         _ => {
             tracing_subscriber::registry()
                 .with(env_filter)
-                .with(fmt::layer().pretty().with_target(true))
+                .with(fmt::layer().pretty().with_target(true).with_writer(std::io::stderr))
                 .init();
         }
     }
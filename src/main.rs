@@ -5,10 +5,22 @@ mod fs;
 mod logging;
 mod enrollment;
 mod template;
+mod diff;
+mod plan;
+mod drift;
+mod vars;
+mod facts;
+mod metadata;
+mod majority;
+mod lock;
+mod queue;
+mod version;
+mod backup;
 mod monitor;
 mod git;
 mod group;
 mod package;
+mod check;
 mod action;
 mod service;
 mod webui;
@@ -18,18 +30,14 @@ use tracing::{info, error, debug, warn};
 use std::path::{Path, PathBuf};
 use std::collections::{HashMap, HashSet};
 
-#[derive(Debug, Clone, Copy)]
-enum PackageStatus {
-    UpToDate,
-    PendingUpdates,
-    PhasedUpdates,  // Updates available but phased/held back
-    Missing,
-}
-
 use crate::{
-    cli::{Cli, Commands, GroupCommands, GroupsCommands, SyncAction},
+    action::{ActionConfig, ActionUpdate},
+    cli::{ActCommands, Cli, Commands, GroupCommands, GroupsCommands, SyncAction, VarsCommands},
     config::Config,
+    enrollment::EnrollOptions,
     error::{Result, LaszooError},
+    package::{check_package_status, check_phased_updates, check_system_updates, PackageStatus},
+    plan::Plan,
 };
 
 #[tokio::main]
@@ -46,6 +54,12 @@ async fn main() -> Result<()> {
     // Log startup info
     info!("Starting Laszoo v{}", env!("CARGO_PKG_VERSION"));
 
+    // Mutating commands only show what they would do
+    if cli.dry_run && !dry_run_supported(&cli.command) {
+        return Err(LaszooError::Other("--dry-run is not supported for this command".to_string()));
+    }
+    let dry_run = cli.dry_run;
+
     match cli.command {
         Commands::Init { mfs_mount } => {
             init_laszoo(&config, &mfs_mount).await?;
@@ -53,41 +67,93 @@ async fn main() -> Result<()> {
         Commands::Commit { message, all } => {
             commit_changes(&config, message.as_deref(), all).await?;
         }
-        Commands::Enroll { group, paths, force, include_hidden, machine, hybrid, before, after, action } => {
-            enroll_files(&config, &group, paths, force, include_hidden, machine, hybrid, before, after, action).await?;
+        Commands::Enroll { group, paths, force, include_hidden, machine, hybrid, before, after, validate, notify_service, action } => {
+            let options = EnrollOptions {
+                force,
+                machine_specific: machine,
+                hybrid,
+                include_hidden,
+                actions: ActionConfig { before, after, validate, notify: notify_service },
+            };
+            enroll_files(&config, &group, paths, options, action, dry_run).await?;
         }
         Commands::Unenroll { group, paths } => {
-            unenroll_files(&config, group, paths).await?;
+            unenroll_files(&config, group, paths, dry_run).await?;
+        }
+        Commands::SetAction { path, action, machine } => {
+            set_file_action(&config, &path, action, machine)?;
+        }
+        Commands::Act { command: Some(ActCommands::List { group }), .. } => {
+            list_file_actions(&config, group)?;
+        }
+        Commands::Act { command: None, group, path, machine: _, before, after, validate, notify_service, no_notify_service, clear } => {
+            let notify = (no_notify_service || !notify_service.is_empty()).then_some(notify_service);
+            let update = ActionUpdate { before, after, validate, notify };
+            set_file_actions(&config, group, path, update, clear, dry_run).await?;
         }
         Commands::Status { detailed } => {
             show_status(&config, detailed).await?;
         }
         Commands::Rollback { target, commits } => {
-            info!("Rolling back {} commits for {}", commits, target);
-            // TODO: Implement rollback
-            println!("Rollback not yet implemented");
+            rollback_changes(&config, &target, commits).await?;
+        }
+        Commands::Restore { path, at, list } => {
+            restore_file(&config, &path, at.as_deref(), list)?;
+        }
+        Commands::Resolve { path, take, keep } => {
+            resolve_conflict(&config, &path, take.as_deref(), keep)?;
+        }
+        Commands::Diff { group, files, json, no_color } => {
+            // Drift exits 1, failing to compare exits 2
+            match show_diff(&config, group.as_deref(), files, json, no_color).await {
+                Ok(true) => std::process::exit(1),
+                Ok(false) => {}
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(2);
+                }
+            }
+        }
+        Commands::Check { group, json, nagios } => {
+            let code = run_check(&config, group.as_deref(), json, nagios).await?;
+            if code != 0 {
+                std::process::exit(code);
+            }
+        }
+        Commands::Drift { command } => {
+            handle_drift_command(&config, command)?;
         }
         Commands::Apply { group, files } => {
-            apply_group_templates(&config, &group, files).await?;
+            apply_group_templates(&config, &group, files, dry_run).await?;
         }
         Commands::Group { name, command } => {
-            handle_group_command(&name, command).await?;
+            handle_group_command(&name, command, dry_run).await?;
         }
         Commands::Groups { command } => {
             handle_groups_command(command).await?;
         }
+        Commands::Vars { command } => {
+            handle_vars_command(&config, command)?;
+        }
         Commands::Watch { group, interval, auto, hard } => {
-            watch_for_changes(&config, group.as_deref(), interval, auto, hard).await?;
+            if dry_run {
+                print_plan(&plan_watch(&config, group.as_deref(), auto, hard)?);
+            } else {
+                watch_for_changes(&config, group.as_deref(), interval, auto, hard).await?;
+            }
         }
         Commands::Install { group, packages, after } => {
-            install_packages(&config, &group, packages, after.as_deref()).await?;
+            install_packages(&config, &group, packages, after.as_deref(), dry_run).await?;
         }
         Commands::Patch { group, before, after, rolling } => {
-            patch_group(&config, &group, before.as_deref(), after.as_deref(), rolling).await?;
+            patch_group(&config, &group, before.as_deref(), after.as_deref(), rolling, dry_run).await?;
         }
         Commands::Service { command } => {
             handle_service_command(command).await?;
         }
+        Commands::Lock { command } => {
+            handle_lock_command(&config, command)?;
+        }
         Commands::WebUI { port, bind: _ } => {
             let webui = crate::webui::WebUI::new(std::sync::Arc::new(config));
             info!("Starting Laszoo Web UI on http://0.0.0.0:{}", port);
@@ -97,6 +163,38 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+/// Whether a command takes `--dry-run`: mutating commands print the plan
+/// they build instead of running it, commands that change nothing run as usual
+fn dry_run_supported(command: &Commands) -> bool {
+    use crate::cli::{DriftCommands, LockCommands, ServiceCommands};
+
+    matches!(command,
+        Commands::Enroll { .. } | Commands::Unenroll { .. } | Commands::Apply { .. } | Commands::Watch { .. }
+        | Commands::Install { .. } | Commands::Patch { .. } | Commands::Act { .. }
+        | Commands::Group { command: GroupCommands::Rename { .. } | GroupCommands::List, .. }
+        | Commands::Status { .. } | Commands::Diff { .. } | Commands::Check { .. } | Commands::Groups { .. }
+        | Commands::Restore { list: true, .. }
+        | Commands::Drift { command: DriftCommands::List { .. } | DriftCommands::Show { .. } }
+        | Commands::Vars { command: VarsCommands::Get { .. } | VarsCommands::List { .. } }
+        | Commands::Lock { command: LockCommands::List }
+        | Commands::Service { command: ServiceCommands::Status })
+}
+
+/// Run a command's plan, or for `--dry-run` print it. Returns whether it ran.
+async fn run_plan(plan: Plan, dry_run: bool) -> Result<bool> {
+    if dry_run {
+        print_plan(&plan);
+        return Ok(false);
+    }
+    plan.run().await?;
+    Ok(true)
+}
+
+fn print_plan(plan: &Plan) {
+    println!("Dry run, nothing was changed. Planned changes:");
+    print!("{}", plan);
+}
+
 async fn init_laszoo(config: &Config, mfs_mount: &std::path::Path) -> Result<()> {
     info!("Initializing Laszoo with distributed filesystem at {:?}", mfs_mount);
 
@@ -164,13 +262,9 @@ async fn enroll_files(
     config: &Config,
     group: &str,
     paths: Vec<PathBuf>,
-    force: bool,
-    _include_hidden: bool,
-    machine: bool,
-    hybrid: bool,
-    before: Option<String>,
-    after: Option<String>,
-    action: crate::cli::SyncAction
+    options: EnrollOptions,
+    action: Option<crate::cli::SyncAction>,
+    dry_run: bool,
 ) -> Result<()> {
     use crate::enrollment::EnrollmentManager;
 
@@ -181,27 +275,62 @@ async fn enroll_files(
     let manager = EnrollmentManager::new(
         config.mfs_mount.clone(),
         "".to_string()
-    );
+    )
+    .with_backups(crate::backup::BackupStore::from_config(config))
+    .with_action_timeout(config.actions.timeout());
+    let (before, after) = (options.actions.before.as_deref(), options.actions.after.as_deref());
 
     // If no paths provided, enroll the machine into the group
     if paths.is_empty() {
-        manager.enroll_path(group, None, force, machine, hybrid, before.clone(), after.clone())?;
-        info!("Successfully enrolled machine into group '{}'", group);
+        let mut plan = Plan::new();
+        manager.plan_enroll_path(group, None, &options, &mut plan)?;
 
-        // Store triggers and action for this group if provided
-        if before.is_some() || after.is_some() || !matches!(action, crate::cli::SyncAction::Converge) {
-            store_group_config(&config.mfs_mount, group, before.as_deref(), after.as_deref(), &action)?;
+        // Store triggers and the group's default action if provided
+        if before.is_some() || after.is_some() || action.is_some() {
+            let action = match action {
+                Some(action) => action,
+                None => load_group_config(&config.mfs_mount, group)?.2,
+            };
+            plan_group_config(&config.mfs_mount, group, before, after, action, &mut plan);
         }
 
+        if run_plan(plan, dry_run).await? {
+            info!("Successfully enrolled machine into group '{}'", group);
+        }
         return Ok(());
     }
 
     let mut enrolled_count = 0;
     let mut error_count = 0;
+    // The plans of all paths, printed together for a dry run
+    let mut planned = Plan::new();
 
     for path in paths {
-        match manager.enroll_path(group, Some(&path), force, machine, hybrid, before.clone(), after.clone()) {
-            Ok(_) => {
+        // Refuse an action that can't be set before the file is enrolled
+        if let Some(e) = action.and_then(|_| manager.check_sync_action_target(group, &path, options.machine_specific).err()) {
+            error!("Failed to enroll {:?}: {}", path, e);
+            error_count += 1;
+            continue;
+        }
+
+        let mut plan = Plan::new();
+        let result = manager.plan_enroll_path(group, Some(&path), &options, &mut plan).and_then(|()| {
+            // The sync action belongs to the enrolled file, not the whole group
+            if let Some(action) = action {
+                plan_sync_action(&manager, group, &crate::fs::absolute_path(&path)?, action, options.machine_specific, &mut plan);
+            }
+            Ok(())
+        });
+        let result = match result {
+            Ok(()) if dry_run => {
+                planned.append(plan);
+                Ok(())
+            }
+            Ok(()) => plan.run().await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => {
                 info!("Enrolled: {:?}", path);
                 enrolled_count += 1;
             }
@@ -212,13 +341,15 @@ async fn enroll_files(
         }
     }
 
-    // Store triggers and action for this group if provided
-    if enrolled_count > 0 && (before.is_some() || after.is_some() || !matches!(action, crate::cli::SyncAction::Converge)) {
-        store_group_config(&config.mfs_mount, group, before.as_deref(), after.as_deref(), &action)?;
+    // Store triggers for this group if provided, keeping its default action
+    if enrolled_count > 0 && (before.is_some() || after.is_some()) {
+        let (_, _, group_action) = load_group_config(&config.mfs_mount, group)?;
+        plan_group_config(&config.mfs_mount, group, before, after, group_action, &mut planned);
+    }
+    if run_plan(planned, dry_run).await? {
+        info!("Enrollment complete: {} files enrolled, {} errors",
+              enrolled_count, error_count);
     }
-
-    info!("Enrollment complete: {} files enrolled, {} errors",
-          enrolled_count, error_count);
 
     if error_count > 0 {
         Err(LaszooError::Other(
@@ -229,7 +360,29 @@ async fn enroll_files(
     }
 }
 
-async fn apply_group_templates(config: &Config, group: &str, files: Vec<PathBuf>) -> Result<()> {
+/// Plan setting the sync action of an enrolled file
+fn plan_sync_action(manager: &crate::enrollment::EnrollmentManager, group: &str, abs_path: &Path, action: SyncAction, machine: bool, plan: &mut Plan) {
+    let manifest_path = if machine { manager.manifest_path() } else { manager.group_manifest_path(group) };
+    plan.metadata(&manifest_path, format!("set sync action of {} to {}", abs_path.display(), action));
+    let (manager, abs_path) = (manager.clone(), abs_path.to_path_buf());
+    plan.then(move || manager.set_sync_action(&abs_path, action, machine).map(|_| ()));
+}
+
+/// Plan storing a group's triggers and default sync action
+fn plan_group_config(mfs_mount: &Path, group: &str, before: Option<&str>, after: Option<&str>, action: SyncAction, plan: &mut Plan) {
+    plan.metadata(&mfs_mount.join("groups").join(group).join("config.json"),
+        format!("set group triggers: {}, sync action {}", describe_triggers(before, after), action));
+    let (mfs_mount, group) = (mfs_mount.to_path_buf(), group.to_string());
+    let (before, after) = (before.map(str::to_string), after.map(str::to_string));
+    plan.then(move || store_group_config(&mfs_mount, &group, before.as_deref(), after.as_deref(), &action));
+}
+
+/// Before and after commands for a plan
+fn describe_triggers(before: Option<&str>, after: Option<&str>) -> String {
+    format!("before `{}`, after `{}`", before.unwrap_or("-"), after.unwrap_or("-"))
+}
+
+async fn apply_group_templates(config: &Config, group: &str, files: Vec<PathBuf>, dry_run: bool) -> Result<()> {
     use crate::enrollment::EnrollmentManager;
 
     // Ensure distributed filesystem is available
@@ -239,35 +392,561 @@ async fn apply_group_templates(config: &Config, group: &str, files: Vec<PathBuf>
     let manager = EnrollmentManager::new(
         config.mfs_mount.clone(),
         "".to_string()
-    );
+    )
+    .with_backups(crate::backup::BackupStore::from_config(config))
+    .with_action_timeout(config.actions.timeout());
 
     info!("Applying all templates from group '{}'", group);
+    let mut plan = Plan::new();
+
+    let mfs_mount = config.mfs_mount.clone();
+    plan.then(move || {
+        if let Err(e) = crate::facts::HostFacts::local().publish(&mfs_mount) {
+            warn!("Failed to publish host facts: {}", e);
+        }
+        Ok(())
+    });
 
+    let in_group = manager.load_machine_groups()?.iter().any(|g| g == group);
     if files.is_empty() {
         // Add machine to group first
-        manager.add_machine_to_group(group)?;
+        manager.plan_add_machine_to_group(group, &mut plan)?;
         // Apply all templates from the group
-        manager.apply_group_templates(group)?;
+        manager.plan_group_templates(group, &mut plan)?;
+
+        // Let the other hosts in the group see which version this host now has
+        let (manager, group) = (manager.clone(), group.to_string());
+        plan.then(move || {
+            if let Err(e) = manager.publish_group_hashes(&group) {
+                warn!("Failed to publish file hashes: {}", e);
+            }
+            Ok(())
+        });
     } else {
         // Apply specific files
-        for _file in files {
+        for _file in files.iter() {
             // TODO: Implement selective file application
             warn!("Selective file application not yet implemented");
         }
     }
 
-    println!("Successfully applied all templates from group '{}'", group);
+    plan.say(format!("Successfully applied all templates from group '{}'", group));
+    
+    // Also apply packages for this group, once the machine is in it
+    plan.say("Checking for package changes...");
+    if files.is_empty() || in_group {
+        let hostname = gethostname::gethostname().to_string_lossy().to_string();
+        let mut packages = Plan::new();
+        match crate::package::PackageManager::new(config.mfs_mount.clone()).plan_group(group, &hostname, &mut packages) {
+            Ok(()) => plan.nest(packages, |result| {
+                if let Err(e) = result {
+                    warn!("Failed to apply packages: {}", e);
+                }
+                Ok(())
+            }),
+            Err(e) => warn!("Failed to apply packages: {}", e),
+        }
+    } else {
+        debug!("Machine is not in group '{}', skipping package application", group);
+    }
     
-    // Also apply packages for this group
-    println!("Checking for package changes...");
-    if let Err(e) = apply_packages_for_group(config, group).await {
-        warn!("Failed to apply packages: {}", e);
+    run_plan(plan, dry_run).await?;
+    Ok(())
+}
+
+async fn rollback_changes(config: &Config, target: &str, commits: u32) -> Result<()> {
+    use crate::enrollment::EnrollmentManager;
+    use crate::git::GitManager;
+
+    info!("Rolling back {} commits for {}", commits, target);
+
+    // Ensure distributed filesystem is available
+    crate::fs::ensure_distributed_fs_available(&config.mfs_mount)?;
+
+    let manager = EnrollmentManager::new(
+        config.mfs_mount.clone(),
+        "".to_string()
+    )
+    .with_backups(crate::backup::BackupStore::from_config(config))
+    .with_action_timeout(config.actions.timeout());
+    let machine_groups = manager.load_machine_groups()?;
+
+    // Resolve the target as a group name first, then as an enrolled file
+    let group_dir = crate::fs::get_group_dir(&config.mfs_mount, "", target);
+    let (scope, template_groups): (Vec<PathBuf>, HashMap<PathBuf, String>) =
+        if !target.contains('/') && group_dir.is_dir() {
+            // This machine's templates for files of the group roll back with it
+            let mut template_groups = HashMap::new();
+            for (path, entry) in &manager.load_manifest()?.entries {
+                if entry.group == target {
+                    template_groups.insert(manager.get_machine_template_path(path)?, entry.group.clone());
+                }
+            }
+            let scope = std::iter::once(PathBuf::from("groups").join(target))
+                .chain(template_groups.keys()
+                    .filter_map(|p| p.strip_prefix(&config.mfs_mount).ok())
+                    .map(|p| p.to_path_buf()))
+                .collect();
+            (scope, template_groups)
+        } else {
+            let file_path = PathBuf::from(target);
+            let abs_path = if file_path.exists() {
+                file_path.canonicalize()?
+            } else {
+                std::env::current_dir()?.join(&file_path)
+            };
+
+            // Candidate templates: one per group plus the machine-specific one
+            let mut template_groups = HashMap::new();
+            for group in &machine_groups {
+                let template_path = manager.get_group_template_path(group, &abs_path)?;
+                template_groups.insert(template_path, group.clone());
+            }
+            if let Some(entry) = manager.load_manifest()?.is_enrolled(&abs_path) {
+                template_groups.insert(manager.get_machine_template_path(&abs_path)?, entry.group.clone());
+            }
+
+            if template_groups.is_empty() {
+                return Err(LaszooError::Other(format!(
+                    "'{}' is neither a group nor a file enrolled on this machine", target
+                )));
+            }
+
+            let scope = template_groups.keys()
+                .filter_map(|p| p.strip_prefix(&config.mfs_mount).ok())
+                .map(|p| p.to_path_buf())
+                .collect();
+            (scope, template_groups)
+        };
+
+    let git = GitManager::new(config.mfs_mount.clone());
+    let restored = git.rollback_paths(|path| {
+        path.extension() == Some(std::ffi::OsStr::new("lasz")) &&
+            scope.iter().any(|s| path.starts_with(s))
+    }, commits as usize)?;
+
+    if restored.is_empty() {
+        println!("Templates for '{}' already match the version {} commit(s) back", target, commits);
+        return Ok(());
+    }
+
+    println!("Rolled back {} template(s) for '{}':", restored.len(), target);
+    for file in &restored {
+        if file.removed {
+            println!("  - {} (removed)", file.path.display());
+        } else {
+            println!("  ↺ {}", file.path.display());
+        }
+    }
+
+    // Record the rollback as its own commit
+    let message = format!(
+        "revert: Roll back {} by {} commit(s)\n\n{}\n\n🦎 Laszoo: Rollback commit",
+        target,
+        commits,
+        restored.iter()
+            .filter_map(|f| f.path.strip_prefix(&config.mfs_mount).ok())
+            .map(|p| format!("- {}", p.display()))
+            .collect::<Vec<_>>()
+            .join("\n")
+    );
+    let restored_paths: Vec<PathBuf> = restored.iter().map(|f| f.path.clone()).collect();
+    let commit_id = git.commit_paths(&message, &restored_paths)?;
+    println!("Recorded rollback as commit {}", commit_id);
+
+    // Re-render the affected groups on this machine
+    let affected_groups: HashSet<String> = restored.iter()
+        .map(|f| template_groups.get(&f.path).cloned().unwrap_or_else(|| target.to_string()))
+        .collect();
+
+    for group in &affected_groups {
+        if machine_groups.contains(group) {
+            manager.apply_group_templates(group)?;
+            println!("Re-applied templates from group '{}'", group);
+        } else {
+            info!("Machine is not in group '{}', skipping local apply", group);
+        }
+    }
+
+    Ok(())
+}
+
+/// Change the sync action of an enrolled path after enrollment
+fn set_file_action(config: &Config, path: &Path, action: SyncAction, machine: bool) -> Result<()> {
+    use crate::enrollment::EnrollmentManager;
+
+    // Ensure distributed filesystem is available
+    crate::fs::ensure_distributed_fs_available(&config.mfs_mount)?;
+
+    let manager = EnrollmentManager::new(
+        config.mfs_mount.clone(),
+        "".to_string()
+    );
+
+    let path = crate::diff::resolve_filter_path(path)?;
+    let group = manager.set_sync_action(&path, action, machine)?;
+
+    if machine {
+        println!("Set sync action of {} to {} on this machine (group: {})", path.display(), action, group);
+    } else {
+        println!("Set sync action of {} to {} (group: {})", path.display(), action, group);
     }
+
+    Ok(())
+}
+
+/// The group `laszoo act` sets actions in, None for this machine, and the
+/// enrolled path or glob pattern they are for
+fn act_target(
+    manager: &crate::enrollment::EnrollmentManager,
+    group: Option<String>,
+    path: Option<PathBuf>,
+) -> Result<(Option<String>, PathBuf)> {
+    // Required on the command line, only `act list` goes without
+    let path = path.ok_or_else(|| LaszooError::Other("Give the file, directory or pattern to set actions for".to_string()))?;
     
+    // A glob pattern may cover files enrolled later on
+    if crate::action::is_glob(&path) {
+        if !path.is_absolute() {
+            return Err(LaszooError::Other(format!("Glob patterns for actions must be absolute: {}", path.display())));
+        }
+        return Ok((group, path));
+    }
+
+    // The path is an enrolled file or directory, lies in one, or holds enrolled files
+    let path = crate::diff::resolve_filter_path(&path)?;
+    let manifests = match &group {
+        Some(group) => vec![manager.load_group_manifest(group)?],
+        None => {
+            let mut manifests = vec![manager.load_manifest()?];
+            for group in manager.load_machine_groups()? {
+                manifests.push(manager.load_group_manifest(&group)?);
+            }
+            manifests
+        }
+    };
+    let enrolled = manifests.iter().any(|manifest| {
+        manifest.enrolled_directory_of(&path).is_some() || manifest.entries.keys().any(|p| p.starts_with(&path))
+    });
+    if !enrolled {
+        let scope = group.as_ref().map(|g| format!(" in group '{}'", g)).unwrap_or_default();
+        return Err(LaszooError::Other(format!("{} is not enrolled{}", path.display(), scope)));
+    }
+    Ok((group, path))
+}
+
+/// Set or clear the actions of an enrolled file
+async fn set_file_actions(
+    config: &Config,
+    group: Option<String>,
+    path: Option<PathBuf>,
+    update: ActionUpdate,
+    clear: bool,
+    dry_run: bool,
+) -> Result<()> {
+    use crate::action::ActionManager;
+    use crate::enrollment::EnrollmentManager;
+
+    // Ensure distributed filesystem is available
+    crate::fs::ensure_distributed_fs_available(&config.mfs_mount)?;
+
+    let manager = EnrollmentManager::new(config.mfs_mount.clone(), "".to_string());
+    let (group, path) = act_target(&manager, group, path)?;
+    if update.is_empty() && !clear {
+        return Err(LaszooError::Other("Nothing to change, give --before, --after, --validate, --notify-service, --no-notify-service or --clear".to_string()));
+    }
+    let mut plan = Plan::new();
+    let actions = ActionManager::new(config.mfs_mount.clone())
+        .plan_update_actions(group.as_deref(), &path, update, clear, &mut plan)?;
+    if !run_plan(plan, dry_run).await? {
+        return Ok(());
+    }
+
+    let scope = match &group {
+        Some(group) => format!("group: {}", group),
+        None => "this machine".to_string(),
+    };
+    if actions.is_empty() {
+        println!("Cleared actions of {} ({})", path.display(), scope);
+    } else {
+        println!("Set actions of {} ({}): {}", path.display(), scope, actions);
+    }
+
+    Ok(())
+}
+
+/// List the actions in effect on this machine
+fn list_file_actions(config: &Config, group: Option<String>) -> Result<()> {
+    use crate::action::ActionManager;
+    use crate::enrollment::EnrollmentManager;
+
+    // Ensure distributed filesystem is available
+    crate::fs::ensure_distributed_fs_available(&config.mfs_mount)?;
+
+    let manager = EnrollmentManager::new(config.mfs_mount.clone(), "".to_string());
+    let groups = match &group {
+        Some(group) => vec![group.clone()],
+        None => manager.load_machine_groups()?,
+    };
+    let mut effective = ActionManager::new(config.mfs_mount.clone()).effective_actions(&groups)?;
+    // Machine actions are listed with a group only for what is enrolled in it
+    if let Some(group) = &group {
+        let manifest = manager.load_group_manifest(group)?;
+        effective.retain(|entry| {
+            entry.group.is_some()
+                || manifest.enrolled_directory_of(&entry.path).is_some()
+                || manifest.entries.keys().any(|p| p == &entry.path || crate::action::scope_covers(&entry.path, p))
+        });
+    }
+    if effective.is_empty() {
+        println!("No actions");
+    }
+    for entry in effective {
+        let source = match &entry.group {
+            Some(group) => format!("group: {}", group),
+            None => "machine".to_string(),
+        };
+        println!("  {}  ({})  {}", entry.path.display(), source, entry.actions);
+    }
+
+    Ok(())
+}
+
+/// Restore a file from the local backup store, or list its backups
+fn restore_file(config: &Config, path: &Path, at: Option<&str>, list: bool) -> Result<()> {
+    use crate::backup::BackupStore;
+
+    let store = BackupStore::from_config(config);
+    let path = crate::diff::resolve_filter_path(path)?;
+
+    if list {
+        let backups = store.list(&path)?;
+        if backups.is_empty() {
+            println!("No backups of {}", path.display());
+            return Ok(());
+        }
+
+        println!("Backups of {}:", path.display());
+        for backup in backups.iter().rev() {
+            println!(
+                "  {}  {}  ({} bytes)",
+                backup.id(),
+                backup.taken_at.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S"),
+                backup.size
+            );
+        }
+        return Ok(());
+    }
+
+    let at = at.map(crate::backup::parse_time).transpose()?;
+    let backup = store.restore(&path, at)?;
+    println!(
+        "Restored {} from backup taken {}",
+        path.display(),
+        backup.taken_at.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S")
+    );
+
+    Ok(())
+}
+
+/// List the conflicting versions of a file's template, or settle them by
+/// taking one host's version or keeping the current template
+fn resolve_conflict(config: &Config, path: &Path, take: Option<&str>, keep: bool) -> Result<()> {
+    use crate::enrollment::EnrollmentManager;
+
+    // Ensure distributed filesystem is available
+    crate::fs::ensure_distributed_fs_available(&config.mfs_mount)?;
+
+    let manager = EnrollmentManager::new(
+        config.mfs_mount.clone(),
+        "".to_string()
+    )
+    .with_backups(crate::backup::BackupStore::from_config(config))
+    .with_action_timeout(config.actions.timeout());
+
+    let path = crate::diff::resolve_filter_path(path)?;
+    let group = manager.enrolled_group(&path)?
+        .ok_or_else(|| LaszooError::Other(format!("{} is not enrolled", path.display())))?;
+    let template_path = manager.get_group_template_path(&group, &path)?;
+    let versions = manager.versions();
+    let conflicts = versions.conflicts(&template_path)?;
+
+    if conflicts.is_empty() {
+        println!("No conflicts for {} (group: {})", path.display(), group);
+        return Ok(());
+    }
+
+    if take.is_none() && !keep {
+        let current = String::from_utf8_lossy(&std::fs::read(&template_path)?).into_owned();
+        println!("Conflicting versions of {} (group: {}):", path.display(), group);
+        for conflict in &conflicts {
+            println!(
+                "\n  {} at {}",
+                conflict.host,
+                conflict.detected_at.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S")
+            );
+            let saved = crate::version::conflict_path(&template_path, &conflict.host);
+            match std::fs::read(&saved) {
+                Ok(content) => print!("{}", crate::diff::unified_diff(
+                    &current,
+                    &String::from_utf8_lossy(&content),
+                    "template",
+                    &conflict.host,
+                )),
+                Err(e) => println!("  Saved version unreadable: {}", e),
+            }
+        }
+        println!("\nSettle with --take <host> or --keep");
+        return Ok(());
+    }
+
+    versions.resolve(&template_path, take)?;
+    match take {
+        Some(host) => println!("Template for {} now holds the version from {}", path.display(), host),
+        None => println!("Kept the current template for {}", path.display()),
+    }
+
+    // Bring this host's copy in line with the settled template
+    manager.apply_single_template(&template_path, &path)?;
+    println!("Applied template to {}", path.display());
+
     Ok(())
 }
 
-async fn unenroll_files(config: &Config, group: Option<String>, paths: Vec<PathBuf>) -> Result<()> {
+/// Print differences between local files and rendered templates.
+/// Returns true if any enrolled file has drifted or is missing.
+async fn show_diff(config: &Config, group: Option<&str>, files: Vec<PathBuf>, json: bool, no_color: bool) -> Result<bool> {
+    use crate::enrollment::EnrollmentManager;
+    use std::io::IsTerminal;
+
+    // Ensure distributed filesystem is available
+    crate::fs::ensure_distributed_fs_available(&config.mfs_mount)?;
+
+    let manager = EnrollmentManager::new(
+        config.mfs_mount.clone(),
+        "".to_string()
+    );
+
+    let groups = match group {
+        Some(group_name) => {
+            if !crate::fs::get_group_dir(&config.mfs_mount, "", group_name).is_dir() {
+                return Err(LaszooError::GroupNotFound { name: group_name.to_string() });
+            }
+            vec![group_name.to_string()]
+        }
+        None => manager.load_machine_groups()?,
+    };
+
+    let filters = files.iter()
+        .map(|f| crate::diff::resolve_filter_path(f))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut diffs = Vec::new();
+    for group_name in &groups {
+        diffs.extend(crate::diff::diff_group(&manager, group_name, &filters)?);
+    }
+
+    let drifted = diffs.iter().filter(|d| d.has_drift()).count();
+    let failed = diffs.iter().filter(|d| d.status == crate::diff::DiffStatus::Error).count();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&diffs)?);
+    } else if drifted == 0 && failed == 0 {
+        println!("No differences found");
+    } else {
+        let colour = !no_color && std::io::stdout().is_terminal();
+        for file_diff in &diffs {
+            match file_diff.status {
+                crate::diff::DiffStatus::Unchanged => continue,
+                crate::diff::DiffStatus::Error => {
+                    eprintln!("✗ Cannot compare {} (group: {}): {}", file_diff.path.display(), file_diff.group,
+                        file_diff.error.as_deref().unwrap_or_default());
+                    continue;
+                }
+                crate::diff::DiffStatus::Missing => {
+                    println!("✗ {} is missing locally (group: {})", file_diff.path.display(), file_diff.group);
+                }
+                crate::diff::DiffStatus::Drifted => {}
+            }
+            if colour {
+                print!("{}", crate::diff::colorize(&file_diff.diff));
+            } else {
+                print!("{}", file_diff.diff);
+            }
+        }
+        println!("\n{} of {} file(s) differ from their templates", drifted, diffs.len());
+    }
+
+    if failed > 0 {
+        return Err(LaszooError::Other(format!("{} file(s) could not be compared", failed)));
+    }
+    Ok(drifted > 0)
+}
+
+/// Run `laszoo check` and return its exit code. Failing to check at all, for
+/// instance with the shared filesystem unavailable, is reported as an error
+/// result rather than aborting, so monitoring sees UNKNOWN.
+async fn run_check(config: &Config, group: Option<&str>, json: bool, nagios: bool) -> Result<i32> {
+    use crate::check::{CheckStatus, Checker};
+
+    let hostname = gethostname::gethostname().to_string_lossy().to_string();
+    let report = match Checker::new(config.mfs_mount.clone(), hostname).check(group).await {
+        Ok(report) => report,
+        Err(e) if nagios => {
+            println!("LASZOO UNKNOWN - {}", e);
+            return Ok(CheckStatus::Error.exit_code());
+        }
+        Err(e) if json => {
+            let failure = serde_json::json!({ "status": "unknown", "message": e.to_string() });
+            println!("{}", serde_json::to_string_pretty(&failure)?);
+            return Ok(CheckStatus::Error.exit_code());
+        }
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return Ok(CheckStatus::Error.exit_code());
+        }
+    };
+
+    if nagios {
+        println!("{}", report.nagios_line());
+    } else if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        println!("=== Laszoo Check ===");
+        println!("Hostname: {}", report.host);
+        println!("Legend: ✓ = compliant, ● = drifted, ✗ = missing, ! = error");
+
+        let mut current_group = None;
+        for item in &report.items {
+            if current_group != Some(&item.group) {
+                println!("\n  [{}]", item.group);
+                current_group = Some(&item.group);
+            }
+            let symbol = match item.status {
+                CheckStatus::Compliant => "✓",
+                CheckStatus::Drifted => "●",
+                CheckStatus::Missing => "✗",
+                CheckStatus::Error => "!",
+            };
+            if item.detail.is_empty() {
+                println!("    {} {} {}", symbol, item.kind, item.name);
+            } else {
+                println!("    {} {} {} ({})", symbol, item.kind, item.name, item.detail);
+            }
+        }
+
+        println!("\nResult: {} ({} compliant, {} drifted, {} missing, {} errors)",
+            report.status,
+            report.count(CheckStatus::Compliant),
+            report.count(CheckStatus::Drifted),
+            report.count(CheckStatus::Missing),
+            report.count(CheckStatus::Error));
+    }
+
+    Ok(report.exit_code())
+}
+
+async fn unenroll_files(config: &Config, group: Option<String>, paths: Vec<PathBuf>, dry_run: bool) -> Result<()> {
     use crate::enrollment::EnrollmentManager;
 
     // Ensure distributed filesystem is available
@@ -300,9 +979,20 @@ async fn unenroll_files(config: &Config, group: Option<String>, paths: Vec<PathB
     // Unenroll specified files
     let mut unenrolled_count = 0;
     let mut error_count = 0;
+    // The plans of all paths, printed together for a dry run
+    let mut planned = Plan::new();
 
     for path in paths {
-        match manager.unenroll_file(&path) {
+        let mut plan = Plan::new();
+        let result = match manager.plan_unenroll_file(&path, &mut plan) {
+            Ok(()) if dry_run => {
+                planned.append(plan);
+                Ok(())
+            }
+            Ok(()) => plan.run().await,
+            Err(e) => Err(e),
+        };
+        match result {
             Ok(()) => {
                 info!("Unenrolled: {:?}", path);
                 unenrolled_count += 1;
@@ -314,8 +1004,10 @@ async fn unenroll_files(config: &Config, group: Option<String>, paths: Vec<PathB
         }
     }
 
-    info!("Unenrollment complete: {} files unenrolled, {} errors",
-          unenrolled_count, error_count);
+    if run_plan(planned, dry_run).await? {
+        info!("Unenrollment complete: {} files unenrolled, {} errors",
+              unenrolled_count, error_count);
+    }
 
     if error_count > 0 {
         Err(LaszooError::Other(
@@ -330,8 +1022,12 @@ async fn show_status(config: &Config, detailed: bool) -> Result<()> {
     use crate::enrollment::EnrollmentManager;
     use std::collections::HashMap;
 
-    // Ensure distributed filesystem is available
-    crate::fs::ensure_distributed_fs_available(&config.mfs_mount)?;
+    // Ensure distributed filesystem is available, local changes made while
+    // it is not are still worth showing
+    if let Err(e) = crate::fs::ensure_distributed_fs_available(&config.mfs_mount) {
+        print_pending_changes(config)?;
+        return Err(e);
+    }
 
     let hostname = gethostname::gethostname()
         .to_string_lossy()
@@ -340,6 +1036,7 @@ async fn show_status(config: &Config, detailed: bool) -> Result<()> {
     println!("=== Laszoo Status ===");
     println!("Mount Point: {:?}", config.mfs_mount);
     println!("Hostname: {}", hostname);
+    print_pending_changes(config)?;
 
     // Read machine's groups.conf
     let groups_file = config.mfs_mount
@@ -478,23 +1175,10 @@ async fn show_status(config: &Config, detailed: bool) -> Result<()> {
 
                                     if template_path.exists() {
                                         // Template exists, check if file matches
-                                        if let Ok(template_content) = std::fs::read_to_string(&template_path) {
-                                            if let Ok(file_content) = std::fs::read_to_string(&file_path) {
-                                                // Process template to compare
-                                                if let Ok(processed) = crate::template::process_handlebars(&template_content, &hostname) {
-                                                    if processed == file_content {
-                                                        unchanged_count += 1;
-                                                    } else {
-                                                        modified_count += 1;
-                                                    }
-                                                } else {
-                                                    modified_count += 1; // Can't process, assume modified
-                                                }
-                                            } else {
-                                                missing_count += 1; // Can't read file
-                                            }
-                                        } else {
-                                            missing_count += 1; // Can't read template
+                                        match template_matches_file(&enrollment_manager, &template_path, &file_path) {
+                                            Ok(true) => unchanged_count += 1,
+                                            Ok(false) => modified_count += 1,
+                                            Err(_) => missing_count += 1, // Can't read file or template
                                         }
                                     } else {
                                         new_count += 1; // No template yet
@@ -536,6 +1220,11 @@ async fn show_status(config: &Config, detailed: bool) -> Result<()> {
                         status_parts.push(format!("? {}% new ({}/{})", percent, new_count, file_count));
                     }
 
+                    let sync_action = enrollment_manager.effective_sync_action(group_name, dir_path)?;
+                    if sync_action != SyncAction::Converge {
+                        status_parts.push(format!("action: {}", sync_action));
+                    }
+
                     println!("    {} {} ({})", status, dir_path.display(), status_parts.join(", "));
 
                     // Show enrollment timestamp for directory in detailed mode
@@ -611,23 +1300,10 @@ async fn show_status(config: &Config, detailed: bool) -> Result<()> {
 
                                         let file_status = if template_path.exists() {
                                             // Template exists, check if file matches
-                                            if let Ok(template_content) = std::fs::read_to_string(&template_path) {
-                                                if let Ok(file_content) = std::fs::read_to_string(&file_path) {
-                                                    // Process template to compare
-                                                    if let Ok(processed) = crate::template::process_handlebars(&template_content, &hostname) {
-                                                        if processed == file_content {
-                                                            "✓"
-                                                        } else {
-                                                            "●"
-                                                        }
-                                                    } else {
-                                                        "?"
-                                                    }
-                                                } else {
-                                                    "?"
-                                                }
-                                            } else {
-                                                "?"
+                                            match template_matches_file(&enrollment_manager, &template_path, &file_path) {
+                                                Ok(true) => "✓",
+                                                Ok(false) => "●",
+                                                Err(_) => "?",
                                             }
                                         } else {
                                             continue; // Skip new files - already shown above
@@ -659,22 +1335,10 @@ async fn show_status(config: &Config, detailed: bool) -> Result<()> {
                         // Check if file matches template
                         if let Some(template_path) = &entry.template_path {
                             if template_path.exists() {
-                                if let Ok(template_content) = std::fs::read_to_string(template_path) {
-                                    if let Ok(file_content) = std::fs::read_to_string(file_path) {
-                                        if let Ok(processed) = crate::template::process_handlebars(&template_content, &hostname) {
-                                            if processed == file_content {
-                                                "✓"
-                                            } else {
-                                                "●"
-                                            }
-                                        } else {
-                                            "?"
-                                        }
-                                    } else {
-                                        "?"
-                                    }
-                                } else {
-                                    "?"
+                                match template_matches_file(&enrollment_manager, template_path, file_path) {
+                                    Ok(true) => "✓",
+                                    Ok(false) => "●",
+                                    Err(_) => "?",
                                 }
                             } else {
                                 "✗" // Template missing
@@ -686,10 +1350,31 @@ async fn show_status(config: &Config, detailed: bool) -> Result<()> {
                         "✗" // File missing
                     };
 
+                let sync_action = enrollment_manager.effective_sync_action(group_name, file_path)?;
+
                 debug!("About to print status '{}' for file '{}'", status, file_path.display());
-                println!("    {} {}", status, file_path.display());
+                if sync_action == SyncAction::Converge {
+                    println!("    {} {}", status, file_path.display());
+                } else {
+                    println!("    {} {} [{}]", status, file_path.display(), sync_action);
+                }
 
-                // Debug: write to file
+                if let Some(template_path) = &entry.template_path {
+                    let conflicts = enrollment_manager.versions().conflicts(template_path).unwrap_or_default();
+                    if !conflicts.is_empty() {
+                        let hosts: Vec<&str> = conflicts.iter().map(|c| c.host.as_str()).collect();
+                        println!("      Conflicting versions from {} (see `laszoo resolve {}`)", hosts.join(", "), file_path.display());
+                    }
+                }
+
+                if sync_action == SyncAction::Drift {
+                    if let Ok(Some(drift)) = enrollment_manager.drift_ledger().get(&hostname, file_path) {
+                        println!("      Drifted since {} (see `laszoo drift show {}`)",
+                            drift.first_drift.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S"), file_path.display());
+                    }
+                }
+
+                // Debug: write to file
                 if let Ok(mut f) = std::fs::OpenOptions::new().create(true).append(true).open("/tmp/laszoo-debug.log") {
                     use std::io::Write;
                     writeln!(f, "SUCCESSFULLY PRINTED file: {} with status {}", file_path.display(), status).ok();
@@ -703,6 +1388,7 @@ async fn show_status(config: &Config, detailed: bool) -> Result<()> {
                         println!("      Template: {}", template_path.display());
                     }
                     println!("      Enrolled: {}", entry.enrolled_at.format("%Y-%m-%d %H:%M:%S"));
+                    println!("      Sync action: {}", sync_action);
                     if entry.is_hybrid == Some(true) {
                         println!("      Mode: hybrid");
                     }
@@ -890,144 +1576,6 @@ async fn show_status(config: &Config, detailed: bool) -> Result<()> {
     Ok(())
 }
 
-async fn check_package_status(pkg_mgr: &crate::package::PackageManagerType, package: &str) -> PackageStatus {
-    use tokio::process::Command;
-    
-    let check_cmd = match pkg_mgr {
-        crate::package::PackageManagerType::Apt => {
-            format!("dpkg -l {} 2>/dev/null | grep -q '^ii'", package)
-        }
-        crate::package::PackageManagerType::Yum | 
-        crate::package::PackageManagerType::Dnf => {
-            format!("rpm -q {} >/dev/null 2>&1", package)
-        }
-        crate::package::PackageManagerType::Pacman => {
-            format!("pacman -Q {} >/dev/null 2>&1", package)
-        }
-        crate::package::PackageManagerType::Zypper => {
-            format!("rpm -q {} >/dev/null 2>&1", package)
-        }
-        crate::package::PackageManagerType::Apk => {
-            format!("apk info -e {} >/dev/null 2>&1", package)
-        }
-    };
-    
-    match Command::new("sh")
-        .arg("-c")
-        .arg(&check_cmd)
-        .status()
-        .await
-    {
-        Ok(status) => {
-            if status.success() {
-                // Package is installed, check if updates are available
-                let update_check_cmd = match pkg_mgr {
-                    crate::package::PackageManagerType::Apt => {
-                        format!("apt-cache policy {} | grep -q 'Installed:.*Candidate:' && ! apt-cache policy {} | grep -q 'Installed:.*Candidate:.*none'", package, package)
-                    }
-                    _ => {
-                        // For other package managers, we'll just say it's up to date if installed
-                        return PackageStatus::UpToDate;
-                    }
-                };
-                
-                match Command::new("sh")
-                    .arg("-c")
-                    .arg(&update_check_cmd)
-                    .status()
-                    .await
-                {
-                    Ok(update_status) => {
-                        if update_status.success() {
-                            PackageStatus::PendingUpdates
-                        } else {
-                            PackageStatus::UpToDate
-                        }
-                    }
-                    Err(_) => PackageStatus::UpToDate,
-                }
-            } else {
-                PackageStatus::Missing
-            }
-        }
-        Err(_) => PackageStatus::Missing,
-    }
-}
-
-async fn check_system_updates(pkg_mgr: &crate::package::PackageManagerType) -> bool {
-    use tokio::process::Command;
-    
-    let check_cmd = match pkg_mgr {
-        crate::package::PackageManagerType::Apt => {
-            "apt list --upgradable 2>/dev/null | grep -q upgradable"
-        }
-        crate::package::PackageManagerType::Yum => {
-            "yum check-update >/dev/null 2>&1; [ $? -eq 100 ]"
-        }
-        crate::package::PackageManagerType::Dnf => {
-            "dnf check-update >/dev/null 2>&1; [ $? -eq 100 ]"
-        }
-        crate::package::PackageManagerType::Pacman => {
-            "pacman -Qu >/dev/null 2>&1"
-        }
-        crate::package::PackageManagerType::Zypper => {
-            "zypper list-updates | grep -q '^v |'"
-        }
-        crate::package::PackageManagerType::Apk => {
-            "apk version -l '<' | grep -q '<'"
-        }
-    };
-    
-    match Command::new("sh")
-        .arg("-c")
-        .arg(check_cmd)
-        .status()
-        .await
-    {
-        Ok(status) => status.success(),
-        Err(_) => false,
-    }
-}
-
-async fn check_phased_updates(pkg_mgr: &crate::package::PackageManagerType) -> bool {
-    use tokio::process::Command;
-    
-    // Only APT has phased updates
-    match pkg_mgr {
-        crate::package::PackageManagerType::Apt => {
-            // A simpler approach: check if apt-get upgrade -s would do nothing
-            // but apt list --upgradable shows packages
-            // More resilient command that handles edge cases
-            let check_cmd = r#"#!/bin/bash
-# Count total upgradable packages (excluding header line)
-total=$(apt list --upgradable 2>/dev/null | grep -v "^Listing" | grep "/" | wc -l)
-# Count packages that would actually be upgraded by apt-get upgrade
-would=$(apt-get -s upgrade 2>/dev/null | grep "^Inst " | wc -l)
-# Ensure we have numbers
-total=${total:-0}
-would=${would:-0}
-# If there are upgradable packages but apt-get upgrade would do nothing,
-# those are phased/held back
-if [ "$total" -gt 0 ] && [ "$would" -eq 0 ]; then
-    exit 0  # Phased updates detected
-else
-    exit 1  # No phased updates
-fi"#;
-            
-            match Command::new("sh")
-                .arg("-c")
-                .arg(check_cmd)
-                .status()
-                .await
-            {
-                Ok(status) => status.success(),
-                Err(_) => false,
-            }
-        }
-        _ => false, // Other package managers don't have phased updates
-    }
-}
-
 async fn commit_changes(
     config: &Config,
     user_message: Option<&str>,
@@ -1090,7 +1638,7 @@ async fn commit_changes(
     Ok(())
 }
 
-async fn handle_group_command(group_name: &str, command: GroupCommands) -> Result<()> {
+async fn handle_group_command(group_name: &str, command: GroupCommands, dry_run: bool) -> Result<()> {
     // Load config to get MFS mount
     let config = Config::load(None)?;
 
@@ -1193,7 +1741,7 @@ async fn handle_group_command(group_name: &str, command: GroupCommands) -> Resul
         GroupCommands::List {} => {
             info!("Listing machines in group '{}'", group_name);
 
-            let machines = list_machines_in_group(&config.mfs_mount, group_name)?;
+            let machines = crate::fs::list_machines_in_group(&config.mfs_mount, group_name)?;
 
             if machines.is_empty() {
                 println!("No machines in group '{}'", group_name);
@@ -1206,48 +1754,62 @@ async fn handle_group_command(group_name: &str, command: GroupCommands) -> Resul
         }
         GroupCommands::Rename { new_name } => {
             info!("Renaming group '{}' to '{}'", group_name, new_name);
-
-            // Check if new group already exists
-            let new_group_dir = config.mfs_mount.join("groups").join(&new_name);
-            if new_group_dir.exists() {
-                return Err(LaszooError::Other(format!("Group '{}' already exists", new_name)));
+            if !run_plan(plan_group_rename(&config, group_name, &new_name)?, dry_run).await? {
+                return Ok(());
             }
 
-            // Rename group directory
-            let old_group_dir = config.mfs_mount.join("groups").join(group_name);
-            if old_group_dir.exists() {
-                std::fs::rename(&old_group_dir, &new_group_dir)?;
-            }
+            println!("Successfully renamed group '{}' to '{}'", group_name, new_name);
+        }
+    }
 
-            // Update all machines' groups.conf files
-            let machines_dir = config.mfs_mount.join("machines");
-            if let Ok(entries) = std::fs::read_dir(&machines_dir) {
-                for entry in entries.flatten() {
-                    if let Some(machine_name) = entry.file_name().to_str() {
-                        let groups_file = machines_dir
-                            .join(machine_name)
-                            .join("etc")
-                            .join("laszoo")
-                            .join("groups.conf");
-
-                        if groups_file.exists() {
-                            let content = std::fs::read_to_string(&groups_file)?;
-                            let groups: Vec<String> = content
-                                .lines()
-                                .map(|l| if l.trim() == group_name { new_name.to_string() } else { l.to_string() })
-                                .collect();
-
-                            std::fs::write(&groups_file, groups.join("\n") + "\n")?;
-                        }
-                    }
+    Ok(())
+}
+
+/// A machine's groups.conf with one group renamed
+fn renamed_groups_conf(content: &str, group_name: &str, new_name: &str) -> String {
+    let groups: Vec<String> = content
+        .lines()
+        .map(|l| if l.trim() == group_name { new_name.to_string() } else { l.to_string() })
+        .collect();
+    groups.join("\n") + "\n"
+}
+
+/// Plan `laszoo group <name> rename`: the group directory is renamed and
+/// every machine's groups.conf updated
+fn plan_group_rename(config: &Config, group_name: &str, new_name: &str) -> Result<Plan> {
+    let mut plan = Plan::new();
+
+    // Check if new group already exists
+    let new_group_dir = config.mfs_mount.join("groups").join(new_name);
+    if new_group_dir.exists() {
+        return Err(LaszooError::Other(format!("Group '{}' already exists", new_name)));
+    }
+
+    // Rename group directory
+    let old_group_dir = config.mfs_mount.join("groups").join(group_name);
+    if old_group_dir.exists() {
+        plan.rename(&old_group_dir, &new_group_dir);
+        plan.then(move || Ok(std::fs::rename(&old_group_dir, &new_group_dir)?));
+    }
+
+    // Update all machines' groups.conf files
+    let machines_dir = config.mfs_mount.join("machines");
+    if let Ok(entries) = std::fs::read_dir(&machines_dir) {
+        let mut machines: Vec<_> = entries.flatten().map(|e| e.file_name()).collect();
+        machines.sort();
+        for machine_name in machines {
+            let groups_file = machines_dir.join(machine_name).join("etc").join("laszoo").join("groups.conf");
+            if let Ok(content) = std::fs::read_to_string(&groups_file) {
+                let renamed = renamed_groups_conf(&content, group_name, new_name);
+                if renamed != content {
+                    plan.write(&groups_file, Some(content.as_bytes()), renamed.as_bytes());
+                    plan.then(move || Ok(std::fs::write(&groups_file, renamed)?));
                 }
             }
-
-            println!("Successfully renamed group '{}' to '{}'", group_name, new_name);
         }
     }
 
-    Ok(())
+    Ok(plan)
 }
 
 async fn handle_groups_command(command: GroupsCommands) -> Result<()> {
@@ -1276,7 +1838,7 @@ async fn handle_groups_command(command: GroupsCommands) -> Result<()> {
                         if metadata.is_dir() {
                             if let Some(group_name) = entry.file_name().to_str() {
                                 // Count machines in this group
-                                let machines = list_machines_in_group(&config.mfs_mount, group_name)?;
+                                let machines = crate::fs::list_machines_in_group(&config.mfs_mount, group_name)?;
                                 groups.push((group_name.to_string(), machines.len()));
                             }
                         }
@@ -1304,6 +1866,56 @@ async fn handle_groups_command(command: GroupsCommands) -> Result<()> {
     Ok(())
 }
 
+/// Show, set and unset the template variables of groups and machines
+fn handle_vars_command(config: &Config, command: VarsCommands) -> Result<()> {
+    use crate::vars::{VarScope, VarsManager};
+
+    crate::fs::ensure_distributed_fs_available(&config.mfs_mount)?;
+
+    let hostname = gethostname::gethostname().to_string_lossy().to_string();
+    let manager = VarsManager::new(config.mfs_mount.clone(), hostname);
+
+    // Variables from a single vars file, or merged the way templates see them
+    let load = |group: Option<String>, machine: Option<String>, resolved: bool| -> Result<serde_json::Map<String, serde_json::Value>> {
+        match (group, machine) {
+            (Some(group), None) if !resolved => manager.load_scope(&VarScope::Group(group)),
+            (None, Some(machine)) => manager.load_scope(&VarScope::Machine(machine)),
+            (group, _) => Ok(manager.variables_for(group.as_deref())?.into_iter().collect()),
+        }
+    };
+
+    match command {
+        VarsCommands::Get { key, group, machine, resolved } => {
+            let vars = load(group, machine, resolved)?;
+            match crate::vars::get_path(&vars, &key) {
+                Some(serde_json::Value::String(value)) => println!("{}", value),
+                Some(value) => println!("{}", value),
+                None => return Err(LaszooError::Other(format!("Variable not set: {}", key))),
+            }
+        }
+        VarsCommands::Set { key, value, group, machine } => {
+            let scope = match (group, machine) {
+                (Some(group), _) => VarScope::Group(group),
+                (None, Some(machine)) => VarScope::Machine(machine),
+                (None, None) => unreachable!("clap requires a scope"),
+            };
+            manager.set(&scope, &key, crate::vars::parse_value(&value))?;
+            println!("Set {} for {}", key, scope);
+        }
+        VarsCommands::List { group, machine, resolved } => {
+            let vars = load(group, machine, resolved)?;
+            if vars.is_empty() {
+                println!("No variables set");
+            }
+            for (key, value) in crate::vars::flatten(&vars) {
+                println!("{} = {}", key, value);
+            }
+        }
+    }
+
+    Ok(())
+}
+
 // Helper function to update machine's groups.conf
 fn update_machine_groups(mfs_mount: &Path, machine_name: &str, group_name: &str, add: bool) -> Result<()> {
     let groups_file = mfs_mount
@@ -1406,47 +2018,22 @@ fn update_membership_symlinks(mfs_mount: &Path, machine_name: &str, groups: &[St
     Ok(())
 }
 
-/// Calculate checksum of a file
-fn calculate_file_checksum(path: &Path) -> Result<String> {
-    use sha2::{Sha256, Digest};
-    let mut file = std::fs::File::open(path)?;
-    let mut hasher = Sha256::new();
-    std::io::copy(&mut file, &mut hasher)?;
-    Ok(format!("{:x}", hasher.finalize()))
-}
-
-// Helper function to list machines in a group
-fn list_machines_in_group(mfs_mount: &Path, group_name: &str) -> Result<Vec<String>> {
-    let machines_dir = mfs_mount.join("machines");
-    let mut machines = Vec::new();
-
-    if let Ok(entries) = std::fs::read_dir(&machines_dir) {
-        for entry in entries.flatten() {
-            if let Some(machine_name) = entry.file_name().to_str() {
-                let groups_file = machines_dir
-                    .join(machine_name)
-                    .join("etc")
-                    .join("laszoo")
-                    .join("groups.conf");
-
-                if groups_file.exists() {
-                    let content = std::fs::read_to_string(&groups_file)?;
-                    if content.lines().any(|l| l.trim() == group_name) {
-                        machines.push(machine_name.to_string());
-                    }
-                }
-            }
-        }
-    }
+/// Whether a local file matches what its template renders to on this machine.
+/// Enrolled symlinks are compared by their link target.
+fn template_matches_file(
+    enrollment_manager: &crate::enrollment::EnrollmentManager,
+    template_path: &Path,
+    file_path: &Path,
+) -> Result<bool> {
+    let rendered = enrollment_manager.render_template_bytes(template_path, file_path)?;
+    let local = enrollment_manager.local_content(template_path, file_path)?
+        .ok_or_else(|| LaszooError::FileNotFound { path: file_path.to_path_buf() })?;
 
-    machines.sort();
-    Ok(machines)
+    Ok(crate::fs::content_checksum(&rendered) == crate::fs::content_checksum(&local))
 }
+
 async fn watch_for_changes(config: &Config, group: Option<&str>, _interval: u64, auto: bool, hard: bool) -> Result<()> {
-    use notify::{Watcher, RecursiveMode, Event, EventKind};
-    use std::sync::mpsc::channel;
     use std::time::Duration;
-    use std::collections::HashSet;
 
     info!("Starting watch mode for group: {:?}, auto: {}", group, auto);
 
@@ -1455,7 +2042,7 @@ async fn watch_for_changes(config: &Config, group: Option<&str>, _interval: u64,
         // Check if filesystem is mounted
         if !is_filesystem_mounted(&config.mfs_mount) {
             println!("Warning: {} is not mounted. Waiting for filesystem to become available...", config.mfs_mount.display());
-            tokio::time::sleep(Duration::from_secs(30)).await;
+            queue_changes_while_offline(config, Duration::from_secs(30)).await?;
             continue;
         }
 
@@ -1467,9 +2054,9 @@ async fn watch_for_changes(config: &Config, group: Option<&str>, _interval: u64,
             }
             Err(e) => {
                 // Check if it's a filesystem error
-                if is_filesystem_error(&e) {
+                if crate::fs::is_filesystem_error(&e) {
                     println!("Filesystem became unavailable: {}. Retrying in 30 seconds...", e);
-                    tokio::time::sleep(Duration::from_secs(30)).await;
+                    queue_changes_while_offline(config, Duration::from_secs(30)).await?;
                     continue;
                 } else {
                     // Other error, propagate it
@@ -1482,6 +2069,125 @@ async fn watch_for_changes(config: &Config, group: Option<&str>, _interval: u64,
     Ok(())
 }
 
+/// Plan a single pass of `laszoo watch`: a full reconcile pass, with what
+/// would be done about the differences it finds
+fn plan_watch(config: &Config, group: Option<&str>, auto: bool, hard: bool) -> Result<Plan> {
+    use crate::monitor::{Decision, ReconcileOptions, Reconciler, Report};
+
+    crate::fs::ensure_distributed_fs_available(&config.mfs_mount)?;
+    let mut plan = Plan::new();
+    let groups = watched_groups(config, group)?;
+    if groups.is_empty() {
+        println!("This machine is not in any groups. Nothing to watch.");
+        return Ok(plan);
+    }
+
+    let options = ReconcileOptions {
+        full_reconcile_interval: Some(std::time::Duration::MAX),
+        ..ReconcileOptions::new(auto, hard)
+    };
+    let mut reconciler = Reconciler::new(config, groups, options)?;
+    let decisions = reconciler.decide()?;
+    for decision in &decisions {
+        // Held changes are left for the user, say so
+        if matches!(decision, Decision::HoldTemplate(..) | Decision::HoldPackages(..) | Decision::FullReconcile(_)) {
+            print_reconcile_report(&Report { decision: decision.clone(), outcomes: Vec::new() });
+        }
+    }
+    reconciler.plan_all(&decisions, &mut plan);
+
+    Ok(plan)
+}
+
+/// List local changes queued while the shared filesystem was unavailable
+fn print_pending_changes(config: &Config) -> Result<()> {
+    let pending = crate::queue::PendingQueue::from_config(config).list()?;
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    println!("\nPending changes (queued while {} was unavailable):", config.mfs_mount.display());
+    for change in &pending {
+        println!(
+            "  #{} {} {} (group: {}, at {})",
+            change.seq,
+            change.kind,
+            change.path.display(),
+            change.group,
+            change.recorded_at.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S")
+        );
+    }
+    Ok(())
+}
+
+/// Record changes to converge-mode files in the local queue until the shared
+/// filesystem is mounted again, checking every `retry`
+async fn queue_changes_while_offline(config: &Config, retry: std::time::Duration) -> Result<()> {
+    use notify::{Watcher, RecursiveMode, Event, EventKind};
+    use crate::queue::{ChangeKind, PendingQueue};
+    use std::time::{Duration, Instant};
+
+    let queue = PendingQueue::from_config(config);
+    let targets = queue.load_targets()?;
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: std::result::Result<Event, notify::Error>| {
+        if let Ok(event) = event {
+            let _ = tx.send(event);
+        }
+    })?;
+    for target in &targets {
+        let (path, mode) = if target.directory {
+            (target.path.as_path(), RecursiveMode::Recursive)
+        } else {
+            (target.path.parent().unwrap_or(&target.path), RecursiveMode::NonRecursive)
+        };
+        if let Err(e) = watcher.watch(path, mode) {
+            debug!("Failed to watch {:?} while offline: {}", path, e);
+        }
+    }
+    if !targets.is_empty() {
+        println!("Queueing local changes to {} converge paths until the filesystem returns", targets.len());
+    }
+
+    // A single save fires several events, only queue the first of them
+    let mut last_queued: HashMap<PathBuf, (ChangeKind, Instant)> = HashMap::new();
+    let mut last_check = Instant::now();
+    loop {
+        match tokio::time::timeout(Duration::from_millis(500), rx.recv()).await {
+            Ok(Some(event)) => {
+                if matches!(event.kind, EventKind::Modify(_) | EventKind::Create(_) | EventKind::Remove(_)) {
+                    for path in event.paths {
+                        let Some(target) = PendingQueue::target_for(&targets, &path) else { continue };
+                        let kind = if path.symlink_metadata().is_ok() { ChangeKind::Modified } else { ChangeKind::Deleted };
+                        if last_queued.get(&path).is_some_and(|(k, at)| *k == kind && at.elapsed() < Duration::from_secs(2)) {
+                            continue;
+                        }
+                        match queue.record(&path, &target.group, kind) {
+                            Ok(change) => {
+                                println!("  Queued {} {} (#{})", kind, path.display(), change.seq);
+                                last_queued.insert(path, (kind, Instant::now()));
+                            }
+                            Err(e) => error!("Failed to queue change to {}: {}", path.display(), e),
+                        }
+                    }
+                }
+            }
+            Ok(None) => break,
+            Err(_) => {}
+        }
+
+        if last_check.elapsed() >= retry {
+            if is_filesystem_mounted(&config.mfs_mount) {
+                break;
+            }
+            last_check = Instant::now();
+        }
+    }
+
+    Ok(())
+}
+
 fn is_filesystem_mounted(path: &Path) -> bool {
     // Use mountpoint command to check if path is mounted
     match std::process::Command::new("mountpoint")
@@ -1497,45 +2203,10 @@ fn is_filesystem_mounted(path: &Path) -> bool {
     }
 }
 
-fn is_filesystem_error(error: &LaszooError) -> bool {
-    match error {
-        LaszooError::Io(e) => {
-            // Check for common filesystem unavailability errors
-            matches!(e.kind(), 
-                std::io::ErrorKind::NotFound |
-                std::io::ErrorKind::PermissionDenied |
-                std::io::ErrorKind::Other
-            )
-        }
-        LaszooError::Other(msg) => {
-            msg.contains("filesystem") || 
-            msg.contains("mount") ||
-            msg.contains("not available") ||
-            msg.contains("Input/output error")
-        }
-        _ => false,
-    }
-}
-
-async fn watch_with_recovery(config: &Config, group: Option<&str>, auto: bool, hard: bool) -> Result<()> {
-    use notify::{Watcher, RecursiveMode, Event, EventKind};
-    use std::sync::mpsc::channel;
-    use std::time::Duration;
-    use std::collections::HashSet;
-
+/// Groups watch follows: the given group, which this machine must be in, or
+/// all of the machine's groups
+fn watched_groups(config: &Config, group: Option<&str>) -> Result<Vec<String>> {
     let hostname = gethostname::gethostname().to_string_lossy().to_string();
-    let enrollment_manager = crate::enrollment::EnrollmentManager::new(
-        config.mfs_mount.clone(),
-        hostname.clone(),
-    );
-
-    println!("Starting watch mode...");
-    if auto {
-        println!("Auto-apply mode enabled - changes will be applied automatically");
-    } else {
-        println!("Manual mode - you will be prompted before applying changes");
-    }
-    println!("Press Ctrl+C to stop watching\n");
 
     // Get machine's groups
     let groups_file = config.mfs_mount
@@ -1556,85 +2227,94 @@ async fn watch_with_recovery(config: &Config, group: Option<&str>, auto: bool, h
     };
 
     // Filter groups based on command line argument
-    let groups_to_watch = if let Some(group_name) = group {
-        if machine_groups.contains(&group_name.to_string()) {
-            vec![group_name.to_string()]
-        } else {
+    match group {
+        Some(group_name) if machine_groups.contains(&group_name.to_string()) => Ok(vec![group_name.to_string()]),
+        Some(group_name) => {
             error!("Machine is not in group '{}'", group_name);
-            return Err(LaszooError::Other(format!("Machine is not in group '{}'", group_name)));
+            Err(LaszooError::Other(format!("Machine is not in group '{}'", group_name)))
         }
+        None => Ok(machine_groups),
+    }
+}
+
+async fn watch_with_recovery(config: &Config, group: Option<&str>, auto: bool, hard: bool) -> Result<()> {
+    use notify::{Watcher, RecursiveMode, Event};
+    use crate::monitor::{Decision, LocalChange, ReconcileEvent, ReconcileOptions, Reconciler};
+    use std::time::Duration;
+
+    println!("Starting watch mode...");
+    if auto {
+        println!("Auto-apply mode enabled - changes will be applied automatically");
     } else {
-        machine_groups
-    };
+        println!("Manual mode - you will be prompted before applying changes");
+    }
+    println!("Press Ctrl+C to stop watching\n");
+
+    if let Err(e) = crate::facts::HostFacts::local().publish(&config.mfs_mount) {
+        warn!("Failed to publish host facts: {}", e);
+    }
 
+    let groups_to_watch = watched_groups(config, group)?;
     if groups_to_watch.is_empty() {
         println!("This machine is not in any groups. Nothing to watch.");
         return Ok(());
     }
 
-    // Collect all enrolled paths to watch from manifests
-    let mut watch_paths = HashSet::new();
-    let mut path_to_group_map = std::collections::HashMap::new();
-    let mut enrolled_directories = HashSet::new();
-    let mut enrolled_files = HashSet::new();
+    let options = ReconcileOptions {
+        auto_commit: config.auto_commit,
+        full_reconcile_interval: (config.monitoring.poll_interval > 0)
+            .then(|| Duration::from_secs(config.monitoring.poll_interval)),
+        ..ReconcileOptions::new(auto, hard)
+    };
+    let reconciler = Reconciler::new(config, groups_to_watch.clone(), options)?
+        .with_approval(Box::new(move |changes: &[LocalChange]| confirm_local_changes(changes, auto)));
 
     for group_name in &groups_to_watch {
-        // Watch the group directory for new templates
-        let group_dir = crate::fs::get_group_dir(&config.mfs_mount, "", group_name);
-        watch_paths.insert(group_dir.clone());
-        path_to_group_map.insert(group_dir, group_name.clone());
-
-        // Load both group and machine manifests
-        if let Ok(group_manifest) = enrollment_manager.load_group_manifest(group_name) {
-            for (path, entry) in &group_manifest.entries {
-                watch_paths.insert(path.clone());
-                path_to_group_map.insert(path.clone(), group_name.clone());
-
-                if entry.checksum == "directory" {
-                    enrolled_directories.insert(path.clone());
-                } else {
-                    enrolled_files.insert(path.clone());
-                }
-            }
-        }
-
-        let machine_manifest = enrollment_manager.load_manifest()?;
-        for (path, entry) in &machine_manifest.entries {
-            if &entry.group == group_name {
-                watch_paths.insert(path.clone());
-                path_to_group_map.insert(path.clone(), group_name.clone());
-
-                if entry.checksum == "directory" {
-                    enrolled_directories.insert(path.clone());
-                } else {
-                    enrolled_files.insert(path.clone());
-                }
-            }
-        }
-    }
-
-    if watch_paths.is_empty() {
-        println!("No enrolled paths found in the specified group(s).");
-        return Ok(());
-    }
+        if let Err(e) = reconciler.manager().publish_group_hashes(group_name) {
+            warn!("Failed to publish file hashes for group '{}': {}", group_name, e);
+        }
+    }
+
+    // Remember the converge-mode paths so changes to them can be queued while
+    // the filesystem is unavailable, then replay what was queued last time
+    let queue = crate::queue::PendingQueue::from_config(config);
+    let targets = reconciler.targets().clone();
+    let offline_targets: Vec<crate::queue::OfflineTarget> = targets.files.iter().map(|path| (path, false))
+        .chain(targets.directories.iter().map(|path| (path, true)))
+        .filter_map(|(path, directory)| {
+            let group = targets.group_of.get(path)?;
+            let sync_action = reconciler.manager().effective_sync_action(group, path).ok()?;
+            (sync_action == SyncAction::Converge).then(|| crate::queue::OfflineTarget {
+                path: path.clone(),
+                group: group.clone(),
+                directory,
+            })
+        })
+        .collect();
+    if let Err(e) = queue.save_targets(&offline_targets) {
+        warn!("Failed to save paths to watch while offline: {}", e);
+    }
+    reconciler.replay_pending(&queue).await?;
 
     println!("Watching {} paths ({} directories, {} files) across {} group(s):",
-        watch_paths.len(), enrolled_directories.len(), enrolled_files.len(), groups_to_watch.len());
+        targets.directories.len() + targets.files.len() + groups_to_watch.len(),
+        targets.directories.len(), targets.files.len(), groups_to_watch.len());
     for group in &groups_to_watch {
         println!("  • {}", group);
     }
     println!();
 
-    // Create a channel for file events
-    let (tx, rx) = channel();
-
-    // Create a channel for completed commits
-    let (commit_tx, commit_rx) = std::sync::mpsc::channel::<HashSet<PathBuf>>();
+    // Filesystem events, and commits finishing in the background, go to the reconciler
+    let (event_tx, event_rx) = tokio::sync::mpsc::unbounded_channel();
+    let mfs_groups_dir = config.mfs_mount.join("groups");
 
-    // Create a debounced watcher
+    let watcher_tx = event_tx.clone();
+    let groups_dir = mfs_groups_dir.clone();
     let mut watcher = notify::recommended_watcher(move |event: std::result::Result<Event, notify::Error>| {
         if let Ok(event) = event {
-            let _ = tx.send(event);
+            for event in ReconcileEvent::from_notify(&event, &groups_dir) {
+                let _ = watcher_tx.send(event);
+            }
         }
     })?;
 
@@ -1642,7 +2322,7 @@ async fn watch_with_recovery(config: &Config, group: Option<&str>, auto: bool, h
     let mut watched_count = 0;
 
     // Watch directories recursively
-    for dir in &enrolled_directories {
+    for dir in &targets.directories {
         if dir.exists() {
             if let Err(e) = watcher.watch(dir, RecursiveMode::Recursive) {
                 warn!("Failed to watch directory {:?}: {}", dir, e);
@@ -1657,7 +2337,7 @@ async fn watch_with_recovery(config: &Config, group: Option<&str>, auto: bool, h
 
     // Watch individual files (and their parent directories non-recursively)
     let mut watched_file_dirs = HashSet::new();
-    for file in &enrolled_files {
+    for file in &targets.files {
         if let Some(parent) = file.parent() {
             if !watched_file_dirs.contains(parent) && parent.exists() {
                 if let Err(e) = watcher.watch(parent, RecursiveMode::NonRecursive) {
@@ -1676,7 +2356,6 @@ async fn watch_with_recovery(config: &Config, group: Option<&str>, auto: bool, h
     }
 
     // Also watch the MooseFS mount for template changes to commit
-    let mfs_groups_dir = config.mfs_mount.join("groups");
     if mfs_groups_dir.exists() {
         if let Err(e) = watcher.watch(&mfs_groups_dir, RecursiveMode::Recursive) {
             warn!("Failed to watch MooseFS groups directory: {}", e);
@@ -1691,727 +2370,147 @@ async fn watch_with_recovery(config: &Config, group: Option<&str>, auto: bool, h
         println!("Successfully watching {} directories.", watched_count);
     }
 
-    // Initial scan for missing files if --hard is enabled
     if hard {
         println!("\nScanning enrolled directories for missing files...");
-        let mut missing_files = Vec::new();
-
-        // For enrolled directories, scan templates and check if files exist
-        for dir in &enrolled_directories {
-            if let Some(group) = path_to_group_map.get(dir) {
-                let group_dir = crate::fs::get_group_dir(&config.mfs_mount, "", group);
-
-                // Scan templates in this group directory
-                for entry in walkdir::WalkDir::new(&group_dir).into_iter().filter_map(|e| e.ok()) {
-                        if entry.file_type().is_file() &&
-                           entry.path().extension() == Some(std::ffi::OsStr::new("lasz")) {
-
-                            // Extract the original file path from template path
-                            if let Ok(relative_path) = entry.path().strip_prefix(&group_dir) {
-                                let path_str = relative_path.to_string_lossy();
-                                if path_str.ends_with(".lasz") {
-                                    // The relative path is like "tmp/test/file.txt.lasz", we need "/tmp/test/file.txt"
-                                    let original_path = PathBuf::from("/").join(&path_str[..path_str.len() - 5]);
-
-                                    debug!("Checking template: {:?} -> original path: {:?}", entry.path(), original_path);
-
-                                    // Check if this file is within our enrolled directory
-                                    if original_path.starts_with(dir) && !original_path.exists() {
-                                        missing_files.push((original_path, group.clone(), entry.path().to_path_buf()));
-                                    }
-                                }
-                            }
-                        }
-                    }
-            }
-        }
-
-        // For enrolled files, check if they exist
-        for file in &enrolled_files {
-            if !file.exists() {
-                if let Some(group) = path_to_group_map.get(file) {
-                    let template_path = enrollment_manager.get_group_template_path(group, file)?;
-                    if template_path.exists() {
-                        missing_files.push((file.clone(), group.clone(), template_path));
-                    }
-                }
-            }
-        }
-
-        if !missing_files.is_empty() {
-            println!("Found {} missing file(s):", missing_files.len());
-            for (path, group, template_path) in &missing_files {
-                println!("  ✗ {} (group: {})", path.display(), group);
-
-                // Load group configuration to get sync action
-                let (_before_trigger, _after_trigger, sync_action) =
-                    load_group_config(&config.mfs_mount, group)?;
-
-                // For converge with --hard, delete the template
-                if matches!(sync_action, SyncAction::Converge) {
-                    if let Err(e) = std::fs::remove_file(template_path) {
-                        if e.kind() == std::io::ErrorKind::NotFound {
-                            println!("    → Template already deleted");
-                        } else {
-                            return Err(LaszooError::Io(e));
-                        }
-                    } else {
-                        println!("    → Deleted template for missing file");
-                    }
-                }
-            }
+    }
 
-            // Commit template deletions if any were made
-            if config.auto_commit && !missing_files.is_empty() {
-                println!("\nScheduling background commit for template deletions...");
+    let (report_tx, mut report_rx) = tokio::sync::mpsc::unbounded_channel();
+    let reporting = async {
+        while let Some(report) = report_rx.recv().await {
+            print_reconcile_report(&report);
 
-                // Clone config for background task
+            // Commits run in the background and report back when done
+            if let Decision::Commit(templates) = report.decision {
                 let config_clone = config.clone();
-
-                // Spawn background commit task
+                let commit_tx = event_tx.clone();
                 tokio::spawn(async move {
-                    if let Err(e) = commit_changes(&config_clone, Some("Removed templates for missing files"), true).await {
-                        error!("Failed to auto-commit template deletions: {}", e);
+                    if let Err(e) = commit_changes(&config_clone, Some("Template changes from local file modifications"), true).await {
+                        error!("Failed to auto-commit template changes: {}", e);
+                        let _ = commit_tx.send(ReconcileEvent::Committed(HashSet::new()));
                     } else {
-                        println!("✓ Template deletion commit completed");
+                        println!("✓ Background commit completed for {} template changes", templates.len());
+                        let _ = commit_tx.send(ReconcileEvent::Committed(templates));
                     }
                 });
             }
         }
+    };
+
+    tokio::select! {
+        result = reconciler.run(event_rx, report_tx) => result,
+        () = reporting => Ok(()),
     }
+}
 
-    // Process events
-    let mut debounce_buffer = HashSet::new();
-    let mut template_changes = HashSet::new();
-    let mut local_file_changes = HashSet::new(); // Track local file changes
-    let mut local_template_changes = HashSet::new(); // Track template changes that originated locally
-    let mut committed_template_changes = HashSet::new(); // Track template changes that have been committed
-    let mut ignore_file_changes = HashSet::new(); // Track files we're currently applying templates to (ignore subsequent changes)
-    let mut ignore_file_timestamps: HashMap<PathBuf, std::time::Instant> = HashMap::new(); // Track when files were added to ignore list
-    let debounce_duration = Duration::from_millis(500);
-    let mut last_event_time = std::time::Instant::now();
-    let mut last_template_time = std::time::Instant::now();
-    let mut last_template_scan = std::time::Instant::now();
-    let template_scan_interval = Duration::from_secs(2); // Scan every 2 seconds
-    let mut known_templates: HashSet<PathBuf> = HashSet::new();
-    let mut known_template_timestamps: std::collections::HashMap<PathBuf, std::time::SystemTime> = std::collections::HashMap::new();
-    let mut known_template_checksums: std::collections::HashMap<PathBuf, String> = std::collections::HashMap::new();
+/// List debounced local changes and, unless in auto mode, ask whether to
+/// sync them
+fn confirm_local_changes(changes: &[crate::monitor::LocalChange], auto: bool) -> bool {
+    use std::io::{self, Write};
 
-    // Track packages.conf files
-    let mut packages_conf_checksums: HashMap<PathBuf, String> = HashMap::new();
-    let mut last_packages_scan = std::time::Instant::now();
-    let packages_scan_interval = Duration::from_secs(2); // Check every 2 seconds
+    println!("\n[{}] Changes detected in {} file(s):",
+        chrono::Local::now().format("%H:%M:%S"),
+        changes.len()
+    );
+    for change in changes {
+        println!("  {} {} (group: {})", change.kind.symbol(), change.path.display(), change.group);
+    }
 
-    // Initial scan of templates and packages.conf
-    for group_name in &groups_to_watch {
-        let group_dir = crate::fs::get_group_dir(&config.mfs_mount, "", group_name);
-        
-        // Check for packages.conf
-        let packages_conf_path = group_dir.join("etc").join("laszoo").join("packages.conf");
-        if packages_conf_path.exists() {
-            if let Ok(checksum) = calculate_file_checksum(&packages_conf_path) {
-                packages_conf_checksums.insert(packages_conf_path, checksum);
+    if auto {
+        return true;
+    }
+
+    print!("\nApply changes? [y/N] ");
+    let _ = io::stdout().flush();
+    let mut input = String::new();
+    io::stdin().read_line(&mut input).is_ok() && input.trim().to_lowercase() == "y"
+}
+
+/// Print a reconcile decision and how carrying it out went
+fn print_reconcile_report(report: &crate::monitor::Report) {
+    use crate::monitor::{Decision, HoldReason, Outcome};
+
+    let now = chrono::Local::now().format("%H:%M:%S");
+    match &report.decision {
+        Decision::SyncLocal(_) => {
+            for outcome in &report.outcomes {
+                match outcome {
+                    Outcome::TemplateUpdated { path, .. } => println!("✓ Updated template for {}", path.display()),
+                    Outcome::Failed { path: Some(path), error } => println!("✗ Failed to handle change for {}: {}", path.display(), error),
+                    Outcome::Declined => println!("Changes not applied."),
+                    _ => {}
+                }
             }
+            println!(); // Add blank line for readability
         }
-        
-        for entry in walkdir::WalkDir::new(&group_dir) {
-            if let Ok(entry) = entry {
-                if entry.file_type().is_file() && entry.path().extension() == Some(std::ffi::OsStr::new("lasz")) {
-                    let template_path = entry.path().to_path_buf();
-                    known_templates.insert(template_path.clone());
-
-                    // Record initial timestamp and checksum
-                    if let Ok(metadata) = std::fs::metadata(&template_path) {
-                        if let Ok(modified) = metadata.modified() {
-                            known_template_timestamps.insert(template_path.clone(), modified);
-                        }
-                    }
-
-                    // Calculate initial checksum
-                    if let Ok(checksum) = calculate_file_checksum(&template_path) {
-                        known_template_checksums.insert(template_path, checksum);
-                    }
-                }
-            }
-        }
-    }
-    
-    // Also check machine-specific packages.conf
-    let machine_packages_conf = config.mfs_mount
-        .join("machines")
-        .join(&hostname)
-        .join("etc")
-        .join("laszoo")
-        .join("packages.conf");
-    if machine_packages_conf.exists() {
-        if let Ok(checksum) = calculate_file_checksum(&machine_packages_conf) {
-            packages_conf_checksums.insert(machine_packages_conf, checksum);
-        }
-    }
-
-    loop {
-        // Check for completed commits (non-blocking)
-        while let Ok(completed_changes) = commit_rx.try_recv() {
-            if completed_changes.is_empty() {
-                // Commit failed, remove from committed_template_changes to allow retry
-                debug!("Commit failed, will retry on next cycle");
+        Decision::ApplyTemplate(change) | Decision::HoldTemplate(change, _) => {
+            if change.new {
+                println!("\n[{}] New template detected: {}", now, change.template_path.display());
             } else {
-                // Successfully committed, can now remove from local_template_changes
-                for change in &completed_changes {
-                    local_template_changes.remove(change);
-                    committed_template_changes.remove(change);
-                }
-                debug!("Cleaned up {} committed template changes", completed_changes.len());
-            }
-        }
-
-        // Check for events with timeout
-        match rx.recv_timeout(Duration::from_millis(100)) {
-            Ok(event) => {
-                match event.kind {
-                    EventKind::Modify(_) | EventKind::Create(_) | EventKind::Remove(_) => {
-                        for path in event.paths {
-                            // Check if it's a template change in MooseFS
-                            if path.starts_with(&mfs_groups_dir) &&
-                               (path.extension() == Some(std::ffi::OsStr::new("lasz")) ||
-                                path.extension() == Some(std::ffi::OsStr::new("json"))) {
-                                template_changes.insert(path);
-                                last_template_time = std::time::Instant::now();
-                            }
-                            // Check if it's a file we should track (including deleted files)
-                            else {
-                                let mut should_track = false;
-
-                                // Check if it's an enrolled file
-                                if enrolled_files.contains(&path) {
-                                    should_track = true;
-                                } else {
-                                    // Check if it's within an enrolled directory
-                                    for dir in &enrolled_directories {
-                                        if path.starts_with(dir) {
-                                            should_track = true;
-                                            break;
-                                        }
-                                    }
-                                }
-
-                                if should_track {
-                                    if ignore_file_changes.contains(&path) {
-                                        debug!("Ignoring file change event for {:?} (template application in progress)", path);
-                                    } else {
-                                        debounce_buffer.insert(path.clone());
-                                        local_file_changes.insert(path.clone()); // Track this as a local change
-                                        last_event_time = std::time::Instant::now();
-                                        debug!("Tracking file change for {:?}", path);
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    _ => {}
-                }
+                println!("\n[{}] Template modified: {}", now, change.template_path.display());
             }
-            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
-                // Check if we should process debounced events
-                if !debounce_buffer.is_empty() &&
-                   last_event_time.elapsed() > debounce_duration {
-
-                    // Process the buffered changes
-                    println!("\n[{}] Changes detected in {} file(s):",
-                        chrono::Local::now().format("%H:%M:%S"),
-                        debounce_buffer.len()
-                    );
-
-                    let mut affected_groups = HashSet::new();
-                    let mut files_by_group: HashMap<String, Vec<PathBuf>> = HashMap::new();
-
-                    for path in &debounce_buffer {
-                        // Skip files that are currently being ignored (template applications)
-                        if ignore_file_changes.contains(path) {
-                            debug!("Skipping file change for {:?} (currently applying template)", path);
-                            continue;
-                        }
-
-                        // Find which group this file belongs to
-                        let mut found_group = None;
-
-                        // Check if this is an enrolled file
-                        if enrolled_files.contains(path) {
-                            if let Some(group) = path_to_group_map.get(path) {
-                                found_group = Some(group.clone());
-                            }
-                        } else {
-                            // Check if it's within an enrolled directory
-                            for dir in &enrolled_directories {
-                                if path.starts_with(dir) {
-                                    if let Some(group) = path_to_group_map.get(dir) {
-                                        found_group = Some(group.clone());
-                                        break;
-                                    }
-                                }
-                            }
-                        }
-
-                        if let Some(group) = found_group {
-                            affected_groups.insert(group.clone());
-                            files_by_group.entry(group.clone())
-                                .or_insert_with(Vec::new)
-                                .push(path.clone());
-
-                            let status_char = if path.exists() {
-                                // Check if template exists
-                                let template_path = enrollment_manager.get_group_template_path(&group, path)?;
-                                if template_path.exists() {
-                                    "●"  // Modified
-                                } else {
-                                    "?"  // New file
-                                }
-                            } else {
-                                "✗"  // Deleted
-                            };
-                            println!("  {} {} (group: {})", status_char, path.display(), group);
-                        }
-                    }
-
-                    // Apply changes if auto mode is enabled or user confirms
-                    let should_apply = if auto {
-                        true
-                    } else {
-                        print!("\nApply changes? [y/N] ");
-                        use std::io::{self, Write};
-                        io::stdout().flush()?;
-
-                        let mut input = String::new();
-                        io::stdin().read_line(&mut input)?;
-                        input.trim().to_lowercase() == "y"
-                    };
-
-                    if should_apply {
-                        // Process changes for each affected group
-                        for group_name in affected_groups {
-                            // Load group configuration to get sync action
-                            let (_before_trigger, _after_trigger, sync_action) =
-                                load_group_config(&config.mfs_mount, &group_name)?;
-
-                            println!("\nProcessing group '{}' with sync action: {:?}", group_name, sync_action);
-
-                            // Process each changed file in this group according to sync action
-                            if let Some(files) = files_by_group.get(&group_name) {
-                                for path in files {
-                                    match handle_file_change(
-                                        &enrollment_manager,
-                                        path,
-                                        &group_name,
-                                        &sync_action,
-                                        hard,
-                                    ).await {
-                                        Ok(template_changed) => {
-                                            if template_changed {
-                                                println!("✓ Updated template for {}", path.display());
-
-                                                // Track that this template change originated from local file change
-                                                let template_path = enrollment_manager.get_group_template_path(&group_name, path)?;
-                                                local_template_changes.insert(template_path);
-                                            }
-                                        }
-                                        Err(e) => {
-                                            error!("Failed to handle change for {}: {}", path.display(), e);
-                                            println!("✗ Failed to handle change for {}: {}", path.display(), e);
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    } else {
-                        println!("Changes not applied.");
-                    }
-
-                    // Clear the buffer
-                    debounce_buffer.clear();
-                    println!(); // Add blank line for readability
+            match &report.decision {
+                Decision::HoldTemplate(_, HoldReason::LocalOrigin) => {
+                    println!("  → Skipping auto-apply (originated from local file change)");
                 }
-
-                // Check if we should process template changes
-                if !template_changes.is_empty() &&
-                   last_template_time.elapsed() > debounce_duration {
-
-                    debug!("Template changes detected: {} files", template_changes.len());
-
-                    // Only auto-commit template changes that originated from local file changes
-                    if config.auto_commit && !local_template_changes.is_empty() {
-                        println!("\nScheduling background commit for {} local template changes...", local_template_changes.len());
-
-                        // Clone the changes being committed (excluding already committed ones)
-                        let mut changes_to_commit = HashSet::new();
-                        for change in &local_template_changes {
-                            if !committed_template_changes.contains(change) {
-                                changes_to_commit.insert(change.clone());
-                            }
-                        }
-
-                        if !changes_to_commit.is_empty() {
-                            // Mark these as being committed
-                            for change in &changes_to_commit {
-                                committed_template_changes.insert(change.clone());
-                            }
-
-                            // Clone config and channel for background task
-                            let config_clone = config.clone();
-                            let commit_tx_clone = commit_tx.clone();
-                            let changes_clone = changes_to_commit.clone();
-
-                            // Spawn background commit task
-                            tokio::spawn(async move {
-                                if let Err(e) = commit_changes(&config_clone, Some("Template changes from local file modifications"), true).await {
-                                    error!("Failed to auto-commit template changes: {}", e);
-                                    // Send back empty set to indicate failure
-                                    let _ = commit_tx_clone.send(HashSet::new());
-                                } else {
-                                    println!("✓ Background commit completed for {} template changes", changes_clone.len());
-                                    // Send back the committed changes
-                                    let _ = commit_tx_clone.send(changes_clone);
-                                }
-                            });
-                        }
-                    }
-
-                    // Clear template_changes but keep local_template_changes for race condition handling
-                    template_changes.clear();
-                    // Don't clear local_template_changes - they'll be cleaned up when commits complete
+                Decision::HoldTemplate(_, HoldReason::Manual) => {
+                    println!("  → Template change detected (manual mode - run 'laszoo apply {}' to apply)", change.group);
                 }
-
-                // Periodic template scanning for MooseFS (since inotify doesn't work)
-                if last_template_scan.elapsed() > template_scan_interval {
-                    debug!("Performing periodic template scan...");
-
-                    for group_name in &groups_to_watch {
-                        let group_dir = crate::fs::get_group_dir(&config.mfs_mount, "", group_name);
-
-                        for entry in walkdir::WalkDir::new(&group_dir) {
-                            if let Ok(entry) = entry {
-                                if entry.file_type().is_file() && entry.path().extension() == Some(std::ffi::OsStr::new("lasz")) {
-                                    let template_path = entry.path().to_path_buf();
-
-                                    // Check if this is a new template or if it has been modified
-                                    let is_new = !known_templates.contains(&template_path);
-                                    let mut is_modified = false;
-
-                                    if !is_new {
-                                        // Check if content changed (using checksum)
-                                        if let Ok(current_checksum) = calculate_file_checksum(&template_path) {
-                                            if let Some(known_checksum) = known_template_checksums.get(&template_path) {
-                                                if &current_checksum != known_checksum {
-                                                    is_modified = true;
-                                                    known_template_checksums.insert(template_path.clone(), current_checksum);
-                                                    debug!("Template checksum changed for {:?}", template_path);
-                                                }
-                                            } else {
-                                                // No known checksum, treat as modified
-                                                is_modified = true;
-                                                known_template_checksums.insert(template_path.clone(), current_checksum);
-                                            }
-                                        }
-
-                                        // Also update timestamp for reference
-                                        if let Ok(metadata) = std::fs::metadata(&template_path) {
-                                            if let Ok(modified) = metadata.modified() {
-                                                known_template_timestamps.insert(template_path.clone(), modified);
-                                            }
-                                        }
-                                    } else {
-                                        // New template - record its checksum and timestamp
-                                        if let Ok(checksum) = calculate_file_checksum(&template_path) {
-                                            known_template_checksums.insert(template_path.clone(), checksum);
-                                        }
-
-                                        if let Ok(metadata) = std::fs::metadata(&template_path) {
-                                            if let Ok(modified) = metadata.modified() {
-                                                known_template_timestamps.insert(template_path.clone(), modified);
-                                            }
-                                        }
-                                    }
-
-                                    if is_new || is_modified {
-                                        // Extract the original file path from template path
-                                        if let Ok(relative_path) = template_path.strip_prefix(&group_dir) {
-                                            let path_str = relative_path.to_string_lossy();
-                                            if path_str.ends_with(".lasz") {
-                                                let original_path = PathBuf::from("/").join(&path_str[..path_str.len() - 5]);
-
-                                                // Check if this template change was triggered by a local file change
-                                                let was_local_change = local_file_changes.contains(&original_path);
-
-                                                if is_new {
-                                                    println!("\n[{}] New template detected: {}",
-                                                        chrono::Local::now().format("%H:%M:%S"),
-                                                        template_path.display()
-                                                    );
-                                                } else if is_modified {
-                                                    println!("\n[{}] Template modified: {}",
-                                                        chrono::Local::now().format("%H:%M:%S"),
-                                                        template_path.display()
-                                                    );
-                                                }
-
-                                                known_templates.insert(template_path.clone());
-
-                                                // Only auto-apply if this wasn't a local change and auto mode is enabled
-                                                if !was_local_change && auto {
-                                                    println!("  → Auto-applying template change from remote machine");
-
-                                                    // Add to ignore list before applying
-                                                    ignore_file_changes.insert(original_path.clone());
-                                                    ignore_file_timestamps.insert(original_path.clone(), std::time::Instant::now());
-
-                                                    // Apply this specific template
-                                                    if let Err(e) = enrollment_manager.apply_single_template(&template_path, &original_path) {
-                                                        error!("Failed to apply template {:?}: {}", template_path, e);
-                                                        println!("  ✗ Failed to apply template: {}", e);
-                                                    } else {
-                                                        println!("  ✓ Applied template change to {}", original_path.display());
-                                                    }
-                                                } else if was_local_change {
-                                                    println!("  → Skipping auto-apply (originated from local file change)");
-                                                } else if !auto {
-                                                    println!("  → Template change detected (manual mode - run 'laszoo apply {}' to apply)", group_name);
-                                                }
-
-                                                template_changes.insert(template_path);
-                                                last_template_time = std::time::Instant::now();
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-
-                    // Clear local file changes that are older than template scan interval
-                    // This prevents false positives where we think a template change was local
-                    local_file_changes.clear();
-
-                    // Clean up expired ignore entries (older than 5 seconds)
-                    let ignore_timeout = Duration::from_secs(5);
-                    let now = std::time::Instant::now();
-                    let mut expired_ignores = Vec::new();
-
-                    for (path, timestamp) in &ignore_file_timestamps {
-                        if now.duration_since(*timestamp) > ignore_timeout {
-                            expired_ignores.push(path.clone());
+                _ => {
+                    println!("  → Auto-applying template change from remote machine");
+                    for outcome in &report.outcomes {
+                        match outcome {
+                            Outcome::TemplateApplied { path } => println!("  ✓ Applied template change to {}", path.display()),
+                            Outcome::Failed { error, .. } => println!("  ✗ Failed to apply template: {}", error),
+                            _ => {}
                         }
                     }
-
-                    for path in expired_ignores {
-                        ignore_file_changes.remove(&path);
-                        ignore_file_timestamps.remove(&path);
-                        debug!("Expired ignore for file: {:?}", path);
-                    }
-
-                    last_template_scan = std::time::Instant::now();
                 }
-                
-                // Periodic packages.conf scanning
-                if last_packages_scan.elapsed() > packages_scan_interval {
-                    debug!("Performing periodic packages.conf scan...");
-                    
-                    let mut packages_changed = false;
-                    
-                    // Check group packages.conf files
-                    for group_name in &groups_to_watch {
-                        let group_dir = crate::fs::get_group_dir(&config.mfs_mount, "", group_name);
-                        let packages_conf_path = group_dir.join("etc").join("laszoo").join("packages.conf");
-                        
-                        if packages_conf_path.exists() {
-                            if let Ok(current_checksum) = calculate_file_checksum(&packages_conf_path) {
-                                if let Some(known_checksum) = packages_conf_checksums.get(&packages_conf_path) {
-                                    if &current_checksum != known_checksum {
-                                        println!("\n[{}] Packages configuration changed for group '{}'",
-                                            chrono::Local::now().format("%H:%M:%S"),
-                                            group_name
-                                        );
-                                        packages_conf_checksums.insert(packages_conf_path.clone(), current_checksum);
-                                        packages_changed = true;
-                                        
-                                        // Apply package changes if auto mode is enabled
-                                        if auto {
-                                            println!("  → Auto-applying package changes...");
-                                            if let Err(e) = apply_packages_for_group(config, group_name).await {
-                                                error!("Failed to apply package changes: {}", e);
-                                                println!("  ✗ Failed to apply package changes: {}", e);
-                                            } else {
-                                                println!("  ✓ Package changes applied");
-                                            }
-                                        } else {
-                                            println!("  → Package changes detected (manual mode - run 'laszoo install {} --apply' to apply)", group_name);
-                                        }
-                                    }
-                                } else {
-                                    // New packages.conf file
-                                    packages_conf_checksums.insert(packages_conf_path.clone(), current_checksum);
-                                    println!("\n[{}] New packages configuration detected for group '{}'",
-                                        chrono::Local::now().format("%H:%M:%S"),
-                                        group_name
-                                    );
-                                    packages_changed = true;
-                                }
-                            }
-                        }
-                    }
-                    
-                    // Check machine-specific packages.conf
-                    let machine_packages_conf = config.mfs_mount
-                        .join("machines")
-                        .join(&hostname)
-                        .join("etc")
-                        .join("laszoo")
-                        .join("packages.conf");
-                        
-                    if machine_packages_conf.exists() {
-                        if let Ok(current_checksum) = calculate_file_checksum(&machine_packages_conf) {
-                            if let Some(known_checksum) = packages_conf_checksums.get(&machine_packages_conf) {
-                                if &current_checksum != known_checksum {
-                                    println!("\n[{}] Machine-specific packages configuration changed",
-                                        chrono::Local::now().format("%H:%M:%S")
-                                    );
-                                    packages_conf_checksums.insert(machine_packages_conf.clone(), current_checksum);
-                                    packages_changed = true;
-                                    
-                                    // Apply package changes if auto mode is enabled
-                                    if auto {
-                                        println!("  → Auto-applying machine-specific package changes...");
-                                        if let Err(e) = apply_machine_packages(config).await {
-                                            error!("Failed to apply package changes: {}", e);
-                                            println!("  ✗ Failed to apply package changes: {}", e);
-                                        } else {
-                                            println!("  ✓ Package changes applied");
-                                        }
-                                    } else {
-                                        println!("  → Package changes detected (manual mode)");
-                                    }
-                                }
-                            } else {
-                                // New packages.conf file
-                                packages_conf_checksums.insert(machine_packages_conf.clone(), current_checksum);
-                            }
-                        }
-                    }
-                    
-                    if packages_changed {
-                        println!(); // Add blank line for readability
+            }
+        }
+        Decision::ApplyPackages(change) | Decision::HoldPackages(change, _) => {
+            match (&change.group, change.new) {
+                (Some(group), true) => println!("\n[{}] New packages configuration detected for group '{}'", now, group),
+                (Some(group), false) => println!("\n[{}] Packages configuration changed for group '{}'", now, group),
+                (None, _) => println!("\n[{}] Machine-specific packages configuration changed", now),
+            }
+            if let Decision::HoldPackages(..) = &report.decision {
+                match &change.group {
+                    Some(group) => println!("  → Package changes detected (manual mode - run 'laszoo install {} --apply' to apply)", group),
+                    None => println!("  → Package changes detected (manual mode)"),
+                }
+            } else {
+                println!("  → Auto-applying package changes...");
+                for outcome in &report.outcomes {
+                    match outcome {
+                        Outcome::PackagesApplied { .. } => println!("  ✓ Package changes applied"),
+                        Outcome::Failed { error, .. } => println!("  ✗ Failed to apply package changes: {}", error),
+                        _ => {}
                     }
-                    
-                    last_packages_scan = std::time::Instant::now();
                 }
             }
-            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => {
-                error!("Watcher channel disconnected");
-                break;
+            println!(); // Add blank line for readability
+        }
+        Decision::PruneMissing(files) => {
+            println!("Found {} missing file(s):", files.len());
+            for (file, outcome) in files.iter().zip(&report.outcomes) {
+                println!("  ✗ {} (group: {})", file.path.display(), file.group);
+                match outcome {
+                    Outcome::TemplateDeleted { .. } => println!("    → Deleted template for missing file"),
+                    Outcome::Failed { error, .. } => println!("    → Failed to delete template: {}", error),
+                    _ => {}
+                }
             }
         }
-    }
-
-    Ok(())
-}
-
-/// Handle a file change according to the sync action
-async fn handle_file_change(
-    enrollment_manager: &crate::enrollment::EnrollmentManager,
-    file_path: &Path,
-    group: &str,
-    sync_action: &SyncAction,
-    hard: bool,
-) -> Result<bool> {
-    use crate::template::TemplateEngine;
-
-    let template_path = enrollment_manager.get_group_template_path(group, file_path)?;
-    let template_exists = template_path.exists();
-    let file_exists = file_path.exists();
-
-    match (file_exists, template_exists, sync_action) {
-        // File deleted locally
-        (false, true, SyncAction::Converge) => {
-            if hard {
-                // Delete template if --hard is specified
-                std::fs::remove_file(&template_path)?;
-                info!("Deleted template for removed file: {:?}", file_path);
-                Ok(true)
-            } else {
-                // Just show as missing without --hard
-                println!("  File deleted locally: {} (template preserved)", file_path.display());
-                Ok(false)
+        Decision::Commit(templates) => {
+            println!("\nScheduling background commit for {} local template changes...", templates.len());
+        }
+        Decision::FullReconcile(summary) => {
+            // Quiet unless the pass found something missed
+            if summary.startup || summary.corrections() > 0 {
+                println!("\n[{}] Full reconcile pass: {} files checked, {} corrections ({} local, {} templates)",
+                    now, summary.checked, summary.corrections(), summary.local.len(), summary.templates.len());
             }
-        },
-
-        // File deleted locally with rollback - restore from template
-        (false, true, SyncAction::Rollback) => {
-            // Apply template to restore file
-            enrollment_manager.apply_single_template(&template_path, file_path)?;
-            println!("  Restored deleted file from template: {}", file_path.display());
-            Ok(false)
-        },
-
-        // File modified locally with converge - update template
-        (true, true, SyncAction::Converge) => {
-            // Read current file content
-            let file_content = std::fs::read_to_string(file_path)?;
-
-            // Load template to preserve variables
-            let template_content = std::fs::read_to_string(&template_path)?;
-
-            // Use template engine to merge changes while preserving variables
-            let template_engine = TemplateEngine::new()?;
-            let updated_template = template_engine.merge_file_changes_to_template(
-                &template_content,
-                &file_content,
-            )?;
-
-            // Write updated template
-            std::fs::write(&template_path, &updated_template)?;
-            info!("Updated template with local changes: {:?}", template_path);
-            Ok(true)
-        },
-
-        // File modified locally with rollback - restore from template
-        (true, true, SyncAction::Rollback) => {
-            // Apply template to revert changes
-            enrollment_manager.apply_single_template(&template_path, file_path)?;
-            println!("  Rolled back local changes from template: {}", file_path.display());
-            Ok(false)
-        },
-
-        // File modified with freeze - do nothing
-        (true, true, SyncAction::Freeze) => {
-            println!("  Frozen file, changes ignored: {}", file_path.display());
-            Ok(false)
-        },
-
-        // File modified with drift - track but don't sync
-        (true, true, SyncAction::Drift) => {
-            println!("  Drift allowed, changes tracked: {}", file_path.display());
-            // TODO: Record drift in audit log
-            Ok(false)
-        },
-
-        // File exists but no template - could be new file or deleted template
-        (true, false, _) => {
-            // For new files in watched directories, don't delete them
-            // They should remain as "? new/unknown" status
-            // Only delete if we can confirm the template was actually deleted
-            // For now, preserve the file and show it as new
-            println!("  ? New/unknown file: {}", file_path.display());
-            Ok(false)
-        },
-
-        // Both deleted - nothing to do
-        (false, false, _) => Ok(false),
-
-        // New file created locally
-        (true, false, SyncAction::Converge) => {
-            // This is handled separately for new files in watched directories
-            Ok(false)
-        },
-
-        _ => Ok(false),
+        }
     }
 }
 
@@ -2506,17 +2605,7 @@ fn store_group_config(
     Ok(())
 }
 
-async fn add_machine_to_group(config: &Config, group: &str) -> Result<()> {
-    let enrollment_manager = enrollment::EnrollmentManager::new(
-        config.mfs_mount.clone(),
-        "".to_string(),  // laszoo_dir is unused in the constructor
-    );
-    
-    enrollment_manager.add_machine_to_group(group)?;
-    Ok(())
-}
-
-async fn install_packages(config: &Config, group: &str, packages: Vec<String>, after: Option<&str>) -> Result<()> {
+async fn install_packages(config: &Config, group: &str, packages: Vec<String>, after: Option<&str>, dry_run: bool) -> Result<()> {
     use crate::package::PackageManager;
     
     info!("Installing packages for group '{}'", group);
@@ -2526,9 +2615,10 @@ async fn install_packages(config: &Config, group: &str, packages: Vec<String>, a
     
     // Create package manager
     let pkg_manager = PackageManager::new(config.mfs_mount.clone());
+    let mut plan = Plan::new();
     
     // Add packages to group's packages.conf
-    pkg_manager.add_packages_to_group(group, &packages, false)?;
+    let group_operations = pkg_manager.plan_add_packages_to_group(group, &packages, false, &mut plan)?;
     
     // Get current hostname
     let hostname = gethostname::gethostname()
@@ -2555,34 +2645,48 @@ async fn install_packages(config: &Config, group: &str, packages: Vec<String>, a
         info!("This machine is not in group '{}', adding it now", group);
         
         // Add this machine to the group
-        add_machine_to_group(config, group).await?;
+        let enrollment_manager = enrollment::EnrollmentManager::new(config.mfs_mount.clone(), "".to_string());
+        enrollment_manager.plan_add_machine_to_group(group, &mut plan)?;
         
-        info!("Added machine to group '{}', applying package changes locally", group);
+        let group = group.to_string();
+        plan.then(move || {
+            info!("Added machine to group '{}', applying package changes locally", group);
+            Ok(())
+        });
     } else {
         info!("This machine is in group '{}', applying package changes locally", group);
     }
     
-    // Load operations for this machine
-    let operations = pkg_manager.load_package_operations(group, Some(&hostname))?;
+    // Operations for this machine, once packages.conf is written
+    let operations = pkg_manager.with_machine_operations(group_operations, &hostname)?;
     
     // Apply operations
-    pkg_manager.apply_operations_with_group(&operations, Some(group)).await?;
+    pkg_manager.plan_operations(&operations, Some(group), &mut plan)?;
     
     // Run after command if provided
     if let Some(cmd) = after {
-        info!("Running after command: {}", cmd);
-        use tokio::process::Command;
-        
-        let output = Command::new("sh")
-            .arg("-c")
-            .arg(cmd)
-            .output()
-            .await?;
-        
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            warn!("After command failed: {}", stderr);
-        }
+        plan.action("after", None, cmd);
+        let cmd = cmd.to_string();
+        plan.then_async(move || async move {
+            info!("Running after command: {}", cmd);
+            use tokio::process::Command;
+            
+            let output = Command::new("sh")
+                .arg("-c")
+                .arg(&cmd)
+                .output()
+                .await?;
+            
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                warn!("After command failed: {}", stderr);
+            }
+            Ok(())
+        });
+    }
+    
+    if !run_plan(plan, dry_run).await? {
+        return Ok(());
     }
     
     println!("Successfully updated package configuration for group '{}'", group);
@@ -2593,106 +2697,65 @@ async fn install_packages(config: &Config, group: &str, packages: Vec<String>, a
     Ok(())
 }
 
-async fn apply_packages_for_group(config: &Config, group: &str) -> Result<()> {
+async fn patch_group(config: &Config, group: &str, before: Option<&str>, after: Option<&str>, rolling: bool, dry_run: bool) -> Result<()> {
     use crate::package::PackageManager;
     
-    let hostname = gethostname::gethostname()
-        .to_string_lossy()
-        .to_string();
-    
-    // Check if this machine is in the group
-    let groups_file = config.mfs_mount
-        .join("machines")
-        .join(&hostname)
-        .join("etc")
-        .join("laszoo")
-        .join("groups.conf");
-    
-    let in_group = if groups_file.exists() {
-        let content = std::fs::read_to_string(&groups_file)?;
-        content.lines()
-            .any(|line| line.trim() == group)
-    } else {
-        false
-    };
-    
-    if !in_group {
-        debug!("Machine is not in group '{}', skipping package application", group);
-        return Ok(());
-    }
-    
-    // Create package manager and load operations
-    let pkg_manager = PackageManager::new(config.mfs_mount.clone());
-    let operations = pkg_manager.load_package_operations(group, Some(&hostname))?;
-    
-    if !operations.is_empty() {
-        info!("Applying {} package operations for group '{}'", operations.len(), group);
-        pkg_manager.apply_operations_with_group(&operations, Some(group)).await?;
-    }
-    
-    Ok(())
-}
-
-async fn apply_machine_packages(config: &Config) -> Result<()> {
-    use crate::package::PackageManager;
+    info!("Adding patch commands to group '{}'", group);
     
-    let hostname = gethostname::gethostname()
-        .to_string_lossy()
-        .to_string();
+    // Ensure distributed filesystem is available
+    crate::fs::ensure_distributed_fs_available(&config.mfs_mount)?;
     
-    // Create package manager and load machine-specific operations
+    // Create package manager
     let pkg_manager = PackageManager::new(config.mfs_mount.clone());
     
-    // Load operations from all groups the machine belongs to, plus machine-specific
-    let groups_file = config.mfs_mount
-        .join("machines")
-        .join(&hostname)
-        .join("etc")
-        .join("laszoo")
-        .join("groups.conf");
+    // Read existing packages.conf
+    let packages_conf_path = pkg_manager.get_group_packages_path(group);
     
-    let machine_groups: Vec<String> = if groups_file.exists() {
-        std::fs::read_to_string(&groups_file)?
-            .lines()
-            .map(|s| s.trim().to_string())
-            .filter(|s| !s.is_empty())
-            .collect()
-    } else {
-        Vec::new()
-    };
+    let (content, added) = patched_packages_conf(&packages_conf_path, before, after, rolling)?;
     
-    // Collect all operations from groups and machine-specific
-    let mut all_operations = Vec::new();
+    // Append the patch commands if they don't exist
+    if added.is_empty() {
+        println!("Patch commands already exist in {}/etc/laszoo/packages.conf", group);
+        return Ok(());
+    }
     
-    for group in &machine_groups {
-        let operations = pkg_manager.load_package_operations(group, Some(&hostname))?;
-        all_operations.extend(operations);
+    let mut plan = Plan::new();
+    plan.write_file(&packages_conf_path, content.as_bytes());
+    plan.then(move || {
+        // Create directory if needed
+        if let Some(parent) = packages_conf_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // Write back to packages.conf
+        std::fs::write(&packages_conf_path, content)?;
+        Ok(())
+    });
+    if !run_plan(plan, dry_run).await? {
+        return Ok(());
     }
     
-    // Apply operations if any
-    if !all_operations.is_empty() {
-        info!("Applying {} package operations for machine", all_operations.len());
-        pkg_manager.apply_operations(&all_operations).await?;
+    println!("Added patch commands to {}/etc/laszoo/packages.conf", group);
+    for line in &added {
+        println!("  + {}", line);
     }
+    println!("\nMachines in group '{}' will apply patches when they run 'laszoo watch' or 'laszoo apply'", group);
     
     Ok(())
 }
 
-async fn patch_group(config: &Config, group: &str, before: Option<&str>, after: Option<&str>, _rolling: bool) -> Result<()> {
-    use crate::package::PackageManager;
-    
-    info!("Adding patch commands to group '{}'", group);
-    
-    // Ensure distributed filesystem is available
-    crate::fs::ensure_distributed_fs_available(&config.mfs_mount)?;
-    
-    // Create package manager
-    let pkg_manager = PackageManager::new(config.mfs_mount.clone());
-    
+/// A group's packages.conf with the ++update and ++upgrade lines of
+/// `laszoo patch` added where missing, and the lines that were added
+fn patched_packages_conf(packages_conf_path: &Path, before: Option<&str>, after: Option<&str>, rolling: bool) -> Result<(String, Vec<String>)> {
     // Build the ++update and ++upgrade lines
     let mut update_line = "++update".to_string();
     let mut upgrade_line = "++upgrade".to_string();
     
+    // Hosts take turns running rolling patches
+    if rolling {
+        update_line.push_str(" --rolling");
+        upgrade_line.push_str(" --rolling");
+    }
+    
     // Add before/after actions if provided
     if let Some(before_cmd) = before {
         update_line.push_str(&format!(" --before {}", before_cmd));
@@ -2704,16 +2767,8 @@ async fn patch_group(config: &Config, group: &str, before: Option<&str>, after:
         upgrade_line.push_str(&format!(" --after {}", after_cmd));
     }
     
-    // Read existing packages.conf
-    let packages_conf_path = pkg_manager.get_group_packages_path(group);
-    
-    // Create directory if needed
-    if let Some(parent) = packages_conf_path.parent() {
-        std::fs::create_dir_all(parent)?;
-    }
-    
     let mut content = if packages_conf_path.exists() {
-        std::fs::read_to_string(&packages_conf_path)?
+        std::fs::read_to_string(packages_conf_path)?
     } else {
         // Create default header
         "# Laszoo Package Configuration\n# Syntax:\n# ^package - Upgrade package\n# ^package --upgrade=command - Upgrade with post-action\n# ++update - Update package lists\n# ++update --before cmd --after cmd - Update with before/after actions\n# ++upgrade - Upgrade all packages\n# ++upgrade --before cmd --after cmd - Upgrade all with before/after actions\n# +package - Install package\n# =package - Keep package (don't auto-install/remove)\n# !package - Remove package\n# !!!package - Purge package\n\n".to_string()
@@ -2723,38 +2778,25 @@ async fn patch_group(config: &Config, group: &str, before: Option<&str>, after:
     let has_update = content.lines().any(|line| line.trim().starts_with("++update"));
     let has_upgrade = content.lines().any(|line| line.trim().starts_with("++upgrade"));
     
-    // Append the patch commands if they don't exist
-    if !has_update || !has_upgrade {
+    let mut added = Vec::new();
+    if !has_update {
+        added.push(update_line);
+    }
+    if !has_upgrade {
+        added.push(upgrade_line);
+    }
+    
+    if !added.is_empty() {
         if !content.ends_with('\n') && !content.is_empty() {
             content.push('\n');
         }
-        
-        if !has_update {
-            content.push_str(&update_line);
+        for line in &added {
+            content.push_str(line);
             content.push('\n');
         }
-        
-        if !has_upgrade {
-            content.push_str(&upgrade_line);
-            content.push('\n');
-        }
-        
-        // Write back to packages.conf
-        std::fs::write(&packages_conf_path, content)?;
-        
-        println!("Added patch commands to {}/etc/laszoo/packages.conf", group);
-        if !has_update {
-            println!("  + {}", update_line);
-        }
-        if !has_upgrade {
-            println!("  + {}", upgrade_line);
-        }
-        println!("\nMachines in group '{}' will apply patches when they run 'laszoo watch' or 'laszoo apply'", group);
-    } else {
-        println!("Patch commands already exist in {}/etc/laszoo/packages.conf", group);
     }
     
-    Ok(())
+    Ok((content, added))
 }
 
 async fn handle_service_command(command: crate::cli::ServiceCommands) -> Result<()> {
@@ -2777,3 +2819,121 @@ async fn handle_service_command(command: crate::cli::ServiceCommands) -> Result<
     
     Ok(())
 }
+
+fn handle_lock_command(config: &Config, command: crate::cli::LockCommands) -> Result<()> {
+    use crate::cli::LockCommands;
+    use crate::lock::LockManager;
+    use std::time::Duration;
+
+    // Ensure distributed filesystem is available
+    crate::fs::ensure_distributed_fs_available(&config.mfs_mount)?;
+
+    let locks = LockManager::new(config.mfs_mount.clone());
+    let local_time = |time: chrono::DateTime<chrono::Utc>| {
+        time.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S").to_string()
+    };
+
+    match command {
+        LockCommands::Acquire { name, ttl, wait, pid } => {
+            // Hold the lease for the script that called us, not this short-lived process
+            let pid = pid.unwrap_or_else(std::os::unix::process::parent_id);
+            let lease = locks.with_pid(pid)
+                .acquire(&name, Duration::from_secs(ttl), Duration::from_secs(wait))?;
+            println!("Acquired lock '{}' with token {} until {}", name, lease.token, local_time(lease.expires_at));
+        }
+        LockCommands::Release { name, force } => {
+            match locks.release_name(&name, force)? {
+                Some(lease) => println!("Released lock '{}' held by {} (pid {})", name, lease.owner, lease.pid),
+                None => println!("Lock '{}' is not held", name),
+            }
+        }
+        LockCommands::List => {
+            let leases = locks.list()?;
+            if leases.is_empty() {
+                println!("No locks");
+            }
+            for lease in leases {
+                let state = if lease.is_expired() {
+                    "free".to_string()
+                } else {
+                    format!("held by {} (pid {}) until {}", lease.owner, lease.pid, local_time(lease.expires_at))
+                };
+                println!("  {}  token {}  {}", lease.name, lease.token, state);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn handle_drift_command(config: &Config, command: crate::cli::DriftCommands) -> Result<()> {
+    use crate::cli::DriftCommands;
+    use crate::enrollment::EnrollmentManager;
+
+    // Ensure distributed filesystem is available
+    crate::fs::ensure_distributed_fs_available(&config.mfs_mount)?;
+
+    let hostname = gethostname::gethostname().to_string_lossy().to_string();
+    let manager = EnrollmentManager::new(config.mfs_mount.clone(), hostname.clone());
+    let ledger = manager.drift_ledger();
+    let local_time = |time: chrono::DateTime<chrono::Utc>| {
+        time.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S").to_string()
+    };
+
+    match command {
+        DriftCommands::List { host, all } => {
+            let hosts = if all { ledger.hosts()? } else { vec![host.unwrap_or(hostname)] };
+            let mut found = false;
+            for host in hosts {
+                for entry in ledger.list(&host)? {
+                    found = true;
+                    println!(
+                        "  {}  {} (group: {})  drifted since {}, {} change(s), last at {}",
+                        host,
+                        entry.path.display(),
+                        entry.group,
+                        local_time(entry.first_drift),
+                        entry.records.len(),
+                        entry.latest().map(|r| local_time(r.recorded_at)).unwrap_or_default()
+                    );
+                    if let Some(split) = &entry.split {
+                        println!("    split vote, majority: {}; minority: {}",
+                                 split.majority_hosts.join(", "), split.minority_hosts.join(", "));
+                    }
+                }
+            }
+            if !found {
+                println!("No drift recorded");
+            }
+        }
+        DriftCommands::Show { path, host } => {
+            let path = crate::diff::resolve_filter_path(&path)?;
+            let host = host.unwrap_or(hostname);
+            let entry = ledger.get(&host, &path)?
+                .ok_or_else(|| LaszooError::Other(format!("No drift recorded for {} on {}", path.display(), host)))?;
+
+            println!("{} on {} (group: {})", entry.path.display(), entry.host, entry.group);
+            println!("Drifted since {}", local_time(entry.first_drift));
+            for record in &entry.records {
+                println!("\n  {} sha256:{}", local_time(record.recorded_at), record.hash);
+            }
+            if let Some(record) = entry.latest() {
+                print!("\n{}", record.diff);
+            }
+        }
+        DriftCommands::Accept { path } => {
+            let path = crate::diff::resolve_filter_path(&path)?;
+            let entry = ledger.get(&hostname, &path)?
+                .ok_or_else(|| LaszooError::Other(format!("No drift recorded for {}", path.display())))?;
+
+            manager.promote_to_machine_template(&path)?;
+            ledger.clear(&path)?;
+            println!(
+                "Accepted drift of {} since {} as the machine-specific template for {}",
+                path.display(), local_time(entry.first_drift), hostname
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,249 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+use tracing::{debug, warn};
+use crate::error::Result;
+use crate::template::TemplateEngine;
+
+/// Normalized hash of a host's copy of an enrolled file, as published to
+/// `machines/<host>/hashes.json`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PublishedHash {
+    pub group: String,
+    pub hash: String,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Where a host publishes the hashes of its enrolled files
+pub fn hashes_path(mfs_mount: &Path, hostname: &str) -> PathBuf {
+    crate::fs::get_machine_dir(mfs_mount, "", hostname).join("hashes.json")
+}
+
+/// Load the hashes a host has published, keyed by file path
+pub fn load_hashes(mfs_mount: &Path, hostname: &str) -> Result<BTreeMap<PathBuf, PublishedHash>> {
+    let path = hashes_path(mfs_mount, hostname);
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let content = std::fs::read_to_string(&path)?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+/// Publish this host's hash for a file. The file is only rewritten when the
+/// hash changed.
+pub fn publish_hash(mfs_mount: &Path, hostname: &str, group: &str, file_path: &Path, hash: &str) -> Result<()> {
+    let mut hashes = load_hashes(mfs_mount, hostname)?;
+    if hashes.get(file_path).is_some_and(|h| h.group == group && h.hash == hash) {
+        return Ok(());
+    }
+
+    hashes.insert(file_path.to_path_buf(), PublishedHash {
+        group: group.to_string(),
+        hash: hash.to_string(),
+        updated_at: chrono::Utc::now(),
+    });
+
+    let content = serde_json::to_string_pretty(&hashes)?;
+    crate::fs::atomic_write(&hashes_path(mfs_mount, hostname), content.as_bytes(), |_| Ok(()))?;
+    debug!("Published hash {} for {:?} in group {}", hash, file_path, group);
+    Ok(())
+}
+
+/// Hashes published for a file by the given hosts, skipping hosts that have
+/// not published one for this group or whose hashes can't be read
+pub fn collect_votes(mfs_mount: &Path, group: &str, file_path: &Path, hosts: &[String]) -> Result<Vec<(String, String)>> {
+    let mut votes = Vec::new();
+    for host in hosts {
+        let mut hashes = match load_hashes(mfs_mount, host) {
+            Ok(hashes) => hashes,
+            Err(e) => {
+                warn!("Ignoring hashes published by {}: {}", host, e);
+                continue;
+            }
+        };
+        if let Some(published) = hashes.remove(file_path) {
+            if published.group == group {
+                votes.push((host.clone(), published.hash));
+            }
+        }
+    }
+    Ok(votes)
+}
+
+/// What to do with this host's copy of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// This host agrees with the majority and the template
+    InSync,
+    /// A majority of the other hosts agree on a different version
+    Rollback,
+    /// This host agrees with the majority, which has moved away from the template
+    Converge,
+    /// The other hosts don't agree on a version, or the majority holds one
+    /// that is neither this host's nor the template's; nothing is changed
+    Split,
+    /// No other host has published a hash for this file
+    NoPeers,
+}
+
+/// Outcome of a majority vote over a file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Resolution {
+    pub verdict: Verdict,
+    /// Version held by a strict majority of the other hosts, if any
+    pub majority_hash: Option<String>,
+    pub majority_hosts: Vec<String>,
+    /// Hosts, this one included, whose version differs from the majority
+    pub minority_hosts: Vec<String>,
+}
+
+/// Decides between rolling back, converging or flagging a split by comparing
+/// this host's normalized hash with those published by the other hosts.
+///
+/// A version wins when a strict majority of the other hosts hold it, so a
+/// single edited host is rolled back while an edit that most hosts share is
+/// converged into the template.
+pub struct MajorityResolver {
+    engine: TemplateEngine,
+}
+
+impl MajorityResolver {
+    pub fn new() -> Result<Self> {
+        Ok(Self { engine: TemplateEngine::new()? })
+    }
+
+    /// Hash of content with host-specific values removed. `template_form` is
+    /// the content expressed as a template, with `{{ }}` and quack tags.
+    pub fn normalized_hash(&self, template_form: &str) -> String {
+        crate::fs::content_checksum(self.engine.normalize_template(template_form).as_bytes())
+    }
+
+    pub fn resolve(
+        &self,
+        local_host: &str,
+        local_hash: &str,
+        template_hash: &str,
+        votes: &[(String, String)],
+    ) -> Result<Resolution> {
+        let peers: Vec<(&str, &str)> = votes.iter()
+            .filter(|(host, _)| host != local_host)
+            .map(|(host, hash)| (host.as_str(), hash.as_str()))
+            .collect();
+
+        if peers.is_empty() {
+            return Ok(Resolution {
+                verdict: Verdict::NoPeers,
+                majority_hash: None,
+                majority_hosts: Vec::new(),
+                minority_hosts: Vec::new(),
+            });
+        }
+
+        let mut hosts_by_hash: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+        for (host, hash) in &peers {
+            hosts_by_hash.entry(hash).or_default().push(host.to_string());
+        }
+        let (majority_hash, mut majority_hosts) = hosts_by_hash.into_iter()
+            .max_by_key(|(_, hosts)| hosts.len())
+            .expect("peers is not empty");
+        majority_hosts.sort();
+
+        if majority_hosts.len() * 2 <= peers.len() {
+            let mut minority_hosts: Vec<String> = peers.iter().map(|(host, _)| host.to_string()).collect();
+            minority_hosts.push(local_host.to_string());
+            minority_hosts.sort();
+            return Ok(Resolution {
+                verdict: Verdict::Split,
+                majority_hash: None,
+                majority_hosts: Vec::new(),
+                minority_hosts,
+            });
+        }
+
+        let majority_hash = majority_hash.to_string();
+        let mut minority_hosts: Vec<String> = peers.iter()
+            .filter(|(_, hash)| *hash != majority_hash)
+            .map(|(host, _)| host.to_string())
+            .collect();
+
+        let verdict = if local_hash != majority_hash {
+            minority_hosts.push(local_host.to_string());
+            // Rolling back brings the template's version, which is only
+            // right when that is what the majority holds
+            if majority_hash == template_hash { Verdict::Rollback } else { Verdict::Split }
+        } else {
+            majority_hosts.push(local_host.to_string());
+            majority_hosts.sort();
+            if majority_hash == template_hash { Verdict::InSync } else { Verdict::Converge }
+        };
+        minority_hosts.sort();
+
+        Ok(Resolution {
+            verdict,
+            majority_hash: Some(majority_hash),
+            majority_hosts,
+            minority_hosts,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn votes(entries: &[(&str, &str)]) -> Vec<(String, String)> {
+        entries.iter().map(|(h, v)| (h.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_resolve_verdicts() {
+        let resolver = MajorityResolver::new().unwrap();
+
+        // Most of the other hosts still hold the template's version
+        let resolution = resolver.resolve("web01", "b", "a", &votes(&[("web02", "a"), ("web03", "a"), ("web04", "b")])).unwrap();
+        assert_eq!(resolution.verdict, Verdict::Rollback);
+        assert_eq!(resolution.majority_hosts, vec!["web02", "web03"]);
+        assert_eq!(resolution.minority_hosts, vec!["web01", "web04"]);
+
+        // Most hosts carry the change the template doesn't have yet
+        let resolution = resolver.resolve("web01", "b", "a", &votes(&[("web02", "b"), ("web03", "b"), ("web04", "a")])).unwrap();
+        assert_eq!(resolution.verdict, Verdict::Converge);
+        assert_eq!(resolution.minority_hosts, vec!["web04"]);
+
+        let resolution = resolver.resolve("web01", "c", "a", &votes(&[("web02", "a"), ("web03", "b")])).unwrap();
+        assert_eq!(resolution.verdict, Verdict::Split);
+        assert_eq!(resolution.minority_hosts, vec!["web01", "web02", "web03"]);
+
+        // The majority moved on to a version the template doesn't have
+        let resolution = resolver.resolve("web01", "c", "a", &votes(&[("web02", "b"), ("web03", "b"), ("web04", "a")])).unwrap();
+        assert_eq!(resolution.verdict, Verdict::Split);
+        assert_eq!(resolution.majority_hash.as_deref(), Some("b"));
+        assert_eq!(resolution.minority_hosts, vec!["web01", "web04"]);
+
+        let resolution = resolver.resolve("web01", "a", "a", &votes(&[("web01", "a")])).unwrap();
+        assert_eq!(resolution.verdict, Verdict::NoPeers);
+    }
+
+    #[test]
+    fn test_collect_votes_skips_unreadable_hashes() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = Path::new("/etc/app.conf");
+        publish_hash(dir.path(), "web02", "web", file, "a").unwrap();
+        publish_hash(dir.path(), "web03", "db", file, "b").unwrap();
+        std::fs::create_dir_all(hashes_path(dir.path(), "web04").parent().unwrap()).unwrap();
+        std::fs::write(hashes_path(dir.path(), "web04"), "{ not json").unwrap();
+
+        let hosts = vec!["web02".to_string(), "web03".to_string(), "web04".to_string(), "web05".to_string()];
+        let votes = collect_votes(dir.path(), "web", file, &hosts).unwrap();
+        assert_eq!(votes, vec![("web02".to_string(), "a".to_string())]);
+    }
+
+    #[test]
+    fn test_normalized_hash_ignores_host_values() {
+        let resolver = MajorityResolver::new().unwrap();
+        assert_eq!(
+            resolver.normalized_hash("name = {{ hostname }}\n[[x id = 1 x]]\n"),
+            resolver.normalized_hash("name = {{ hostname }}\n[[x id = 2 x]]\n")
+        );
+    }
+}
@@ -0,0 +1,395 @@
+use std::collections::BTreeMap;
+use std::ffi::{CStr, CString};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use serde::{Serialize, Deserialize};
+use tracing::{debug, warn};
+use crate::error::{LaszooError, Result};
+
+/// Extended attributes carried along with a file; others are host specific
+const PRESERVED_XATTRS: &[&str] = &["user.", "security.selinux", "security.capability"];
+
+/// Ownership, permissions, ACLs and extended attributes of an enrolled file.
+///
+/// Recorded at enrollment in a `.attrs` file next to the template and restored
+/// when the template is applied. Owner and group are stored by name and by id;
+/// names are resolved on the applying host so differing UIDs are handled, and
+/// the id is only used when the name does not exist there.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileMetadata {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// Extended POSIX ACL entries in `getfacl` form, e.g. `user:deploy:r--`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub acl: Vec<String>,
+    /// Selected extended attributes, hex encoded
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: BTreeMap<String, String>,
+}
+
+impl FileMetadata {
+    /// Read the metadata of a file
+    pub fn capture(path: &Path) -> Result<Self> {
+        let metadata = std::fs::metadata(path)?;
+        let uid = metadata.uid();
+        let gid = metadata.gid();
+
+        Ok(Self {
+            mode: metadata.mode() & 0o7777,
+            uid,
+            gid,
+            owner: user_name(uid),
+            group: group_name(gid),
+            acl: read_acl(path),
+            xattrs: read_xattrs(path),
+        })
+    }
+
+    /// Where the metadata for a template is stored
+    pub fn sidecar_path(template_path: &Path) -> PathBuf {
+        let mut path = template_path.to_path_buf();
+        let name = format!("{}.attrs", path.file_name().unwrap_or_default().to_string_lossy());
+        path.set_file_name(name);
+        path
+    }
+
+    /// Load the metadata recorded for a template, if any
+    pub fn load_for_template(template_path: &Path) -> Result<Option<Self>> {
+        let path = Self::sidecar_path(template_path);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = std::fs::read_to_string(&path)?;
+        Ok(Some(serde_json::from_str(&content)?))
+    }
+
+    /// Record this metadata next to a template
+    pub fn save_for_template(&self, template_path: &Path) -> Result<()> {
+        let content = serde_json::to_string_pretty(self)?;
+        std::fs::write(Self::sidecar_path(template_path), content)?;
+        Ok(())
+    }
+
+    /// What restoring this metadata onto a file with `current` metadata
+    /// would change, or everything recorded when there is no file yet
+    pub fn changes_from(&self, current: Option<&Self>) -> Vec<String> {
+        let owner = |m: &Self| m.owner.clone().unwrap_or_else(|| m.uid.to_string());
+        let group = |m: &Self| m.group.clone().unwrap_or_else(|| m.gid.to_string());
+        let mut changes = Vec::new();
+
+        match current {
+            Some(current) => {
+                if current.mode != self.mode {
+                    changes.push(format!("mode {:04o} -> {:04o}", current.mode, self.mode));
+                }
+                if owner(current) != owner(self) || group(current) != group(self) {
+                    changes.push(format!("owner {}:{} -> {}:{}", owner(current), group(current), owner(self), group(self)));
+                }
+                if current.acl != self.acl {
+                    changes.push(format!("acl [{}] -> [{}]", current.acl.join(", "), self.acl.join(", ")));
+                }
+                if current.xattrs != self.xattrs {
+                    changes.push(format!("xattrs [{}]", self.xattrs.keys().cloned().collect::<Vec<_>>().join(", ")));
+                }
+            }
+            None => {
+                changes.push(format!("mode {:04o}", self.mode));
+                changes.push(format!("owner {}:{}", owner(self), group(self)));
+                if !self.acl.is_empty() {
+                    changes.push(format!("acl [{}]", self.acl.join(", ")));
+                }
+                if !self.xattrs.is_empty() {
+                    changes.push(format!("xattrs [{}]", self.xattrs.keys().cloned().collect::<Vec<_>>().join(", ")));
+                }
+            }
+        }
+
+        changes
+    }
+
+    /// Restore this metadata onto a file. ACL entries and preserved extended
+    /// attributes that weren't recorded are removed.
+    ///
+    /// Ownership is changed first since chown clears setuid bits and file
+    /// capabilities. Changes that need privileges we don't have are logged and
+    /// skipped rather than failing the apply.
+    pub fn apply(&self, path: &Path) -> Result<()> {
+        let current = std::fs::metadata(path)?;
+
+        let uid = self.owner.as_deref().and_then(user_id).unwrap_or(self.uid);
+        let gid = self.group.as_deref().and_then(group_id).unwrap_or(self.gid);
+        if current.uid() != uid || current.gid() != gid {
+            match std::os::unix::fs::chown(path, Some(uid), Some(gid)) {
+                Ok(()) => debug!("Changed ownership of {:?} to {}:{}", path, uid, gid),
+                Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                    debug!("Cannot change ownership of {:?} to {}:{} - requires elevated privileges", path, uid, gid);
+                }
+                Err(e) => warn!("Failed to change ownership of {:?}: {}", path, e),
+            }
+        }
+
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(self.mode))?;
+
+        if !self.acl.is_empty() {
+            if let Err(e) = write_acl(path, &self.acl) {
+                warn!("Failed to restore ACL on {:?}: {}", path, e);
+            }
+        } else if !read_acl(path).is_empty() {
+            if let Err(e) = remove_acl(path) {
+                warn!("Failed to remove ACL from {:?}: {}", path, e);
+            }
+        }
+
+        for name in read_xattrs(path).into_keys().filter(|name| !self.xattrs.contains_key(name)) {
+            if let Err(e) = remove_xattr(path, &name) {
+                warn!("Failed to remove extended attribute {} from {:?}: {}", name, path, e);
+            }
+        }
+        for (name, value) in &self.xattrs {
+            if let Err(e) = set_xattr(path, name, &decode_hex(value)) {
+                warn!("Failed to restore extended attribute {} on {:?}: {}", name, path, e);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn c_path(path: &Path) -> Option<CString> {
+    CString::new(path.as_os_str().as_bytes()).ok()
+}
+
+/// Look up an entry in the user or group database using the reentrant libc calls
+fn lookup<T, R>(
+    lookup: impl Fn(*mut T, *mut libc::c_char, libc::size_t, *mut *mut T) -> libc::c_int,
+    extract: impl Fn(&T) -> R,
+) -> Option<R> {
+    let mut buffer = vec![0 as libc::c_char; 16384];
+    let mut entry: T = unsafe { std::mem::zeroed() };
+    let mut result: *mut T = std::ptr::null_mut();
+
+    let status = lookup(&mut entry, buffer.as_mut_ptr(), buffer.len(), &mut result);
+    if status != 0 || result.is_null() {
+        return None;
+    }
+    Some(extract(&entry))
+}
+
+fn user_name(uid: u32) -> Option<String> {
+    lookup(
+        |pwd, buf, len, result| unsafe { libc::getpwuid_r(uid, pwd, buf, len, result) },
+        |pwd: &libc::passwd| unsafe { CStr::from_ptr(pwd.pw_name) }.to_string_lossy().to_string(),
+    )
+}
+
+fn user_id(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    lookup(
+        |pwd, buf, len, result| unsafe { libc::getpwnam_r(name.as_ptr(), pwd, buf, len, result) },
+        |pwd: &libc::passwd| pwd.pw_uid,
+    )
+}
+
+fn group_name(gid: u32) -> Option<String> {
+    lookup(
+        |grp, buf, len, result| unsafe { libc::getgrgid_r(gid, grp, buf, len, result) },
+        |grp: &libc::group| unsafe { CStr::from_ptr(grp.gr_name) }.to_string_lossy().to_string(),
+    )
+}
+
+fn group_id(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    lookup(
+        |grp, buf, len, result| unsafe { libc::getgrnam_r(name.as_ptr(), grp, buf, len, result) },
+        |grp: &libc::group| grp.gr_gid,
+    )
+}
+
+/// Extended ACL entries of a file. The base entries are already covered by the mode.
+fn read_acl(path: &Path) -> Vec<String> {
+    let output = match Command::new("getfacl").args(["--omit-header", "--absolute-names"]).arg(path).output() {
+        Ok(output) if output.status.success() => output,
+        Ok(_) | Err(_) => {
+            debug!("Could not read ACL of {:?}, getfacl unavailable or failed", path);
+            return Vec::new();
+        }
+    };
+
+    parse_acl(&String::from_utf8_lossy(&output.stdout))
+}
+
+fn parse_acl(getfacl_output: &str) -> Vec<String> {
+    let entries: Vec<String> = getfacl_output.lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect();
+
+    let is_extended = |entry: &String| {
+        !(entry.starts_with("user::") || entry.starts_with("group::") || entry.starts_with("other::"))
+    };
+    if entries.iter().any(is_extended) {
+        entries.into_iter().filter(is_extended).collect()
+    } else {
+        Vec::new()
+    }
+}
+
+fn write_acl(path: &Path, entries: &[String]) -> Result<()> {
+    setfacl(Command::new("setfacl").arg("-b").arg("-m").arg(entries.join(",")).arg(path))
+}
+
+/// Remove all extended ACL entries of a file
+fn remove_acl(path: &Path) -> Result<()> {
+    setfacl(Command::new("setfacl").arg("-b").arg(path))
+}
+
+fn setfacl(command: &mut Command) -> Result<()> {
+    let output = command.output()?;
+
+    if !output.status.success() {
+        return Err(LaszooError::Other(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+    }
+    Ok(())
+}
+
+fn read_xattrs(path: &Path) -> BTreeMap<String, String> {
+    let mut xattrs = BTreeMap::new();
+    let Some(c_path) = c_path(path) else { return xattrs };
+
+    let size = unsafe { libc::listxattr(c_path.as_ptr(), std::ptr::null_mut(), 0) };
+    if size <= 0 {
+        return xattrs;
+    }
+    let mut names = vec![0u8; size as usize];
+    let size = unsafe { libc::listxattr(c_path.as_ptr(), names.as_mut_ptr() as *mut libc::c_char, names.len()) };
+    if size <= 0 {
+        return xattrs;
+    }
+    names.truncate(size as usize);
+
+    for name in names.split(|b| *b == 0).filter(|n| !n.is_empty()) {
+        let name_str = String::from_utf8_lossy(name).to_string();
+        if !PRESERVED_XATTRS.iter().any(|p| name_str.starts_with(p)) {
+            continue;
+        }
+        let Ok(c_name) = CString::new(name) else { continue };
+
+        let size = unsafe { libc::getxattr(c_path.as_ptr(), c_name.as_ptr(), std::ptr::null_mut(), 0) };
+        if size < 0 {
+            continue;
+        }
+        let mut value = vec![0u8; size as usize];
+        let size = unsafe {
+            libc::getxattr(c_path.as_ptr(), c_name.as_ptr(), value.as_mut_ptr() as *mut libc::c_void, value.len())
+        };
+        if size < 0 {
+            continue;
+        }
+        value.truncate(size as usize);
+        xattrs.insert(name_str, encode_hex(&value));
+    }
+
+    xattrs
+}
+
+fn set_xattr(path: &Path, name: &str, value: &[u8]) -> Result<()> {
+    let c_path = c_path(path).ok_or_else(|| LaszooError::InvalidPath { path: path.to_path_buf() })?;
+    let c_name = CString::new(name).map_err(|e| LaszooError::Other(e.to_string()))?;
+
+    let status = unsafe {
+        libc::setxattr(c_path.as_ptr(), c_name.as_ptr(), value.as_ptr() as *const libc::c_void, value.len(), 0)
+    };
+    if status != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+fn remove_xattr(path: &Path, name: &str) -> Result<()> {
+    let c_path = c_path(path).ok_or_else(|| LaszooError::InvalidPath { path: path.to_path_buf() })?;
+    let c_name = CString::new(name).map_err(|e| LaszooError::Other(e.to_string()))?;
+
+    if unsafe { libc::removexattr(c_path.as_ptr(), c_name.as_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(hex: &str) -> Vec<u8> {
+    (0..hex.len() / 2)
+        .filter_map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_acl_keeps_extended_entries() {
+        let output = "user::rw-\nuser:deploy:r--\ngroup::r--\t#effective:r--\nmask::r--\nother::---\n\n";
+        assert_eq!(parse_acl(output), vec!["user:deploy:r--", "mask::r--"]);
+        assert!(parse_acl("user::rw-\ngroup::r--\nother::r--\n").is_empty());
+    }
+
+    #[test]
+    fn test_capture_and_apply_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let target = dir.path().join("target");
+        std::fs::write(&source, "a").unwrap();
+        std::fs::write(&target, "b").unwrap();
+        std::fs::set_permissions(&source, std::fs::Permissions::from_mode(0o640)).unwrap();
+        let xattr_supported = set_xattr(&source, "user.laszoo.test", b"value").is_ok();
+
+        let metadata = FileMetadata::capture(&source).unwrap();
+        let template = dir.path().join("source.lasz");
+        metadata.save_for_template(&template).unwrap();
+        assert!(FileMetadata::sidecar_path(&template).ends_with("source.lasz.attrs"));
+
+        let loaded = FileMetadata::load_for_template(&template).unwrap().unwrap();
+        assert_eq!(loaded, metadata);
+        loaded.apply(&target).unwrap();
+
+        let applied = FileMetadata::capture(&target).unwrap();
+        assert_eq!(applied.mode, 0o640);
+        assert_eq!(applied.owner, metadata.owner);
+        if xattr_supported {
+            assert_eq!(applied.xattrs.get("user.laszoo.test").map(String::as_str), Some("76616c7565"));
+        }
+    }
+
+    #[test]
+    fn test_apply_removes_unrecorded_acl_and_xattrs() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let target = dir.path().join("target");
+        std::fs::write(&source, "a").unwrap();
+        std::fs::write(&target, "b").unwrap();
+        let xattr_supported = set_xattr(&target, "user.laszoo.stale", b"value").is_ok();
+        let acl_supported = write_acl(&target, &["user:0:r--".to_string()]).is_ok();
+
+        let metadata = FileMetadata::capture(&source).unwrap();
+        assert!(metadata.acl.is_empty() && metadata.xattrs.is_empty());
+        metadata.apply(&target).unwrap();
+
+        let applied = FileMetadata::capture(&target).unwrap();
+        if xattr_supported {
+            assert!(applied.xattrs.is_empty(), "{:?}", applied.xattrs);
+        }
+        if acl_supported {
+            assert!(applied.acl.is_empty(), "{:?}", applied.acl);
+        }
+    }
+}
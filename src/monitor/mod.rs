@@ -1,3 +1,7 @@
+pub mod reconciler;
+
+pub use reconciler::{Decision, HoldReason, LocalChange, Outcome, ReconcileEvent, ReconcileOptions, Reconciler, Report};
+
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
@@ -0,0 +1,1212 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::{debug, error, info, warn};
+use crate::action::ActionBatch;
+use crate::cli::SyncAction;
+use crate::config::Config;
+use crate::enrollment::EnrollmentManager;
+use crate::error::{LaszooError, Result};
+use crate::package::PackageManager;
+use crate::plan::Plan;
+
+/// How often `run` wakes up when no event arrives
+const TICK: Duration = Duration::from_millis(100);
+
+/// Input to the reconciler
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReconcileEvent {
+    /// A local path was modified, created or removed
+    LocalChanged(PathBuf),
+    /// A template or group setting changed under the shared groups directory
+    TemplateChanged(PathBuf),
+    /// A commit of these templates finished, empty when it failed
+    Committed(HashSet<PathBuf>),
+    /// Nothing happened; lets debounced changes and periodic scans come due
+    Tick,
+}
+
+impl ReconcileEvent {
+    /// Sort a filesystem notification into reconcile events
+    pub fn from_notify(event: &notify::Event, groups_dir: &Path) -> Vec<Self> {
+        use notify::EventKind;
+
+        if !matches!(event.kind, EventKind::Modify(_) | EventKind::Create(_) | EventKind::Remove(_)) {
+            return Vec::new();
+        }
+
+        event.paths.iter().filter_map(|path| {
+            if !path.starts_with(groups_dir) {
+                Some(ReconcileEvent::LocalChanged(path.clone()))
+            } else if matches!(path.extension().and_then(|e| e.to_str()), Some("lasz") | Some("json")) {
+                Some(ReconcileEvent::TemplateChanged(path.clone()))
+            } else {
+                None
+            }
+        }).collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LocalChangeKind {
+    Modified,
+    /// No template exists for the file yet
+    New,
+    Deleted,
+}
+
+impl LocalChangeKind {
+    /// Marker used when listing changes
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            LocalChangeKind::Modified => "●",
+            LocalChangeKind::New => "?",
+            LocalChangeKind::Deleted => "✗",
+        }
+    }
+}
+
+/// A change to an enrolled file on this machine
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalChange {
+    pub path: PathBuf,
+    pub group: String,
+    pub kind: LocalChangeKind,
+}
+
+/// A template that appeared or changed on the shared filesystem
+#[derive(Debug, Clone, PartialEq)]
+pub struct TemplateChange {
+    pub template_path: PathBuf,
+    pub path: PathBuf,
+    pub group: String,
+    pub new: bool,
+}
+
+/// A packages.conf that appeared or changed, for a group or, with no group,
+/// for this machine
+#[derive(Debug, Clone, PartialEq)]
+pub struct PackagesChange {
+    pub group: Option<String>,
+    pub new: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HoldReason {
+    /// The change came from an edit on this machine
+    LocalOrigin,
+    /// Auto-apply is off
+    Manual,
+}
+
+/// What the reconciler decided to do about the changes it has seen
+#[derive(Debug, Clone, PartialEq)]
+pub enum Decision {
+    /// Handle debounced local changes, each according to its sync action
+    SyncLocal(Vec<LocalChange>),
+    /// Render a changed template onto its file
+    ApplyTemplate(TemplateChange),
+    /// Leave a changed template alone
+    HoldTemplate(TemplateChange, HoldReason),
+    ApplyPackages(PackagesChange),
+    HoldPackages(PackagesChange, HoldReason),
+    /// Drop the templates of converge files removed while not watching (`--hard`)
+    PruneMissing(Vec<LocalChange>),
+    /// Commit templates changed by local edits. Committing is up to the
+    /// driver, which reports back with `ReconcileEvent::Committed`.
+    Commit(HashSet<PathBuf>),
+    /// A full reconcile pass ran; the corrections follow as their own decisions
+    FullReconcile(PassSummary),
+}
+
+/// Differences found by a full reconcile pass, fed in as events
+#[derive(Debug, Clone, PartialEq)]
+pub struct PassSummary {
+    /// The pass made when the reconciler started
+    pub startup: bool,
+    /// Enrolled files compared with their templates
+    pub checked: usize,
+    /// Files changed locally since they were last synced
+    pub local: Vec<PathBuf>,
+    /// Templates changed since this machine last synced with them
+    pub templates: Vec<PathBuf>,
+}
+
+impl PassSummary {
+    pub fn corrections(&self) -> usize {
+        self.local.len() + self.templates.len()
+    }
+}
+
+/// Result of carrying out a decision
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    /// A local change was merged into its template
+    TemplateUpdated { path: PathBuf, template_path: PathBuf },
+    /// Handled without changing the template
+    Unchanged { path: PathBuf },
+    TemplateApplied { path: PathBuf },
+    TemplateDeleted { path: PathBuf, template_path: PathBuf },
+    PackagesApplied { group: Option<String> },
+    /// The local changes were not approved
+    Declined,
+    Failed { path: Option<PathBuf>, error: String },
+}
+
+/// A decision with the outcomes of carrying it out
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    pub decision: Decision,
+    pub outcomes: Vec<Outcome>,
+}
+
+/// Asked before local changes are synced; returns whether to go ahead
+pub type Approval = Box<dyn FnMut(&[LocalChange]) -> bool + Send>;
+
+#[derive(Debug, Clone)]
+pub struct ReconcileOptions {
+    /// Apply remote template and package changes, and sync local changes
+    /// without asking
+    pub auto: bool,
+    /// Delete templates of converge files deleted locally
+    pub hard: bool,
+    /// Decide `Commit` for templates changed by local edits
+    pub auto_commit: bool,
+    /// Quiet time before a burst of events is acted on
+    pub debounce: Duration,
+    /// How often templates and packages.conf files are re-checksummed, as
+    /// change notifications don't arrive from every distributed filesystem
+    pub scan_interval: Duration,
+    /// How long events for a file are ignored after a template was applied to it
+    pub ignore_after_apply: Duration,
+    /// How often every enrolled file and template is re-hashed to catch
+    /// missed events, starting when the reconciler is created. None disables
+    /// the full pass.
+    pub full_reconcile_interval: Option<Duration>,
+}
+
+impl ReconcileOptions {
+    pub fn new(auto: bool, hard: bool) -> Self {
+        Self {
+            auto,
+            hard,
+            auto_commit: false,
+            debounce: Duration::from_millis(500),
+            scan_interval: Duration::from_secs(2),
+            ignore_after_apply: Duration::from_secs(5),
+            full_reconcile_interval: None,
+        }
+    }
+}
+
+/// Enrolled paths of the watched groups
+#[derive(Debug, Clone, Default)]
+pub struct WatchTargets {
+    pub groups: Vec<String>,
+    pub files: HashSet<PathBuf>,
+    pub directories: HashSet<PathBuf>,
+    pub group_of: HashMap<PathBuf, String>,
+}
+
+impl WatchTargets {
+    /// Collect the enrolled paths of `groups` from the group and machine manifests
+    pub fn load(manager: &EnrollmentManager, groups: &[String]) -> Result<Self> {
+        let mut targets = WatchTargets { groups: groups.to_vec(), ..Default::default() };
+        let machine_manifest = manager.load_manifest()?;
+
+        for group in groups {
+            let group_manifest = manager.load_group_manifest(group).ok();
+            let entries = group_manifest.iter().flat_map(|m| m.entries.iter())
+                .chain(machine_manifest.entries.iter().filter(|(_, e)| &e.group == group));
+
+            for (path, entry) in entries {
+                targets.group_of.insert(path.clone(), group.clone());
+                if entry.checksum == "directory" {
+                    targets.directories.insert(path.clone());
+                } else {
+                    targets.files.insert(path.clone());
+                }
+            }
+        }
+
+        Ok(targets)
+    }
+
+    /// Group of an enrolled file, or of the enrolled directory it is in
+    pub fn group_for(&self, path: &Path) -> Option<&str> {
+        if self.files.contains(path) {
+            return self.group_of.get(path).map(String::as_str);
+        }
+        self.directories.iter()
+            .find(|dir| path.starts_with(dir))
+            .and_then(|dir| self.group_of.get(dir))
+            .map(String::as_str)
+    }
+}
+
+/// The engine behind `laszoo watch`.
+///
+/// Typed events go in through `observe`; `decide` turns what has been seen
+/// into decisions once they are due, and `act_all` carries them out. `handle`
+/// does all three for one event and `run` drives it from a channel. Local
+/// changes are synced according to their sync action, templates changed by
+/// other hosts are applied, and packages.conf changes are installed.
+pub struct Reconciler {
+    manager: EnrollmentManager,
+    packages: PackageManager,
+    mfs_mount: PathBuf,
+    hostname: String,
+    options: ReconcileOptions,
+    targets: WatchTargets,
+    approval: Option<Approval>,
+
+    pending_local: HashSet<PathBuf>,
+    last_local_event: Instant,
+    pending_templates: HashSet<PathBuf>,
+    last_template_event: Instant,
+    /// Local changes since the last scan, to tell our own template updates
+    /// from remote ones
+    recent_local: HashSet<PathBuf>,
+    /// Templates updated from local changes and not committed yet
+    local_templates: HashSet<PathBuf>,
+    committing: HashSet<PathBuf>,
+    /// Files a template was just applied to, their events are our own
+    applying: HashMap<PathBuf, Instant>,
+    template_checksums: HashMap<PathBuf, Option<String>>,
+    packages_checksums: HashMap<PathBuf, String>,
+    last_scan: Instant,
+    missing: Vec<LocalChange>,
+    /// None until the startup pass has run
+    last_full_pass: Option<Instant>,
+    /// Act on pending changes without waiting for the debounce
+    flush: bool,
+}
+
+impl Reconciler {
+    /// Reconciler for `groups`, which this machine must be in
+    pub fn new(config: &Config, groups: Vec<String>, options: ReconcileOptions) -> Result<Self> {
+        let hostname = gethostname::gethostname().to_string_lossy().to_string();
+        let manager = EnrollmentManager::new(config.mfs_mount.clone(), hostname.clone())
+            .with_backups(crate::backup::BackupStore::from_config(config))
+            .with_action_timeout(config.actions.timeout());
+        let targets = WatchTargets::load(&manager, &groups)?;
+
+        let mut reconciler = Self {
+            manager,
+            packages: PackageManager::new(config.mfs_mount.clone()),
+            mfs_mount: config.mfs_mount.clone(),
+            hostname,
+            options,
+            targets,
+            approval: None,
+            pending_local: HashSet::new(),
+            last_local_event: Instant::now(),
+            pending_templates: HashSet::new(),
+            last_template_event: Instant::now(),
+            recent_local: HashSet::new(),
+            local_templates: HashSet::new(),
+            committing: HashSet::new(),
+            applying: HashMap::new(),
+            template_checksums: HashMap::new(),
+            packages_checksums: HashMap::new(),
+            last_scan: Instant::now(),
+            missing: Vec::new(),
+            last_full_pass: None,
+            flush: false,
+        };
+
+        // Start from what is on the shared filesystem now
+        for template in reconciler.templates() {
+            let checksum = crate::fs::calculate_file_checksum(&template).ok();
+            reconciler.template_checksums.insert(template, checksum);
+        }
+        for (path, _) in reconciler.packages_confs() {
+            if let Ok(checksum) = crate::fs::calculate_file_checksum(&path) {
+                reconciler.packages_checksums.insert(path, checksum);
+            }
+        }
+        if reconciler.options.hard {
+            reconciler.missing = reconciler.missing_files()?;
+        }
+
+        Ok(reconciler)
+    }
+
+    /// Ask `approval` before syncing local changes, instead of going by
+    /// `ReconcileOptions::auto`
+    pub fn with_approval(mut self, approval: Approval) -> Self {
+        self.approval = Some(approval);
+        self
+    }
+
+    pub fn targets(&self) -> &WatchTargets {
+        &self.targets
+    }
+
+    pub fn manager(&self) -> &EnrollmentManager {
+        &self.manager
+    }
+
+    /// Take in an event. Nothing is acted on until `decide`.
+    pub fn observe(&mut self, event: ReconcileEvent) {
+        match event {
+            ReconcileEvent::LocalChanged(path) => {
+                if self.targets.group_for(&path).is_none() {
+                    return;
+                }
+                if self.applying.contains_key(&path) {
+                    debug!("Ignoring file change event for {:?} (template application in progress)", path);
+                    return;
+                }
+                debug!("Tracking file change for {:?}", path);
+                self.recent_local.insert(path.clone());
+                self.pending_local.insert(path);
+                self.last_local_event = Instant::now();
+            }
+            ReconcileEvent::TemplateChanged(path) => {
+                self.pending_templates.insert(path);
+                self.last_template_event = Instant::now();
+            }
+            ReconcileEvent::Committed(templates) => {
+                if templates.is_empty() {
+                    // Retried with the next template change
+                    debug!("Commit failed, will retry on next cycle");
+                    self.committing.clear();
+                }
+                for template in &templates {
+                    self.local_templates.remove(template);
+                    self.committing.remove(template);
+                }
+            }
+            ReconcileEvent::Tick => {}
+        }
+    }
+
+    /// Decisions that have come due
+    pub fn decide(&mut self) -> Result<Vec<Decision>> {
+        let mut decisions = Vec::new();
+
+        if !self.missing.is_empty() {
+            decisions.push(Decision::PruneMissing(std::mem::take(&mut self.missing)));
+        }
+
+        if let Some(interval) = self.options.full_reconcile_interval {
+            if self.last_full_pass.is_none_or(|last| last.elapsed() >= interval) {
+                let mut corrections = Vec::new();
+                let summary = self.full_pass(&mut corrections)?;
+                decisions.push(Decision::FullReconcile(summary));
+                decisions.extend(corrections);
+                self.last_full_pass = Some(Instant::now());
+            }
+        }
+        let flush = std::mem::take(&mut self.flush);
+
+        if !self.pending_local.is_empty() && (flush || self.last_local_event.elapsed() >= self.options.debounce) {
+            let mut changes = Vec::new();
+            for path in std::mem::take(&mut self.pending_local) {
+                if self.applying.contains_key(&path) {
+                    debug!("Skipping file change for {:?} (currently applying template)", path);
+                    continue;
+                }
+                let Some(group) = self.targets.group_for(&path).map(str::to_string) else { continue };
+                let kind = if path.symlink_metadata().is_err() {
+                    LocalChangeKind::Deleted
+                } else if self.manager.get_group_template_path(&group, &path)?.exists()
+                    || self.manager.get_machine_template_path(&path)?.exists() {
+                    LocalChangeKind::Modified
+                } else {
+                    LocalChangeKind::New
+                };
+                changes.push(LocalChange { path, group, kind });
+            }
+            if !changes.is_empty() {
+                changes.sort_by(|a, b| a.path.cmp(&b.path));
+                decisions.push(Decision::SyncLocal(changes));
+            }
+        }
+
+        if !self.pending_templates.is_empty() && self.last_template_event.elapsed() >= self.options.debounce {
+            debug!("Template changes detected: {} files", self.pending_templates.len());
+            self.pending_templates.clear();
+
+            // Only templates updated from local changes are committed here
+            if self.options.auto_commit {
+                let to_commit: HashSet<PathBuf> = self.local_templates.difference(&self.committing).cloned().collect();
+                if !to_commit.is_empty() {
+                    self.committing.extend(to_commit.iter().cloned());
+                    decisions.push(Decision::Commit(to_commit));
+                }
+            }
+        }
+
+        if flush || self.last_scan.elapsed() >= self.options.scan_interval {
+            self.scan(&mut decisions);
+            self.last_scan = Instant::now();
+        }
+
+        Ok(decisions)
+    }
+
+    /// Carry out the decisions that came due together, syncing local changes
+    /// only if the approval hook, or auto mode, allows it. The file actions
+    /// of the templates they apply are coalesced: each distinct before action
+    /// runs once ahead of the first write, each after action once after the
+    /// last. A template whose after action failed is reported as failed.
+    pub async fn act_all(&mut self, decisions: Vec<Decision>) -> Vec<Report> {
+        let mut planned = Vec::new();
+        for decision in decisions {
+            match &decision {
+                Decision::SyncLocal(changes) if !self.approve(changes) => {
+                    planned.push((decision, Some(vec![Outcome::Declined])));
+                }
+                Decision::ApplyTemplate(change) => {
+                    // Our own write would show up as a local change
+                    self.applying.insert(change.path.clone(), Instant::now());
+                    planned.push((decision, None));
+                }
+                _ => planned.push((decision, None)),
+            }
+        }
+
+        let mut plan = Plan::new();
+        let approved: Vec<Decision> = planned.iter()
+            .filter(|(_, declined)| declined.is_none())
+            .map(|(decision, _)| decision.clone())
+            .collect();
+        let mut outcomes = self.plan_all(&approved, &mut plan).into_iter();
+        // Outcomes are recorded as the plan goes, it doesn't fail as a whole
+        let _ = plan.run().await;
+
+        let reports: Vec<Report> = planned.into_iter()
+            .map(|(decision, declined)| {
+                let outcomes = declined.unwrap_or_else(|| {
+                    outcomes.next().map(|o| std::mem::take(&mut *o.lock().unwrap())).unwrap_or_default()
+                });
+                Report { decision, outcomes }
+            })
+            .collect();
+
+        for outcome in reports.iter().flat_map(|r| r.outcomes.iter()) {
+            match outcome {
+                Outcome::TemplateUpdated { path, template_path } => {
+                    // The next scan will see our own template change
+                    self.recent_local.insert(path.clone());
+                    self.local_templates.insert(template_path.clone());
+                }
+                Outcome::TemplateDeleted { template_path, .. } => {
+                    // Committed along with other template changes from this host
+                    self.local_templates.insert(template_path.clone());
+                    self.pending_templates.insert(template_path.clone());
+                    self.last_template_event = Instant::now();
+                }
+                _ => {}
+            }
+        }
+        reports
+    }
+
+    /// Plan carrying out decisions that came due together, local changes
+    /// without asking; approval is up to the caller. Returns the outcomes of
+    /// each decision, filled in as the plan runs.
+    pub fn plan_all(&self, decisions: &[Decision], plan: &mut Plan) -> Vec<Outcomes> {
+        let batch = self.manager.begin_action_batch(&applied_templates(decisions), plan);
+        let outcomes: Vec<Outcomes> = decisions.iter()
+            .map(|decision| self.plan_decision(decision, &batch, plan))
+            .collect();
+
+        let reported = outcomes.clone();
+        self.manager.finish_action_batch(batch, plan, move |failures| {
+            let mut reported: Vec<_> = reported.iter().map(|o| o.lock().unwrap()).collect();
+            for (path, e) in failures {
+                error!("After action for {} failed, its previous content was restored: {}", path.display(), e);
+                let applied = reported.iter_mut()
+                    .flat_map(|outcomes| outcomes.iter_mut())
+                    .find(|o| matches!(o, Outcome::TemplateApplied { path: p } if *p == path));
+                if let Some(outcome) = applied {
+                    *outcome = failed(Some(&path), e);
+                }
+            }
+            Ok(())
+        });
+        outcomes
+    }
+
+    /// Plan a decision, applying templates as part of `batch`
+    fn plan_decision(&self, decision: &Decision, batch: &ActionBatch, plan: &mut Plan) -> Outcomes {
+        let outcomes = Outcomes::default();
+        match decision {
+            Decision::SyncLocal(changes) => {
+                for change in changes {
+                    let mut item = Plan::new();
+                    let planned = self.plan_sync(&change.path, &change.group, &mut item).and_then(|changed| {
+                        Ok(if changed {
+                            let template_path = self.manager.get_group_template_path(&change.group, &change.path)?;
+                            Outcome::TemplateUpdated { path: change.path.clone(), template_path }
+                        } else {
+                            Outcome::Unchanged { path: change.path.clone() }
+                        })
+                    });
+                    let context = format!("Failed to handle change for {}", change.path.display());
+                    plan_outcome(plan, &outcomes, Some(&change.path), item, planned, context);
+                }
+            }
+            Decision::ApplyTemplate(change) => {
+                let mut item = Plan::new();
+                let planned = self.manager.plan_single_template_in(batch, &change.template_path, &change.path, &mut item)
+                    .map(|()| Outcome::TemplateApplied { path: change.path.clone() });
+                let context = format!("Failed to apply template {:?}", change.template_path);
+                plan_outcome(plan, &outcomes, Some(&change.path), item, planned, context);
+            }
+            Decision::ApplyPackages(change) => {
+                let mut item = Plan::new();
+                let planned = match &change.group {
+                    Some(group) => self.packages.plan_group(group, &self.hostname, &mut item),
+                    None => self.packages.plan_machine(&self.targets.groups, &self.hostname, &mut item),
+                };
+                let planned = planned.map(|()| Outcome::PackagesApplied { group: change.group.clone() });
+                plan_outcome(plan, &outcomes, None, item, planned, "Failed to apply package changes".to_string());
+            }
+            Decision::PruneMissing(files) => {
+                for file in files {
+                    let mut item = Plan::new();
+                    let planned = self.plan_prune(file, &mut item);
+                    let context = format!("Failed to prune {}", file.path.display());
+                    plan_outcome(plan, &outcomes, Some(&file.path), item, planned, context);
+                }
+            }
+            Decision::HoldTemplate(..) | Decision::HoldPackages(..) | Decision::Commit(_)
+            | Decision::FullReconcile(_) => {}
+        }
+        outcomes
+    }
+
+    /// Observe an event, then act on whatever came due
+    pub async fn handle(&mut self, event: ReconcileEvent) -> Result<Vec<Report>> {
+        self.observe(event);
+
+        let decisions = self.decide()?;
+        Ok(self.act_all(decisions).await)
+    }
+
+    /// Handle events from `events` until the channel closes, sending a report
+    /// for every decision. Ticks are generated while no event arrives.
+    pub async fn run(mut self, mut events: mpsc::UnboundedReceiver<ReconcileEvent>, reports: mpsc::UnboundedSender<Report>) -> Result<()> {
+        loop {
+            let event = match tokio::time::timeout(TICK, events.recv()).await {
+                Ok(Some(event)) => event,
+                Ok(None) => return Ok(()),
+                Err(_) => ReconcileEvent::Tick,
+            };
+
+            for report in self.handle(event).await? {
+                if reports.send(report).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Sync one local change according to the file's sync action. Returns
+    /// whether the template changed.
+    pub async fn sync_file(&self, file_path: &Path, group: &str) -> Result<bool> {
+        let mut plan = Plan::new();
+        let changed = self.plan_sync(file_path, group, &mut plan)?;
+        plan.run().await?;
+        Ok(changed)
+    }
+
+    /// Plan `sync_file`. Returns whether the template changes.
+    fn plan_sync(&self, file_path: &Path, group: &str, plan: &mut Plan) -> Result<bool> {
+        let sync_action = self.manager.effective_sync_action(group, file_path)?;
+        debug!("Sync action for {}: {}", file_path.display(), sync_action);
+        plan_file_change(&self.manager, file_path, group, &sync_action, self.options.hard, plan)
+    }
+
+    /// Replay changes queued while the shared filesystem was unavailable,
+    /// oldest first. Templates changed by other hosts in the meantime are
+    /// caught by the version check when merging, and the local copy is kept
+    /// as a conflict.
+    pub async fn replay_pending(&self, queue: &crate::queue::PendingQueue) -> Result<()> {
+        let pending = queue.list()?;
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        println!("Replaying {} changes queued while {} was unavailable", pending.len(), self.mfs_mount.display());
+        let mut replayed = HashSet::new();
+        for change in &pending {
+            // The file's current state covers every later change to it
+            if replayed.insert(change.path.clone()) {
+                match self.sync_file(&change.path, &change.group).await {
+                    Ok(true) => println!("✓ Updated template for {} (queued #{})", change.path.display(), change.seq),
+                    Ok(false) => {}
+                    // Keep the rest of the queue for the next time the filesystem is back
+                    Err(e) if crate::fs::is_filesystem_error(&e) => return Err(e),
+                    Err(e) => {
+                        error!("Failed to replay change to {}: {}", change.path.display(), e);
+                        println!("✗ Failed to replay change to {}: {}", change.path.display(), e);
+                    }
+                }
+            }
+            queue.remove(change)?;
+        }
+
+        Ok(())
+    }
+
+    fn approve(&mut self, changes: &[LocalChange]) -> bool {
+        match self.approval.as_mut() {
+            Some(approval) => approval(changes),
+            None => self.options.auto,
+        }
+    }
+
+    /// Templates of the watched groups
+    fn templates(&self) -> Vec<PathBuf> {
+        let mut templates = Vec::new();
+        for group in &self.targets.groups {
+            let group_dir = crate::fs::get_group_dir(&self.mfs_mount, "", group);
+            for entry in walkdir::WalkDir::new(&group_dir).into_iter().filter_map(|e| e.ok()) {
+                if entry.file_type().is_file() && entry.path().extension() == Some(std::ffi::OsStr::new("lasz")) {
+                    templates.push(entry.path().to_path_buf());
+                }
+            }
+        }
+        templates
+    }
+
+    /// packages.conf files that apply to this machine, with their group
+    fn packages_confs(&self) -> Vec<(PathBuf, Option<String>)> {
+        let mut confs: Vec<(PathBuf, Option<String>)> = self.targets.groups.iter()
+            .map(|group| (self.packages.get_group_packages_path(group), Some(group.clone())))
+            .collect();
+        confs.push((self.packages.get_machine_packages_path(&self.hostname), None));
+        confs
+    }
+
+    /// The file a group template renders to
+    fn template_target(&self, group: &str, template: &Path) -> Option<PathBuf> {
+        let group_dir = crate::fs::get_group_dir(&self.mfs_mount, "", group);
+        let relative = template.strip_prefix(&group_dir).ok()?.to_string_lossy().into_owned();
+        relative.strip_suffix(".lasz").map(|path| PathBuf::from("/").join(path))
+    }
+
+    /// Re-checksum templates and packages.conf files, for filesystems that
+    /// don't deliver change notifications
+    fn scan(&mut self, decisions: &mut Vec<Decision>) {
+        debug!("Performing periodic template scan...");
+
+        for group in self.targets.groups.clone() {
+            let group_dir = crate::fs::get_group_dir(&self.mfs_mount, "", &group);
+            for template_path in self.templates().into_iter().filter(|t| t.starts_with(&group_dir)) {
+                let checksum = crate::fs::calculate_file_checksum(&template_path).ok();
+                let new = !self.template_checksums.contains_key(&template_path);
+                let modified = match self.template_checksums.get(&template_path) {
+                    Some(known) => checksum.is_some() && &checksum != known,
+                    None => false,
+                };
+                if !new && !modified {
+                    continue;
+                }
+                if modified {
+                    debug!("Template checksum changed for {:?}", template_path);
+                }
+                self.template_checksums.insert(template_path.clone(), checksum);
+
+                let Some(path) = self.template_target(&group, &template_path) else { continue };
+                let change = TemplateChange { template_path, path, group: group.clone(), new };
+                decisions.push(self.template_decision(change));
+            }
+        }
+
+        for (path, group) in self.packages_confs() {
+            let Ok(checksum) = crate::fs::calculate_file_checksum(&path) else { continue };
+            let new = match self.packages_checksums.get(&path) {
+                Some(known) if known == &checksum => continue,
+                Some(_) => false,
+                None => true,
+            };
+            self.packages_checksums.insert(path, checksum);
+
+            let change = PackagesChange { group, new };
+            decisions.push(if self.options.auto {
+                Decision::ApplyPackages(change)
+            } else {
+                Decision::HoldPackages(change, HoldReason::Manual)
+            });
+        }
+
+        // Changes older than a scan no longer explain a template change
+        self.recent_local.clear();
+
+        let ignore_for = self.options.ignore_after_apply;
+        self.applying.retain(|path, since| {
+            let keep = since.elapsed() <= ignore_for;
+            if !keep {
+                debug!("Expired ignore for file: {:?}", path);
+            }
+            keep
+        });
+    }
+
+    /// What to do about a template changed by another host
+    fn template_decision(&mut self, change: TemplateChange) -> Decision {
+        self.pending_templates.insert(change.template_path.clone());
+        self.last_template_event = Instant::now();
+
+        if self.recent_local.contains(&change.path) {
+            Decision::HoldTemplate(change, HoldReason::LocalOrigin)
+        } else if self.options.auto {
+            Decision::ApplyTemplate(change)
+        } else {
+            Decision::HoldTemplate(change, HoldReason::Manual)
+        }
+    }
+
+    /// Re-hash every enrolled file and template of the watched groups. Files
+    /// that differ from their rendered template are fed in as local changes
+    /// when they changed since the checksum in the manifest, and templates
+    /// that moved on since this machine last synced are added to `decisions`.
+    fn full_pass(&mut self, decisions: &mut Vec<Decision>) -> Result<PassSummary> {
+        debug!("Performing full reconcile pass...");
+        let mut summary = PassSummary {
+            startup: self.last_full_pass.is_none(),
+            checked: 0,
+            local: Vec::new(),
+            templates: Vec::new(),
+        };
+        let machine_manifest = self.manager.load_manifest()?;
+        let versions = self.manager.versions();
+
+        for group in self.targets.groups.clone() {
+            for (template_path, path) in self.manager.list_group_templates(&group)? {
+                if self.targets.group_for(&path) != Some(group.as_str()) {
+                    continue;
+                }
+                summary.checked += 1;
+
+                let rendered = match self.manager.render_template_bytes(&template_path, &path) {
+                    Ok(rendered) => rendered,
+                    Err(e) => {
+                        debug!("Skipping {:?} in full reconcile pass: {}", path, e);
+                        continue;
+                    }
+                };
+                let Some(local) = skip_unreadable(&path, self.manager.local_content(&template_path, &path))? else {
+                    continue;
+                };
+                let local_hash = local.as_deref().map(crate::fs::content_checksum);
+                if local_hash.as_deref() == Some(crate::fs::content_checksum(&rendered).as_str()) {
+                    continue;
+                }
+
+                let Some(template) = skip_unreadable(&path, std::fs::read(&template_path).map_err(Into::into))? else {
+                    continue;
+                };
+                let template_hash = crate::fs::content_checksum(&template);
+                let template_changed = versions.base(&template_path)?.is_some_and(|base| base != template_hash);
+                // Files in enrolled directories have no checksum of their own
+                // and are taken to have changed when the template didn't
+                let local_changed = match machine_manifest.is_enrolled(&path) {
+                    Some(entry) => local_hash.as_deref() != Some(entry.checksum.as_str()),
+                    None => !template_changed,
+                };
+
+                if template_changed && !local_changed {
+                    // Not from a local change, whatever the last scan saw
+                    self.template_checksums.insert(template_path.clone(), Some(template_hash));
+                    self.recent_local.remove(&path);
+                    summary.templates.push(template_path.clone());
+                    let change = TemplateChange { template_path, path, group: group.clone(), new: false };
+                    decisions.push(self.template_decision(change));
+                } else if self.needs_local_correction(&group, &path, &template_hash, local_hash.as_deref())? {
+                    self.observe(ReconcileEvent::LocalChanged(path.clone()));
+                    summary.local.push(path);
+                }
+            }
+        }
+
+        if summary.corrections() > 0 {
+            self.flush = true;
+        }
+        info!("Full reconcile pass checked {} files, {} corrections", summary.checked, summary.corrections());
+        Ok(summary)
+    }
+
+    /// Whether handling a file that differs from its template would do
+    /// anything, so lasting differences aren't handled again every pass
+    fn needs_local_correction(&self, group: &str, path: &Path, template_hash: &str, local_hash: Option<&str>) -> Result<bool> {
+        Ok(match (self.manager.effective_sync_action(group, path)?, local_hash) {
+            (SyncAction::Freeze, _) => false,
+            (SyncAction::Rollback, Some(hash)) => !self.manager.drift_ledger().split_unchanged(path, template_hash, hash)?,
+            (SyncAction::Drift, Some(hash)) => self.manager.drift_ledger().get(&self.hostname, path)?
+                .and_then(|entry| entry.latest().map(|r| r.hash != hash))
+                .unwrap_or(true),
+            (SyncAction::Drift, None) => false,
+            (SyncAction::Converge, None) => self.options.hard,
+            _ => true,
+        })
+    }
+
+    /// Enrolled files that are gone while their template is still there
+    fn missing_files(&self) -> Result<Vec<LocalChange>> {
+        let mut missing = Vec::new();
+
+        for dir in &self.targets.directories {
+            let Some(group) = self.targets.group_of.get(dir) else { continue };
+            let group_dir = crate::fs::get_group_dir(&self.mfs_mount, "", group);
+            for template in self.templates().into_iter().filter(|t| t.starts_with(&group_dir)) {
+                if let Some(path) = self.template_target(group, &template) {
+                    debug!("Checking template: {:?} -> original path: {:?}", template, path);
+                    if path.starts_with(dir) && !path.exists() {
+                        missing.push(LocalChange { path, group: group.clone(), kind: LocalChangeKind::Deleted });
+                    }
+                }
+            }
+        }
+
+        for file in &self.targets.files {
+            let Some(group) = self.targets.group_of.get(file) else { continue };
+            if !file.exists() && self.manager.get_group_template_path(group, file)?.exists() {
+                missing.push(LocalChange { path: file.clone(), group: group.clone(), kind: LocalChangeKind::Deleted });
+            }
+        }
+
+        missing.sort_by(|a, b| a.path.cmp(&b.path));
+        missing.dedup();
+        Ok(missing)
+    }
+
+    /// Plan deleting the template of a missing converge file
+    fn plan_prune(&self, file: &LocalChange, plan: &mut Plan) -> Result<Outcome> {
+        if self.manager.effective_sync_action(&file.group, &file.path)? != SyncAction::Converge {
+            return Ok(Outcome::Unchanged { path: file.path.clone() });
+        }
+
+        let template_path = self.manager.get_group_template_path(&file.group, &file.path)?;
+        if template_path.exists() {
+            plan.remove(&template_path);
+        }
+        let removed = template_path.clone();
+        plan.then(move || match std::fs::remove_file(&removed) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        });
+        Ok(Outcome::TemplateDeleted { path: file.path.clone(), template_path })
+    }
+}
+
+/// Outcomes of a decision, filled in as its plan runs
+pub type Outcomes = Arc<Mutex<Vec<Outcome>>>;
+
+/// Add `item`, the plan for one part of a decision, as a part of `plan` that
+/// records `planned` in `outcomes` once it ran, or the error it failed with,
+/// logged after `context`
+fn plan_outcome(plan: &mut Plan, outcomes: &Outcomes, path: Option<&Path>, item: Plan, planned: Result<Outcome>, context: String) {
+    let (outcomes, path) = (outcomes.clone(), path.map(Path::to_path_buf));
+    let planned = planned.map_err(|e| {
+        error!("{}: {}", context, e);
+        failed(path.as_deref(), e)
+    });
+    plan.nest(item, move |result| {
+        let outcome = match (planned, result) {
+            (Ok(outcome), Ok(())) => outcome,
+            (Ok(_), Err(e)) => {
+                error!("{}: {}", context, e);
+                failed(path.as_deref(), e)
+            }
+            (Err(outcome), _) => outcome,
+        };
+        outcomes.lock().unwrap().push(outcome);
+        Ok(())
+    });
+}
+
+/// Templates the decisions apply, with their target files
+fn applied_templates(decisions: &[Decision]) -> Vec<(PathBuf, PathBuf)> {
+    decisions.iter()
+        .filter_map(|decision| match decision {
+            Decision::ApplyTemplate(change) => Some((change.template_path.clone(), change.path.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// A read in the full pass, `None` when it failed for this file only. Errors
+/// meaning the filesystem went away are passed on.
+fn skip_unreadable<T>(path: &Path, read: Result<T>) -> Result<Option<T>> {
+    match read {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if crate::fs::is_filesystem_error(&e) => Err(e),
+        Err(e) => {
+            debug!("Skipping {:?} in full reconcile pass: {}", path, e);
+            Ok(None)
+        }
+    }
+}
+
+fn failed(path: Option<&Path>, error: LaszooError) -> Outcome {
+    Outcome::Failed { path: path.map(Path::to_path_buf), error: error.to_string() }
+}
+
+/// Plan handling a file change according to the sync action. Returns whether
+/// the template changes.
+pub fn plan_file_change(
+    enrollment_manager: &EnrollmentManager,
+    file_path: &Path,
+    group: &str,
+    sync_action: &SyncAction,
+    hard: bool,
+    plan: &mut Plan,
+) -> Result<bool> {
+    let template_path = enrollment_manager.get_group_template_path(group, file_path)?;
+    let template_exists = template_path.exists();
+    let file_exists = file_path.symlink_metadata().is_ok();
+    // Drift is measured against what apply would write, a machine template
+    // standing in for a missing group template
+    let renderable = template_exists || enrollment_manager.get_machine_template_path(file_path)?.exists();
+
+    match (file_exists, template_exists, sync_action) {
+        // File deleted locally
+        (false, true, SyncAction::Converge) => {
+            if hard {
+                // Delete template if --hard is specified
+                plan.remove(&template_path);
+                let file_path = file_path.to_path_buf();
+                plan.then(move || {
+                    std::fs::remove_file(&template_path)?;
+                    info!("Deleted template for removed file: {:?}", file_path);
+                    Ok(())
+                });
+                Ok(true)
+            } else {
+                // Just show as missing without --hard
+                plan.say(format!("  File deleted locally: {} (template preserved)", file_path.display()));
+                Ok(false)
+            }
+        },
+
+        // File deleted locally with rollback - restore from template
+        (false, true, SyncAction::Rollback) => {
+            // Apply template to restore file
+            enrollment_manager.plan_single_template(&template_path, file_path, plan)?;
+            plan.say(format!("  Restored deleted file from template: {}", file_path.display()));
+            Ok(false)
+        },
+
+        // File modified locally with converge - update template
+        (true, true, SyncAction::Converge) => {
+            plan_merge(enrollment_manager, &template_path, file_path, plan)?;
+            Ok(true)
+        },
+
+        // File modified locally with rollback - let the other hosts in the group vote
+        (true, true, SyncAction::Rollback) => {
+            use crate::majority::Verdict;
+
+            let resolution = enrollment_manager.preview_majority(group, &template_path, file_path)?;
+            if resolution.is_some() {
+                enrollment_manager.plan_publish_vote(group, &template_path, file_path, plan);
+            }
+            let verdict = resolution.as_ref().map_or(Verdict::NoPeers, |r| r.verdict);
+            if verdict != Verdict::Split {
+                enrollment_manager.drift_ledger().plan_clear(file_path, plan)?;
+            }
+            match verdict {
+                Verdict::InSync => {
+                    plan.say(format!("  Local copy matches the majority of the group: {}", file_path.display()));
+                    Ok(false)
+                }
+                Verdict::Converge => {
+                    plan.say(format!("  Majority of the group shares the local changes, updating template: {}", file_path.display()));
+                    plan_merge(enrollment_manager, &template_path, file_path, plan)?;
+                    Ok(true)
+                }
+                Verdict::Split => {
+                    plan.say(match resolution.as_ref().filter(|r| r.majority_hash.is_some()) {
+                        Some(r) => format!("  Majority of group '{}' holds a version the template doesn't have, leaving {} unchanged (majority: {}; minority: {})",
+                                           group, file_path.display(), r.majority_hosts.join(", "), r.minority_hosts.join(", ")),
+                        None => {
+                            let hosts = resolution.as_ref().map(|r| r.minority_hosts.join(", ")).unwrap_or_default();
+                            format!("  No majority version in group '{}', leaving {} unchanged (hosts split: {})",
+                                    group, file_path.display(), hosts)
+                        }
+                    });
+                    warn!("Split vote for {:?} in group {}", file_path, group);
+                    if let (Some(r), Some(local)) = (resolution, enrollment_manager.local_content(&template_path, file_path)?) {
+                        let rendered = enrollment_manager.render_template_bytes(&template_path, file_path)?;
+                        let verdict = crate::drift::SplitVerdict {
+                            template_hash: crate::fs::calculate_file_checksum(&template_path)?,
+                            majority_hosts: r.majority_hosts,
+                            minority_hosts: r.minority_hosts,
+                        };
+                        enrollment_manager.drift_ledger().plan_record_split(file_path, group, rendered, local, verdict, plan);
+                    }
+                    Ok(false)
+                }
+                // Outvoted, or no other host to vote with: the template wins
+                Verdict::Rollback | Verdict::NoPeers => {
+                    enrollment_manager.plan_single_template(&template_path, file_path, plan)?;
+                    plan.say(match resolution.filter(|r| r.verdict == Verdict::Rollback) {
+                        Some(r) => format!("  Rolled back local changes to {}, majority: {}; minority: {}",
+                                           file_path.display(), r.majority_hosts.join(", "), r.minority_hosts.join(", ")),
+                        None => format!("  Rolled back local changes from template: {}", file_path.display()),
+                    });
+
+                    // Publish the rolled back version
+                    enrollment_manager.plan_publish_vote(group, &template_path, file_path, plan);
+                    Ok(false)
+                }
+            }
+        },
+
+        // File modified with freeze - do nothing
+        (true, true, SyncAction::Freeze) => {
+            plan.say(format!("  Frozen file, changes ignored: {}", file_path.display()));
+            Ok(false)
+        },
+
+        // File modified with drift - record it in the drift ledger but don't sync
+        (true, _, SyncAction::Drift) if renderable => {
+            let ledger = enrollment_manager.drift_ledger();
+            ledger.plan_check(enrollment_manager, group, &template_path, file_path, plan)?;
+            let file_path = file_path.to_path_buf();
+            plan.then(move || {
+                match ledger.current(&file_path)? {
+                    Some(entry) => println!("  Drift allowed, recorded in drift ledger (drifted since {}): {}",
+                                            entry.first_drift.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S"),
+                                            file_path.display()),
+                    None => println!("  Drifted file matches its template again: {}", file_path.display()),
+                }
+                Ok(())
+            });
+            Ok(false)
+        },
+
+        // File exists but no template - could be new file or deleted template
+        (true, false, _) => {
+            // For new files in watched directories, don't delete them
+            // They should remain as "? new/unknown" status
+            // Only delete if we can confirm the template was actually deleted
+            // For now, preserve the file and show it as new
+            plan.say(format!("  ? New/unknown file: {}", file_path.display()));
+            Ok(false)
+        },
+
+        // Both deleted - nothing to do
+        (false, false, _) => Ok(false),
+
+        _ => Ok(false),
+    }
+}
+
+/// Plan merging a locally modified file back into its group template.
+///
+/// The merge only goes ahead while the template is still the version this
+/// host's file was synced from. If another host converged its own edit in the
+/// meantime, the local version is saved next to the template for
+/// `laszoo resolve` and a `SyncConflict` is returned when the plan runs.
+fn plan_merge(enrollment_manager: &EnrollmentManager, template_path: &Path, file_path: &Path, plan: &mut Plan) -> Result<()> {
+    let template_bytes = std::fs::read(template_path)?;
+    let base = enrollment_manager.versions().check_base(template_path, &template_bytes);
+    let updated_template = merged_template(enrollment_manager, template_path, file_path, &template_bytes, base.is_err())?;
+
+    let base = match base {
+        Ok(base) => {
+            plan.write(template_path, Some(&template_bytes), &updated_template);
+            Ok(base)
+        }
+        Err(LaszooError::SyncConflict(reason)) => {
+            plan.metadata(template_path, format!(
+                "save local version of {} as a conflict: {}", file_path.display(), reason
+            ));
+            Err(LaszooError::SyncConflict(reason))
+        }
+        Err(e) => return Err(e),
+    };
+
+    let versions = enrollment_manager.versions();
+    let (template_path, file_path) = (template_path.to_path_buf(), file_path.to_path_buf());
+    plan.then(move || {
+        // Write updated template
+        match base.and_then(|base| versions.write(&template_path, &base, &updated_template)) {
+            Ok(version) => {
+                info!("Updated template with local changes: {:?} (generation {})", template_path, version.generation);
+                Ok(())
+            }
+            Err(LaszooError::SyncConflict(reason)) => {
+                let saved = versions.record_conflict(&template_path, &updated_template)?;
+                Err(LaszooError::SyncConflict(format!(
+                    "{}; local version saved to {}, run `laszoo resolve {}`",
+                    reason, saved.display(), file_path.display()
+                )))
+            }
+            Err(e) => Err(e),
+        }
+    });
+    Ok(())
+}
+
+/// The template a locally modified file merges into. A `conflicted` edit,
+/// made against an older template, that can't be merged is kept as it is.
+fn merged_template(
+    enrollment_manager: &EnrollmentManager,
+    template_path: &Path,
+    file_path: &Path,
+    template_bytes: &[u8],
+    conflicted: bool,
+) -> Result<Vec<u8>> {
+    use crate::template::TemplateEngine;
+
+    // Read current file content, or the link target of an enrolled symlink
+    let file_bytes = enrollment_manager.local_content(template_path, file_path)?
+        .ok_or_else(|| LaszooError::FileNotFound { path: file_path.to_path_buf() })?;
+
+    // Binary files have nothing to merge, the local copy becomes the template
+    if enrollment_manager.is_binary_template(template_path, file_path, template_bytes)?
+        || crate::fs::is_binary_content(&file_bytes) {
+        return Ok(file_bytes);
+    }
+
+    let file_content = String::from_utf8_lossy(&file_bytes).into_owned();
+
+    // Load template to preserve variables, and what it rendered to before the edit
+    let template_content = String::from_utf8_lossy(template_bytes).into_owned();
+    let previous_render = enrollment_manager.render_template(template_path, file_path)?;
+
+    // Use template engine to merge changes while preserving variables
+    let template_engine = TemplateEngine::new()?;
+    match template_engine.merge_file_changes_to_template(&template_content, &previous_render, &file_content) {
+        Ok(updated) => Ok(updated.into_bytes()),
+        // Edited against an older template, keep the local copy as it is for the conflict
+        Err(LaszooError::SyncConflict(_)) if conflicted => Ok(file_bytes),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notify_events_are_sorted_by_location() {
+        use notify::event::{AccessKind, EventKind, ModifyKind};
+
+        let groups_dir = Path::new("/mnt/laszoo/groups");
+        let event = notify::Event::new(EventKind::Modify(ModifyKind::Any))
+            .add_path(PathBuf::from("/etc/app.conf"))
+            .add_path(groups_dir.join("web/etc/app.conf.lasz"))
+            .add_path(groups_dir.join("web/etc/app.conf.lasz.attrs"));
+        assert_eq!(ReconcileEvent::from_notify(&event, groups_dir), vec![
+            ReconcileEvent::LocalChanged(PathBuf::from("/etc/app.conf")),
+            ReconcileEvent::TemplateChanged(groups_dir.join("web/etc/app.conf.lasz")),
+        ]);
+
+        let access = notify::Event::new(EventKind::Access(AccessKind::Any))
+            .add_path(PathBuf::from("/etc/app.conf"));
+        assert!(ReconcileEvent::from_notify(&access, groups_dir).is_empty());
+    }
+}
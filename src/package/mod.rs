@@ -4,17 +4,25 @@ use tracing::{info, warn, error, debug};
 use serde::{Serialize, Deserialize};
 use chrono::{DateTime, Utc};
 
+use crate::action::ActionOutput;
 use crate::error::{Result, LaszooError};
+use crate::lock::{LeaseGuard, LockManager};
+use crate::plan::Plan;
+
+/// How long a host may hold the rolling patch lock before others take over
+const PATCH_LEASE_TTL: std::time::Duration = std::time::Duration::from_secs(2 * 60 * 60);
 
 /// Package operation types
 #[derive(Debug, Clone, PartialEq)]
 pub enum PackageOperation {
     /// ^package - Upgrade package
     Upgrade { name: String, post_action: Option<String> },
-    /// ++update - Update package lists with before/after actions
-    UpdateAll { start_action: Option<String>, end_action: Option<String> },
-    /// ++upgrade - Upgrade all packages with before/after actions
-    UpgradeAll { start_action: Option<String>, end_action: Option<String> },
+    /// ++update - Update package lists with before/after actions, one host
+    /// of the group at a time with --rolling
+    UpdateAll { start_action: Option<String>, end_action: Option<String>, rolling: bool },
+    /// ++upgrade - Upgrade all packages with before/after actions, one host
+    /// of the group at a time with --rolling
+    UpgradeAll { start_action: Option<String>, end_action: Option<String>, rolling: bool },
     /// +package - Install package
     Install { name: String },
     /// =package - Keep package (don't auto-install/remove)
@@ -25,6 +33,20 @@ pub enum PackageOperation {
     Purge { name: String },
 }
 
+impl PackageOperation {
+    /// Package the operation is about, None for ++update and ++upgrade
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            PackageOperation::Upgrade { name, .. }
+            | PackageOperation::Install { name }
+            | PackageOperation::Keep { name }
+            | PackageOperation::Remove { name }
+            | PackageOperation::Purge { name } => Some(name),
+            PackageOperation::UpdateAll { .. } | PackageOperation::UpgradeAll { .. } => None,
+        }
+    }
+}
+
 /// Action record for tracking all operations
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ActionRecord {
@@ -35,9 +57,34 @@ pub struct ActionRecord {
     pub group: Option<String>,
     pub status: String,       // "started", "completed", "failed"
     pub details: Option<String>,
+    /// Exit status and captured output of a command the record is about
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<ActionOutput>,
+}
+
+impl ActionRecord {
+    /// Store the record under `actions/<host>/` on the shared filesystem,
+    /// returning its path. Records taken in the same instant get distinct names.
+    pub fn save(&self, mfs_mount: &Path) -> Result<PathBuf> {
+        let host_actions_dir = mfs_mount.join("actions").join(&self.hostname);
+        std::fs::create_dir_all(&host_actions_dir)?;
+
+        let stem = format!("{}-{}", self.timestamp.format("%Y%m%d-%H%M%S"), self.action_type);
+        let mut action_file = host_actions_dir.join(format!("{}.json", stem));
+        let mut n = 1;
+        while action_file.exists() {
+            action_file = host_actions_dir.join(format!("{}-{}.json", stem, n));
+            n += 1;
+        }
+
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(&action_file, json)?;
+        Ok(action_file)
+    }
 }
 
 /// Package manager for handling package operations
+#[derive(Clone)]
 pub struct PackageManager {
     mfs_mount: PathBuf,
 }
@@ -49,28 +96,7 @@ impl PackageManager {
     
     /// Record an action to the actions database
     pub fn record_action(&self, action: &ActionRecord) -> Result<()> {
-        let hostname = gethostname::gethostname()
-            .to_string_lossy()
-            .to_string();
-            
-        // Create actions directory if it doesn't exist
-        let actions_dir = self.mfs_mount.join("actions");
-        std::fs::create_dir_all(&actions_dir)?;
-        
-        // Create hostname-specific directory
-        let host_actions_dir = actions_dir.join(&hostname);
-        std::fs::create_dir_all(&host_actions_dir)?;
-        
-        // Create filename with timestamp
-        let filename = format!("{}-{}.json", 
-            action.timestamp.format("%Y%m%d-%H%M%S"),
-            action.action_type
-        );
-        
-        let action_file = host_actions_dir.join(filename);
-        let json = serde_json::to_string_pretty(action)?;
-        std::fs::write(action_file, json)?;
-        
+        action.save(&self.mfs_mount)?;
         Ok(())
     }
     
@@ -201,7 +227,8 @@ impl PackageManager {
                 }
             }
             
-            return Ok(Some(PackageOperation::UpdateAll { start_action, end_action }));
+            let rolling = line.split_whitespace().any(|part| part == "--rolling");
+            return Ok(Some(PackageOperation::UpdateAll { start_action, end_action, rolling }));
         }
         
         // Handle upgrade all: ++upgrade or ++upgrade --before cmd --after cmd
@@ -238,7 +265,8 @@ impl PackageManager {
                 }
             }
             
-            return Ok(Some(PackageOperation::UpgradeAll { start_action, end_action }));
+            let rolling = line.split_whitespace().any(|part| part == "--rolling");
+            return Ok(Some(PackageOperation::UpgradeAll { start_action, end_action, rolling }));
         }
         
         // Handle upgrade with post-action: ^nginx --upgrade=systemctl restart nginx
@@ -280,86 +308,58 @@ impl PackageManager {
 
     /// Load package operations for a group and optionally a specific machine
     pub fn load_package_operations(&self, group: &str, hostname: Option<&str>) -> Result<Vec<PackageOperation>> {
-        let mut operations = Vec::new();
-        let mut operation_map: HashMap<String, PackageOperation> = HashMap::new();
-
         // First, load group packages
         let group_path = self.get_group_packages_path(group);
-        if group_path.exists() {
+        let group_ops = if group_path.exists() {
             debug!("Loading group packages from: {}", group_path.display());
             let content = std::fs::read_to_string(&group_path)?;
-            let group_ops = self.parse_packages_conf(&content)?;
-            
-            // Add to map
-            for op in group_ops {
-                match &op {
-                    PackageOperation::UpdateAll { .. } |
-                    PackageOperation::UpgradeAll { .. } => {
-                        // UpdateAll and UpgradeAll are special operations that don't have a package name
-                        operations.push(op);
-                    }
-                    _ => {
-                        let name = match &op {
-                            PackageOperation::Upgrade { name, .. } => name,
-                            PackageOperation::Install { name } => name,
-                            PackageOperation::Keep { name } => name,
-                            PackageOperation::Remove { name } => name,
-                            PackageOperation::Purge { name } => name,
-                            PackageOperation::UpdateAll { .. } => unreachable!(),
-                            PackageOperation::UpgradeAll { .. } => unreachable!(),
-                        };
-                        operation_map.insert(name.clone(), op);
-                    }
-                }
-            }
-        }
+            self.parse_packages_conf(&content)?
+        } else {
+            Vec::new()
+        };
 
-        // Then, override with machine-specific packages if provided
-        if let Some(host) = hostname {
-            let machine_path = self.get_machine_packages_path(host);
-            if machine_path.exists() {
-                debug!("Loading machine packages from: {}", machine_path.display());
-                let content = std::fs::read_to_string(&machine_path)?;
-                let machine_ops = self.parse_packages_conf(&content)?;
-                
-                // Override group operations
-                for op in machine_ops {
-                    match &op {
-                        PackageOperation::UpdateAll { .. } |
-                        PackageOperation::UpgradeAll { .. } => {
-                            // UpdateAll and UpgradeAll are special operations that don't have a package name
-                            operations.push(op);
-                        }
-                        _ => {
-                            let name = match &op {
-                                PackageOperation::Upgrade { name, .. } => name,
-                                PackageOperation::Install { name } => name,
-                                PackageOperation::Keep { name } => name,
-                                PackageOperation::Remove { name } => name,
-                                PackageOperation::Purge { name } => name,
-                                PackageOperation::UpdateAll { .. } => unreachable!(),
-                                PackageOperation::UpgradeAll { .. } => unreachable!(),
-                            };
-                            operation_map.insert(name.clone(), op);
-                        }
-                    }
-                }
-            }
+        match hostname {
+            Some(host) => self.with_machine_operations(group_ops, host),
+            None => Ok(merge_operations(group_ops, Vec::new())),
         }
+    }
 
-        // Convert map back to vec
-        operations.extend(operation_map.into_values());
-        Ok(operations)
+    /// `group_ops` overridden by the machine-specific packages of `hostname`
+    pub fn with_machine_operations(&self, group_ops: Vec<PackageOperation>, hostname: &str) -> Result<Vec<PackageOperation>> {
+        let machine_path = self.get_machine_packages_path(hostname);
+        let machine_ops = if machine_path.exists() {
+            debug!("Loading machine packages from: {}", machine_path.display());
+            let content = std::fs::read_to_string(&machine_path)?;
+            self.parse_packages_conf(&content)?
+        } else {
+            Vec::new()
+        };
+        Ok(merge_operations(group_ops, machine_ops))
     }
 
-    /// Add packages to a group's packages.conf
-    pub fn add_packages_to_group(&self, group: &str, packages: &[String], upgrade: bool) -> Result<()> {
+    /// Plan adding packages to a group's packages.conf. Returns the group's
+    /// operations as they will be once the packages are added.
+    pub fn plan_add_packages_to_group(&self, group: &str, packages: &[String], upgrade: bool, plan: &mut Plan) -> Result<Vec<PackageOperation>> {
         let packages_path = self.get_group_packages_path(group);
+        let operations = self.group_operations_with(group, packages, upgrade)?;
+        plan.write_file(&packages_path, packages_conf_content(&operations).as_bytes());
         
-        // Ensure directory exists
-        if let Some(parent) = packages_path.parent() {
-            std::fs::create_dir_all(parent)?;
-        }
+        let (this, written, group, count) = (self.clone(), operations.clone(), group.to_string(), packages.len());
+        plan.then(move || {
+            // Ensure directory exists
+            if let Some(parent) = packages_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            this.write_packages_conf(&packages_path, &written)?;
+            info!("Added {} packages to group '{}'", count, group);
+            Ok(())
+        });
+        Ok(operations)
+    }
+
+    /// A group's package operations with `packages` added, unless already listed
+    fn group_operations_with(&self, group: &str, packages: &[String], upgrade: bool) -> Result<Vec<PackageOperation>> {
+        let packages_path = self.get_group_packages_path(group);
 
         // Load existing packages
         let mut existing_ops = if packages_path.exists() {
@@ -370,17 +370,9 @@ impl PackageManager {
         };
 
         // Create a set of existing package names for deduplication
-        let mut existing_names: HashSet<String> = existing_ops.iter().filter_map(|op| {
-            match op {
-                PackageOperation::Upgrade { name, .. } => Some(name.clone()),
-                PackageOperation::Install { name } => Some(name.clone()),
-                PackageOperation::Keep { name } => Some(name.clone()),
-                PackageOperation::Remove { name } => Some(name.clone()),
-                PackageOperation::Purge { name } => Some(name.clone()),
-                PackageOperation::UpdateAll { .. } => None, // UpdateAll doesn't have a package name
-                PackageOperation::UpgradeAll { .. } => None, // UpgradeAll doesn't have a package name
-            }
-        }).collect();
+        let mut existing_names: HashSet<String> = existing_ops.iter()
+            .filter_map(|op| op.name().map(str::to_string))
+            .collect();
 
         // Add new packages
         for package in packages {
@@ -395,75 +387,12 @@ impl PackageManager {
             }
         }
 
-        // Write back to file
-        self.write_packages_conf(&packages_path, &existing_ops)?;
-        
-        info!("Added {} packages to group '{}'", packages.len(), group);
-        Ok(())
+        Ok(existing_ops)
     }
 
     /// Write package operations to a packages.conf file
     fn write_packages_conf(&self, path: &Path, operations: &[PackageOperation]) -> Result<()> {
-        let mut content = String::new();
-        
-        // Add header
-        content.push_str("# Laszoo Package Configuration\n");
-        content.push_str("# Syntax:\n");
-        content.push_str("# ^package - Upgrade package\n");
-        content.push_str("# ^package --upgrade=command - Upgrade with post-action\n");
-        content.push_str("# ++upgrade - Upgrade all packages\n");
-        content.push_str("# ++upgrade --start cmd --end cmd - Upgrade all with start/end actions\n");
-        content.push_str("# +package - Install package\n");
-        content.push_str("# =package - Keep package (don't auto-install/remove)\n");
-        content.push_str("# !package - Remove package\n");
-        content.push_str("# !!!package - Purge package\n\n");
-
-        // Write operations
-        for op in operations {
-            match op {
-                PackageOperation::Upgrade { name, post_action } => {
-                    if let Some(action) = post_action {
-                        content.push_str(&format!("^{} --upgrade={}\n", name, action));
-                    } else {
-                        content.push_str(&format!("^{}\n", name));
-                    }
-                }
-                PackageOperation::UpdateAll { start_action, end_action } => {
-                    let mut line = String::from("++update");
-                    if let Some(start) = start_action {
-                        line.push_str(&format!(" --before {}", start));
-                    }
-                    if let Some(end) = end_action {
-                        line.push_str(&format!(" --after {}", end));
-                    }
-                    content.push_str(&format!("{}\n", line));
-                }
-                PackageOperation::UpgradeAll { start_action, end_action } => {
-                    let mut line = String::from("++upgrade");
-                    if let Some(start) = start_action {
-                        line.push_str(&format!(" --before {}", start));
-                    }
-                    if let Some(end) = end_action {
-                        line.push_str(&format!(" --after {}", end));
-                    }
-                    content.push_str(&format!("{}\n", line));
-                }
-                PackageOperation::Install { name } => {
-                    content.push_str(&format!("+{}\n", name));
-                }
-                PackageOperation::Keep { name } => {
-                    content.push_str(&format!("={}\n", name));
-                }
-                PackageOperation::Remove { name } => {
-                    content.push_str(&format!("!{}\n", name));
-                }
-                PackageOperation::Purge { name } => {
-                    content.push_str(&format!("!!!{}\n", name));
-                }
-            }
-        }
-
-        std::fs::write(path, content)?;
+        std::fs::write(path, packages_conf_content(operations))?;
         Ok(())
     }
 
@@ -473,143 +402,45 @@ impl PackageManager {
             .ok_or_else(|| LaszooError::Other("No supported package manager found".to_string()))
     }
 
-    /// Apply package operations on the local system with group context
-    pub async fn apply_operations_with_group(&self, operations: &[PackageOperation], group: Option<&str>) -> Result<()> {
+    /// Plan applying package operations on the local system, with the group
+    /// they come from. Rolling patches hold the group's patch lock, so only
+    /// one host of the group runs them at a time.
+    pub fn plan_operations(&self, operations: &[PackageOperation], group: Option<&str>, plan: &mut Plan) -> Result<()> {
         let pkg_mgr = Self::detect_package_manager()?;
         let hostname = gethostname::gethostname()
             .to_string_lossy()
             .to_string();
         
+        let mut commands = Plan::new();
         for op in operations {
             match op {
                 PackageOperation::Install { name } => {
-                    info!("Installing package: {}", name);
-                    self.install_package(&pkg_mgr, name).await?;
+                    commands.package(pkg_mgr.install_command(name));
+                    self.then_run(&mut commands, &pkg_mgr.install_command(name), format!("Installing package: {}", name));
                 }
                 PackageOperation::Upgrade { name, post_action } => {
-                    info!("Upgrading package: {}", name);
-                    self.upgrade_package(&pkg_mgr, name).await?;
-                    
+                    commands.package(pkg_mgr.upgrade_command(name));
+                    self.then_run(&mut commands, &pkg_mgr.upgrade_command(name), format!("Upgrading package: {}", name));
                     if let Some(action) = post_action {
-                        info!("Running post-upgrade action: {}", action);
-                        self.run_command(action).await?;
+                        commands.action("post-upgrade", None, action);
+                        self.then_run(&mut commands, action, format!("Running post-upgrade action: {}", action));
                     }
                 }
-                PackageOperation::UpdateAll { start_action, end_action } => {
-                    // Record action start
-                    let action_record = ActionRecord {
-                        timestamp: Utc::now(),
-                        hostname: hostname.clone(),
-                        action_type: "package_update_all".to_string(),
-                        target: "++update".to_string(),
-                        group: group.map(|s| s.to_string()),
-                        status: "started".to_string(),
-                        details: None,
-                    };
-                    let _ = self.record_action(&action_record);
-                    
-                    if let Some(action) = start_action {
-                        info!("Running pre-update action: {}", action);
-                        self.run_command(action).await?;
-                    }
-                    
-                    info!("Updating package lists");
-                    match self.system_update(&pkg_mgr).await {
-                        Ok(_) => {
-                            // Record success
-                            let action_record = ActionRecord {
-                                timestamp: Utc::now(),
-                                hostname: hostname.clone(),
-                                action_type: "package_update_all".to_string(),
-                                target: "++update".to_string(),
-                                group: group.map(|s| s.to_string()),
-                                status: "completed".to_string(),
-                                details: None,
-                            };
-                            let _ = self.record_action(&action_record);
-                        }
-                        Err(e) => {
-                            // Record failure
-                            let action_record = ActionRecord {
-                                timestamp: Utc::now(),
-                                hostname: hostname.clone(),
-                                action_type: "package_update_all".to_string(),
-                                target: "++update".to_string(),
-                                group: group.map(|s| s.to_string()),
-                                status: "failed".to_string(),
-                                details: Some(format!("Error: {}", e)),
-                            };
-                            let _ = self.record_action(&action_record);
-                            return Err(e);
-                        }
-                    }
-                    
-                    if let Some(action) = end_action {
-                        info!("Running post-update action: {}", action);
-                        self.run_command(action).await?;
-                    }
+                PackageOperation::UpdateAll { start_action, end_action, .. } => {
+                    let patch = SystemPatch { kind: "update", command: pkg_mgr.update_command(), message: "Updating package lists" };
+                    self.plan_system_patch(&mut commands, patch, start_action.as_deref(), end_action.as_deref(), group, &hostname);
                 }
-                PackageOperation::UpgradeAll { start_action, end_action } => {
-                    // Record action start
-                    let action_record = ActionRecord {
-                        timestamp: Utc::now(),
-                        hostname: hostname.clone(),
-                        action_type: "package_upgrade_all".to_string(),
-                        target: "++upgrade".to_string(),
-                        group: group.map(|s| s.to_string()),
-                        status: "started".to_string(),
-                        details: None,
-                    };
-                    let _ = self.record_action(&action_record);
-                    
-                    if let Some(action) = start_action {
-                        info!("Running pre-upgrade action: {}", action);
-                        self.run_command(action).await?;
-                    }
-                    
-                    info!("Upgrading all packages");
-                    match self.system_upgrade(&pkg_mgr).await {
-                        Ok(_) => {
-                            // Record success
-                            let action_record = ActionRecord {
-                                timestamp: Utc::now(),
-                                hostname: hostname.clone(),
-                                action_type: "package_upgrade_all".to_string(),
-                                target: "++upgrade".to_string(),
-                                group: group.map(|s| s.to_string()),
-                                status: "completed".to_string(),
-                                details: None,
-                            };
-                            let _ = self.record_action(&action_record);
-                        }
-                        Err(e) => {
-                            // Record failure
-                            let action_record = ActionRecord {
-                                timestamp: Utc::now(),
-                                hostname: hostname.clone(),
-                                action_type: "package_upgrade_all".to_string(),
-                                target: "++upgrade".to_string(),
-                                group: group.map(|s| s.to_string()),
-                                status: "failed".to_string(),
-                                details: Some(format!("Error: {}", e)),
-                            };
-                            let _ = self.record_action(&action_record);
-                            return Err(e);
-                        }
-                    }
-                    
-                    if let Some(action) = end_action {
-                        info!("Running post-upgrade action: {}", action);
-                        self.run_command(action).await?;
-                    }
+                PackageOperation::UpgradeAll { start_action, end_action, .. } => {
+                    let patch = SystemPatch { kind: "upgrade", command: pkg_mgr.upgrade_all_command(), message: "Upgrading all packages" };
+                    self.plan_system_patch(&mut commands, patch, start_action.as_deref(), end_action.as_deref(), group, &hostname);
                 }
                 PackageOperation::Remove { name } => {
-                    info!("Removing package: {}", name);
-                    self.remove_package(&pkg_mgr, name).await?;
+                    commands.package(pkg_mgr.remove_command(name));
+                    self.then_run(&mut commands, &pkg_mgr.remove_command(name), format!("Removing package: {}", name));
                 }
                 PackageOperation::Purge { name } => {
-                    info!("Purging package: {}", name);
-                    self.purge_package(&pkg_mgr, name).await?;
+                    commands.package(pkg_mgr.purge_command(name));
+                    self.then_run(&mut commands, &pkg_mgr.purge_command(name), format!("Purging package: {}", name));
                 }
                 PackageOperation::Keep { name } => {
                     debug!("Keeping package: {} (no action needed)", name);
@@ -617,128 +448,493 @@ impl PackageManager {
             }
         }
 
+        let rolling = operations.iter().any(|op| matches!(op,
+            PackageOperation::UpdateAll { rolling: true, .. } | PackageOperation::UpgradeAll { rolling: true, .. }));
+        match group.filter(|_| rolling) {
+            Some(group) => {
+                let lock = format!("patch.{}", group);
+                plan.metadata(&self.mfs_mount.join("locks").join(format!("{}.lease", lock)),
+                    format!("hold lock '{}' while patching", lock));
+                let (this, group) = (self.clone(), group.to_string());
+                plan.nest_with(commands, move |commands| async move {
+                    let locks = LockManager::new(this.mfs_mount.clone());
+                    let _lease = this.wait_for_patch_lock(&locks, &group).await?;
+                    commands.run().await
+                });
+            }
+            None => plan.append(commands),
+        }
         Ok(())
     }
+
+    /// Plan `++update` or `++upgrade`: `patch.command` between the start
+    /// and end actions, recorded with the group's actions
+    fn plan_system_patch(&self, plan: &mut Plan, patch: SystemPatch, start_action: Option<&str>, end_action: Option<&str>, group: Option<&str>, hostname: &str) {
+        let record = ActionRecord {
+            timestamp: Utc::now(),
+            hostname: hostname.to_string(),
+            action_type: format!("package_{}_all", patch.kind),
+            target: format!("++{}", patch.kind),
+            group: group.map(|s| s.to_string()),
+            status: "started".to_string(),
+            details: None,
+            output: None,
+        };
+        plan.metadata(&self.mfs_mount.join("actions").join(hostname), format!("record {}", record.target));
+        
+        // Record action start
+        let (this, started) = (self.clone(), record.clone());
+        plan.then(move || {
+            let _ = this.record_action(&ActionRecord { timestamp: Utc::now(), ..started });
+            Ok(())
+        });
+        
+        if let Some(action) = start_action {
+            plan.action(&format!("pre-{}", patch.kind), None, action);
+            self.then_run(plan, action, format!("Running pre-{} action: {}", patch.kind, action));
+        }
+        
+        plan.package(patch.command);
+        let this = self.clone();
+        plan.then_async(move || async move {
+            info!("{}", patch.message);
+            let result = this.run_command(patch.command).await;
+            // Record success or failure
+            let (status, details) = match &result {
+                Ok(_) => ("completed", None),
+                Err(e) => ("failed", Some(format!("Error: {}", e))),
+            };
+            let _ = this.record_action(&ActionRecord {
+                timestamp: Utc::now(),
+                status: status.to_string(),
+                details,
+                ..record
+            });
+            result
+        });
+        
+        if let Some(action) = end_action {
+            plan.action(&format!("post-{}", patch.kind), None, action);
+            self.then_run(plan, action, format!("Running post-{} action: {}", patch.kind, action));
+        }
+    }
+
+    /// Run a shell command when the plan runs, logging `message` first
+    fn then_run(&self, plan: &mut Plan, command: &str, message: String) {
+        let (this, command) = (self.clone(), command.to_string());
+        plan.then_async(move || async move {
+            info!("{}", message);
+            this.run_command(&command).await
+        });
+    }
+    
+    /// Wait for this host's turn to patch a group
+    async fn wait_for_patch_lock<'a>(&self, locks: &'a LockManager, group: &str) -> Result<LeaseGuard<'a>> {
+        let name = format!("patch.{}", group);
+        let mut waiting = false;
+        loop {
+            match locks.hold(&name, PATCH_LEASE_TTL, std::time::Duration::ZERO) {
+                Ok(guard) => {
+                    info!("Patching group '{}' with lock token {}", group, guard.lease().token);
+                    return Ok(guard);
+                }
+                Err(e @ LaszooError::LockHeld { .. }) => {
+                    if !waiting {
+                        info!("Waiting for rolling patch lock: {}", e);
+                        waiting = true;
+                    }
+                    tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
     
-    /// Apply package operations on the local system (without group context)
-    pub async fn apply_operations(&self, operations: &[PackageOperation]) -> Result<()> {
-        self.apply_operations_with_group(operations, None).await
+    /// Plan applying a group's package operations, with this machine's additions
+    pub fn plan_group(&self, group: &str, hostname: &str, plan: &mut Plan) -> Result<()> {
+        let operations = self.load_package_operations(group, Some(hostname))?;
+        if operations.is_empty() {
+            return Ok(());
+        }
+        let (count, name) = (operations.len(), group.to_string());
+        plan.then(move || {
+            info!("Applying {} package operations for group '{}'", count, name);
+            Ok(())
+        });
+        self.plan_operations(&operations, Some(group), plan)
+    }
+
+    /// Plan applying the package operations of every group the machine is
+    /// in, plus its machine-specific ones
+    pub fn plan_machine(&self, groups: &[String], hostname: &str, plan: &mut Plan) -> Result<()> {
+        let mut all_operations = Vec::new();
+        for group in groups {
+            all_operations.extend(self.load_package_operations(group, Some(hostname))?);
+        }
+        if all_operations.is_empty() {
+            return Ok(());
+        }
+        let count = all_operations.len();
+        plan.then(move || {
+            info!("Applying {} package operations for machine", count);
+            Ok(())
+        });
+        self.plan_operations(&all_operations, None, plan)
     }
 
-    /// Install a package using the appropriate package manager
-    async fn install_package(&self, pkg_mgr: &PackageManagerType, package: &str) -> Result<()> {
-        let cmd = match pkg_mgr {
+    /// Run a shell command
+    async fn run_command(&self, cmd: &str) -> Result<()> {
+        use tokio::process::Command;
+        
+        debug!("Running command: {}", cmd);
+        
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(cmd)
+            .output()
+            .await?;
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Err(LaszooError::Other(format!("Command failed: {}", stderr)))
+        }
+    }
+}
+
+/// A system-wide patch, `++update` or `++upgrade`
+struct SystemPatch {
+    kind: &'static str,
+    command: &'static str,
+    /// Logged when the command runs
+    message: &'static str,
+}
+
+/// Merge group and machine operations, the machine's overriding the
+/// group's for the same package
+fn merge_operations(group_ops: Vec<PackageOperation>, machine_ops: Vec<PackageOperation>) -> Vec<PackageOperation> {
+    let mut operations = Vec::new();
+    let mut operation_map: HashMap<String, PackageOperation> = HashMap::new();
+    for op in group_ops.into_iter().chain(machine_ops) {
+        match op.name() {
+            Some(name) => {
+                operation_map.insert(name.to_string(), op);
+            }
+            // UpdateAll and UpgradeAll are special operations that don't have a package name
+            None => operations.push(op),
+        }
+    }
+
+    // Convert map back to vec
+    operations.extend(operation_map.into_values());
+    operations
+}
+
+/// Content of a packages.conf listing `operations`
+fn packages_conf_content(operations: &[PackageOperation]) -> String {
+    let mut content = String::new();
+    
+    // Add header
+    content.push_str("# Laszoo Package Configuration\n");
+    content.push_str("# Syntax:\n");
+    content.push_str("# ^package - Upgrade package\n");
+    content.push_str("# ^package --upgrade=command - Upgrade with post-action\n");
+    content.push_str("# ++upgrade - Upgrade all packages\n");
+    content.push_str("# ++upgrade --start cmd --end cmd - Upgrade all with start/end actions\n");
+    content.push_str("# +package - Install package\n");
+    content.push_str("# =package - Keep package (don't auto-install/remove)\n");
+    content.push_str("# !package - Remove package\n");
+    content.push_str("# !!!package - Purge package\n\n");
+
+    // Write operations
+    for op in operations {
+        match op {
+            PackageOperation::Upgrade { name, post_action } => {
+                if let Some(action) = post_action {
+                    content.push_str(&format!("^{} --upgrade={}\n", name, action));
+                } else {
+                    content.push_str(&format!("^{}\n", name));
+                }
+            }
+            PackageOperation::UpdateAll { start_action, end_action, rolling } => {
+                let mut line = String::from("++update");
+                if *rolling {
+                    line.push_str(" --rolling");
+                }
+                if let Some(start) = start_action {
+                    line.push_str(&format!(" --before {}", start));
+                }
+                if let Some(end) = end_action {
+                    line.push_str(&format!(" --after {}", end));
+                }
+                content.push_str(&format!("{}\n", line));
+            }
+            PackageOperation::UpgradeAll { start_action, end_action, rolling } => {
+                let mut line = String::from("++upgrade");
+                if *rolling {
+                    line.push_str(" --rolling");
+                }
+                if let Some(start) = start_action {
+                    line.push_str(&format!(" --before {}", start));
+                }
+                if let Some(end) = end_action {
+                    line.push_str(&format!(" --after {}", end));
+                }
+                content.push_str(&format!("{}\n", line));
+            }
+            PackageOperation::Install { name } => {
+                content.push_str(&format!("+{}\n", name));
+            }
+            PackageOperation::Keep { name } => {
+                content.push_str(&format!("={}\n", name));
+            }
+            PackageOperation::Remove { name } => {
+                content.push_str(&format!("!{}\n", name));
+            }
+            PackageOperation::Purge { name } => {
+                content.push_str(&format!("!!!{}\n", name));
+            }
+        }
+    }
+
+    content
+}
+
+/// Supported package manager types
+#[derive(Debug, Clone, Copy)]
+pub enum PackageManagerType {
+    Apt,
+    Yum,
+    Dnf,
+    Pacman,
+    Zypper,
+    Apk,
+}
+
+impl PackageManagerType {
+    /// Command installing a package
+    pub fn install_command(&self, package: &str) -> String {
+        match self {
             PackageManagerType::Apt => format!("apt-get install -y {}", package),
             PackageManagerType::Yum => format!("yum install -y {}", package),
             PackageManagerType::Dnf => format!("dnf install -y {}", package),
             PackageManagerType::Pacman => format!("pacman -S --noconfirm {}", package),
             PackageManagerType::Zypper => format!("zypper install -y {}", package),
             PackageManagerType::Apk => format!("apk add {}", package),
-        };
-
-        self.run_command(&cmd).await
+        }
     }
 
-    /// Upgrade a package
-    async fn upgrade_package(&self, pkg_mgr: &PackageManagerType, package: &str) -> Result<()> {
-        let cmd = match pkg_mgr {
+    /// Command upgrading a package
+    pub fn upgrade_command(&self, package: &str) -> String {
+        match self {
             PackageManagerType::Apt => format!("apt-get install --only-upgrade -y {}", package),
             PackageManagerType::Yum => format!("yum update -y {}", package),
             PackageManagerType::Dnf => format!("dnf upgrade -y {}", package),
             PackageManagerType::Pacman => format!("pacman -S --noconfirm {}", package),
             PackageManagerType::Zypper => format!("zypper update -y {}", package),
             PackageManagerType::Apk => format!("apk upgrade {}", package),
-        };
-
-        self.run_command(&cmd).await
+        }
     }
 
-    /// Remove a package
-    async fn remove_package(&self, pkg_mgr: &PackageManagerType, package: &str) -> Result<()> {
-        let cmd = match pkg_mgr {
+    /// Command removing a package
+    pub fn remove_command(&self, package: &str) -> String {
+        match self {
             PackageManagerType::Apt => format!("apt-get remove -y {}", package),
             PackageManagerType::Yum => format!("yum remove -y {}", package),
             PackageManagerType::Dnf => format!("dnf remove -y {}", package),
             PackageManagerType::Pacman => format!("pacman -R --noconfirm {}", package),
             PackageManagerType::Zypper => format!("zypper remove -y {}", package),
             PackageManagerType::Apk => format!("apk del {}", package),
-        };
-
-        self.run_command(&cmd).await
+        }
     }
 
-    /// Purge a package
-    async fn purge_package(&self, pkg_mgr: &PackageManagerType, package: &str) -> Result<()> {
-        let cmd = match pkg_mgr {
+    /// Command purging a package
+    pub fn purge_command(&self, package: &str) -> String {
+        match self {
             PackageManagerType::Apt => format!("apt-get purge -y {}", package),
             PackageManagerType::Yum => format!("yum remove -y {}", package), // No purge in yum
             PackageManagerType::Dnf => format!("dnf remove -y {}", package), // No purge in dnf
             PackageManagerType::Pacman => format!("pacman -Rn --noconfirm {}", package),
             PackageManagerType::Zypper => format!("zypper remove -y --clean-deps {}", package),
             PackageManagerType::Apk => format!("apk del --purge {}", package),
-        };
-
-        self.run_command(&cmd).await
+        }
     }
 
-    /// Run a system update (refresh package lists)
-    pub async fn system_update(&self, pkg_mgr: &PackageManagerType) -> Result<()> {
-        let cmd = match pkg_mgr {
+    /// Command refreshing the package lists
+    pub fn update_command(&self) -> &'static str {
+        match self {
             PackageManagerType::Apt => "apt-get update",
             PackageManagerType::Yum => "yum check-update || true", // check-update returns 100 if updates available
             PackageManagerType::Dnf => "dnf check-update || true", // check-update returns 100 if updates available
             PackageManagerType::Pacman => "pacman -Sy",
             PackageManagerType::Zypper => "zypper refresh",
             PackageManagerType::Apk => "apk update",
-        };
-
-        self.run_command(cmd).await
+        }
     }
 
-    /// Run a system upgrade
-    pub async fn system_upgrade(&self, pkg_mgr: &PackageManagerType) -> Result<()> {
-        let cmd = match pkg_mgr {
+    /// Command upgrading all packages
+    pub fn upgrade_all_command(&self) -> &'static str {
+        match self {
             PackageManagerType::Apt => "apt-get upgrade -y",
             PackageManagerType::Yum => "yum upgrade -y",
             PackageManagerType::Dnf => "dnf upgrade -y",
             PackageManagerType::Pacman => "pacman -Syu --noconfirm",
             PackageManagerType::Zypper => "zypper update -y",
             PackageManagerType::Apk => "apk upgrade",
-        };
-
-        self.run_command(cmd).await
+        }
     }
+}
 
-    /// Run a shell command
-    async fn run_command(&self, cmd: &str) -> Result<()> {
-        use tokio::process::Command;
-        
-        debug!("Running command: {}", cmd);
-        
-        let output = Command::new("sh")
-            .arg("-c")
-            .arg(cmd)
-            .output()
-            .await?;
+/// Installation state of a package on this machine
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PackageStatus {
+    UpToDate,
+    PendingUpdates,
+    PhasedUpdates,  // Updates available but phased/held back
+    Missing,
+}
 
-        if output.status.success() {
-            Ok(())
-        } else {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            Err(LaszooError::Other(format!("Command failed: {}", stderr)))
+/// Whether a package is installed and has updates available
+pub async fn check_package_status(pkg_mgr: &PackageManagerType, package: &str) -> PackageStatus {
+    use tokio::process::Command;
+    
+    let check_cmd = match pkg_mgr {
+        PackageManagerType::Apt => {
+            format!("dpkg -l {} 2>/dev/null | grep -q '^ii'", package)
+        }
+        PackageManagerType::Yum | 
+        PackageManagerType::Dnf => {
+            format!("rpm -q {} >/dev/null 2>&1", package)
+        }
+        PackageManagerType::Pacman => {
+            format!("pacman -Q {} >/dev/null 2>&1", package)
+        }
+        PackageManagerType::Zypper => {
+            format!("rpm -q {} >/dev/null 2>&1", package)
+        }
+        PackageManagerType::Apk => {
+            format!("apk info -e {} >/dev/null 2>&1", package)
+        }
+    };
+    
+    match Command::new("sh")
+        .arg("-c")
+        .arg(&check_cmd)
+        .status()
+        .await
+    {
+        Ok(status) => {
+            if status.success() {
+                // Package is installed, check if updates are available
+                let update_check_cmd = match pkg_mgr {
+                    PackageManagerType::Apt => {
+                        format!("apt-cache policy {} | grep -q 'Installed:.*Candidate:' && ! apt-cache policy {} | grep -q 'Installed:.*Candidate:.*none'", package, package)
+                    }
+                    _ => {
+                        // For other package managers, we'll just say it's up to date if installed
+                        return PackageStatus::UpToDate;
+                    }
+                };
+                
+                match Command::new("sh")
+                    .arg("-c")
+                    .arg(&update_check_cmd)
+                    .status()
+                    .await
+                {
+                    Ok(update_status) => {
+                        if update_status.success() {
+                            PackageStatus::PendingUpdates
+                        } else {
+                            PackageStatus::UpToDate
+                        }
+                    }
+                    Err(_) => PackageStatus::UpToDate,
+                }
+            } else {
+                PackageStatus::Missing
+            }
         }
+        Err(_) => PackageStatus::Missing,
     }
 }
 
-/// Supported package manager types
-#[derive(Debug, Clone, Copy)]
-pub enum PackageManagerType {
-    Apt,
-    Yum,
-    Dnf,
-    Pacman,
-    Zypper,
-    Apk,
+/// Whether the system has package updates available
+pub async fn check_system_updates(pkg_mgr: &PackageManagerType) -> bool {
+    use tokio::process::Command;
+    
+    let check_cmd = match pkg_mgr {
+        PackageManagerType::Apt => {
+            "apt list --upgradable 2>/dev/null | grep -q upgradable"
+        }
+        PackageManagerType::Yum => {
+            "yum check-update >/dev/null 2>&1; [ $? -eq 100 ]"
+        }
+        PackageManagerType::Dnf => {
+            "dnf check-update >/dev/null 2>&1; [ $? -eq 100 ]"
+        }
+        PackageManagerType::Pacman => {
+            "pacman -Qu >/dev/null 2>&1"
+        }
+        PackageManagerType::Zypper => {
+            "zypper list-updates | grep -q '^v |'"
+        }
+        PackageManagerType::Apk => {
+            "apk version -l '<' | grep -q '<'"
+        }
+    };
+    
+    match Command::new("sh")
+        .arg("-c")
+        .arg(check_cmd)
+        .status()
+        .await
+    {
+        Ok(status) => status.success(),
+        Err(_) => false,
+    }
+}
+
+/// Whether the available updates are phased or held back, so upgrading would
+/// not install them
+pub async fn check_phased_updates(pkg_mgr: &PackageManagerType) -> bool {
+    use tokio::process::Command;
+    
+    // Only APT has phased updates
+    match pkg_mgr {
+        PackageManagerType::Apt => {
+            // A simpler approach: check if apt-get upgrade -s would do nothing
+            // but apt list --upgradable shows packages
+            // More resilient command that handles edge cases
+            let check_cmd = r#"#!/bin/bash
+# Count total upgradable packages (excluding header line)
+total=$(apt list --upgradable 2>/dev/null | grep -v "^Listing" | grep "/" | wc -l)
+# Count packages that would actually be upgraded by apt-get upgrade
+would=$(apt-get -s upgrade 2>/dev/null | grep "^Inst " | wc -l)
+# Ensure we have numbers
+total=${total:-0}
+would=${would:-0}
+# If there are upgradable packages but apt-get upgrade would do nothing,
+# those are phased/held back
+if [ "$total" -gt 0 ] && [ "$would" -eq 0 ]; then
+    exit 0  # Phased updates detected
+else
+    exit 1  # No phased updates
+fi"#;
+            
+            match Command::new("sh")
+                .arg("-c")
+                .arg(check_cmd)
+                .status()
+                .await
+            {
+                Ok(status) => status.success(),
+                Err(_) => false,
+            }
+        }
+        _ => false, // Other package managers don't have phased updates
+    }
 }
 
 /// Detect the package manager on the current system (returns Option)
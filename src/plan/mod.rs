@@ -0,0 +1,254 @@
+use std::fmt;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use futures::future::BoxFuture;
+use serde::Serialize;
+use crate::error::Result;
+use crate::fs::is_binary_content;
+
+/// One thing a command would do to the system or the shared filesystem
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Step {
+    /// Create or overwrite a file, with a unified diff from its current content
+    Write { path: PathBuf, new: bool, diff: String },
+    /// Delete a file or directory
+    Remove { path: PathBuf },
+    /// Move a file or directory
+    Rename { from: PathBuf, to: PathBuf },
+    /// Bookkeeping kept on the shared filesystem: manifest entries, group
+    /// membership, stored actions and the like
+    Metadata { path: PathBuf, change: String },
+    /// A before/after action or trigger command
+    Action { phase: String, path: Option<PathBuf>, command: String },
+    /// A package manager command
+    Package { command: String },
+}
+
+/// A change carried out when the plan is run
+struct Change {
+    run: Box<dyn FnOnce() -> BoxFuture<'static, Result<()>> + Send>,
+    /// Runs even after an earlier change failed
+    always: bool,
+}
+
+/// What a mutating command does. The command builds its plan, reading the
+/// system and the shared filesystem, then runs it; `--dry-run` prints the
+/// steps instead.
+///
+/// Steps describe the plan, the changes added along with them carry it out,
+/// in the order they were added.
+#[derive(Default, Serialize)]
+pub struct Plan {
+    pub steps: Vec<Step>,
+    #[serde(skip)]
+    changes: Vec<Change>,
+}
+
+impl Plan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    pub fn push(&mut self, step: Step) {
+        self.steps.push(step);
+    }
+
+    /// Write `content` to `path`, where `current` is what is there now. Nothing
+    /// is planned when the content is unchanged.
+    pub fn write(&mut self, path: &Path, current: Option<&[u8]>, content: &[u8]) {
+        if current == Some(content) {
+            return;
+        }
+
+        let old = current.unwrap_or_default();
+        let diff = if is_binary_content(old) || is_binary_content(content) {
+            format!("Binary file {} differs\n", path.display())
+        } else {
+            crate::diff::unified_diff(
+                &String::from_utf8_lossy(old),
+                &String::from_utf8_lossy(content),
+                &format!("{} (current)", path.display()),
+                &format!("{} (planned)", path.display()),
+            )
+        };
+        self.push(Step::Write { path: path.to_path_buf(), new: current.is_none(), diff });
+    }
+
+    /// Write `content` to `path`, diffed against what is on disk
+    pub fn write_file(&mut self, path: &Path, content: &[u8]) {
+        let current = std::fs::read(path).ok();
+        self.write(path, current.as_deref(), content);
+    }
+
+    pub fn remove(&mut self, path: &Path) {
+        self.push(Step::Remove { path: path.to_path_buf() });
+    }
+
+    pub fn rename(&mut self, from: &Path, to: &Path) {
+        self.push(Step::Rename { from: from.to_path_buf(), to: to.to_path_buf() });
+    }
+
+    pub fn metadata(&mut self, path: &Path, change: impl Into<String>) {
+        self.push(Step::Metadata { path: path.to_path_buf(), change: change.into() });
+    }
+
+    pub fn action(&mut self, phase: &str, path: Option<&Path>, command: &str) {
+        self.push(Step::Action {
+            phase: phase.to_string(),
+            path: path.map(Path::to_path_buf),
+            command: command.to_string(),
+        });
+    }
+
+    pub fn package(&mut self, command: impl Into<String>) {
+        self.push(Step::Package { command: command.into() });
+    }
+
+    /// Carry out `change` when the plan runs, after the changes added so far.
+    /// Nothing runs once a change has failed.
+    pub fn then(&mut self, change: impl FnOnce() -> Result<()> + Send + 'static) {
+        self.then_async(move || std::future::ready(change()));
+    }
+
+    /// Carry out an asynchronous change, see `then`
+    pub fn then_async<F>(&mut self, change: impl FnOnce() -> F + Send + 'static)
+    where
+        F: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.changes.push(Change { run: Box::new(move || Box::pin(change())), always: false });
+    }
+
+    /// Carry out `change` even when an earlier change failed, to finish
+    /// what was started. Its error is only returned if nothing failed before.
+    pub fn finally(&mut self, change: impl FnOnce() -> Result<()> + Send + 'static) {
+        self.changes.push(Change { run: Box::new(move || Box::pin(std::future::ready(change()))), always: true });
+    }
+
+    /// Print `message` when the plan runs, to tell how it went
+    pub fn say(&mut self, message: impl Into<String>) {
+        let message = message.into();
+        self.then(move || {
+            println!("{}", message);
+            Ok(())
+        });
+    }
+
+    /// Take on the steps of `other`, running its changes as one change of
+    /// this plan. `outcome` gets how they went and decides whether this plan
+    /// goes on.
+    pub fn nest(&mut self, other: Plan, outcome: impl FnOnce(Result<()>) -> Result<()> + Send + 'static) {
+        self.nest_with(other, move |other| async move { outcome(other.run().await) });
+    }
+
+    /// Take on the steps of `other`, leaving `run` to carry it out as one
+    /// change of this plan, for changes that need something held while
+    /// they run
+    pub fn nest_with<F>(&mut self, mut other: Plan, run: impl FnOnce(Plan) -> F + Send + 'static)
+    where
+        F: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.steps.append(&mut other.steps);
+        self.then_async(move || run(other));
+    }
+
+    /// Add the steps and changes of `other` after those of this plan
+    pub fn append(&mut self, other: Plan) {
+        self.steps.extend(other.steps);
+        self.changes.extend(other.changes);
+    }
+
+    /// Carry out the plan, stopping at the first change that fails
+    pub async fn run(self) -> Result<()> {
+        run_changes(self.changes).await
+    }
+
+    /// Carry out a plan outside of async code. Changes that wait on the
+    /// runtime, like package commands, need `run`.
+    pub fn run_blocking(self) -> Result<()> {
+        futures::executor::block_on(self.run())
+    }
+}
+
+async fn run_changes(changes: Vec<Change>) -> Result<()> {
+    let mut result = Ok(());
+    for change in changes {
+        if result.is_err() && !change.always {
+            continue;
+        }
+        let ran = (change.run)().await;
+        if result.is_ok() {
+            result = ran;
+        }
+    }
+    result
+}
+
+impl fmt::Debug for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Plan")
+            .field("steps", &self.steps)
+            .field("changes", &self.changes.len())
+            .finish()
+    }
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Step::Write { path, new, diff } => {
+                writeln!(f, "{} {}", if *new { "create" } else { "write " }, path.display())?;
+                for line in diff.lines() {
+                    writeln!(f, "        {}", line)?;
+                }
+                Ok(())
+            }
+            Step::Remove { path } => writeln!(f, "remove {}", path.display()),
+            Step::Rename { from, to } => writeln!(f, "rename {} -> {}", from.display(), to.display()),
+            Step::Metadata { path, change } => writeln!(f, "update {}: {}", path.display(), change),
+            Step::Action { phase, path: Some(path), command } => {
+                writeln!(f, "run    {} action for {}: {}", phase, path.display(), command)
+            }
+            Step::Action { phase, path: None, command } => writeln!(f, "run    {} action: {}", phase, command),
+            Step::Package { command } => writeln!(f, "run    {}", command),
+        }
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "  Nothing to do");
+        }
+        for step in &self.steps {
+            write!(f, "  {}", step)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unchanged_content_is_not_planned() {
+        let mut plan = Plan::new();
+        plan.write(Path::new("/etc/app.conf"), Some(b"workers = 4\n"), b"workers = 4\n");
+        assert!(plan.is_empty());
+
+        plan.write(Path::new("/etc/app.conf"), Some(b"workers = 4\n"), b"workers = 8\n");
+        plan.write(Path::new("/etc/new.conf"), None, b"enabled = true\n");
+        match &plan.steps[..] {
+            [Step::Write { new: false, diff, .. }, Step::Write { new: true, .. }] => {
+                assert!(diff.contains("-workers = 4"));
+                assert!(diff.contains("+workers = 8"));
+            }
+            steps => panic!("unexpected plan: {:?}", steps),
+        }
+    }
+}
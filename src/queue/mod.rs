@@ -0,0 +1,169 @@
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+use tracing::debug;
+use crate::config::Config;
+use crate::error::Result;
+
+/// A converge-mode path watched while the shared filesystem is available,
+/// remembered so its changes can still be queued while it is not
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OfflineTarget {
+    pub path: PathBuf,
+    pub group: String,
+    /// An enrolled directory, watched recursively
+    #[serde(default)]
+    pub directory: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Modified,
+    Deleted,
+}
+
+impl std::fmt::Display for ChangeKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChangeKind::Modified => write!(f, "modified"),
+            ChangeKind::Deleted => write!(f, "deleted"),
+        }
+    }
+}
+
+/// A local change recorded while the shared filesystem was unavailable
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PendingChange {
+    /// Position in the queue, changes are replayed in this order
+    pub seq: u64,
+    pub recorded_at: chrono::DateTime<chrono::Utc>,
+    pub path: PathBuf,
+    pub group: String,
+    pub kind: ChangeKind,
+}
+
+/// Local journal of changes to converge-mode files made while the shared
+/// filesystem is unavailable.
+///
+/// Each change is a JSON file in `<state_dir>/pending/` named after its
+/// sequence number. The paths to watch while offline are kept alongside in
+/// `targets.json`, written whenever watch starts with the filesystem up.
+pub struct PendingQueue {
+    dir: PathBuf,
+}
+
+impl PendingQueue {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(config.state_dir.join("pending"))
+    }
+
+    fn targets_path(&self) -> PathBuf {
+        self.dir.join("targets.json")
+    }
+
+    fn change_path(&self, seq: u64) -> PathBuf {
+        self.dir.join(format!("{:010}.json", seq))
+    }
+
+    /// Remember the paths to watch while the shared filesystem is down
+    pub fn save_targets(&self, targets: &[OfflineTarget]) -> Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let content = serde_json::to_string_pretty(targets)?;
+        crate::fs::atomic_write(&self.targets_path(), content.as_bytes(), |_| Ok(()))
+    }
+
+    pub fn load_targets(&self) -> Result<Vec<OfflineTarget>> {
+        match std::fs::read_to_string(self.targets_path()) {
+            Ok(content) => Ok(serde_json::from_str(&content)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The watched target a changed path falls under, if any
+    pub fn target_for<'a>(targets: &'a [OfflineTarget], path: &Path) -> Option<&'a OfflineTarget> {
+        targets.iter().find(|t| if t.directory { path.starts_with(&t.path) } else { path == t.path })
+    }
+
+    /// Append a change to the queue
+    pub fn record(&self, path: &Path, group: &str, kind: ChangeKind) -> Result<PendingChange> {
+        std::fs::create_dir_all(&self.dir)?;
+        let seq = self.list()?.last().map_or(1, |c| c.seq + 1);
+        let change = PendingChange {
+            seq,
+            recorded_at: chrono::Utc::now(),
+            path: path.to_path_buf(),
+            group: group.to_string(),
+            kind,
+        };
+        let content = serde_json::to_string_pretty(&change)?;
+        crate::fs::atomic_write(&self.change_path(seq), content.as_bytes(), |_| Ok(()))?;
+        debug!("Queued {} change {} for {:?}", change.kind, seq, path);
+        Ok(change)
+    }
+
+    /// Queued changes, oldest first
+    pub fn list(&self) -> Result<Vec<PendingChange>> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut changes = Vec::new();
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let is_change = path.file_stem()
+                .and_then(|s| s.to_str())
+                .is_some_and(|s| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit()));
+            if !is_change || path.extension() != Some(std::ffi::OsStr::new("json")) {
+                continue;
+            }
+            let content = std::fs::read_to_string(&path)?;
+            changes.push(serde_json::from_str::<PendingChange>(&content)?);
+        }
+        changes.sort_by_key(|c| c.seq);
+        Ok(changes)
+    }
+
+    /// Drop a change once it has been replayed
+    pub fn remove(&self, change: &PendingChange) -> Result<()> {
+        match std::fs::remove_file(self.change_path(change.seq)) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_changes_are_kept_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = PendingQueue::new(dir.path().join("pending"));
+        queue.save_targets(&[OfflineTarget {
+            path: PathBuf::from("/etc/app"),
+            group: "web".to_string(),
+            directory: true,
+        }]).unwrap();
+
+        let first = queue.record(Path::new("/etc/app/a.conf"), "web", ChangeKind::Modified).unwrap();
+        let second = queue.record(Path::new("/etc/app/b.conf"), "web", ChangeKind::Deleted).unwrap();
+        assert_eq!((first.seq, second.seq), (1, 2));
+        assert_eq!(queue.list().unwrap(), vec![first.clone(), second.clone()]);
+
+        queue.remove(&first).unwrap();
+        assert_eq!(queue.list().unwrap(), vec![second]);
+
+        let targets = queue.load_targets().unwrap();
+        assert!(PendingQueue::target_for(&targets, Path::new("/etc/app/c.conf")).is_some());
+        assert!(PendingQueue::target_for(&targets, Path::new("/etc/other.conf")).is_none());
+    }
+}
@@ -1,3 +1,5 @@
+pub mod units;
+
 use crate::error::{LaszooError, Result};
 use std::fs;
 use std::io::Write;
@@ -0,0 +1,302 @@
+use crate::error::{LaszooError, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::process::Command;
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Runs the service manager's commands. `SystemRunner` runs them for real,
+/// tests put a fake in its place.
+pub trait CommandRunner: Send + Sync {
+    fn run(&self, program: &str, args: &[&str]) -> Result<CommandOutput>;
+}
+
+/// How a service manager command ended
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CommandOutput {
+    /// Exit code, None when the command was killed
+    pub code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl CommandOutput {
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+}
+
+/// Runs commands as child processes, killing those still running after
+/// `timeout` like actions are
+pub struct SystemRunner {
+    timeout: Option<Duration>,
+}
+
+impl SystemRunner {
+    pub fn new(timeout: Option<Duration>) -> Self {
+        Self { timeout }
+    }
+}
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, program: &str, args: &[&str]) -> Result<CommandOutput> {
+        let command = std::iter::once(program).chain(args.iter().copied()).collect::<Vec<_>>().join(" ");
+        let mut cmd = Command::new(program);
+        cmd.args(args);
+        let output = crate::action::run_process(cmd, &command, self.timeout)
+            .map_err(|e| LaszooError::Other(format!("Failed to run {}: {}", program, e)))?;
+        if output.timed_out {
+            return Err(LaszooError::Other(format!("`{}` timed out after {}ms", command, output.duration_ms)));
+        }
+        Ok(CommandOutput { code: output.exit_code, stdout: output.stdout, stderr: output.stderr })
+    }
+}
+
+/// What a unit is told to do when its files change
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnitAction {
+    /// Reload the configuration, restarting units that can't reload
+    Reload,
+    Restart,
+}
+
+impl UnitAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UnitAction::Reload => "reload",
+            UnitAction::Restart => "restart",
+        }
+    }
+}
+
+/// A unit to reload or restart after its files are written, given as
+/// `UNIT:ACTION`, e.g. `nginx:reload`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ServiceNotify {
+    pub unit: String,
+    pub action: UnitAction,
+}
+
+impl FromStr for ServiceNotify {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (unit, action) = s.rsplit_once(':')
+            .ok_or_else(|| format!("expected UNIT:ACTION, e.g. nginx:reload, got '{}'", s))?;
+        let action = match action {
+            "reload" => UnitAction::Reload,
+            "restart" => UnitAction::Restart,
+            _ => return Err(format!("unknown action '{}' for {}, expected reload or restart", action, unit)),
+        };
+        if unit.is_empty() {
+            return Err(format!("no unit given in '{}'", s));
+        }
+        Ok(Self { unit: unit.to_string(), action })
+    }
+}
+
+impl TryFrom<String> for ServiceNotify {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<ServiceNotify> for String {
+    fn from(notify: ServiceNotify) -> Self {
+        notify.to_string()
+    }
+}
+
+impl fmt::Display for ServiceNotify {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.unit, self.action.as_str())
+    }
+}
+
+/// What notifying a unit did
+#[derive(Debug, Clone, PartialEq)]
+pub struct NotifyOutcome {
+    pub notify: ServiceNotify,
+    /// What was done, None when the unit was not running and not reloaded
+    pub performed: Option<UnitAction>,
+    /// `systemctl is-active` state after the action
+    pub state: String,
+}
+
+impl NotifyOutcome {
+    /// Whether the unit ended up in a good state
+    pub fn success(&self) -> bool {
+        self.performed.is_none() || self.state == "active"
+    }
+}
+
+impl fmt::Display for NotifyOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.performed {
+            None => write!(f, "{} is not running, {} skipped ({})", self.notify.unit, self.notify.action.as_str(), self.state),
+            Some(performed) if performed != self.notify.action => {
+                write!(f, "{} can't reload, {} instead, now {}", self.notify.unit, performed.as_str(), self.state)
+            }
+            Some(performed) => write!(f, "{} {}, now {}", self.notify.unit, performed.as_str(), self.state),
+        }
+    }
+}
+
+/// Units managed by systemd, through `systemctl`
+pub struct Systemd {
+    runner: Box<dyn CommandRunner>,
+}
+
+impl Systemd {
+    /// Systemd run through `systemctl`, killed after `timeout`
+    pub fn new(timeout: Option<Duration>) -> Self {
+        Self::with_runner(Box::new(SystemRunner::new(timeout)))
+    }
+
+    pub fn with_runner(runner: Box<dyn CommandRunner>) -> Self {
+        Self { runner }
+    }
+
+    fn systemctl(&self, args: &[&str]) -> Result<CommandOutput> {
+        self.runner.run("systemctl", args)
+    }
+
+    /// The unit's active state: active, inactive, failed, ...
+    pub fn state(&self, unit: &str) -> Result<String> {
+        let output = self.systemctl(&["is-active", unit])?;
+        let state = output.stdout.trim();
+        Ok(if state.is_empty() { "unknown" } else { state }.to_string())
+    }
+
+    /// Whether the unit supports reloading its configuration
+    pub fn can_reload(&self, unit: &str) -> Result<bool> {
+        let output = self.systemctl(&["show", "--property=CanReload", "--value", unit])?;
+        Ok(output.stdout.trim() == "yes")
+    }
+
+    /// Reload or restart a unit, falling back to a restart when it can't
+    /// reload. A unit that is not running is not reloaded, but restarting it
+    /// starts it. Fails when the unit is not active afterwards.
+    pub fn notify(&self, notify: &ServiceNotify) -> Result<NotifyOutcome> {
+        let unit = notify.unit.as_str();
+        let state = self.state(unit)?;
+        if state != "active" && notify.action == UnitAction::Reload {
+            info!("Unit {} is {}, not reloading it", unit, state);
+            return Ok(NotifyOutcome { notify: notify.clone(), performed: None, state });
+        }
+
+        let performed = match notify.action {
+            UnitAction::Reload if !self.can_reload(unit)? => {
+                warn!("Unit {} does not support reload, restarting it", unit);
+                UnitAction::Restart
+            }
+            action => action,
+        };
+        info!("Running systemctl {} {}", performed.as_str(), unit);
+        let output = self.systemctl(&[performed.as_str(), unit])?;
+
+        let outcome = NotifyOutcome { notify: notify.clone(), performed: Some(performed), state: self.state(unit)? };
+        if !output.success() || !outcome.success() {
+            return Err(LaszooError::Other(format!(
+                "systemctl {} {} failed, unit is {}: {}", performed.as_str(), unit, outcome.state, output.stderr.trim_end()
+            )));
+        }
+        Ok(outcome)
+    }
+}
+
+impl Default for Systemd {
+    fn default() -> Self {
+        Self::new(crate::config::ActionsConfig::default().timeout())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    /// Answers systemctl from a table of canned outputs, keeping the calls
+    #[derive(Default, Clone)]
+    struct FakeSystemctl {
+        answers: Arc<Mutex<HashMap<String, Vec<CommandOutput>>>>,
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl FakeSystemctl {
+        fn answer(&self, args: &str, stdout: &str, code: i32) {
+            let output = CommandOutput { code: Some(code), stdout: format!("{}\n", stdout), stderr: String::new() };
+            self.answers.lock().unwrap().entry(args.to_string()).or_default().push(output);
+        }
+    }
+
+    impl CommandRunner for FakeSystemctl {
+        fn run(&self, program: &str, args: &[&str]) -> Result<CommandOutput> {
+            assert_eq!(program, "systemctl");
+            let args = args.join(" ");
+            self.calls.lock().unwrap().push(args.clone());
+            let mut answers = self.answers.lock().unwrap();
+            let queue = answers.get_mut(&args).unwrap_or_else(|| panic!("unexpected systemctl {}", args));
+            Ok(if queue.len() > 1 { queue.remove(0) } else { queue[0].clone() })
+        }
+    }
+
+    #[test]
+    fn test_reload_falls_back_to_restart() {
+        let fake = FakeSystemctl::default();
+        fake.answer("is-active nginx", "active", 0);
+        fake.answer("show --property=CanReload --value nginx", "no", 0);
+        fake.answer("restart nginx", "", 0);
+        let systemd = Systemd::with_runner(Box::new(fake.clone()));
+
+        let outcome = systemd.notify(&"nginx:reload".parse().unwrap()).unwrap();
+        assert_eq!(outcome.performed, Some(UnitAction::Restart));
+        assert_eq!(outcome.to_string(), "nginx can't reload, restart instead, now active");
+        assert_eq!(*fake.calls.lock().unwrap(), [
+            "is-active nginx", "show --property=CanReload --value nginx", "restart nginx", "is-active nginx",
+        ]);
+    }
+
+    #[test]
+    fn test_stopped_unit_is_not_reloaded_but_restarted() {
+        let fake = FakeSystemctl::default();
+        fake.answer("is-active php-fpm", "inactive", 3);
+        fake.answer("is-active redis", "inactive", 3);
+        fake.answer("is-active redis", "active", 0);
+        fake.answer("restart redis", "", 0);
+        fake.answer("is-active nginx", "active", 0);
+        fake.answer("is-active nginx", "failed", 3);
+        fake.answer("restart nginx", "", 1);
+        let systemd = Systemd::with_runner(Box::new(fake.clone()));
+
+        let outcome = systemd.notify(&"php-fpm:reload".parse().unwrap()).unwrap();
+        assert_eq!(outcome.performed, None);
+        assert_eq!(outcome.to_string(), "php-fpm is not running, reload skipped (inactive)");
+
+        let outcome = systemd.notify(&"redis:restart".parse().unwrap()).unwrap();
+        assert_eq!(outcome.performed, Some(UnitAction::Restart));
+        assert_eq!(outcome.to_string(), "redis restart, now active");
+
+        let err = systemd.notify(&"nginx:restart".parse().unwrap()).unwrap_err();
+        assert!(err.to_string().contains("systemctl restart nginx failed, unit is failed"), "{}", err);
+
+        assert!("nginx".parse::<ServiceNotify>().is_err());
+        assert!("nginx:stop".parse::<ServiceNotify>().is_err());
+        assert_eq!(serde_json::to_string(&"app@1.service:reload".parse::<ServiceNotify>().unwrap()).unwrap(),
+            "\"app@1.service:reload\"");
+    }
+
+    #[test]
+    fn test_system_runner_times_out() {
+        let output = SystemRunner::new(None).run("sh", &["-c", "echo ok"]).unwrap();
+        assert_eq!(output, CommandOutput { code: Some(0), stdout: "ok\n".to_string(), stderr: String::new() });
+
+        let err = SystemRunner::new(Some(Duration::from_millis(200))).run("sleep", &["30"]).unwrap_err();
+        assert!(err.to_string().contains("`sleep 30` timed out"), "{}", err);
+    }
+}
@@ -13,10 +13,173 @@ pub struct TemplateEngine {
 
 impl TemplateEngine {
     /// Merge file changes back to template while preserving variables
-    pub fn merge_file_changes_to_template(&self, template_content: &str, file_content: &str) -> Result<String> {
-        // For now, just return the file content as the new template
-        // TODO: Implement intelligent merging that preserves {{ variables }} and [[x quack x]] tags
-        Ok(file_content.to_string())
+    ///
+    /// This is a three-way merge: `previous_render` is what the template rendered
+    /// to before the local edit, and `file_content` is the edited file. Edits to
+    /// literal text are mapped back into the template, edits inside a quack tag
+    /// update the tag's content, and `{{ handlebars }}` expressions are kept where
+    /// they were. An edit that touches the rendered output of an expression cannot
+    /// be expressed in the template and fails with `LaszooError::SyncConflict`.
+    pub fn merge_file_changes_to_template(
+        &self,
+        template_content: &str,
+        previous_render: &str,
+        file_content: &str,
+    ) -> Result<String> {
+        if previous_render == file_content {
+            return Ok(template_content.to_string());
+        }
+        
+        let segments = self.align_segments(template_content, previous_render)?;
+        let edits = compute_edits(previous_render, file_content);
+        
+        // Translate each edit of the render into an edit of the template
+        let mut template_edits: Vec<(std::ops::Range<usize>, String)> = Vec::new();
+        for (range, replacement) in edits {
+            let target = segments.iter().find(|seg| {
+                let editable = match seg.kind {
+                    SegmentKind::Literal => true,
+                    // Quack content renders verbatim, so it can be edited in place
+                    SegmentKind::Quack => previous_render[seg.rendered.clone()] == template_content[seg.template.clone()],
+                    SegmentKind::Expression => false,
+                };
+                editable && seg.rendered.start <= range.start && range.end <= seg.rendered.end
+            });
+            
+            match target {
+                Some(seg) => {
+                    let start = seg.template.start + (range.start - seg.rendered.start);
+                    let end = seg.template.start + (range.end - seg.rendered.start);
+                    template_edits.push((start..end, replacement));
+                }
+                None => {
+                    let line = previous_render[..range.start].matches('\n').count() + 1;
+                    let touched = segments.iter()
+                        .filter(|seg| seg.kind != SegmentKind::Literal)
+                        .find(|seg| seg.rendered.start < range.end.max(range.start + 1) && range.start < seg.rendered.end.max(seg.rendered.start + 1))
+                        .map(|seg| template_content[seg.source.clone()].to_string())
+                        .unwrap_or_default();
+                    return Err(LaszooError::SyncConflict(format!(
+                        "Local edit at line {} changes rendered template expression `{}`; update the template by hand",
+                        line, touched.trim()
+                    )));
+                }
+            }
+        }
+        
+        let mut merged = template_content.to_string();
+        for (range, replacement) in template_edits.into_iter().rev() {
+            merged.replace_range(range, &replacement);
+        }
+        
+        debug!("Merged {} bytes of local changes into template", file_content.len());
+        Ok(merged)
+    }
+    
+    /// Split a template into literal text and dynamic regions, and locate each
+    /// region in a render of that template
+    fn align_segments(&self, template: &str, rendered: &str) -> Result<Vec<Segment>> {
+        let regions = self.dynamic_regions(template);
+        
+        // Literal text between dynamic regions anchors the alignment
+        let mut literals = Vec::new();
+        let mut cursor = 0;
+        for region in &regions {
+            literals.push(cursor..region.source.start);
+            cursor = region.source.end;
+        }
+        literals.push(cursor..template.len());
+        
+        let positions = align_literals(template, &literals, rendered)
+            .ok_or_else(|| LaszooError::SyncConflict(
+                "Cannot line up the previous render with its template; update the template by hand".to_string()
+            ))?;
+        
+        let mut segments = Vec::new();
+        for (index, literal) in literals.iter().enumerate() {
+            let start = positions[index];
+            segments.push(Segment {
+                kind: SegmentKind::Literal,
+                source: literal.clone(),
+                template: literal.clone(),
+                rendered: start..start + literal.len(),
+            });
+            
+            if let Some(region) = regions.get(index) {
+                let rendered_end = positions[index + 1];
+                segments.push(Segment {
+                    kind: region.kind,
+                    source: region.source.clone(),
+                    template: region.content.clone(),
+                    rendered: start + literal.len()..rendered_end,
+                });
+            }
+        }
+        
+        Ok(segments)
+    }
+    
+    /// Find handlebars expressions, blocks and quack tags in a template.
+    /// Adjacent regions are combined since their renders cannot be told apart.
+    fn dynamic_regions(&self, template: &str) -> Vec<DynamicRegion> {
+        let mut regions: Vec<DynamicRegion> = Vec::new();
+        
+        let quacks: Vec<DynamicRegion> = self.quack_regex.captures_iter(template)
+            .map(|caps| {
+                let full = caps.get(0).unwrap();
+                let content = caps.get(1).map_or(full.start()..full.start(), |m| m.range());
+                // Quack content with expressions inside is rendered, not copied
+                let kind = if template[content.clone()].contains("{{") {
+                    SegmentKind::Expression
+                } else {
+                    SegmentKind::Quack
+                };
+                DynamicRegion { kind, source: full.range(), content }
+            })
+            .collect();
+        
+        let mut position = 0;
+        let mut quack_iter = quacks.into_iter().peekable();
+        while position < template.len() {
+            let next_quack = quack_iter.peek().map(|q| q.source.start);
+            let next_expr = template[position..].find("{{").map(|i| i + position);
+            
+            match (next_quack, next_expr) {
+                (Some(q), e) if e.is_none_or(|e| q <= e) => {
+                    let quack = quack_iter.next().unwrap();
+                    position = quack.source.end;
+                    regions.push(quack);
+                }
+                (_, Some(start)) => {
+                    let end = handlebars_region_end(template, start);
+                    position = end;
+                    regions.push(DynamicRegion {
+                        kind: SegmentKind::Expression,
+                        source: start..end,
+                        content: start..end,
+                    });
+                    // Skip quack tags swallowed by a block expression
+                    while quack_iter.peek().is_some_and(|q| q.source.start < end) {
+                        quack_iter.next();
+                    }
+                }
+                _ => break,
+            }
+        }
+        
+        // Combine regions that touch, there is no literal text to separate them
+        let mut combined: Vec<DynamicRegion> = Vec::new();
+        for region in regions {
+            match combined.last_mut() {
+                Some(last) if last.source.end == region.source.start => {
+                    last.kind = SegmentKind::Expression;
+                    last.source.end = region.source.end;
+                    last.content = last.source.clone();
+                }
+                _ => combined.push(region),
+            }
+        }
+        combined
     }
 
     pub fn new() -> Result<Self> {
@@ -88,6 +251,19 @@ impl TemplateEngine {
             .collect()
     }
     
+    /// Template content with `{{ }}` expressions and quack tags removed, so
+    /// versions that differ only in host-specific values compare equal
+    pub fn normalize_template(&self, template_content: &str) -> String {
+        let mut normalized = String::with_capacity(template_content.len());
+        let mut position = 0;
+        for region in self.dynamic_regions(template_content) {
+            normalized.push_str(&template_content[position..region.source.start]);
+            position = region.source.end;
+        }
+        normalized.push_str(&template_content[position..]);
+        normalized
+    }
+    
     /// Compare two templates and identify divergences
     pub fn compare_templates(
         &self,
@@ -170,12 +346,18 @@ impl TemplateEngine {
 
 /// Process template with handlebars variables only
 pub fn process_handlebars(template_content: &str, hostname: &str) -> Result<String> {
-    let engine = TemplateEngine::new()?;
     let mut vars = HashMap::new();
     vars.insert("hostname".to_string(), serde_json::json!(hostname));
     
+    process_handlebars_with_vars(template_content, &vars)
+}
+
+/// Process template with the given handlebars variables, rendering quack tags as their content
+pub fn process_handlebars_with_vars(template_content: &str, vars: &HashMap<String, Value>) -> Result<String> {
+    let engine = TemplateEngine::new()?;
+    
     // First process the template to expand handlebars variables
-    let processed = engine.process_template(template_content, &vars, false)?;
+    let processed = engine.process_template(template_content, vars, false)?;
     
     // Then handle quack tags - render them as their content (without the [[x x]] markers)
     let final_content = engine.quack_regex.replace_all(&processed, |caps: &regex::Captures| {
@@ -185,8 +367,9 @@ pub fn process_handlebars(template_content: &str, hostname: &str) -> Result<Stri
     Ok(final_content)
 }
 
-/// Process template with quack tags from machine-specific content
-pub fn process_with_quacks(group_template: &str, machine_template: &str) -> Result<String> {
+/// Process template with quack tags from machine-specific content, rendering
+/// the rest of the group template with `vars`
+pub fn process_with_quacks(group_template: &str, machine_template: &str, vars: &HashMap<String, Value>) -> Result<String> {
     let engine = TemplateEngine::new()?;
     
     // Extract quack tags from machine template
@@ -206,20 +389,197 @@ pub fn process_with_quacks(group_template: &str, machine_template: &str) -> Resu
         .map(|m| (m.start(), m.end()))
         .collect();
     
-    // Replace in reverse order to maintain correct positions
+    // Mark each placeholder so the machine's values are kept out of the
+    // handlebars render, replacing in reverse order to keep positions valid
+    let marker = |i: usize| format!("__QUACK_VALUE_{}__", i);
     for (i, (start, end)) in placeholders.iter().enumerate().rev() {
-        if let Some(quack_tag) = machine_quacks.get(i) {
-            result.replace_range(*start..*end, &quack_tag.content);
-        } else {
-            // If no corresponding quack tag, replace with empty string
-            result.replace_range(*start..*end, "");
-        }
+        result.replace_range(*start..*end, &marker(i));
+    }
+    let mut result = process_handlebars_with_vars(&result, vars)?;
+    
+    for i in 0..placeholders.len() {
+        // If no corresponding quack tag, replace with empty string
+        let value = machine_quacks.get(i).map_or("", |tag| tag.content.as_str());
+        result = result.replacen(&marker(i), value, 1);
     }
     
     debug!("Final result: {:?}", result);
     Ok(result)
 }
 
+/// Find where the handlebars expression or block starting at `start` ends
+fn handlebars_region_end(template: &str, start: usize) -> usize {
+    let tag_end = |from: usize| -> usize {
+        let close = if template[from..].starts_with("{{{") { "}}}" } else { "}}" };
+        let close = if template[from..].starts_with("{{!--") { "--}}" } else { close };
+        template[from + 2..].find(close)
+            .map(|i| from + 2 + i + close.len())
+            .unwrap_or(template.len())
+    };
+    
+    let first_end = tag_end(start);
+    let opener = template[start + 2..first_end].trim_start_matches('~').trim_start();
+    if !opener.starts_with('#') && !opener.starts_with('^') {
+        return first_end;
+    }
+    
+    // Block helper: include everything up to the matching close tag
+    let mut depth = 1;
+    let mut position = first_end;
+    while let Some(offset) = template[position..].find("{{") {
+        let tag_start = position + offset;
+        let end = tag_end(tag_start);
+        let inner = template[tag_start + 2..end].trim_start_matches(['{', '~']).trim_start();
+        if inner.starts_with('#') {
+            depth += 1;
+        } else if inner.starts_with('/') {
+            depth -= 1;
+            if depth == 0 {
+                return end;
+            }
+        }
+        position = end;
+    }
+    template.len()
+}
+
+/// Locate each literal of a template, in order, within a render of it.
+/// Returns the render offset of every literal, backtracking when a literal also
+/// occurs inside the output of the expression before it.
+fn align_literals(template: &str, literals: &[std::ops::Range<usize>], rendered: &str) -> Option<Vec<usize>> {
+    fn search(
+        template: &str,
+        literals: &[std::ops::Range<usize>],
+        rendered: &str,
+        index: usize,
+        from: usize,
+        positions: &mut Vec<usize>,
+        budget: &mut usize,
+    ) -> bool {
+        if *budget == 0 {
+            return false;
+        }
+        *budget -= 1;
+        
+        let text = &template[literals[index].clone()];
+        let last = index == literals.len() - 1;
+        
+        let candidates: Vec<usize> = if index == 0 {
+            if rendered.starts_with(text) { vec![0] } else { vec![] }
+        } else if last {
+            if rendered.len() >= from + text.len() && rendered.ends_with(text) {
+                vec![rendered.len() - text.len()]
+            } else {
+                vec![]
+            }
+        } else if text.is_empty() {
+            (from..=rendered.len()).filter(|i| rendered.is_char_boundary(*i)).collect()
+        } else {
+            rendered[from..].match_indices(text).map(|(i, _)| from + i).collect()
+        };
+        
+        for candidate in candidates {
+            positions.push(candidate);
+            if last || search(template, literals, rendered, index + 1, candidate + text.len(), positions, budget) {
+                return true;
+            }
+            positions.pop();
+        }
+        false
+    }
+    
+    let mut positions = Vec::with_capacity(literals.len());
+    let mut budget = 10_000;
+    if search(template, literals, rendered, 0, 0, &mut positions, &mut budget) {
+        Some(positions)
+    } else {
+        None
+    }
+}
+
+/// Compute the edits turning `old` into `new` as (byte range in old, replacement).
+/// Changed lines are refined to character level so an edit elsewhere on a line
+/// does not appear to touch an expression on that line.
+fn compute_edits(old: &str, new: &str) -> Vec<(std::ops::Range<usize>, String)> {
+    use similar::{DiffOp, TextDiff};
+    
+    fn line_offsets(text: &str) -> Vec<usize> {
+        let mut offsets = vec![0];
+        for line in text.split_inclusive('\n') {
+            offsets.push(offsets.last().unwrap() + line.len());
+        }
+        offsets
+    }
+    
+    fn char_offsets(text: &str) -> Vec<usize> {
+        let mut offsets: Vec<usize> = text.char_indices().map(|(i, _)| i).collect();
+        offsets.push(text.len());
+        offsets
+    }
+    
+    let old_lines = line_offsets(old);
+    let new_lines = line_offsets(new);
+    let mut edits = Vec::new();
+    
+    for op in TextDiff::from_lines(old, new).ops() {
+        match *op {
+            DiffOp::Equal { .. } => {}
+            DiffOp::Delete { old_index, old_len, .. } => {
+                edits.push((old_lines[old_index]..old_lines[old_index + old_len], String::new()));
+            }
+            DiffOp::Insert { old_index, new_index, new_len } => {
+                let at = old_lines[old_index];
+                edits.push((at..at, new[new_lines[new_index]..new_lines[new_index + new_len]].to_string()));
+            }
+            DiffOp::Replace { old_index, old_len, new_index, new_len } => {
+                let old_start = old_lines[old_index];
+                let old_block = &old[old_start..old_lines[old_index + old_len]];
+                let new_block = &new[new_lines[new_index]..new_lines[new_index + new_len]];
+                let old_chars = char_offsets(old_block);
+                let new_chars = char_offsets(new_block);
+                
+                for char_op in TextDiff::from_chars(old_block, new_block).ops() {
+                    let (old_range, new_range) = (char_op.old_range(), char_op.new_range());
+                    if matches!(char_op, DiffOp::Equal { .. }) {
+                        continue;
+                    }
+                    edits.push((
+                        old_start + old_chars[old_range.start]..old_start + old_chars[old_range.end],
+                        new_block[new_chars[new_range.start]..new_chars[new_range.end]].to_string(),
+                    ));
+                }
+            }
+        }
+    }
+    
+    edits
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SegmentKind {
+    Literal,
+    Quack,
+    Expression,
+}
+
+/// A dynamic part of a template: `source` is the whole tag, `content` the part
+/// a local edit may rewrite (the text inside a quack tag)
+#[derive(Debug, Clone)]
+struct DynamicRegion {
+    kind: SegmentKind,
+    source: std::ops::Range<usize>,
+    content: std::ops::Range<usize>,
+}
+
+/// A template region with its location in the template and in a render of it
+#[derive(Debug, Clone)]
+struct Segment {
+    kind: SegmentKind,
+    source: std::ops::Range<usize>,
+    template: std::ops::Range<usize>,
+    rendered: std::ops::Range<usize>,
+}
+
 #[derive(Debug, Clone)]
 pub struct QuackTag {
     pub id: usize,
@@ -284,4 +644,72 @@ server {
         assert!(result.contains("host = \"example.com\""));
         assert!(result.contains("[[x debug = true x]]"));
     }
+    
+    #[test]
+    fn test_hybrid_render_uses_vars() {
+        let mut vars = HashMap::new();
+        vars.insert("nginx".to_string(), serde_json::json!({"port": 8080}));
+        
+        let result = process_with_quacks(
+            "port = {{ nginx.port }}\nworkers = {{ quack }}\n",
+            "[[x {{ not rendered }} x]]",
+            &vars,
+        ).unwrap();
+        assert_eq!(result, "port = 8080\nworkers = {{ not rendered }}\n");
+    }
+    
+    #[test]
+    fn test_merge_literal_changes_preserves_variables() {
+        let engine = TemplateEngine::new().unwrap();
+        let template = "server_name {{ hostname }};\nlisten 80;\n";
+        let previous = "server_name web01;\nlisten 80;\n";
+        let edited = "server_name web01;\nlisten 8080;\nkeepalive 65;\n";
+        
+        let merged = engine.merge_file_changes_to_template(template, previous, edited).unwrap();
+        assert_eq!(merged, "server_name {{ hostname }};\nlisten 8080;\nkeepalive 65;\n");
+    }
+    
+    #[test]
+    fn test_merge_edit_on_line_with_variable() {
+        let engine = TemplateEngine::new().unwrap();
+        let template = "host = {{ hostname }} # primary\n{{#if debug}}debug = true\n{{/if}}end\n";
+        let previous = "host = web01 # primary\nend\n";
+        let edited = "host = web01 # secondary\nend\n";
+        
+        let merged = engine.merge_file_changes_to_template(template, previous, edited).unwrap();
+        assert_eq!(merged, "host = {{ hostname }} # secondary\n{{#if debug}}debug = true\n{{/if}}end\n");
+    }
+    
+    #[test]
+    fn test_merge_updates_quack_content() {
+        let engine = TemplateEngine::new().unwrap();
+        let template = "a\n[[x port = 80 x]]\nb\n";
+        let previous = "a\nport = 80\nb\n";
+        let edited = "a\nport = 81\nb\n";
+        
+        let merged = engine.merge_file_changes_to_template(template, previous, edited).unwrap();
+        assert_eq!(merged, "a\n[[x port = 81 x]]\nb\n");
+    }
+    
+    #[test]
+    fn test_merge_conflict_on_variable_edit() {
+        let engine = TemplateEngine::new().unwrap();
+        let template = "server_name {{ hostname }};\nlisten 80;\n";
+        let previous = "server_name web01;\nlisten 80;\n";
+        let edited = "server_name web02;\nlisten 80;\n";
+        
+        let result = engine.merge_file_changes_to_template(template, previous, edited);
+        assert!(matches!(result, Err(LaszooError::SyncConflict(_))));
+    }
+    
+    #[test]
+    fn test_normalize_ignores_host_specific_values() {
+        let engine = TemplateEngine::new().unwrap();
+        let web01 = "name = {{ hostname }}\n[[x port = 80 x]]\nworkers = 4\n";
+        let web02 = "name = {{ hostname }}\n[[x port = 8080 x]]\nworkers = 4\n";
+        
+        assert_eq!(engine.normalize_template(web01), "name = \n\nworkers = 4\n");
+        assert_eq!(engine.normalize_template(web01), engine.normalize_template(web02));
+        assert_ne!(engine.normalize_template(web01), engine.normalize_template("workers = 8\n"));
+    }
 }
\ No newline at end of file
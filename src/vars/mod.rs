@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use serde_json::{Map, Value};
+use tracing::debug;
+use crate::error::{LaszooError, Result};
+
+/// Where a set of template variables is stored
+#[derive(Debug, Clone, PartialEq)]
+pub enum VarScope {
+    Group(String),
+    Machine(String),
+}
+
+impl std::fmt::Display for VarScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VarScope::Group(group) => write!(f, "group {}", group),
+            VarScope::Machine(host) => write!(f, "machine {}", host),
+        }
+    }
+}
+
+/// Loads and stores template variables from `vars.toml`/`vars.json` files in
+/// group and machine directories on the shared filesystem.
+///
+/// Variables are merged with machine values taking precedence over group
+/// values, which take precedence over built-ins such as `hostname`. Nested
+/// tables are merged key by key. When a directory has both files, `vars.json`
+/// overrides `vars.toml`.
+pub struct VarsManager {
+    mfs_mount: PathBuf,
+    hostname: String,
+}
+
+impl VarsManager {
+    pub fn new(mfs_mount: PathBuf, hostname: String) -> Self {
+        Self { mfs_mount, hostname }
+    }
+
+    /// Directory holding the variable files for a scope
+    pub fn scope_dir(&self, scope: &VarScope) -> PathBuf {
+        match scope {
+            VarScope::Group(group) => crate::fs::get_group_dir(&self.mfs_mount, "", group),
+            VarScope::Machine(host) => crate::fs::get_machine_dir(&self.mfs_mount, "", host),
+        }
+    }
+
+    /// Variables every template gets regardless of vars files: `hostname`
+    /// and the local host's `facts`
+    pub fn builtin_vars(&self) -> Map<String, Value> {
+        let mut vars = Map::new();
+        vars.insert("hostname".to_string(), Value::String(self.hostname.clone()));
+        if let Ok(facts) = serde_json::to_value(crate::facts::HostFacts::local()) {
+            vars.insert("facts".to_string(), facts);
+        }
+        vars
+    }
+
+    /// Load the variables defined directly in a scope
+    pub fn load_scope(&self, scope: &VarScope) -> Result<Map<String, Value>> {
+        let dir = self.scope_dir(scope);
+        let mut vars = Map::new();
+
+        let toml_path = dir.join("vars.toml");
+        if toml_path.exists() {
+            let content = std::fs::read_to_string(&toml_path)?;
+            let table: toml::Table = toml::from_str(&content)?;
+            let value = serde_json::to_value(table)?;
+            merge_into(&mut vars, value_to_map(value, &toml_path)?);
+        }
+
+        let json_path = dir.join("vars.json");
+        if json_path.exists() {
+            let content = std::fs::read_to_string(&json_path)?;
+            let value: Value = serde_json::from_str(&content)?;
+            merge_into(&mut vars, value_to_map(value, &json_path)?);
+        }
+
+        debug!("Loaded {} variables for {}", vars.len(), scope);
+        Ok(vars)
+    }
+
+    /// Variables for rendering a template of `group` on this machine
+    pub fn variables_for(&self, group: Option<&str>) -> Result<HashMap<String, Value>> {
+        let mut vars = self.builtin_vars();
+
+        if let Some(group) = group {
+            merge_into(&mut vars, self.load_scope(&VarScope::Group(group.to_string()))?);
+        }
+        merge_into(&mut vars, self.load_scope(&VarScope::Machine(self.hostname.clone()))?);
+
+        Ok(vars.into_iter().collect())
+    }
+
+    /// Set a variable in a scope. Dotted keys address nested tables.
+    ///
+    /// Values are written to `vars.json` if it exists, since it overrides
+    /// `vars.toml`, otherwise to `vars.toml`.
+    pub fn set(&self, scope: &VarScope, key: &str, value: Value) -> Result<()> {
+        let dir = self.scope_dir(scope);
+        std::fs::create_dir_all(&dir)?;
+
+        let json_path = dir.join("vars.json");
+        let toml_path = dir.join("vars.toml");
+        let use_json = json_path.exists();
+        let path = if use_json { json_path } else { toml_path };
+
+        let mut vars = if path.exists() {
+            let content = std::fs::read_to_string(&path)?;
+            let value = if use_json {
+                serde_json::from_str(&content)?
+            } else {
+                serde_json::to_value(toml::from_str::<toml::Table>(&content)?)?
+            };
+            value_to_map(value, &path)?
+        } else {
+            Map::new()
+        };
+
+        set_path(&mut vars, key, value)?;
+
+        let content = if use_json {
+            serde_json::to_string_pretty(&vars)?
+        } else {
+            toml::to_string_pretty(&vars)
+                .map_err(|e| LaszooError::Template(format!("Cannot write {:?}: {}", path, e)))?
+        };
+        std::fs::write(&path, content)?;
+
+        debug!("Set {} for {} in {:?}", key, scope, path);
+        Ok(())
+    }
+}
+
+/// Parse a value given on the command line: JSON literals (numbers, booleans,
+/// arrays, objects, quoted strings) are kept typed, anything else is a string
+pub fn parse_value(raw: &str) -> Value {
+    serde_json::from_str(raw).unwrap_or_else(|_| Value::String(raw.to_string()))
+}
+
+/// Look up a dotted key in a set of variables
+pub fn get_path<'a>(vars: &'a Map<String, Value>, key: &str) -> Option<&'a Value> {
+    let mut parts = key.split('.');
+    let mut current = vars.get(parts.next()?)?;
+    for part in parts {
+        current = current.as_object()?.get(part)?;
+    }
+    Some(current)
+}
+
+/// Flatten nested tables into dotted keys, sorted
+pub fn flatten(vars: &Map<String, Value>) -> Vec<(String, Value)> {
+    fn walk(prefix: &str, vars: &Map<String, Value>, out: &mut Vec<(String, Value)>) {
+        for (key, value) in vars {
+            let full_key = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+            match value {
+                Value::Object(table) if !table.is_empty() => walk(&full_key, table, out),
+                _ => out.push((full_key, value.clone())),
+            }
+        }
+    }
+
+    let mut out = Vec::new();
+    walk("", vars, &mut out);
+    out.sort_by(|a, b| a.0.cmp(&b.0));
+    out
+}
+
+/// Merge `overlay` into `base`, recursing into tables present in both
+fn merge_into(base: &mut Map<String, Value>, overlay: Map<String, Value>) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Object(existing)), Value::Object(table)) => merge_into(existing, table),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+fn set_path(vars: &mut Map<String, Value>, key: &str, value: Value) -> Result<()> {
+    let parts: Vec<&str> = key.split('.').collect();
+    if parts.iter().any(|p| p.is_empty()) {
+        return Err(LaszooError::Template(format!("Invalid variable name: {}", key)));
+    }
+
+    let mut current = vars;
+    for part in &parts[..parts.len() - 1] {
+        let entry = current.entry(part.to_string()).or_insert_with(|| Value::Object(Map::new()));
+        current = entry.as_object_mut().ok_or_else(|| LaszooError::Template(format!(
+            "Cannot set {}: {} is not a table", key, part
+        )))?;
+    }
+    current.insert(parts[parts.len() - 1].to_string(), value);
+    Ok(())
+}
+
+fn value_to_map(value: Value, path: &Path) -> Result<Map<String, Value>> {
+    match value {
+        Value::Object(map) => Ok(map),
+        _ => Err(LaszooError::Template(format!("Variables in {:?} must be a table", path))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_precedence_machine_over_group_over_builtin() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = VarsManager::new(dir.path().to_path_buf(), "web01".to_string());
+
+        let group_dir = manager.scope_dir(&VarScope::Group("web".to_string()));
+        std::fs::create_dir_all(&group_dir).unwrap();
+        std::fs::write(group_dir.join("vars.toml"), "port = 80\nhostname = \"group\"\n[nginx]\nworkers = 2\nuser = \"www\"\n").unwrap();
+
+        let machine_dir = manager.scope_dir(&VarScope::Machine("web01".to_string()));
+        std::fs::create_dir_all(&machine_dir).unwrap();
+        std::fs::write(machine_dir.join("vars.json"), r#"{"port": 8080, "nginx": {"workers": 8}}"#).unwrap();
+
+        let vars = manager.variables_for(Some("web")).unwrap();
+        assert_eq!(vars["port"], serde_json::json!(8080));
+        assert_eq!(vars["hostname"], serde_json::json!("group"));
+        assert_eq!(vars["nginx"], serde_json::json!({"workers": 8, "user": "www"}));
+    }
+
+    #[test]
+    fn test_set_nested_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = VarsManager::new(dir.path().to_path_buf(), "web01".to_string());
+        let scope = VarScope::Group("web".to_string());
+
+        manager.set(&scope, "nginx.port", parse_value("8080")).unwrap();
+        manager.set(&scope, "nginx.name", parse_value("example")).unwrap();
+
+        let vars = manager.load_scope(&scope).unwrap();
+        assert_eq!(get_path(&vars, "nginx.port"), Some(&serde_json::json!(8080)));
+        assert_eq!(flatten(&vars), vec![
+            ("nginx.name".to_string(), serde_json::json!("example")),
+            ("nginx.port".to_string(), serde_json::json!(8080)),
+        ]);
+        assert!(manager.scope_dir(&scope).join("vars.toml").exists());
+    }
+
+    #[test]
+    fn test_set_writes_json_when_both_files_exist() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = VarsManager::new(dir.path().to_path_buf(), "web01".to_string());
+        let scope = VarScope::Group("web".to_string());
+
+        let group_dir = manager.scope_dir(&scope);
+        std::fs::create_dir_all(&group_dir).unwrap();
+        std::fs::write(group_dir.join("vars.toml"), "port = 80
+user = \"www\"\n").unwrap();
+        std::fs::write(group_dir.join("vars.json"), r#"{"port": 8080}"#).unwrap();
+
+        manager.set(&scope, "port", parse_value("9090")).unwrap();
+
+        let vars = manager.load_scope(&scope).unwrap();
+        assert_eq!(vars["port"], serde_json::json!(9090));
+        assert_eq!(vars["user"], serde_json::json!("www"));
+        let toml = std::fs::read_to_string(group_dir.join("vars.toml")).unwrap();
+        assert_eq!(toml, "port = 80\nuser = \"www\"\n");
+    }
+}
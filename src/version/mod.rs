@@ -0,0 +1,317 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+use tracing::{debug, info, warn};
+use crate::error::{LaszooError, Result};
+use crate::lock::LockManager;
+
+/// How long a template write may hold the template's lock
+const TEMPLATE_LEASE_TTL: std::time::Duration = std::time::Duration::from_secs(30);
+/// How long to wait for another host to finish writing a template
+const TEMPLATE_LOCK_WAIT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Version of a group template, recorded in a `.lasz.meta` file next to it.
+///
+/// Every write of a template through [`TemplateVersions`] bumps the
+/// generation, so a host can tell whether the template it last synced with
+/// is still the current one before merging a local edit into it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TemplateVersion {
+    pub generation: u64,
+    /// Checksum of the template content
+    pub hash: String,
+    /// Host that wrote this version
+    pub writer: String,
+    pub written_at: chrono::DateTime<chrono::Utc>,
+    /// Versions that lost a race with this one and wait to be resolved
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conflicts: Vec<Conflict>,
+}
+
+/// A local edit that could not be merged because the template changed
+/// underneath it, kept in a `.lasz.conflict-<host>` file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Conflict {
+    pub host: String,
+    /// Template version the host had edited, if known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub base: Option<String>,
+    /// Template version that was current when the edit was rejected
+    pub current: String,
+    pub detected_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl TemplateVersion {
+    /// Where the version of a template is stored
+    pub fn sidecar_path(template_path: &Path) -> PathBuf {
+        let mut path = template_path.to_path_buf();
+        let name = format!("{}.meta", path.file_name().unwrap_or_default().to_string_lossy());
+        path.set_file_name(name);
+        path
+    }
+
+    /// Load the version recorded for a template, if any
+    pub fn load(template_path: &Path) -> Result<Option<Self>> {
+        let path = Self::sidecar_path(template_path);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = std::fs::read_to_string(&path)?;
+        Ok(Some(serde_json::from_str(&content)?))
+    }
+
+    pub fn save(&self, template_path: &Path) -> Result<()> {
+        let content = serde_json::to_string_pretty(self)?;
+        crate::fs::atomic_write(&Self::sidecar_path(template_path), content.as_bytes(), |_| Ok(()))
+    }
+}
+
+/// Where a host's conflicting version of a template is saved
+pub fn conflict_path(template_path: &Path, host: &str) -> PathBuf {
+    let mut path = template_path.to_path_buf();
+    let name = format!("{}.conflict-{}", path.file_name().unwrap_or_default().to_string_lossy(), host);
+    path.set_file_name(name);
+    path
+}
+
+/// Optimistic concurrency for group templates.
+///
+/// Each host records in `machines/<host>/bases.json` which version of every
+/// template its local files were last synced with. Merging a local edit is
+/// only allowed while that base is still the template's current version; a
+/// host that lost the race gets a [`LaszooError::SyncConflict`] and its
+/// version is saved next to the template for `laszoo resolve`.
+pub struct TemplateVersions {
+    mfs_mount: PathBuf,
+    hostname: String,
+}
+
+impl TemplateVersions {
+    pub fn new(mfs_mount: PathBuf, hostname: String) -> Self {
+        Self { mfs_mount, hostname }
+    }
+
+    fn bases_path(&self) -> PathBuf {
+        crate::fs::get_machine_dir(&self.mfs_mount, "", &self.hostname).join("bases.json")
+    }
+
+    /// Key of a template in the bases file, its path relative to the mount
+    fn base_key(&self, template_path: &Path) -> String {
+        template_path.strip_prefix(&self.mfs_mount)
+            .unwrap_or(template_path)
+            .to_string_lossy()
+            .to_string()
+    }
+
+    fn locks(&self) -> LockManager {
+        LockManager::new(self.mfs_mount.clone())
+    }
+
+    /// Name of the lock guarding a template and its version file
+    fn lock_name(&self, template_path: &Path) -> String {
+        format!("template.{}", self.base_key(template_path))
+    }
+
+    fn load_bases(&self) -> Result<BTreeMap<String, String>> {
+        let path = self.bases_path();
+        if !path.exists() {
+            return Ok(BTreeMap::new());
+        }
+        let content = std::fs::read_to_string(&path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    /// Template version this host last synced the file with
+    pub fn base(&self, template_path: &Path) -> Result<Option<String>> {
+        Ok(self.load_bases()?.remove(&self.base_key(template_path)))
+    }
+
+    /// Record that this host's file now matches the template's current content
+    pub fn mark_synced(&self, template_path: &Path) -> Result<()> {
+        let hash = crate::fs::content_checksum(&std::fs::read(template_path)?);
+        let key = self.base_key(template_path);
+        let mut bases = self.load_bases()?;
+        if bases.get(&key) == Some(&hash) {
+            return Ok(());
+        }
+        bases.insert(key, hash);
+
+        let path = self.bases_path();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&path, serde_json::to_string_pretty(&bases)?)?;
+        Ok(())
+    }
+
+    /// Fail if the template changed since this host last synced with it.
+    /// Returns the hash of the current content, to pass to [`Self::write`].
+    pub fn check_base(&self, template_path: &Path, current: &[u8]) -> Result<String> {
+        let current_hash = crate::fs::content_checksum(current);
+        match self.base(template_path)? {
+            Some(base) if base != current_hash => Err(self.conflict_error(template_path)),
+            _ => Ok(current_hash),
+        }
+    }
+
+    /// Replace a template's content, provided it still has the content hashed
+    /// as `expected`. Bumps the template's generation and records the new
+    /// version as this host's base.
+    ///
+    /// The check and the write happen under the template's lock, so of two
+    /// hosts writing at once the second sees the first one's version.
+    pub fn write(&self, template_path: &Path, expected: &str, content: &[u8]) -> Result<TemplateVersion> {
+        let locks = self.locks();
+        let _lease = locks.hold(&self.lock_name(template_path), TEMPLATE_LEASE_TTL, TEMPLATE_LOCK_WAIT)?;
+        
+        let current = std::fs::read(template_path)?;
+        if crate::fs::content_checksum(&current) != expected {
+            return Err(self.conflict_error(template_path));
+        }
+        self.write_unchecked(template_path, content)
+    }
+
+    /// Replace a template's content regardless of its current version
+    pub fn write_unchecked(&self, template_path: &Path, content: &[u8]) -> Result<TemplateVersion> {
+        let locks = self.locks();
+        let _lease = locks.hold(&self.lock_name(template_path), TEMPLATE_LEASE_TTL, TEMPLATE_LOCK_WAIT)?;
+        
+        let previous = TemplateVersion::load(template_path)?;
+        crate::fs::atomic_write(template_path, content, |_| Ok(()))?;
+
+        let version = TemplateVersion {
+            generation: previous.as_ref().map_or(0, |v| v.generation) + 1,
+            hash: crate::fs::content_checksum(content),
+            writer: self.hostname.clone(),
+            written_at: chrono::Utc::now(),
+            conflicts: previous.map(|v| v.conflicts).unwrap_or_default(),
+        };
+        version.save(template_path)?;
+        self.mark_synced(template_path)?;
+        debug!("Wrote generation {} of {:?}", version.generation, template_path);
+        Ok(version)
+    }
+
+    fn conflict_error(&self, template_path: &Path) -> LaszooError {
+        let writer = TemplateVersion::load(template_path).ok().flatten()
+            .map(|v| format!(" by {}", v.writer))
+            .unwrap_or_default();
+        LaszooError::SyncConflict(format!(
+            "{} was changed{} since this host last synced it",
+            template_path.display(), writer
+        ))
+    }
+
+    /// Save this host's version of a template that lost a race, and record the
+    /// conflict in the template's version file. Returns where it was saved.
+    pub fn record_conflict(&self, template_path: &Path, content: &[u8]) -> Result<PathBuf> {
+        let path = conflict_path(template_path, &self.hostname);
+        crate::fs::atomic_write(&path, content, |_| Ok(()))?;
+
+        let locks = self.locks();
+        let _lease = locks.hold(&self.lock_name(template_path), TEMPLATE_LEASE_TTL, TEMPLATE_LOCK_WAIT)?;
+        let current = crate::fs::content_checksum(&std::fs::read(template_path)?);
+        let mut version = TemplateVersion::load(template_path)?.unwrap_or_else(|| TemplateVersion {
+            generation: 0,
+            hash: current.clone(),
+            writer: String::new(),
+            written_at: chrono::Utc::now(),
+            conflicts: Vec::new(),
+        });
+        version.conflicts.retain(|c| c.host != self.hostname);
+        version.conflicts.push(Conflict {
+            host: self.hostname.clone(),
+            base: self.base(template_path)?,
+            current,
+            detected_at: chrono::Utc::now(),
+        });
+        version.save(template_path)?;
+
+        warn!("Conflicting version of {:?} saved to {:?}", template_path, path);
+        Ok(path)
+    }
+
+    /// Unresolved conflicts of a template
+    pub fn conflicts(&self, template_path: &Path) -> Result<Vec<Conflict>> {
+        Ok(TemplateVersion::load(template_path)?.map(|v| v.conflicts).unwrap_or_default())
+    }
+
+    /// Settle the conflicts of a template. With `take`, that host's saved
+    /// version becomes the template; otherwise the current template is kept.
+    /// The saved versions are removed either way.
+    pub fn resolve(&self, template_path: &Path, take: Option<&str>) -> Result<TemplateVersion> {
+        let locks = self.locks();
+        let _lease = locks.hold(&self.lock_name(template_path), TEMPLATE_LEASE_TTL, TEMPLATE_LOCK_WAIT)?;
+        
+        let conflicts = self.conflicts(template_path)?;
+        if let Some(host) = take {
+            if !conflicts.iter().any(|c| c.host == host) {
+                return Err(LaszooError::Other(format!(
+                    "No conflicting version from {} for {}", host, template_path.display()
+                )));
+            }
+            let content = std::fs::read(conflict_path(template_path, host))?;
+            self.write_unchecked(template_path, &content)?;
+        }
+
+        for conflict in &conflicts {
+            let path = conflict_path(template_path, &conflict.host);
+            if let Err(e) = std::fs::remove_file(&path) {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    return Err(e.into());
+                }
+            }
+        }
+
+        let mut version = TemplateVersion::load(template_path)?
+            .ok_or_else(|| LaszooError::Other(format!("No version recorded for {}", template_path.display())))?;
+        version.conflicts.clear();
+        version.save(template_path)?;
+        info!("Resolved {} conflicts of {:?}", conflicts.len(), template_path);
+        Ok(version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stale_base_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let template = dir.path().join("groups/web/app.conf.lasz");
+        std::fs::create_dir_all(template.parent().unwrap()).unwrap();
+        std::fs::write(&template, "workers = 4\n").unwrap();
+
+        let web01 = TemplateVersions::new(dir.path().to_path_buf(), "web01".to_string());
+        let web02 = TemplateVersions::new(dir.path().to_path_buf(), "web02".to_string());
+        web01.mark_synced(&template).unwrap();
+        web02.mark_synced(&template).unwrap();
+
+        // web01 converges its edit first
+        let base = web01.check_base(&template, b"workers = 4\n").unwrap();
+        let version = web01.write(&template, &base, b"workers = 8\n").unwrap();
+        assert_eq!(version.generation, 1);
+        assert_eq!(version.writer, "web01");
+
+        // Written under the template's lock, released afterwards
+        let leases = LockManager::new(dir.path().to_path_buf()).list().unwrap();
+        assert_eq!(leases.len(), 1);
+        assert_eq!(leases[0].name, "template.groups/web/app.conf.lasz");
+        assert!(leases[0].is_expired());
+
+        // web02 edited the version web01 replaced
+        let result = web02.check_base(&template, &std::fs::read(&template).unwrap());
+        assert!(matches!(result, Err(LaszooError::SyncConflict(_))));
+
+        let saved = web02.record_conflict(&template, b"workers = 6\n").unwrap();
+        assert_eq!(saved, conflict_path(&template, "web02"));
+        assert_eq!(web02.conflicts(&template).unwrap().len(), 1);
+
+        let version = web01.resolve(&template, Some("web02")).unwrap();
+        assert_eq!(version.generation, 2);
+        assert!(version.conflicts.is_empty());
+        assert_eq!(std::fs::read_to_string(&template).unwrap(), "workers = 6\n");
+        assert!(!saved.exists());
+    }
+}
@@ -121,7 +121,9 @@ pub async fn enroll_file(
     let enrollment_manager = EnrollmentManager::new(
         state.config.mfs_mount.clone(),
         hostname,
-    );
+    )
+    .with_backups(crate::backup::BackupStore::from_config(&state.config))
+    .with_action_timeout(state.config.actions.timeout());
     
     let path = PathBuf::from(&req.path);
     let action = match req.action.as_str() {
@@ -132,16 +134,20 @@ pub async fn enroll_file(
         _ => crate::cli::SyncAction::Converge,
     };
     
-    match enrollment_manager.enroll_path(
-        &req.group,
-        Some(&path),
-        false,
-        req.machine_specific,
-        false,
-        None,
-        None,
-    ) {
+    let options = crate::enrollment::EnrollOptions {
+        machine_specific: req.machine_specific,
+        ..Default::default()
+    };
+    match enrollment_manager.enroll_path(&req.group, Some(&path), &options) {
         Ok(_) => {
+            let abs_path = crate::fs::absolute_path(&path).unwrap_or_else(|_| path.clone());
+            if let Err(e) = enrollment_manager.set_sync_action(&abs_path, action, req.machine_specific) {
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(ApiResponse::<&str>::error(format!("Failed to set sync action: {}", e)))
+                ).into_response();
+            }
+            
             // Update UI state
             let mut ui_state = state.ui_state.write().await;
             ui_state.enrolled_files.push(crate::webui::EnrolledFile {
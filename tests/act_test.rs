@@ -0,0 +1,112 @@
+mod common;
+
+use common::*;
+use std::fs;
+
+#[test]
+fn test_act_sets_clears_and_lists_actions() {
+    let env = TestEnvironment::new("act_set");
+    let app = env.create_test_file("app.conf", "workers = 4\n").canonicalize().unwrap();
+    let db = env.create_test_file("db.conf", "pool = 10\n").canonicalize().unwrap();
+    let log = env.test_dir.join("actions.log");
+
+    let output = env.run_laszoo(&["enroll", "testgroup", app.to_str().unwrap(), db.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    // Actions are set without re-enrolling, one command at a time
+    let reload = format!("echo reload >> {}", log.display());
+    let output = env.run_laszoo(&["act", app.to_str().unwrap(), "--group", "testgroup", "--after", &reload]).unwrap();
+    assert!(output.status.success(), "Act failed: {}", String::from_utf8_lossy(&output.stderr));
+    let output = env.run_laszoo(&["act", app.to_str().unwrap(), "--group", "testgroup", "--validate", "grep -q workers %s"]).unwrap();
+    assert!(output.status.success(), "Act failed: {}", String::from_utf8_lossy(&output.stderr));
+    let output = env.run_laszoo(&["act", db.to_str().unwrap(), "--group", "testgroup", "--before", "true"]).unwrap();
+    assert!(output.status.success(), "Act failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let template = env.mfs_mount.join("groups/testgroup").join(app.strip_prefix("/").unwrap()).with_extension("conf.lasz");
+    fs::write(&template, "workers = 8\n").unwrap();
+    let output = env.run_laszoo(&["apply", "testgroup"]).unwrap();
+    assert!(output.status.success(), "Apply failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(env.read_file(&log), "reload\n");
+
+    // Machine-specific actions replace the group's
+    let output = env.run_laszoo(&["act", "--machine", app.to_str().unwrap(), "--after", "true"]).unwrap();
+    assert!(output.status.success(), "Act failed: {}", String::from_utf8_lossy(&output.stderr));
+    let output = env.run_laszoo(&["act", "list"]).unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "List failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains(&format!("{}  (machine)  after `true`", app.display())), "{}", stdout);
+    assert!(stdout.contains(&format!("{}  (group: testgroup)  before `true`", db.display())), "{}", stdout);
+
+    // Clearing is planned by a dry run and done without one
+    let output = env.run_laszoo(&["--dry-run", "act", db.to_str().unwrap(), "--group", "testgroup", "--clear"]).unwrap();
+    assert!(String::from_utf8_lossy(&output.stdout).contains(&format!("clear actions for {}", db.display())));
+    let output = env.run_laszoo(&["act", db.to_str().unwrap(), "--group", "testgroup", "--clear"]).unwrap();
+    assert!(output.status.success(), "Act failed: {}", String::from_utf8_lossy(&output.stderr));
+    let output = env.run_laszoo(&["act", "list", "testgroup"]).unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!stdout.contains(db.to_str().unwrap()), "{}", stdout);
+
+    // Machine actions of files in other groups are not listed with this one
+    let cache = env.create_test_file("cache.conf", "size = 1\n").canonicalize().unwrap();
+    let output = env.run_laszoo(&["enroll", "othergroup", cache.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+    let output = env.run_laszoo(&["act", "--machine", cache.to_str().unwrap(), "--after", "true"]).unwrap();
+    assert!(output.status.success(), "Act failed: {}", String::from_utf8_lossy(&output.stderr));
+    let output = env.run_laszoo(&["act", "list", "testgroup"]).unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(&format!("{}  (machine)", app.display())), "{}", stdout);
+    assert!(!stdout.contains(cache.to_str().unwrap()), "{}", stdout);
+
+    // The path is always given, with either a group or --machine
+    let output = env.run_laszoo(&["act", "--machine", app.to_str().unwrap(), "--group", "testgroup", "--after", "true"]).unwrap();
+    assert!(!output.status.success());
+    let output = env.run_laszoo(&["act", app.to_str().unwrap(), "--after", "true"]).unwrap();
+    assert!(!output.status.success());
+
+    // Only enrolled files take actions
+    let other = env.create_test_file("other.conf", "x\n").canonicalize().unwrap();
+    let output = env.run_laszoo(&["act", other.to_str().unwrap(), "--group", "testgroup", "--after", "true"]).unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is not enrolled in group 'testgroup'"));
+}
+
+#[test]
+fn test_directory_and_glob_actions_cover_their_files() {
+    let env = TestEnvironment::new("act_scoped");
+    let conf_dir = env.test_dir.join("nginx");
+    for name in ["nginx.conf", "mime.types", "conf.d/gzip.conf"] {
+        env.create_test_file(&format!("nginx/{}", name), "# default\n");
+    }
+    let conf_dir = conf_dir.canonicalize().unwrap();
+    let log = env.test_dir.join("actions.log");
+
+    // Actions given for a directory apply to the files adopted into it
+    let reload = format!("echo \"reload $(echo \"$LASZOO_FILES\" | wc -l)\" >> {}", log.display());
+    let output = env.run_laszoo(&["enroll", "web", conf_dir.to_str().unwrap(), "--after", &reload]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    // A glob reaching deeper than the directory is more specific, for the
+    // files it matches
+    let pattern = format!("{}/conf.d/*.conf", conf_dir.display());
+    let test = format!("echo \"test $(echo \"$LASZOO_FILES\" | wc -l)\" >> {}", log.display());
+    let output = env.run_laszoo(&["act", &pattern, "--group", "web", "--after", &test]).unwrap();
+    assert!(output.status.success(), "Act failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let group_dir = env.mfs_mount.join("groups/web").join(conf_dir.strip_prefix("/").unwrap());
+    for name in ["nginx.conf.lasz", "mime.types.lasz", "conf.d/gzip.conf.lasz"] {
+        fs::write(group_dir.join(name), "# changed\n").unwrap();
+    }
+    let output = env.run_laszoo(&["apply", "web"]).unwrap();
+    assert!(output.status.success(), "Apply failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(env.read_file(&conf_dir.join("conf.d/gzip.conf")), "# changed\n");
+
+    // Each scoped action ran once, for every file it covers
+    let mut runs: Vec<String> = env.read_file(&log).lines().map(str::to_string).collect();
+    runs.sort();
+    assert_eq!(runs, ["reload 2", "test 1"]);
+
+    let output = env.run_laszoo(&["act", "list", "web"]).unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(&format!("{}  (group: web)  after", pattern)), "{}", stdout);
+    assert!(stdout.contains(&format!("{}  (group: web)  after", conf_dir.display())), "{}", stdout);
+}
@@ -1,3 +1,7 @@
+mod common;
+
+use common::*;
+use laszoo::enrollment::EnrollmentManager;
 use std::fs;
 use std::path::Path;
 use std::process::Command;
@@ -181,4 +185,252 @@ fn test_upgrade_all_with_actions() {
     assert!(content.contains("++upgrade"), "++upgrade not in packages.conf");
     assert!(content.contains("--start"), "--start flag not in packages.conf");
     assert!(content.contains("--end"), "--end flag not in packages.conf");
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_action_environment_timeout_and_records() {
+    let env = TestEnvironment::new("actions_timeout");
+    let test_file = env.create_test_file("app.conf", "workers = 4\n");
+
+    let output = env.run_laszoo(&[
+        "enroll", "testgroup", test_file.to_str().unwrap(),
+        "--after", "echo \"$LASZOO_PHASE $LASZOO_GROUP $LASZOO_FILE $LASZOO_TEMPLATE\"; sleep 30",
+    ]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let template_path = group_template(&env, "testgroup", &test_file);
+    fs::write(&template_path, "workers = 8\n").unwrap();
+
+    // The after action outlives its timeout and is killed
+    let started = std::time::Instant::now();
+    let output = env.laszoo_command(&["apply", "testgroup"]).unwrap()
+        .env("LASZOO_ACTION_TIMEOUT", "1")
+        .output()
+        .unwrap();
+    assert!(!output.status.success(), "Apply should report the failed action");
+    assert!(started.elapsed() < std::time::Duration::from_secs(20), "Action was not killed");
+    // A failed after action puts the previous content back
+    assert_eq!(fs::read_to_string(&test_file).unwrap(), "workers = 4\n");
+
+    // Its output and outcome are kept under actions/<host>/
+    let actions_dir = fs::read_dir(env.mfs_mount.join("actions")).unwrap().next().unwrap().unwrap().path();
+    let records: Vec<serde_json::Value> = fs::read_dir(&actions_dir)
+        .unwrap()
+        .map(|e| serde_json::from_str(&fs::read_to_string(e.unwrap().path()).unwrap()).unwrap())
+        .collect();
+    let record = records.iter().find(|r| r["action_type"] == "after_action").expect("No after action record");
+    assert_eq!(record["status"], "failed");
+    assert_eq!(record["group"], "testgroup");
+    assert_eq!(record["output"]["timed_out"], true);
+    assert_eq!(record["output"]["stdout"],
+        format!("after testgroup {} {}\n", test_file.display(), template_path.display()));
+}
+
+#[test]
+fn test_validate_and_revert_on_failed_after_action() {
+    let env = TestEnvironment::new("actions_validate");
+    let test_file = env.create_test_file("app.conf", "ok = 1\n");
+
+    let output = env.run_laszoo(&[
+        "enroll", "testgroup", test_file.to_str().unwrap(),
+        "--validate", "grep -q ok %s",
+        "--after", "! grep -q broken \"$LASZOO_FILE\"",
+    ]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let actions: serde_json::Value = serde_json::from_str(
+        &fs::read_to_string(env.mfs_mount.join("groups/testgroup/actions.json")).unwrap()
+    ).unwrap();
+    let file_actions = &actions["actions"][test_file.to_str().unwrap()];
+    assert_eq!(file_actions["validate"], "grep -q ok %s");
+
+    let template_path = group_template(&env, "testgroup", &test_file);
+
+    // Content failing validation is never installed
+    fs::write(&template_path, "nope = 2\n").unwrap();
+    let output = env.run_laszoo(&["apply", "testgroup"]).unwrap();
+    assert!(!output.status.success(), "Apply should fail validation");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Validation of"),
+        "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read_to_string(&test_file).unwrap(), "ok = 1\n");
+
+    // Content passing validation but breaking the after action is reverted
+    fs::write(&template_path, "ok = 2 broken\n").unwrap();
+    let output = env.run_laszoo(&["apply", "testgroup"]).unwrap();
+    assert!(!output.status.success(), "Apply should report the failed after action");
+    assert_eq!(fs::read_to_string(&test_file).unwrap(), "ok = 1\n");
+
+    // Valid content goes through
+    fs::write(&template_path, "ok = 3\n").unwrap();
+    let output = env.run_laszoo(&["apply", "testgroup"]).unwrap();
+    assert!(output.status.success(), "Apply failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read_to_string(&test_file).unwrap(), "ok = 3\n");
+
+    // A file the failed apply created is removed again
+    fs::remove_file(&test_file).unwrap();
+    fs::write(&template_path, "ok = 4 broken\n").unwrap();
+    let output = env.run_laszoo(&["apply", "testgroup"]).unwrap();
+    assert!(!output.status.success(), "Apply should report the failed after action");
+    assert!(!test_file.exists());
+
+    let actions_dir = fs::read_dir(env.mfs_mount.join("actions")).unwrap().next().unwrap().unwrap().path();
+    let records: Vec<serde_json::Value> = fs::read_dir(&actions_dir)
+        .unwrap()
+        .map(|e| serde_json::from_str(&fs::read_to_string(e.unwrap().path()).unwrap()).unwrap())
+        .collect();
+    assert!(records.iter().any(|r| r["action_type"] == "validate_action" && r["status"] == "failed"));
+    let revert = records.iter().find(|r| r["action_type"] == "revert").expect("No revert record");
+    assert_eq!(revert["status"], "completed");
+    assert_eq!(revert["group"], "testgroup");
+}
+
+#[test]
+fn test_machine_template_is_validated() {
+    let env = TestEnvironment::new("actions_validate_machine");
+    let test_file = env.create_test_file("app.conf", "ok = 1\n").canonicalize().unwrap();
+
+    let output = env.run_laszoo(&[
+        "enroll", "testgroup", test_file.to_str().unwrap(), "--machine",
+        "--validate", "grep -q ok %s",
+    ]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let mut machine_template = env.mfs_mount.join("machines").join(&env.original_hostname)
+        .join(test_file.strip_prefix("/").unwrap());
+    machine_template.set_file_name("app.conf.lasz");
+    assert!(machine_template.exists());
+
+    fs::write(&machine_template, "nope = 2\n").unwrap();
+    let manager = EnrollmentManager::new(env.mfs_mount.clone(), env.original_hostname.clone());
+    let err = manager.apply_single_template(&machine_template, &test_file).unwrap_err();
+    assert!(err.to_string().contains("Validation of"), "{}", err);
+    assert_eq!(fs::read_to_string(&test_file).unwrap(), "ok = 1\n");
+
+    fs::write(&machine_template, "ok = 2\n").unwrap();
+    manager.apply_single_template(&machine_template, &test_file).unwrap();
+    assert_eq!(fs::read_to_string(&test_file).unwrap(), "ok = 2\n");
+}
+
+#[test]
+fn test_apply_runs_shared_actions_once() {
+    let env = TestEnvironment::new("actions_shared");
+    let log = env.test_dir.join("actions.log");
+    let files: Vec<_> = ["a.conf", "b.conf", "c.conf"].iter()
+        .map(|name| env.create_test_file(&format!("nginx/{}", name), "listen 80;\n"))
+        .collect();
+
+    for file in &files {
+        let output = env.run_laszoo(&[
+            "enroll", "web", file.to_str().unwrap(),
+            "--before", &format!("echo stop >> {}", log.display()),
+            "--after", &format!("echo \"reload $(echo \"$LASZOO_FILES\" | wc -l)\" >> {}", log.display()),
+        ]).unwrap();
+        assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+    }
+    let _ = fs::remove_file(&log);
+
+    for file in &files {
+        fs::write(group_template(&env, "web", file), "listen 8080;\n").unwrap();
+    }
+
+    // A dry run plans each action once
+    let output = env.run_laszoo(&["--dry-run", "apply", "web"]).unwrap();
+    assert!(output.status.success(), "Dry run failed: {}", String::from_utf8_lossy(&output.stderr));
+    let planned = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(planned.matches("echo stop").count(), 1, "{}", planned);
+    assert_eq!(planned.matches("wc -l").count(), 1, "{}", planned);
+    assert!(!log.exists());
+
+    let output = env.run_laszoo(&["apply", "web"]).unwrap();
+    assert!(output.status.success(), "Apply failed: {}", String::from_utf8_lossy(&output.stderr));
+    for file in &files {
+        assert_eq!(fs::read_to_string(file).unwrap(), "listen 8080;\n");
+    }
+    assert_eq!(fs::read_to_string(&log).unwrap(), "stop\nreload 3\n");
+}
+
+#[test]
+fn test_before_actions_run_ahead_of_every_write() {
+    let env = TestEnvironment::new("actions_before_first");
+    let log = env.test_dir.join("actions.log");
+    let app = env.create_test_file("app.conf", "workers = 4\n");
+    let db = env.create_test_file("db.conf", "pool = 10\n");
+
+    // The before action of the second file sees the first one unwritten
+    for (file, before) in [(&app, "echo stop app".to_string()), (&db, format!("cat {}", app.display()))] {
+        let output = env.run_laszoo(&["enroll", "web", file.to_str().unwrap(), "--before", &format!("{} >> {}", before, log.display())]).unwrap();
+        assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+    }
+    let _ = fs::remove_file(&log);
+    fs::write(group_template(&env, "web", &app), "workers = 8\n").unwrap();
+    fs::write(group_template(&env, "web", &db), "pool = 20\n").unwrap();
+
+    let output = env.run_laszoo(&["--dry-run", "apply", "web"]).unwrap();
+    let planned = String::from_utf8_lossy(&output.stdout);
+    let first_write = planned.find("write ").expect("No write planned");
+    assert!(planned.rfind("before action").unwrap() < first_write, "{}", planned);
+
+    let output = env.run_laszoo(&["apply", "web"]).unwrap();
+    assert!(output.status.success(), "Apply failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read_to_string(&log).unwrap(), "stop app\nworkers = 4\n");
+    assert_eq!(fs::read_to_string(&db).unwrap(), "pool = 20\n");
+}
+
+#[test]
+fn test_notify_service_reloads_unit_once() {
+    let env = TestEnvironment::new("actions_notify");
+    let bin_dir = env.test_dir.join("bin");
+    let calls = env.test_dir.join("systemctl.log");
+    fs::create_dir_all(&bin_dir).unwrap();
+    let files: Vec<_> = ["a.conf", "b.conf"].iter().map(|name| env.create_test_file(name, "listen 80;\n")).collect();
+
+    // Stand in for systemctl: nginx is running and can reload
+    let systemctl = bin_dir.join("systemctl");
+    fs::write(&systemctl, format!(
+        "#!/bin/sh\necho \"$*\" >> {}\ncase \"$1\" in\n  is-active) echo active ;;\n  show) echo yes ;;\nesac\n",
+        calls.display(),
+    )).unwrap();
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(&systemctl, fs::Permissions::from_mode(0o755)).unwrap();
+    let path = format!("{}:{}", bin_dir.display(), std::env::var("PATH").unwrap_or_default());
+
+    let laszoo = |args: &[&str]| env.laszoo_command(args).unwrap().env("PATH", &path).output().unwrap();
+
+    let output = laszoo(&["enroll", "web", files[0].to_str().unwrap(), "--notify-service", "nginx:stop"]);
+    assert!(!output.status.success(), "Unknown unit action accepted");
+
+    for file in &files {
+        let output = laszoo(&["enroll", "web", file.to_str().unwrap(), "--notify-service", "nginx:reload"]);
+        assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+    }
+    let actions = fs::read_to_string(env.mfs_mount.join("groups/web/actions.json")).unwrap();
+    assert!(actions.contains("\"nginx:reload\""), "{}", actions);
+
+    for file in &files {
+        fs::write(group_template(&env, "web", file), "listen 8080;\n").unwrap();
+    }
+
+    let output = laszoo(&["--dry-run", "apply", "web"]);
+    let planned = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(planned.matches("notify nginx:reload").count(), 1, "{}", planned);
+    assert!(!calls.exists());
+
+    let output = laszoo(&["apply", "web"]);
+    assert!(output.status.success(), "Apply failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read_to_string(&calls).unwrap(),
+        "is-active nginx\nshow --property=CanReload --value nginx\nreload nginx\nis-active nginx\n");
+
+    let records: Vec<serde_json::Value> = fs::read_dir(env.mfs_mount.join("actions"))
+        .unwrap()
+        .flat_map(|host| fs::read_dir(host.unwrap().path()).unwrap())
+        .map(|entry| serde_json::from_str(&fs::read_to_string(entry.unwrap().path()).unwrap()).unwrap())
+        .collect();
+    let notified = records.iter().find(|r| r["action_type"] == "notify").expect("No notify record");
+    assert_eq!(notified["status"], "completed");
+    assert_eq!(notified["details"], "nginx reload, now active");
+
+    let output = laszoo(&["act", files[0].to_str().unwrap(), "--group", "web", "--no-notify-service"]);
+    assert!(output.status.success(), "Act failed: {}", String::from_utf8_lossy(&output.stderr));
+    let actions = fs::read_to_string(env.mfs_mount.join("groups/web/actions.json")).unwrap();
+    assert_eq!(actions.matches("\"nginx:reload\"").count(), 1, "{}", actions);
+}
@@ -0,0 +1,72 @@
+mod common;
+
+use common::*;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+
+#[test]
+fn test_apply_backs_up_and_restore_brings_back() {
+    let env = TestEnvironment::new("backup_restore");
+    let test_file = env.create_test_file("fstab", "/dev/sda1 / ext4 defaults 0 1\n");
+    fs::set_permissions(&test_file, fs::Permissions::from_mode(0o600)).unwrap();
+
+    let output = env.run_laszoo(&["enroll", "testgroup", test_file.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    // A local edit is overwritten by apply, but kept in the backup store
+    fs::write(&test_file, "/dev/sdb1 / xfs defaults 0 1\n").unwrap();
+    let output = env.run_laszoo(&["apply", "testgroup"]).unwrap();
+    assert!(output.status.success(), "Apply failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(env.read_file(&test_file), "/dev/sda1 / ext4 defaults 0 1\n");
+    assert_eq!(fs::metadata(&test_file).unwrap().permissions().mode() & 0o7777, 0o600);
+
+    let output = env.run_laszoo(&["restore", test_file.to_str().unwrap(), "--list"]).unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "List failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains("Backups of"), "{}", stdout);
+
+    let output = env.run_laszoo(&["restore", test_file.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Restore failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(env.read_file(&test_file), "/dev/sdb1 / xfs defaults 0 1\n");
+
+    // No temporary files are left next to the target
+    let leftovers: Vec<_> = fs::read_dir(&env.test_dir).unwrap()
+        .flatten()
+        .filter(|e| e.file_name().to_string_lossy().ends_with(".tmp"))
+        .collect();
+    assert!(leftovers.is_empty(), "{:?}", leftovers);
+}
+
+#[test]
+fn test_restore_before_first_backup_fails() {
+    let env = TestEnvironment::new("backup_restore_at");
+    let test_file = env.create_test_file("hosts", "127.0.0.1 localhost\n");
+
+    let output = env.run_laszoo(&["enroll", "testgroup", test_file.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    fs::write(&test_file, "127.0.0.1 changed\n").unwrap();
+    let output = env.run_laszoo(&["apply", "testgroup"]).unwrap();
+    assert!(output.status.success(), "Apply failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let output = env.run_laszoo(&["restore", test_file.to_str().unwrap(), "--at", "2000-01-01"]).unwrap();
+    assert!(!output.status.success());
+    assert_eq!(env.read_file(&test_file), "127.0.0.1 localhost\n");
+}
+
+#[test]
+fn test_apply_without_writable_state_dir() {
+    let env = TestEnvironment::new("backup_unwritable");
+    let test_file = env.create_test_file("app.conf", "workers = 4\n");
+    let output = env.run_laszoo(&["enroll", "testgroup", test_file.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    // The state directory can't be created below a regular file
+    fs::write(&test_file, "workers = 8\n").unwrap();
+    let output = env.laszoo_command(&["apply", "testgroup"]).unwrap()
+        .env("LASZOO_STATE_DIR", test_file.join("state"))
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "Apply failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(env.read_file(&test_file), "workers = 4\n");
+}
@@ -0,0 +1,68 @@
+mod common;
+
+use common::*;
+use std::fs;
+use std::path::PathBuf;
+
+const BINARY: &[u8] = &[0x7f, b'E', b'L', b'F', 0x00, 0xff, 0xfe, b'{', b'{', b' ', b'x', b' ', b'}', b'}', 0x00];
+
+fn write_binary(env: &TestEnvironment, name: &str) -> PathBuf {
+    let path = env.test_dir.join(name);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(&path, BINARY).unwrap();
+    path
+}
+
+#[test]
+fn test_enroll_binary_file() {
+    let env = TestEnvironment::new("binary_enroll");
+    let binary_file = write_binary(&env, "keystore.jks");
+
+    let output = env.run_laszoo(&["enroll", "testgroup", binary_file.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    assert_eq!(fs::read(group_template(&env, "testgroup", &binary_file)).unwrap(), BINARY);
+
+    let manifest: serde_json::Value = serde_json::from_str(
+        &env.read_file(&env.mfs_mount.join("groups/testgroup/manifest.json"))
+    ).unwrap();
+    let entry = manifest["entries"].as_object().unwrap().values().next().unwrap();
+    assert_eq!(entry["is_binary"], true);
+}
+
+#[test]
+fn test_apply_and_diff_binary_file() {
+    let env = TestEnvironment::new("binary_apply");
+    let binary_file = write_binary(&env, "policy.bin");
+
+    let output = env.run_laszoo(&["enroll", "testgroup", binary_file.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    fs::write(&binary_file, [0x00, 0x01, 0x02]).unwrap();
+
+    let output = env.run_laszoo(&["diff", "testgroup", "--no-color"]).unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout.contains("Binary files"), "{}", stdout);
+
+    // Apply copies the template byte-for-byte, without handlebars processing
+    let output = env.run_laszoo(&["apply", "testgroup"]).unwrap();
+    assert!(output.status.success(), "Apply failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&binary_file).unwrap(), BINARY);
+
+    let output = env.run_laszoo(&["diff", "testgroup"]).unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+}
+
+#[test]
+fn test_enroll_directory_with_binary_file() {
+    let env = TestEnvironment::new("binary_directory");
+    env.create_test_file("conf.d/app.conf", "setting = 1\n");
+    let binary_file = write_binary(&env, "conf.d/image.png");
+
+    let dir = env.test_dir.join("conf.d");
+    let output = env.run_laszoo(&["enroll", "testgroup", dir.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    assert_eq!(fs::read(group_template(&env, "testgroup", &binary_file)).unwrap(), BINARY);
+}
@@ -0,0 +1,70 @@
+mod common;
+
+use common::*;
+use std::fs;
+
+#[test]
+fn test_check_reports_each_file_with_exit_code() {
+    let env = TestEnvironment::new("check_files");
+    let app = env.create_test_file("app.conf", "workers = 4\n").canonicalize().unwrap();
+    let db = env.create_test_file("db.conf", "pool = 10\n").canonicalize().unwrap();
+
+    let output = env.run_laszoo(&["enroll", "testgroup", app.to_str().unwrap(), db.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let output = env.run_laszoo(&["check", "--json"]).unwrap();
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["status"], "compliant");
+    let items = report["items"].as_array().unwrap();
+    assert!(items.iter().any(|i| i["kind"] == "membership" && i["name"] == "testgroup" && i["status"] == "compliant"));
+    assert_eq!(items.iter().filter(|i| i["kind"] == "file" && i["status"] == "compliant").count(), 2);
+
+    // A local edit is drift
+    fs::write(&app, "workers = 8\n").unwrap();
+    let output = env.run_laszoo(&["check", "--nagios"]).unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(1), "{}", stdout);
+    assert_eq!(stdout.lines().count(), 1, "{}", stdout);
+    assert!(stdout.starts_with(&format!("LASZOO WARNING - 1 drifted ({})", app.display())), "{}", stdout);
+    assert!(stdout.contains("| compliant=2 drifted=1 missing=0 error=0"), "{}", stdout);
+
+    // A deleted file is missing, which outranks drift
+    fs::remove_file(&db).unwrap();
+    let output = env.run_laszoo(&["check"]).unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(2), "{}", stdout);
+    assert!(stdout.contains(&format!("● file {} (modified locally)", app.display())), "{}", stdout);
+    assert!(stdout.contains(&format!("✗ file {} (does not exist)", db.display())), "{}", stdout);
+
+    // Checking never changes anything
+    assert_eq!(env.read_file(&app), "workers = 8\n");
+    assert!(!db.exists());
+}
+
+#[test]
+fn test_check_membership_and_unavailable_mount() {
+    let env = TestEnvironment::new("check_membership");
+    let app = env.create_test_file("app.conf", "workers = 4\n").canonicalize().unwrap();
+
+    let output = env.run_laszoo(&["enroll", "testgroup", app.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let output = env.run_laszoo(&["check", "othergroup", "--nagios"]).unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(2), "{}", stdout);
+    assert!(stdout.starts_with("LASZOO CRITICAL - 1 missing (othergroup)"), "{}", stdout);
+
+    // Without the shared filesystem nothing can be checked
+    fs::remove_dir_all(&env.mfs_mount).unwrap();
+    let output = env.run_laszoo(&["check", "--nagios"]).unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(3), "{}", stdout);
+    assert!(stdout.starts_with("LASZOO UNKNOWN - "), "{}", stdout);
+
+    let output = env.run_laszoo(&["check", "--json"]).unwrap();
+    assert_eq!(output.status.code(), Some(3), "{}", String::from_utf8_lossy(&output.stderr));
+    let failure: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(failure["status"], "unknown");
+    assert!(failure["message"].as_str().is_some_and(|m| !m.is_empty()), "{}", failure);
+}
@@ -1,6 +1,9 @@
 use std::path::{Path, PathBuf};
 use std::fs;
 use std::process::Command;
+use std::time::Duration;
+use laszoo::config::Config;
+use laszoo::monitor::{ReconcileOptions, Reconciler};
 use uuid::Uuid;
 
 pub struct TestEnvironment {
@@ -76,6 +79,12 @@ impl TestEnvironment {
     }
     
     pub fn run_laszoo(&self, args: &[&str]) -> Result<std::process::Output, Box<dyn std::error::Error>> {
+        Ok(self.laszoo_command(args)?.output()?)
+    }
+    
+    /// The command `run_laszoo` runs, for tests that need to change its
+    /// environment first
+    pub fn laszoo_command(&self, args: &[&str]) -> Result<Command, Box<dyn std::error::Error>> {
         // Find the binary - when running tests, cargo sets CARGO_BIN_EXE_<name>
         let binary_path = if let Ok(path) = std::env::var("CARGO_BIN_EXE_laszoo") {
             PathBuf::from(path)
@@ -114,6 +123,7 @@ impl TestEnvironment {
         // Set environment variables
         cmd.env("LASZOO_MFS_MOUNT", &self.mfs_mount);
         cmd.env("HOSTNAME", &self.hostname);
+        cmd.env("LASZOO_STATE_DIR", self.test_dir.join("state"));
         
         // Pass through RUST_LOG if set
         if let Ok(rust_log) = std::env::var("RUST_LOG") {
@@ -126,7 +136,7 @@ impl TestEnvironment {
         // Add arguments
         cmd.args(args);
         
-        Ok(cmd.output()?)
+        Ok(cmd)
     }
     
     pub fn cleanup(&self) {
@@ -187,4 +197,44 @@ pub fn create_second_machine(base_env: &TestEnvironment, machine_name: &str) ->
         hostname: machine_name.to_string(),
         original_hostname: base_env.original_hostname.clone(),
     }
-}
\ No newline at end of file
+}
+
+/// Where a group template of `file` lives on the shared filesystem
+pub fn group_template(env: &TestEnvironment, group: &str, file: &Path) -> PathBuf {
+    let mut template = env.mfs_mount
+        .join("groups")
+        .join(group)
+        .join(file.strip_prefix("/").unwrap());
+    let name = format!("{}.lasz", template.file_name().unwrap().to_string_lossy());
+    template.set_file_name(name);
+    template
+}
+
+fn reconciler_config(env: &TestEnvironment) -> Config {
+    Config {
+        mfs_mount: env.mfs_mount.clone(),
+        state_dir: env.test_dir.join("state"),
+        ..Default::default()
+    }
+}
+
+/// A reconciler for `testgroup` acting on every event and tick right away
+pub fn reconciler(env: &TestEnvironment) -> Reconciler {
+    let options = ReconcileOptions {
+        debounce: Duration::ZERO,
+        scan_interval: Duration::ZERO,
+        ..ReconcileOptions::new(true, false)
+    };
+    Reconciler::new(&reconciler_config(env), vec!["testgroup".to_string()], options).unwrap()
+}
+
+/// A reconciler for `testgroup` that only notices changes in full passes
+pub fn full_reconciler(env: &TestEnvironment) -> Reconciler {
+    let options = ReconcileOptions {
+        debounce: Duration::ZERO,
+        scan_interval: Duration::from_secs(3600),
+        full_reconcile_interval: Some(Duration::from_secs(3600)),
+        ..ReconcileOptions::new(true, false)
+    };
+    Reconciler::new(&reconciler_config(env), vec!["testgroup".to_string()], options).unwrap()
+}
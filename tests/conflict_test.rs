@@ -0,0 +1,77 @@
+mod common;
+
+use common::*;
+use laszoo::version::{conflict_path, TemplateVersion, TemplateVersions};
+use std::fs;
+
+#[test]
+fn test_enroll_records_template_version() {
+    let env = TestEnvironment::new("conflict_version");
+    let test_file = env.create_test_file("app.conf", "workers = 4\n");
+
+    let output = env.run_laszoo(&["enroll", "testgroup", test_file.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let version = TemplateVersion::load(&group_template(&env, "testgroup", &test_file))
+        .unwrap()
+        .expect("Template version should be recorded next to the template");
+    assert_eq!(version.generation, 1);
+    assert_eq!(version.writer, env.original_hostname);
+    assert!(version.conflicts.is_empty());
+}
+
+#[test]
+fn test_resolve_takes_conflicting_version() {
+    let env = TestEnvironment::new("conflict_resolve");
+    let test_file = env.create_test_file("app.conf", "workers = 4\n");
+
+    let output = env.run_laszoo(&["enroll", "testgroup", test_file.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    // Another host lost the race with its own edit
+    let template = group_template(&env, "testgroup", &test_file);
+    let peer = TemplateVersions::new(env.mfs_mount.clone(), "peer01".to_string());
+    let saved = peer.record_conflict(&template, b"workers = 8\n").unwrap();
+    assert_eq!(saved, conflict_path(&template, "peer01"));
+
+    let output = env.run_laszoo(&["status"]).unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Conflicting versions from peer01"), "{}", stdout);
+
+    let output = env.run_laszoo(&["resolve", test_file.to_str().unwrap()]).unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "Resolve failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains("+workers = 8"), "{}", stdout);
+
+    let output = env.run_laszoo(&["resolve", test_file.to_str().unwrap(), "--take", "peer01"]).unwrap();
+    assert!(output.status.success(), "Resolve failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    assert_eq!(env.read_file(&template), "workers = 8\n");
+    assert_eq!(env.read_file(&test_file), "workers = 8\n");
+    assert!(!saved.exists());
+
+    let version = TemplateVersion::load(&template).unwrap().unwrap();
+    assert_eq!(version.generation, 2);
+    assert!(version.conflicts.is_empty());
+}
+
+#[test]
+fn test_resolve_keeps_current_template() {
+    let env = TestEnvironment::new("conflict_keep");
+    let test_file = env.create_test_file("app.conf", "workers = 4\n");
+
+    let output = env.run_laszoo(&["enroll", "testgroup", test_file.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let template = group_template(&env, "testgroup", &test_file);
+    let peer = TemplateVersions::new(env.mfs_mount.clone(), "peer01".to_string());
+    let saved = peer.record_conflict(&template, b"workers = 8\n").unwrap();
+
+    let output = env.run_laszoo(&["resolve", test_file.to_str().unwrap(), "--keep"]).unwrap();
+    assert!(output.status.success(), "Resolve failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    assert_eq!(env.read_file(&template), "workers = 4\n");
+    assert!(!saved.exists());
+    assert!(TemplateVersion::load(&template).unwrap().unwrap().conflicts.is_empty());
+    assert!(fs::metadata(&test_file).is_ok());
+}
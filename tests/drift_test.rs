@@ -0,0 +1,94 @@
+mod common;
+
+use common::*;
+use laszoo::monitor::ReconcileEvent;
+use std::fs;
+
+#[tokio::test]
+async fn test_drift_is_recorded_and_shown() {
+    let env = TestEnvironment::new("drift_record");
+    let test_file = env.create_test_file("app.conf", "workers = 4\n").canonicalize().unwrap();
+
+    let output = env.run_laszoo(&["enroll", "testgroup", test_file.to_str().unwrap(), "--action", "drift"]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let mut reconciler = reconciler(&env);
+    fs::write(&test_file, "workers = 8\n").unwrap();
+    reconciler.handle(ReconcileEvent::LocalChanged(test_file.clone())).await.unwrap();
+
+    let ledger = reconciler.manager().drift_ledger();
+    let entry = ledger.get(&env.original_hostname, &test_file).unwrap().expect("Drift should be recorded");
+    assert_eq!(entry.group, "testgroup");
+    assert_eq!(entry.records.len(), 1);
+
+    let output = env.run_laszoo(&["drift", "list"]).unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "Drift list failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains(&format!("{} (group: testgroup)  drifted since", test_file.display())), "{}", stdout);
+
+    let output = env.run_laszoo(&["drift", "show", test_file.to_str().unwrap()]).unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("-workers = 4"), "{}", stdout);
+    assert!(stdout.contains("+workers = 8"), "{}", stdout);
+
+    let output = env.run_laszoo(&["status"]).unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Drifted since"), "{}", stdout);
+
+    // Back in line with the template, the drift is forgotten
+    fs::write(&test_file, "workers = 4\n").unwrap();
+    reconciler.handle(ReconcileEvent::LocalChanged(test_file.clone())).await.unwrap();
+    assert!(ledger.get(&env.original_hostname, &test_file).unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_drift_accept_creates_machine_template() {
+    let env = TestEnvironment::new("drift_accept");
+    let test_file = env.create_test_file("app.conf", "workers = 4\n").canonicalize().unwrap();
+
+    let output = env.run_laszoo(&["enroll", "testgroup", test_file.to_str().unwrap(), "--action", "drift"]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let mut reconciler = reconciler(&env);
+    fs::write(&test_file, "workers = 8\n").unwrap();
+    reconciler.handle(ReconcileEvent::LocalChanged(test_file.clone())).await.unwrap();
+
+    let output = env.run_laszoo(&["drift", "accept", test_file.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Drift accept failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let machine_template = reconciler.manager().get_machine_template_path(&test_file).unwrap();
+    assert_eq!(env.read_file(&machine_template), "workers = 8\n");
+
+    let output = env.run_laszoo(&["drift", "list"]).unwrap();
+    assert!(String::from_utf8_lossy(&output.stdout).contains("No drift recorded"));
+
+    // Apply now keeps this machine's version
+    let output = env.run_laszoo(&["apply", "testgroup"]).unwrap();
+    assert!(output.status.success(), "Apply failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(env.read_file(&test_file), "workers = 8\n");
+}
+
+#[tokio::test]
+async fn test_drift_is_checked_against_machine_template() {
+    let env = TestEnvironment::new("drift_machine");
+    let test_file = env.create_test_file("app.conf", "workers = 4\n").canonicalize().unwrap();
+
+    let output = env.run_laszoo(&["enroll", "testgroup", test_file.to_str().unwrap(), "--machine", "--action", "drift"]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let mut reconciler = reconciler(&env);
+    fs::write(&test_file, "workers = 8\n").unwrap();
+    reconciler.handle(ReconcileEvent::LocalChanged(test_file.clone())).await.unwrap();
+
+    let ledger = reconciler.manager().drift_ledger();
+    let entry = ledger.get(&env.original_hostname, &test_file).unwrap().expect("Drift should be recorded");
+    assert_eq!(entry.group, "testgroup");
+
+    // Matching the machine template is not drift, whatever the group template says
+    let template = group_template(&env, "testgroup", &test_file);
+    fs::create_dir_all(template.parent().unwrap()).unwrap();
+    fs::write(&template, "workers = 16\n").unwrap();
+    fs::write(&test_file, "workers = 4\n").unwrap();
+    reconciler.handle(ReconcileEvent::LocalChanged(test_file.clone())).await.unwrap();
+    assert!(ledger.get(&env.original_hostname, &test_file).unwrap().is_none());
+}
@@ -0,0 +1,88 @@
+mod common;
+
+use common::*;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Every file below a directory with its content
+fn snapshot(dir: &Path) -> BTreeMap<PathBuf, Vec<u8>> {
+    walkdir::WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .map(|e| (e.path().to_path_buf(), fs::read(e.path()).unwrap()))
+        .collect()
+}
+
+#[test]
+fn test_dry_run_enroll_changes_nothing() {
+    let env = TestEnvironment::new("dry_run_enroll");
+    let test_file = env.create_test_file("app.conf", "workers = 4\n").canonicalize().unwrap();
+    let before = snapshot(&env.mfs_mount);
+
+    let output = env.run_laszoo(&["--dry-run", "enroll", "testgroup", test_file.to_str().unwrap(), "--after", "true"]).unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "Dry run failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let template = env.mfs_mount.join("groups/testgroup").join(format!("{}.lasz", test_file.strip_prefix("/").unwrap().display()));
+    assert!(stdout.contains(&format!("create {}", template.display())), "{}", stdout);
+    assert!(stdout.contains("+workers = 4"), "{}", stdout);
+    assert!(stdout.contains(&format!("enroll {} into group 'testgroup'", test_file.display())), "{}", stdout);
+    assert!(stdout.contains("groups.conf"), "{}", stdout);
+    assert_eq!(snapshot(&env.mfs_mount), before);
+}
+
+#[test]
+fn test_dry_run_apply_shows_diff() {
+    let env = TestEnvironment::new("dry_run_apply");
+    let test_file = env.create_test_file("app.conf", "workers = 4\n").canonicalize().unwrap();
+
+    let output = env.run_laszoo(&["enroll", "testgroup", test_file.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let template = env.mfs_mount.join("groups/testgroup").join(format!("{}.lasz", test_file.strip_prefix("/").unwrap().display()));
+    fs::write(&template, "workers = {{ workers }}\n").unwrap();
+    let output = env.run_laszoo(&["vars", "set", "workers", "16", "--group", "testgroup"]).unwrap();
+    assert!(output.status.success(), "Vars set failed: {}", String::from_utf8_lossy(&output.stderr));
+    let before = snapshot(&env.mfs_mount);
+
+    let output = env.run_laszoo(&["--dry-run", "apply", "testgroup"]).unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "Dry run failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains(&format!("write  {}", test_file.display())), "{}", stdout);
+    assert!(stdout.contains("-workers = 4"), "{}", stdout);
+    assert!(stdout.contains("+workers = 16"), "{}", stdout);
+
+    assert_eq!(env.read_file(&test_file), "workers = 4\n");
+    assert_eq!(snapshot(&env.mfs_mount), before);
+
+    // A read-only command still runs
+    let output = env.run_laszoo(&["--dry-run", "status"]).unwrap();
+    assert!(output.status.success(), "Status failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("Dry run"));
+}
+
+#[test]
+fn test_dry_run_group_rename_changes_nothing() {
+    let env = TestEnvironment::new("dry_run_rename");
+    let test_file = env.create_test_file("app.conf", "workers = 4\n").canonicalize().unwrap();
+
+    let output = env.run_laszoo(&["enroll", "testgroup", test_file.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+    let before = snapshot(&env.mfs_mount);
+
+    let output = env.run_laszoo(&["--dry-run", "group", "testgroup", "rename", "webgroup"]).unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "Dry run failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains(&format!("rename {} -> {}",
+        env.mfs_mount.join("groups/testgroup").display(),
+        env.mfs_mount.join("groups/webgroup").display())), "{}", stdout);
+    assert!(stdout.contains("+webgroup"), "{}", stdout);
+    assert_eq!(snapshot(&env.mfs_mount), before);
+
+    // Commands that can't plan refuse to run
+    let output = env.run_laszoo(&["--dry-run", "set-action", test_file.to_str().unwrap(), "freeze"]).unwrap();
+    assert!(!output.status.success());
+    assert_eq!(snapshot(&env.mfs_mount), before);
+}
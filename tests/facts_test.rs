@@ -0,0 +1,37 @@
+mod common;
+
+use common::*;
+use std::fs;
+
+#[test]
+fn test_apply_renders_and_publishes_facts() {
+    let env = TestEnvironment::new("facts_apply");
+    let test_file = env.create_test_file("facts.conf", "placeholder\n");
+
+    let output = env.run_laszoo(&["enroll", "testgroup", test_file.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let template_path = group_template(&env, "testgroup", &test_file);
+    fs::write(&template_path, "cpus={{ facts.cpu_count }}\nhost={{ facts.hostname }}\nkernel={{ facts.kernel }}\n").unwrap();
+
+    let output = env.run_laszoo(&["apply", "testgroup"]).unwrap();
+    assert!(output.status.success(), "Apply failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let facts_path = env.mfs_mount
+        .join("machines")
+        .join(&env.original_hostname)
+        .join("facts.json");
+    let facts: serde_json::Value = serde_json::from_str(&env.read_file(&facts_path))
+        .expect("facts.json should be valid JSON");
+
+    assert!(facts["cpu_count"].as_u64().unwrap() >= 1);
+    assert_eq!(
+        env.read_file(&test_file),
+        format!(
+            "cpus={}\nhost={}\nkernel={}\n",
+            facts["cpu_count"],
+            env.original_hostname,
+            facts["kernel"].as_str().unwrap()
+        )
+    );
+}
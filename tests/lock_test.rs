@@ -0,0 +1,49 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn test_lock_acquire_release_list() {
+    let env = TestEnvironment::new("lock_cli");
+
+    let output = env.run_laszoo(&["lock", "acquire", "deploy", "--pid", "4242"]).unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "Acquire failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains("with token 1"), "{}", stdout);
+
+    // Another process can't take it while the lease is live
+    let output = env.run_laszoo(&["lock", "acquire", "deploy", "--pid", "4343"]).unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("LockHeld") && stderr.contains("pid 4242"), "{}", stderr);
+
+    let output = env.run_laszoo(&["lock", "list"]).unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(&format!("deploy  token 1  held by {} (pid 4242)", env.original_hostname)), "{}", stdout);
+
+    let output = env.run_laszoo(&["lock", "release", "deploy"]).unwrap();
+    assert!(output.status.success(), "Release failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let output = env.run_laszoo(&["lock", "list"]).unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("deploy  token 1  free"), "{}", stdout);
+
+    // The fencing token moves on with every new holder
+    let output = env.run_laszoo(&["lock", "acquire", "deploy", "--pid", "4343"]).unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "Acquire failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains("with token 2"), "{}", stdout);
+}
+
+#[test]
+fn test_expired_lease_is_taken_over() {
+    let env = TestEnvironment::new("lock_expiry");
+
+    let output = env.run_laszoo(&["lock", "acquire", "deploy", "--pid", "4242", "--ttl", "1"]).unwrap();
+    assert!(output.status.success(), "Acquire failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let output = env.run_laszoo(&["lock", "acquire", "deploy", "--pid", "4343", "--wait", "5"]).unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "Acquire failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains("with token 2"), "{}", stdout);
+}
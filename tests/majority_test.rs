@@ -0,0 +1,48 @@
+mod common;
+
+use common::*;
+use laszoo::majority::MajorityResolver;
+use std::fs;
+
+fn published_hashes(env: &TestEnvironment) -> serde_json::Value {
+    let path = env.mfs_mount.join("machines").join(&env.original_hostname).join("hashes.json");
+    serde_json::from_str(&env.read_file(&path)).expect("Hashes should be published after apply")
+}
+
+#[test]
+fn test_apply_publishes_file_hash() {
+    let env = TestEnvironment::new("majority_publish");
+    let test_file = env.create_test_file("app.conf", "workers = 4\n");
+
+    let output = env.run_laszoo(&["enroll", "testgroup", test_file.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let output = env.run_laszoo(&["apply", "testgroup"]).unwrap();
+    assert!(output.status.success(), "Apply failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let hashes = published_hashes(&env);
+    let entry = &hashes[test_file.canonicalize().unwrap().to_str().unwrap()];
+    assert_eq!(entry["group"], "testgroup");
+    assert!(entry["hash"].is_string(), "{}", hashes);
+}
+
+#[test]
+fn test_published_hash_ignores_host_specific_values() {
+    let env = TestEnvironment::new("majority_normalize");
+    let test_file = env.create_test_file("node.conf", "placeholder\n");
+
+    let output = env.run_laszoo(&["enroll", "testgroup", test_file.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let template = "name = {{ hostname }}\nworkers = 4\n";
+    fs::write(group_template(&env, "testgroup", &test_file), template).unwrap();
+
+    let output = env.run_laszoo(&["apply", "testgroup"]).unwrap();
+    assert!(output.status.success(), "Apply failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(env.read_file(&test_file).contains(&env.original_hostname));
+
+    // Every host rendering this template publishes the same hash, whatever its name
+    let hashes = published_hashes(&env);
+    let entry = &hashes[test_file.canonicalize().unwrap().to_str().unwrap()];
+    assert_eq!(entry["hash"], MajorityResolver::new().unwrap().normalized_hash(template));
+}
@@ -0,0 +1,47 @@
+mod common;
+
+use common::*;
+use std::fs;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::PathBuf;
+
+#[test]
+fn test_enroll_records_metadata() {
+    let env = TestEnvironment::new("metadata_record");
+    let test_file = env.create_test_file("secret.conf", "key=value\n");
+    fs::set_permissions(&test_file, fs::Permissions::from_mode(0o640)).unwrap();
+
+    let output = env.run_laszoo(&["enroll", "testgroup", test_file.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let attrs_path = PathBuf::from(format!("{}.attrs", group_template(&env, "testgroup", &test_file).display()));
+    let attrs: serde_json::Value = serde_json::from_str(&env.read_file(&attrs_path))
+        .expect("Metadata should be recorded next to the template");
+
+    let local = fs::metadata(&test_file).unwrap();
+    assert_eq!(attrs["mode"], 0o640);
+    assert_eq!(attrs["uid"], local.uid());
+    assert_eq!(attrs["gid"], local.gid());
+    assert!(attrs["owner"].is_string(), "Owner name should be recorded: {}", attrs);
+}
+
+#[test]
+fn test_apply_restores_mode() {
+    let env = TestEnvironment::new("metadata_apply");
+    let test_file = env.create_test_file("secret.conf", "key=value\n");
+    fs::set_permissions(&test_file, fs::Permissions::from_mode(0o600)).unwrap();
+
+    let output = env.run_laszoo(&["enroll", "testgroup", test_file.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    // Replace the file with a world-readable copy
+    fs::remove_file(&test_file).unwrap();
+    fs::write(&test_file, "key=value\n").unwrap();
+    fs::set_permissions(&test_file, fs::Permissions::from_mode(0o644)).unwrap();
+
+    let output = env.run_laszoo(&["apply", "testgroup"]).unwrap();
+    assert!(output.status.success(), "Apply failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let mode = fs::metadata(&test_file).unwrap().permissions().mode() & 0o7777;
+    assert_eq!(mode, 0o600);
+}
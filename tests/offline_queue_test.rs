@@ -0,0 +1,59 @@
+mod common;
+
+use common::*;
+use laszoo::queue::{ChangeKind, PendingQueue};
+use std::fs;
+
+#[test]
+fn test_status_shows_pending_changes() {
+    let env = TestEnvironment::new("offline_queue_status");
+    let test_file = env.create_test_file("app.conf", "workers = 4\n");
+
+    let output = env.run_laszoo(&["enroll", "testgroup", test_file.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    // A change made while the shared filesystem was away
+    let queue = PendingQueue::new(env.test_dir.join("state").join("pending"));
+    queue.record(&test_file, "testgroup", ChangeKind::Modified).unwrap();
+
+    let output = env.run_laszoo(&["status"]).unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "Status failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains("Pending changes"), "{}", stdout);
+    assert!(stdout.contains(&format!("#1 modified {} (group: testgroup", test_file.display())), "{}", stdout);
+}
+
+#[test]
+fn test_status_without_pending_changes() {
+    let env = TestEnvironment::new("offline_queue_empty");
+    let test_file = env.create_test_file("app.conf", "workers = 4\n");
+
+    let output = env.run_laszoo(&["enroll", "testgroup", test_file.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let output = env.run_laszoo(&["status"]).unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!stdout.contains("Pending changes"), "{}", stdout);
+}
+
+#[tokio::test]
+async fn test_replay_pending_changes() {
+    let env = TestEnvironment::new("offline_queue_replay");
+    let test_file = env.create_test_file("app.conf", "workers = 4\n").canonicalize().unwrap();
+
+    let output = env.run_laszoo(&["enroll", "testgroup", test_file.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    // Edited and queued while the shared filesystem is away
+    let away = env.test_dir.join("mfs.away");
+    fs::rename(&env.mfs_mount, &away).unwrap();
+    fs::write(&test_file, "workers = 8\n").unwrap();
+    let queue = PendingQueue::new(env.test_dir.join("state").join("pending"));
+    queue.record(&test_file, "testgroup", ChangeKind::Modified).unwrap();
+
+    // Once it is back the change reaches the template
+    fs::rename(&away, &env.mfs_mount).unwrap();
+    reconciler(&env).replay_pending(&queue).await.unwrap();
+    assert_eq!(env.read_file(&group_template(&env, "testgroup", &test_file)), "workers = 8\n");
+    assert!(queue.list().unwrap().is_empty());
+}
@@ -0,0 +1,186 @@
+mod common;
+
+use common::*;
+use laszoo::majority::{publish_hash, MajorityResolver};
+use laszoo::monitor::reconciler::{LocalChangeKind, TemplateChange};
+use laszoo::monitor::{Decision, HoldReason, LocalChange, Outcome, ReconcileEvent};
+use std::fs;
+
+#[tokio::test]
+async fn test_local_change_converges_into_template() {
+    let env = TestEnvironment::new("reconciler_local");
+    let test_file = env.create_test_file("app.conf", "workers = 4\n").canonicalize().unwrap();
+
+    let output = env.run_laszoo(&["enroll", "testgroup", test_file.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let mut reconciler = reconciler(&env);
+    fs::write(&test_file, "workers = 8\n").unwrap();
+
+    let reports = reconciler.handle(ReconcileEvent::LocalChanged(test_file.clone())).await.unwrap();
+    let template = group_template(&env, "testgroup", &test_file);
+    assert_eq!(reports.len(), 1, "{:?}", reports);
+    assert_eq!(reports[0].decision, Decision::SyncLocal(vec![LocalChange {
+        path: test_file.clone(),
+        group: "testgroup".to_string(),
+        kind: LocalChangeKind::Modified,
+    }]));
+    assert_eq!(reports[0].outcomes, vec![Outcome::TemplateUpdated {
+        path: test_file.clone(),
+        template_path: template.clone(),
+    }]);
+    assert_eq!(env.read_file(&template), "workers = 8\n");
+
+    // The template change shows up in the next scan, and is known to be ours
+    let reports = reconciler.handle(ReconcileEvent::Tick).await.unwrap();
+    assert_eq!(reports.len(), 1, "{:?}", reports);
+    assert_eq!(reports[0].decision, Decision::HoldTemplate(TemplateChange {
+        template_path: template,
+        path: test_file,
+        group: "testgroup".to_string(),
+        new: false,
+    }, HoldReason::LocalOrigin));
+}
+
+#[tokio::test]
+async fn test_remote_template_change_is_applied() {
+    let env = TestEnvironment::new("reconciler_remote");
+    let test_file = env.create_test_file("app.conf", "workers = 4\n").canonicalize().unwrap();
+
+    let output = env.run_laszoo(&["enroll", "testgroup", test_file.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let mut reconciler = reconciler(&env);
+
+    // Another host converged its edit
+    fs::write(group_template(&env, "testgroup", &test_file), "workers = 16\n").unwrap();
+
+    let reports = reconciler.handle(ReconcileEvent::Tick).await.unwrap();
+    assert_eq!(reports.len(), 1, "{:?}", reports);
+    assert!(matches!(reports[0].decision, Decision::ApplyTemplate(_)), "{:?}", reports);
+    assert_eq!(reports[0].outcomes, vec![Outcome::TemplateApplied { path: test_file.clone() }]);
+    assert_eq!(env.read_file(&test_file), "workers = 16\n");
+
+    // The event from writing the file is not taken for a local edit
+    let reports = reconciler.handle(ReconcileEvent::LocalChanged(test_file)).await.unwrap();
+    assert!(reports.is_empty(), "{:?}", reports);
+}
+
+#[tokio::test]
+async fn test_full_pass_catches_missed_local_change() {
+    let env = TestEnvironment::new("reconciler_full_local");
+    let test_file = env.create_test_file("app.conf", "workers = 4\n").canonicalize().unwrap();
+
+    let output = env.run_laszoo(&["enroll", "testgroup", test_file.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    // Changed while nothing was watching
+    fs::write(&test_file, "workers = 8\n").unwrap();
+    let mut reconciler = full_reconciler(&env);
+
+    let reports = reconciler.handle(ReconcileEvent::Tick).await.unwrap();
+    let summary = reports.iter().find_map(|r| match &r.decision {
+        Decision::FullReconcile(summary) => Some(summary.clone()),
+        _ => None,
+    }).expect("no full reconcile pass");
+    assert!(summary.startup);
+    assert_eq!(summary.local, vec![test_file.clone()]);
+    assert_eq!(summary.corrections(), 1);
+
+    let template = group_template(&env, "testgroup", &test_file);
+    assert!(reports.iter().any(|r| r.outcomes.contains(&Outcome::TemplateUpdated {
+        path: test_file.clone(),
+        template_path: template.clone(),
+    })), "{:?}", reports);
+    assert_eq!(env.read_file(&template), "workers = 8\n");
+
+    // Nothing left to correct until the next pass is due
+    let reports = reconciler.handle(ReconcileEvent::Tick).await.unwrap();
+    assert!(reports.is_empty(), "{:?}", reports);
+}
+
+#[tokio::test]
+async fn test_full_pass_catches_missed_template_change() {
+    let env = TestEnvironment::new("reconciler_full_template");
+    let test_file = env.create_test_file("app.conf", "workers = 4\n").canonicalize().unwrap();
+
+    let output = env.run_laszoo(&["enroll", "testgroup", test_file.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let template = group_template(&env, "testgroup", &test_file);
+    fs::write(&template, "workers = 16\n").unwrap();
+    let mut reconciler = full_reconciler(&env);
+
+    let reports = reconciler.handle(ReconcileEvent::Tick).await.unwrap();
+    let summary = reports.iter().find_map(|r| match &r.decision {
+        Decision::FullReconcile(summary) => Some(summary.clone()),
+        _ => None,
+    }).expect("no full reconcile pass");
+    assert_eq!(summary.templates, vec![template]);
+    assert!(summary.local.is_empty());
+
+    assert!(reports.iter().any(|r| matches!(r.decision, Decision::ApplyTemplate(_))
+        && r.outcomes == vec![Outcome::TemplateApplied { path: test_file.clone() }]), "{:?}", reports);
+    assert_eq!(env.read_file(&test_file), "workers = 16\n");
+}
+
+#[tokio::test]
+async fn test_templates_changed_together_run_after_action_once() {
+    let env = TestEnvironment::new("reconciler_batch");
+    let app = env.create_test_file("app.conf", "workers = 4\n").canonicalize().unwrap();
+    let db = env.create_test_file("db.conf", "pool = 10\n").canonicalize().unwrap();
+    let log = env.test_dir.join("reloads.log");
+    let after = format!("echo \"$LASZOO_FILES\" | wc -l >> {}", log.display());
+
+    let output = env.run_laszoo(&["enroll", "testgroup", app.to_str().unwrap(), db.to_str().unwrap(), "--after", &after]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let mut reconciler = reconciler(&env);
+    fs::write(group_template(&env, "testgroup", &app), "workers = 8\n").unwrap();
+    fs::write(group_template(&env, "testgroup", &db), "pool = 20\n").unwrap();
+
+    let reports = reconciler.handle(ReconcileEvent::Tick).await.unwrap();
+    assert_eq!(reports.len(), 2, "{:?}", reports);
+    assert!(reports.iter().all(|r| matches!(r.outcomes[..], [Outcome::TemplateApplied { .. }])), "{:?}", reports);
+    assert_eq!(env.read_file(&app), "workers = 8\n");
+    assert_eq!(env.read_file(&db), "pool = 20\n");
+
+    // One reload, for both files
+    assert_eq!(env.read_file(&log).trim(), "2");
+}
+
+#[tokio::test]
+async fn test_full_pass_skips_split_vote_until_something_changes() {
+    let env = TestEnvironment::new("reconciler_full_split");
+    let test_file = env.create_test_file("app.conf", "workers = 4\n").canonicalize().unwrap();
+
+    let output = env.run_laszoo(&["enroll", "testgroup", test_file.to_str().unwrap(), "--action", "rollback"]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    // Two other hosts agree on a version the template doesn't have
+    let resolver = MajorityResolver::new().unwrap();
+    for host in ["web01", "web02"] {
+        let groups_conf = env.mfs_mount.join("machines").join(host).join("etc/laszoo/groups.conf");
+        fs::create_dir_all(groups_conf.parent().unwrap()).unwrap();
+        fs::write(&groups_conf, "testgroup\n").unwrap();
+        publish_hash(&env.mfs_mount, host, "testgroup", &test_file, &resolver.normalized_hash("workers = 16\n")).unwrap();
+    }
+    fs::write(&test_file, "workers = 8\n").unwrap();
+
+    let reports = full_reconciler(&env).handle(ReconcileEvent::Tick).await.unwrap();
+    assert!(reports.iter().any(|r| matches!(r.decision, Decision::SyncLocal(_))), "{:?}", reports);
+    assert_eq!(env.read_file(&test_file), "workers = 8\n");
+    let ledger = full_reconciler(&env).manager().drift_ledger();
+    let split = ledger.get(&env.original_hostname, &test_file).unwrap().and_then(|e| e.split).expect("Split should be recorded");
+    assert_eq!(split.majority_hosts, vec!["web01".to_string(), "web02".to_string()]);
+
+    // The same vote isn't taken again
+    let reports = full_reconciler(&env).handle(ReconcileEvent::Tick).await.unwrap();
+    assert!(reports.iter().all(|r| !matches!(r.decision, Decision::SyncLocal(_))), "{:?}", reports);
+
+    // A new local version is voted on
+    fs::write(&test_file, "workers = 16\n").unwrap();
+    let reports = full_reconciler(&env).handle(ReconcileEvent::Tick).await.unwrap();
+    assert!(reports.iter().any(|r| matches!(r.decision, Decision::SyncLocal(_))), "{:?}", reports);
+    assert!(ledger.get(&env.original_hostname, &test_file).unwrap().is_none());
+}
@@ -1,110 +1,183 @@
-use laszoo::config::Config;
-use laszoo::enrollment::EnrollmentManager;
+mod common;
+
+use common::*;
 use std::fs;
 use std::process::Command;
 
-mod common;
-use common::TestEnvironment;
+fn git(env: &TestEnvironment, args: &[&str]) -> String {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(&env.mfs_mount)
+        .output()
+        .expect("Failed to run git");
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
 
-#[tokio::test]
-#[ignore = "rollback command not yet implemented"]
-async fn test_rollback_single_commit() {
+fn commit_all(env: &TestEnvironment, message: &str) {
+    git(env, &["add", "-A"]);
+    git(env, &["commit", "-m", message]);
+}
+
+#[test]
+fn test_rollback_single_commit() {
     let env = TestEnvironment::new("rollback_single");
-    let config = env.create_config();
-    
-    // Initialize git repo
-    Command::new("git")
-        .args(&["init"])
-        .current_dir(&config.mfs_mount)
-        .output()
-        .expect("Failed to init git");
-    
-    // Create and enroll a file
-    let test_file = env.test_dir.join("config.txt");
-    fs::write(&test_file, "version 1").unwrap();
-    
-    let mut enrollment_manager = EnrollmentManager::new(config.clone());
-    enrollment_manager.enroll_file("testgroup", &test_file, false, false, None, None, Default::default()).await.unwrap();
-    
-    // Make a commit
-    Command::new("git")
-        .args(&["add", "."])
-        .current_dir(&config.mfs_mount)
-        .output()
-        .expect("Failed to stage files");
-    
-    Command::new("git")
-        .args(&["commit", "-m", "Initial version"])
-        .current_dir(&config.mfs_mount)
-        .output()
-        .expect("Failed to commit");
-    
-    // Modify the template
-    let template_path = config.mfs_mount.join("groups/testgroup").join(format!("{}.lasz", test_file.display()));
+    env.setup_git().expect("Failed to setup git");
+
+    let test_file = env.create_test_file("config.txt", "version 1");
+    let output = env.run_laszoo(&["enroll", "testgroup", test_file.to_str().unwrap()])
+        .expect("Failed to run laszoo");
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+    commit_all(&env, "Initial version");
+
+    let template_path = group_template(&env, "testgroup", &test_file);
     fs::write(&template_path, "version 2").unwrap();
-    
-    // Commit the change
-    Command::new("git")
-        .args(&["add", "."])
-        .current_dir(&config.mfs_mount)
-        .output()
-        .expect("Failed to stage files");
-    
-    Command::new("git")
-        .args(&["commit", "-m", "Updated version"])
-        .current_dir(&config.mfs_mount)
-        .output()
-        .expect("Failed to commit");
-    
-    // TODO: Run rollback command
-    // `laszoo rollback testgroup`
-    
-    // Template should be back to version 1
-    let content = fs::read_to_string(&template_path).unwrap();
-    assert_eq!(content, "version 1");
+    commit_all(&env, "Updated version");
+
+    let output = env.run_laszoo(&["rollback", "testgroup"])
+        .expect("Failed to run laszoo");
+    assert!(output.status.success(), "Rollback failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    // Template is restored and re-rendered locally
+    assert_eq!(env.read_file(&template_path), "version 1");
+    assert_eq!(env.read_file(&test_file), "version 1");
+
+    // The rollback is recorded as its own commit
+    let log = git(&env, &["log", "--oneline"]);
+    assert_eq!(log.lines().count(), 3, "Expected a rollback commit: {}", log);
+    assert!(log.lines().next().unwrap().contains("Roll back testgroup"));
 }
 
-#[tokio::test]
-#[ignore = "rollback command not yet implemented"]
-async fn test_rollback_multiple_commits() {
+#[test]
+fn test_rollback_multiple_commits() {
     let env = TestEnvironment::new("rollback_multiple");
-    let config = env.create_config();
-    
-    // Initialize git repo and make multiple commits
-    // TODO: Test `laszoo rollback testgroup --commits 3`
-    // Should rollback 3 commits
+    env.setup_git().expect("Failed to setup git");
+
+    let test_file = env.create_test_file("config.txt", "version 1");
+    let output = env.run_laszoo(&["enroll", "testgroup", test_file.to_str().unwrap()])
+        .expect("Failed to run laszoo");
+    assert!(output.status.success());
+    commit_all(&env, "Version 1");
+
+    let template_path = group_template(&env, "testgroup", &test_file);
+    for version in 2..=4 {
+        fs::write(&template_path, format!("version {}", version)).unwrap();
+        commit_all(&env, &format!("Version {}", version));
+    }
+
+    let output = env.run_laszoo(&["rollback", "testgroup", "--commits", "3"])
+        .expect("Failed to run laszoo");
+    assert!(output.status.success(), "Rollback failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    assert_eq!(env.read_file(&template_path), "version 1");
 }
 
-#[tokio::test]
-#[ignore = "rollback command not yet implemented"]
-async fn test_rollback_specific_file() {
+#[test]
+fn test_rollback_specific_file() {
     let env = TestEnvironment::new("rollback_file");
-    let config = env.create_config();
-    
-    // TODO: Test rolling back a specific file
-    // `laszoo rollback /path/to/file.txt`
-    // Should only rollback changes to that file
+    env.setup_git().expect("Failed to setup git");
+
+    let file1 = env.create_test_file("file1.txt", "file1 v1");
+    let file2 = env.create_test_file("file2.txt", "file2 v1");
+    for file in [&file1, &file2] {
+        let output = env.run_laszoo(&["enroll", "testgroup", file.to_str().unwrap()])
+            .expect("Failed to run laszoo");
+        assert!(output.status.success());
+    }
+    commit_all(&env, "Initial versions");
+
+    let template1 = group_template(&env, "testgroup", &file1);
+    let template2 = group_template(&env, "testgroup", &file2);
+    fs::write(&template1, "file1 v2").unwrap();
+    fs::write(&template2, "file2 v2").unwrap();
+    commit_all(&env, "Update both");
+
+    let output = env.run_laszoo(&["rollback", file1.to_str().unwrap()])
+        .expect("Failed to run laszoo");
+    assert!(output.status.success(), "Rollback failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    assert_eq!(env.read_file(&template1), "file1 v1");
+    assert_eq!(env.read_file(&template2), "file2 v2", "Other templates must not be rolled back");
 }
 
-#[tokio::test]
-#[ignore = "rollback command not yet implemented"]
-async fn test_rollback_with_uncommitted_changes() {
+#[test]
+fn test_rollback_with_uncommitted_changes() {
     let env = TestEnvironment::new("rollback_uncommitted");
-    let config = env.create_config();
-    
-    // TODO: Test behavior when there are uncommitted changes
-    // Should either:
-    // 1. Refuse to rollback and prompt to commit/stash
-    // 2. Stash changes, rollback, then reapply
+    env.setup_git().expect("Failed to setup git");
+
+    let test_file = env.create_test_file("config.txt", "version 1");
+    let output = env.run_laszoo(&["enroll", "testgroup", test_file.to_str().unwrap()])
+        .expect("Failed to run laszoo");
+    assert!(output.status.success());
+    commit_all(&env, "Version 1");
+
+    let template_path = group_template(&env, "testgroup", &test_file);
+    fs::write(&template_path, "version 2").unwrap();
+    commit_all(&env, "Version 2");
+
+    // Uncommitted edit to the template
+    fs::write(&template_path, "work in progress").unwrap();
+
+    let output = env.run_laszoo(&["rollback", "testgroup"])
+        .expect("Failed to run laszoo");
+    assert!(!output.status.success(), "Rollback should refuse to discard uncommitted changes");
+    assert_eq!(env.read_file(&template_path), "work in progress");
+}
+
+#[test]
+fn test_rollback_beyond_history_fails() {
+    let env = TestEnvironment::new("rollback_beyond");
+    env.setup_git().expect("Failed to setup git");
+
+    let test_file = env.create_test_file("config.txt", "version 1");
+    let output = env.run_laszoo(&["enroll", "testgroup", test_file.to_str().unwrap()])
+        .expect("Failed to run laszoo");
+    assert!(output.status.success());
+    commit_all(&env, "Version 1");
+
+    let output = env.run_laszoo(&["rollback", "testgroup", "--commits", "5"])
+        .expect("Failed to run laszoo");
+    assert!(!output.status.success(), "Rollback past the first version should fail");
+
+    let template_path = group_template(&env, "testgroup", &test_file);
+    assert_eq!(env.read_file(&template_path), "version 1");
 }
 
-#[tokio::test]
-#[ignore = "rollback command not yet implemented"]
-async fn test_rollback_affects_local_files() {
-    let env = TestEnvironment::new("rollback_apply");
-    let config = env.create_config();
-    
-    // TODO: After rollback, should also update local files
-    // to match the rolled-back templates
-    // Similar to running `laszoo apply` after rollback
-}
\ No newline at end of file
+#[test]
+fn test_rollback_group_restores_machine_templates_only() {
+    let env = TestEnvironment::new("rollback_machine");
+    env.setup_git().expect("Failed to setup git");
+
+    let shared = env.create_test_file("shared.txt", "shared v1");
+    let local = env.create_test_file("local.txt", "local v1");
+    let output = env.run_laszoo(&["enroll", "testgroup", shared.to_str().unwrap()])
+        .expect("Failed to run laszoo");
+    assert!(output.status.success());
+    let output = env.run_laszoo(&["enroll", "testgroup", "--machine", local.to_str().unwrap()])
+        .expect("Failed to run laszoo");
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+    commit_all(&env, "Version 1");
+
+    let local_abs = local.canonicalize().unwrap();
+    let machine_template = fs::read_dir(env.mfs_mount.join("machines"))
+        .unwrap()
+        .map(|host| host.unwrap().path().join(format!("{}.lasz", local_abs.strip_prefix("/").unwrap().display())))
+        .find(|path| path.exists())
+        .expect("Machine template not created");
+    fs::write(&machine_template, "local v2").unwrap();
+    commit_all(&env, "Version 2");
+
+    // Staged work unrelated to the rollback stays out of its commit
+    fs::write(env.mfs_mount.join("notes.txt"), "todo").unwrap();
+    git(&env, &["add", "notes.txt"]);
+
+    let output = env.run_laszoo(&["rollback", "testgroup"])
+        .expect("Failed to run laszoo");
+    assert!(output.status.success(), "Rollback failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    assert_eq!(env.read_file(&machine_template), "local v1");
+    assert_eq!(env.read_file(&local), "local v1");
+    let committed = git(&env, &["show", "--name-only", "--format=", "HEAD"]);
+    assert!(!committed.contains("notes.txt"), "Unrelated change committed: {}", committed);
+    assert!(committed.contains("local.txt.lasz"), "{}", committed);
+    assert_eq!(git(&env, &["diff", "--cached", "--name-only"]).trim(), "notes.txt");
+}
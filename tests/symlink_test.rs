@@ -0,0 +1,88 @@
+mod common;
+
+use common::*;
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+fn group_template(env: &TestEnvironment, group: &str, link: &Path) -> PathBuf {
+    // Resolve the directory only, the link itself must not be followed
+    let abs_link = link.parent().unwrap().canonicalize().unwrap().join(link.file_name().unwrap());
+    let mut template = env.mfs_mount
+        .join("groups")
+        .join(group)
+        .join(abs_link.strip_prefix("/").unwrap());
+    let name = format!("{}.lasz", template.file_name().unwrap().to_string_lossy());
+    template.set_file_name(name);
+    template
+}
+
+fn create_link(env: &TestEnvironment, name: &str, target: &str) -> PathBuf {
+    let link = env.test_dir.join(name);
+    symlink(target, &link).unwrap();
+    link
+}
+
+#[test]
+fn test_enroll_symlink() {
+    let env = TestEnvironment::new("symlink_enroll");
+    env.create_test_file("sites-available/default", "server {}\n");
+    let link = create_link(&env, "default", "sites-available/default");
+
+    let output = env.run_laszoo(&["enroll", "testgroup", link.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    // The template holds the link target, not the content it points at
+    assert_eq!(env.read_file(&group_template(&env, "testgroup", &link)), "sites-available/default");
+
+    let manifest: serde_json::Value = serde_json::from_str(
+        &env.read_file(&env.mfs_mount.join("groups/testgroup/manifest.json"))
+    ).unwrap();
+    let entry = manifest["entries"].as_object().unwrap().values().next().unwrap();
+    assert_eq!(entry["is_symlink"], true);
+}
+
+#[test]
+fn test_apply_repoints_and_recreates_symlink() {
+    let env = TestEnvironment::new("symlink_apply");
+    let link = create_link(&env, "current", "/opt/app/releases/1");
+
+    let output = env.run_laszoo(&["enroll", "testgroup", link.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    fs::remove_file(&link).unwrap();
+    symlink("/opt/app/releases/2", &link).unwrap();
+
+    let output = env.run_laszoo(&["diff", "testgroup", "--no-color"]).unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout.contains("/opt/app/releases/2"), "{}", stdout);
+
+    let output = env.run_laszoo(&["apply", "testgroup"]).unwrap();
+    assert!(output.status.success(), "Apply failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read_link(&link).unwrap(), PathBuf::from("/opt/app/releases/1"));
+
+    fs::remove_file(&link).unwrap();
+    let output = env.run_laszoo(&["apply", "testgroup"]).unwrap();
+    assert!(output.status.success(), "Apply failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+    assert_eq!(fs::read_link(&link).unwrap(), PathBuf::from("/opt/app/releases/1"));
+}
+
+#[test]
+fn test_templated_symlink_target() {
+    let env = TestEnvironment::new("symlink_template");
+    let link = create_link(&env, "host.conf", "/srv/placeholder.conf");
+
+    let output = env.run_laszoo(&["enroll", "testgroup", link.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    fs::write(group_template(&env, "testgroup", &link), "/srv/{{ hostname }}.conf").unwrap();
+
+    let output = env.run_laszoo(&["apply", "testgroup"]).unwrap();
+    assert!(output.status.success(), "Apply failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(
+        fs::read_link(&link).unwrap(),
+        PathBuf::from(format!("/srv/{}.conf", env.original_hostname))
+    );
+}
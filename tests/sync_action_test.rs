@@ -0,0 +1,90 @@
+mod common;
+
+use common::*;
+
+fn group_manifest(env: &TestEnvironment, group: &str) -> serde_json::Value {
+    serde_json::from_str(&env.read_file(&env.mfs_mount.join("groups").join(group).join("manifest.json"))).unwrap()
+}
+
+fn machine_manifest(env: &TestEnvironment) -> serde_json::Value {
+    serde_json::from_str(&env.read_file(
+        &env.mfs_mount.join("machines").join(&env.original_hostname).join("manifest.json")
+    )).unwrap()
+}
+
+#[test]
+fn test_action_is_stored_per_file() {
+    let env = TestEnvironment::new("sync_action_per_file");
+    let strict = env.create_test_file("strict.conf", "a\n");
+    let loose = env.create_test_file("loose.conf", "b\n");
+    let strict_path = strict.canonicalize().unwrap();
+    let loose_path = loose.canonicalize().unwrap();
+
+    let output = env.run_laszoo(&["enroll", "testgroup", strict.to_str().unwrap(), "--action", "rollback"]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+    let output = env.run_laszoo(&["enroll", "testgroup", loose.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    // Enrolling the second file leaves the first file's action alone
+    let manifest = group_manifest(&env, "testgroup");
+    assert_eq!(manifest["entries"][strict_path.to_str().unwrap()]["sync_action"], "rollback");
+    assert!(manifest["entries"][loose_path.to_str().unwrap()].get("sync_action").is_none());
+    assert!(!env.mfs_mount.join("groups/testgroup/config.json").exists());
+
+    let output = env.run_laszoo(&["status"]).unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(&format!("{} [rollback]", strict_path.display())), "{}", stdout);
+}
+
+#[test]
+fn test_set_action_with_machine_override() {
+    let env = TestEnvironment::new("sync_action_set");
+    let file = env.create_test_file("app.conf", "a\n");
+    let file_path = file.canonicalize().unwrap();
+
+    let output = env.run_laszoo(&["enroll", "testgroup", file.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let output = env.run_laszoo(&["set-action", file.to_str().unwrap(), "drift"]).unwrap();
+    assert!(output.status.success(), "set-action failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(group_manifest(&env, "testgroup")["entries"][file_path.to_str().unwrap()]["sync_action"], "drift");
+
+    let output = env.run_laszoo(&["set-action", file.to_str().unwrap(), "freeze", "--machine"]).unwrap();
+    assert!(output.status.success(), "set-action failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(machine_manifest(&env)["entries"][file_path.to_str().unwrap()]["sync_action"], "freeze");
+    assert_eq!(group_manifest(&env, "testgroup")["entries"][file_path.to_str().unwrap()]["sync_action"], "drift");
+
+    // The override survives re-applying the group
+    let output = env.run_laszoo(&["apply", "testgroup"]).unwrap();
+    assert!(output.status.success(), "Apply failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let output = env.run_laszoo(&["status"]).unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(&format!("{} [freeze]", file_path.display())), "{}", stdout);
+}
+
+#[test]
+fn test_set_action_on_unenrolled_file_fails() {
+    let env = TestEnvironment::new("sync_action_unenrolled");
+    let file = env.create_test_file("other.conf", "a\n");
+
+    let output = env.run_laszoo(&["set-action", file.to_str().unwrap(), "rollback"]).unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("not enrolled"));
+}
+
+#[test]
+fn test_enroll_action_in_enrolled_directory_fails_before_enrolling() {
+    let env = TestEnvironment::new("sync_action_directory");
+    let dir = env.create_test_file("conf.d/a.conf", "a\n").parent().unwrap().canonicalize().unwrap();
+    let output = env.run_laszoo(&["enroll", "testgroup", dir.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let file = env.create_test_file("conf.d/b.conf", "b\n").canonicalize().unwrap();
+    let output = env.run_laszoo(&["enroll", "testgroup", file.to_str().unwrap(), "--action", "freeze"]).unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("part of enrolled directory"));
+
+    let template = env.mfs_mount.join("groups/testgroup").join(file.strip_prefix("/").unwrap()).with_extension("conf.lasz");
+    assert!(!template.exists(), "File was enrolled despite the refused action");
+}
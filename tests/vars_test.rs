@@ -0,0 +1,59 @@
+mod common;
+
+use common::*;
+use std::fs;
+
+fn run_ok(env: &TestEnvironment, args: &[&str]) -> String {
+    let output = env.run_laszoo(args).expect("Failed to run laszoo");
+    assert!(output.status.success(), "{:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+#[test]
+fn test_vars_set_get_list() {
+    let env = TestEnvironment::new("vars_set_get");
+
+    run_ok(&env, &["vars", "set", "--group", "web", "nginx.port", "8080"]);
+    run_ok(&env, &["vars", "set", "--group", "web", "domain", "example.com"]);
+
+    let value = run_ok(&env, &["vars", "get", "--group", "web", "nginx.port"]);
+    assert_eq!(value.trim(), "8080");
+
+    let listing = run_ok(&env, &["vars", "list", "--group", "web"]);
+    assert!(listing.contains("domain = \"example.com\""), "{}", listing);
+    assert!(listing.contains("nginx.port = 8080"), "{}", listing);
+
+    let vars_file = env.mfs_mount.join("groups/web/vars.toml");
+    assert!(vars_file.exists(), "Group variables should be stored in vars.toml");
+
+    let output = env.run_laszoo(&["vars", "get", "--group", "web", "missing"]).unwrap();
+    assert!(!output.status.success(), "Unset variables should be an error");
+}
+
+#[test]
+fn test_vars_machine_overrides_group() {
+    let env = TestEnvironment::new("vars_precedence");
+    let test_file = env.create_test_file("app.conf", "placeholder\n");
+
+    let output = env.run_laszoo(&["enroll", "web", test_file.to_str().unwrap()]).unwrap();
+    assert!(output.status.success(), "Enroll failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    fs::write(
+        env.mfs_mount.join("groups/web/vars.json"),
+        r#"{"port": 80, "domain": "example.com"}"#,
+    ).unwrap();
+    run_ok(&env, &["vars", "set", "--machine", &env.original_hostname, "port", "8080"]);
+
+    let resolved = run_ok(&env, &["vars", "get", "--group", "web", "--resolved", "port"]);
+    assert_eq!(resolved.trim(), "8080");
+
+    let template_path = group_template(&env, "web", &test_file);
+    fs::write(&template_path, "host {{ hostname }}.{{ domain }}\nport {{ port }}\n").unwrap();
+
+    run_ok(&env, &["apply", "web"]);
+
+    assert_eq!(
+        env.read_file(&test_file),
+        format!("host {}.example.com\nport 8080\n", env.original_hostname)
+    );
+}